@@ -0,0 +1,149 @@
+use crate::settings::ConnectionConfig;
+use std::{collections::HashMap, fmt};
+
+pub mod pool;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendError {
+    /// No factory is registered under the adapter name in the connection config.
+    UnknownAdapter(String),
+    /// The connection config is unusable for this adapter.
+    Config(String),
+    /// The warehouse rejected or failed a statement.
+    Execution(String),
+    /// Every connection a pool may open is already in use.
+    PoolExhausted(usize),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::UnknownAdapter(name) => write!(f, "no backend registered as {name:?}"),
+            BackendError::Config(e) => write!(f, "invalid connection config: {e}"),
+            BackendError::Execution(e) => write!(f, "statement failed: {e}"),
+            BackendError::PoolExhausted(size) => {
+                write!(f, "all {size} pooled connections are in use")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// A warehouse orbital can run compiled SQL against. Backends are moved between threads,
+/// e.g. out of a [`BackendPool`](pool::BackendPool) shared by the executor's workers.
+pub trait Backend: Send {
+    /// The adapter name this backend was registered under.
+    fn adapter(&self) -> &str;
+
+    /// Runs a single statement, returning the number of rows it affected.
+    fn execute(&mut self, sql: &str) -> Result<u64, BackendError>;
+
+    /// Runs a query returning a single count, such as `SELECT count(*) FROM ...`.
+    fn fetch_count(&mut self, sql: &str) -> Result<u64, BackendError>;
+
+    /// Runs a query returning a single value, such as `SELECT max(loaded_at) FROM ...`, as
+    /// text. `None` if it returned no rows or a null.
+    fn fetch_value(&mut self, sql: &str) -> Result<Option<String>, BackendError>;
+
+    /// Whether one statement can read relations from other databases than the one it
+    /// writes to, e.g. `db.schema.table` on Snowflake. Most warehouses can't.
+    fn supports_cross_database(&self) -> bool {
+        false
+    }
+
+    /// Whether the connection still works, checked before a pooled backend is reused. Runs
+    /// `SELECT 1` unless overridden.
+    fn is_healthy(&mut self) -> bool {
+        self.execute("SELECT 1").is_ok()
+    }
+
+    /// Whether `database.schema.table` exists.
+    fn probe_table(
+        &mut self,
+        database: &str,
+        schema: &str,
+        table: &str,
+    ) -> Result<bool, BackendError>;
+
+    /// Whether `database.schema.table` exists and has `column`.
+    fn probe_column(
+        &mut self,
+        database: &str,
+        schema: &str,
+        table: &str,
+        column: &str,
+    ) -> Result<bool, BackendError>;
+}
+
+type Constructor =
+    Box<dyn Fn(&ConnectionConfig) -> Result<Box<dyn Backend>, BackendError> + Send + Sync>;
+
+/// Maps `ConnectionConfig.adapter` names to backend constructors, so adapters can be added
+/// without touching orbital itself.
+#[derive(Default)]
+pub struct BackendFactory {
+    constructors: HashMap<String, Constructor>,
+}
+
+impl BackendFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `constructor` under `adapter`, replacing any earlier registration.
+    pub fn register_backend<F>(&mut self, adapter: impl Into<String>, constructor: F) -> &mut Self
+    where
+        F: Fn(&ConnectionConfig) -> Result<Box<dyn Backend>, BackendError> + Send + Sync + 'static,
+    {
+        self.constructors
+            .insert(adapter.into(), Box::new(constructor));
+        self
+    }
+
+    /// Whether a backend is registered under `adapter`.
+    pub fn supports(&self, adapter: &str) -> bool {
+        self.constructors.contains_key(adapter)
+    }
+
+    /// Every registered adapter name, sorted.
+    pub fn adapters(&self) -> Vec<&str> {
+        let mut adapters: Vec<_> = self.constructors.keys().map(String::as_str).collect();
+        adapters.sort_unstable();
+        adapters
+    }
+
+    pub fn create(&self, config: &ConnectionConfig) -> Result<Box<dyn Backend>, BackendError> {
+        let constructor = self
+            .constructors
+            .get(config.adapter.as_str())
+            .ok_or_else(|| BackendError::UnknownAdapter(config.adapter.to_string()))?;
+        constructor(config)
+    }
+}
+
+#[cfg(test)]
+mod test_backend {
+    use super::*;
+    use crate::testing::StubBackend;
+    use figment::providers::{Format, Yaml};
+
+    #[test]
+    fn test_registered_adapter_is_constructed_from_config() {
+        let mut factory = BackendFactory::new();
+        factory.register_backend("mock", |_| Ok(Box::new(StubBackend::new("mock"))));
+        let parse = |yaml: &str| {
+            figment::Figment::from(Yaml::string(yaml))
+                .extract::<ConnectionConfig>()
+                .unwrap()
+        };
+
+        let mut backend = factory.create(&parse("adapter: mock")).unwrap();
+        assert_eq!(backend.adapter(), "mock");
+        assert_eq!(backend.execute("SELECT 1"), Ok(0));
+        assert_eq!(
+            factory.create(&parse("adapter: oracle")).err(),
+            Some(BackendError::UnknownAdapter(String::from("oracle")))
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use super::{Backend, BackendError, BackendFactory};
+use crate::settings::{ConnectionConfig, PoolConfig};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+type Connect<'a> = Box<dyn Fn() -> Result<Box<dyn Backend>, BackendError> + Send + Sync + 'a>;
+
+// A panic while holding one of these locks leaves the counts it guards consistent, so a
+// poisoned lock is used as is.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Caps how many connections are in use at once across every [`BackendPool`] sharing it,
+/// e.g. from `--max-connections`. Warehouses usually allow far fewer than the number of
+/// models a layer could build in parallel.
+pub struct ConnectionLimit {
+    max: usize,
+    in_use: Mutex<usize>,
+    released: Condvar,
+}
+
+impl ConnectionLimit {
+    /// Allows `max` connections at once, or one if `max` is 0.
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            in_use: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Waits until one of the `max` permits is free, then takes it until the permit drops.
+    pub fn acquire(&self) -> ConnectionPermit<'_> {
+        let mut in_use = self
+            .released
+            .wait_while(lock(&self.in_use), |in_use| *in_use >= self.max)
+            .unwrap_or_else(PoisonError::into_inner);
+        *in_use += 1;
+        ConnectionPermit { limit: self }
+    }
+}
+
+/// One connection's share of a [`ConnectionLimit`], given back when dropped.
+pub struct ConnectionPermit<'l> {
+    limit: &'l ConnectionLimit,
+}
+
+impl Drop for ConnectionPermit<'_> {
+    fn drop(&mut self) {
+        *lock(&self.limit.in_use) -= 1;
+        self.limit.released.notify_one();
+    }
+}
+
+/// Keeps a connection's backends open between uses, for long-running modes like `serve`.
+/// At most `max_size` are open at once. An idle backend is only handed out again if it
+/// hasn't sat past `idle_timeout` and still passes [`Backend::is_healthy`]; otherwise it's
+/// closed and another tried, so a connection the warehouse dropped is replaced instead of
+/// failing a model mid-run. Backends can be checked out from several threads at once.
+pub struct BackendPool<'a> {
+    connect: Connect<'a>,
+    max_size: usize,
+    idle_timeout: Option<Duration>,
+    limit: Option<&'a ConnectionLimit>,
+    state: Mutex<PoolState>,
+    returned: Condvar, // Signalled whenever a backend goes back idle or is closed
+}
+
+#[derive(Default)]
+struct PoolState {
+    idle: Vec<(Box<dyn Backend>, Instant)>, // Most recently returned last
+    open: usize,
+}
+
+impl<'a> BackendPool<'a> {
+    pub fn new(
+        connect: impl Fn() -> Result<Box<dyn Backend>, BackendError> + Send + Sync + 'a,
+        config: PoolConfig,
+    ) -> Self {
+        Self {
+            connect: Box::new(connect),
+            max_size: config.max_size,
+            idle_timeout: config.idle_timeout.map(Duration::from_secs),
+            limit: None,
+            state: Mutex::new(PoolState::default()),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Opens backends for `connection` through `factory`, sized by its `pool` settings.
+    pub fn for_connection(factory: &'a BackendFactory, connection: &'a ConnectionConfig) -> Self {
+        Self::new(move || factory.create(connection), connection.pool)
+    }
+
+    /// Every checkout first waits for a permit from `limit`, held until the backend is
+    /// returned, so pools sharing it never have more than its `max` backends in use between
+    /// them.
+    pub fn with_limit(mut self, limit: &'a ConnectionLimit) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Backends currently open, in use or idle.
+    pub fn open(&self) -> usize {
+        lock(&self.state).open
+    }
+
+    /// A healthy backend, reusing the most recently returned idle one that passes its
+    /// checks, or opening one. Waits for a permit first if the pool has a limit. Fails if
+    /// the pool is full and nothing idle can be reused.
+    pub fn acquire(&self) -> Result<PooledBackend<'_, 'a>, BackendError> {
+        self.checkout(false)
+    }
+
+    /// [`BackendPool::acquire`], but while the pool is full it waits for a backend to be
+    /// returned instead of failing, e.g. for workers that may outnumber `max_size`.
+    pub fn acquire_waiting(&self) -> Result<PooledBackend<'_, 'a>, BackendError> {
+        self.checkout(true)
+    }
+
+    fn checkout(&self, wait: bool) -> Result<PooledBackend<'_, 'a>, BackendError> {
+        let permit = self.limit.map(ConnectionLimit::acquire);
+        loop {
+            // Checked without holding the lock, as a health check is a round trip.
+            let popped = lock(&self.state).idle.pop();
+            if let Some((mut backend, since)) = popped {
+                let expired = self
+                    .idle_timeout
+                    .is_some_and(|timeout| since.elapsed() >= timeout);
+                if !expired && backend.is_healthy() {
+                    return Ok(PooledBackend {
+                        pool: self,
+                        backend: Some(backend),
+                        _permit: permit,
+                    });
+                }
+                lock(&self.state).open -= 1;
+                self.returned.notify_one();
+                continue;
+            }
+            let mut state = lock(&self.state);
+            if state.open < self.max_size {
+                // Counted before connecting, so threads connecting at once can't overfill it.
+                state.open += 1;
+                break;
+            }
+            if !wait {
+                return Err(BackendError::PoolExhausted(self.max_size));
+            }
+            // Then take whatever freed up, idle backend or room to open one.
+            drop(
+                self.returned
+                    .wait_while(state, |state| {
+                        state.idle.is_empty() && state.open >= self.max_size
+                    })
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+        }
+        match (self.connect)() {
+            Ok(backend) => Ok(PooledBackend {
+                pool: self,
+                backend: Some(backend),
+                _permit: permit,
+            }),
+            Err(e) => {
+                lock(&self.state).open -= 1;
+                self.returned.notify_one();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A backend borrowed from a [`BackendPool`], returned to it as idle when dropped.
+pub struct PooledBackend<'p, 'a> {
+    pool: &'p BackendPool<'a>,
+    backend: Option<Box<dyn Backend>>, // Only `None` while being dropped
+    _permit: Option<ConnectionPermit<'a>>, // Released after the backend is back in the pool
+}
+
+impl Deref for PooledBackend<'_, '_> {
+    type Target = dyn Backend;
+
+    fn deref(&self) -> &Self::Target {
+        self.backend
+            .as_deref()
+            .expect("backend is present until dropped")
+    }
+}
+
+impl DerefMut for PooledBackend<'_, '_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.backend
+            .as_deref_mut()
+            .expect("backend is present until dropped")
+    }
+}
+
+impl Drop for PooledBackend<'_, '_> {
+    fn drop(&mut self) {
+        if let Some(backend) = self.backend.take() {
+            lock(&self.pool.state).idle.push((backend, Instant::now()));
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_pool {
+    use super::*;
+    use crate::testing::StubBackend;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_unhealthy_backend_is_recycled() {
+        let opened = AtomicUsize::new(0);
+        let stub = StubBackend::new("flaky").health_checked();
+        let pool = BackendPool::new(
+            || {
+                // Each backend reports its own number as rows; the first fails its first check.
+                let id = opened.fetch_add(1, Ordering::SeqCst) + 1;
+                let backend = stub.clone().with_rows(id as u64);
+                Ok(Box::new(if id == 1 {
+                    backend.failing_once_on("SELECT 1")
+                } else {
+                    backend
+                }))
+            },
+            PoolConfig {
+                max_size: 1,
+                idle_timeout: None,
+            },
+        );
+
+        let first = pool.acquire().unwrap();
+        assert!(matches!(
+            pool.acquire(),
+            Err(BackendError::PoolExhausted(1))
+        ));
+        drop(first);
+        // The idle backend fails its health check, so it's closed and a new one opened.
+        let mut second = pool.acquire().unwrap();
+        assert_eq!(second.execute("SELECT id"), Ok(2));
+        drop(second);
+        assert_eq!((opened.load(Ordering::SeqCst), pool.open()), (2, 1));
+        // The replacement passes its check and is reused.
+        let mut third = pool.acquire().unwrap();
+        assert_eq!(third.execute("SELECT id"), Ok(2));
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            stub.executed(),
+            ["SELECT 1", "SELECT id", "SELECT 1", "SELECT id"]
+        );
+    }
+
+    #[test]
+    fn test_expired_backend_is_replaced() {
+        let opened = AtomicUsize::new(0);
+        let pool = BackendPool::new(
+            || {
+                opened.fetch_add(1, Ordering::SeqCst);
+                Ok(Box::new(crate::testing::InMemoryBackend::new()))
+            },
+            PoolConfig {
+                max_size: 1,
+                idle_timeout: Some(0),
+            },
+        );
+        drop(pool.acquire().unwrap());
+        drop(pool.acquire().unwrap());
+        assert_eq!((opened.load(Ordering::SeqCst), pool.open()), (2, 1));
+    }
+
+    #[test]
+    fn test_checkouts_wait_for_a_shared_connection_permit() {
+        let limit = ConnectionLimit::new(1);
+        let config = PoolConfig {
+            max_size: 2,
+            idle_timeout: None,
+        };
+        let connect = || Ok(Box::new(crate::testing::InMemoryBackend::new()) as Box<dyn Backend>);
+        let (warehouse, ledger) = (
+            BackendPool::new(connect, config).with_limit(&limit),
+            BackendPool::new(connect, config).with_limit(&limit),
+        );
+        let first = warehouse.acquire().unwrap();
+        let returned = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| {
+                let second = ledger.acquire().unwrap();
+                // Only possible once `first` went back.
+                assert_eq!(returned.load(Ordering::SeqCst), 1);
+                drop(second);
+            });
+            std::thread::sleep(Duration::from_millis(20));
+            returned.store(1, Ordering::SeqCst);
+            drop(first);
+            waiting.join().unwrap();
+        });
+        assert_eq!((warehouse.open(), ledger.open()), (1, 1));
+    }
+}
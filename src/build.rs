@@ -0,0 +1,1486 @@
+use crate::{
+    backend::{
+        pool::{BackendPool, ConnectionLimit, PooledBackend},
+        BackendError, BackendFactory,
+    },
+    executor::{Executor, FailurePolicy, NodeStatus, RunReport},
+    manifest::Manifest,
+    query_graph::{
+        graph::GraphError,
+        query::{
+            resolve_extends, DuplicateId, ExtendsError, PrepareError, Query, QueryCollection,
+            QueryKind, QueryMap, QueryName, RawQuery, ShowError, UndeclaredReference,
+        },
+        selector::SelectorSet,
+        source::{seed_names, FilesystemSource, SourceError, UnreadableFile},
+        GraphMeta,
+    },
+    settings::{
+        parse_config_block, ConfigError, Materialization, Project, ResourceConfig, VarError,
+    },
+    sql::{
+        create_statements, drop_statement, format_sql, quote_ident, IncrementalSql, SnapshotSql,
+    },
+    target::{layered_names, TargetDir},
+    watermark::WatermarkStore,
+};
+use fnv::{FnvHashMap, FnvHashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::Path,
+    time::SystemTime,
+};
+
+/// A project whose config, models and graph all checked out.
+pub struct BuiltProject {
+    pub project: Project,
+    pub meta: GraphMeta,
+    /// Model files that were found but couldn't be read, and so aren't in `meta`.
+    pub skipped: Vec<UnreadableFile>,
+    /// Every seed, declared under `seeds` or found as a file under `seed_path`, sorted.
+    pub seeds: Vec<String>,
+    // Node ids of the models a run is restricted to; every model if `None`
+    selected: Option<FnvHashSet<u64>>,
+}
+
+impl BuiltProject {
+    /// Models configured as ephemeral, which are inlined into their dependents instead of
+    /// being built.
+    pub fn ephemeral(&self) -> HashSet<QueryName> {
+        self.project
+            .models
+            .iter()
+            .filter(|m| m.materialized == Materialization::Ephemeral)
+            .map(|m| QueryName::from(m.name.as_ref().as_str()))
+            .collect()
+    }
+
+    /// The relation every node a model reads from is materialized in, by name, so that
+    /// references in compiled SQL name what was actually built: an enabled model's `alias`
+    /// if it has one, and every name cased by the project's `identifier_case`, as
+    /// [`Project::relation_sql`] does for the model's own DDL.
+    pub fn relation_aliases(&self) -> QueryMap<QueryName, smartstring::alias::String> {
+        let case = self.project.identifier_case;
+        let collection = self.meta.collection();
+        let mut aliases: QueryMap<_, _> = collection
+            .values()
+            .filter_map(|node| match node {
+                QueryKind::Query(q) => Some(q.case_aliases(case)),
+                QueryKind::TableQuery(_) => None,
+            })
+            .flatten()
+            .collect();
+        for model in self.project.models.iter().filter(|m| m.enabled) {
+            let name = QueryName::from(model.name.as_ref().as_str());
+            if matches!(collection.get(&name), Some(QueryKind::Query(_))) {
+                aliases.insert(name, case.apply(model.relation_name().as_ref()).into());
+            }
+        }
+        aliases
+    }
+
+    /// The SQL `model` would run, with ephemeral upstreams inlined and every other upstream
+    /// read from its [`BuiltProject::relation_aliases`] relation.
+    pub fn show(&self, model: &QueryName) -> Result<String, ShowError> {
+        self.meta.collection().show(
+            model,
+            &self.ephemeral(),
+            &self.relation_aliases(),
+            &self.project.dialect(),
+        )
+    }
+
+    /// Writes every non-ephemeral model's SQL, as [`BuiltProject::show`] gives it laid out by
+    /// [`format_sql`], then the manifest, under `target`. With `layered`, each file is named after its execution layer
+    /// as well, as in [`layered_names`]. With `sql_header`, each file starts with the model's
+    /// [`Query::sql_header`](crate::query_graph::query::Query::sql_header) stamped with that
+    /// time. A file that can't be written doesn't stop the rest; each failure is returned as
+    /// a `<name>: <error>` line.
+    pub fn compile(
+        &self,
+        target: &TargetDir,
+        layered: bool,
+        sql_header: Option<SystemTime>,
+    ) -> Vec<String> {
+        let collection = self.meta.collection();
+        let (ephemeral, aliases) = (self.ephemeral(), self.relation_aliases());
+        let dialect = self.project.dialect();
+        let layered_names = if layered {
+            layered_names(&self.meta)
+        } else {
+            HashMap::new()
+        };
+        let mut failures = Vec::new();
+        for node in collection.sorted_values() {
+            let QueryKind::Query(query) = node else {
+                continue;
+            };
+            let name = node.name();
+            if ephemeral.contains(name) {
+                continue;
+            }
+            let written = collection
+                .show(name, &ephemeral, &aliases, &dialect)
+                .map(|sql| format_sql(&sql, query.dialect(&dialect).clone()))
+                .map(|sql| match sql_header {
+                    Some(at) => format!("{}\n{sql}", query.sql_header(at)),
+                    None => sql,
+                })
+                .map_err(|e| e.to_string())
+                .and_then(|sql| {
+                    let file = layered_names
+                        .get(name)
+                        .map_or(name.as_str(), |f| f.as_str());
+                    target.write_compiled(file, &sql).map_err(|e| e.to_string())
+                });
+            if let Err(e) = written {
+                failures.push(format!("{}: {e}", name.as_str()));
+            }
+        }
+        let manifest = Manifest::from_collection(collection).with_materializations(|name| {
+            let model = self
+                .project
+                .models
+                .iter()
+                .find(|m| m.name.as_ref() == name)?;
+            Some(model.materialized.name().to_string())
+        });
+        if let Err(e) = target.write_manifest(&manifest) {
+            failures.push(format!("{}: {e}", target.manifest_path().display()));
+        }
+        failures
+    }
+
+    /// A `DROP` for every enabled model that has a relation, by node id, naming the relation
+    /// as [`Project::relation_sql`] does.
+    pub fn drop_statements(&self) -> FnvHashMap<u64, Vec<String>> {
+        self.project
+            .models
+            .iter()
+            .filter_map(|model| {
+                let query = self
+                    .meta
+                    .collection()
+                    .get(&QueryName::from(model.name.as_ref().as_str()))?;
+                let sql = drop_statement(&self.project.relation_sql(model), &model.materialized)?;
+                Some((**query.id(), vec![sql]))
+            })
+            .collect()
+    }
+
+    /// Node ids of every enabled, selected model that isn't ephemeral, i.e. that has a
+    /// relation in the warehouse.
+    pub fn materialized(&self) -> FnvHashSet<u64> {
+        self.models()
+            .filter(|(_, _, model)| model.materialized != Materialization::Ephemeral)
+            .map(|(node_id, _, _)| node_id)
+            .collect()
+    }
+
+    /// Each model's configured `tags`, by name, for `tag:` selectors.
+    pub fn tags(&self) -> FnvHashMap<QueryName, Vec<String>> {
+        self.project
+            .models
+            .iter()
+            .map(|m| {
+                let tags = m.tags.iter().map(|t| t.to_string()).collect();
+                (QueryName::from(m.name.as_ref().as_str()), tags)
+            })
+            .collect()
+    }
+
+    /// Restricts a run to the models `select` picks, or every one without it, less those
+    /// `exclude` picks, as [`GraphMeta::select_excluding`] does. The rest are left out of
+    /// [`BuiltProject::run_statements`], [`BuiltProject::configure`] and
+    /// [`BuiltProject::advance_watermarks`], so models reading them use their relations as
+    /// they stand.
+    pub fn with_selection(
+        mut self,
+        select: Option<&SelectorSet>,
+        exclude: Option<&SelectorSet>,
+    ) -> Self {
+        let collection = self.meta.collection();
+        let selected = self
+            .meta
+            .select_excluding(select, exclude, &self.tags())
+            .into_iter()
+            .filter_map(|name| collection.get(name).map(|node| **node.id()))
+            .collect();
+        self.selected = Some(selected);
+        self
+    }
+
+    // Every enabled model that made it into the graph and is selected, with its node id and
+    // query.
+    fn models(&self) -> impl Iterator<Item = (u64, &Query, &ResourceConfig)> {
+        self.project.models.iter().filter_map(|model| {
+            let name = QueryName::from(model.name.as_ref().as_str());
+            let node = self.meta.collection().get(&name)?;
+            if !self.selected.as_ref().is_none_or(|s| s.contains(node.id())) {
+                return None;
+            }
+            match node {
+                QueryKind::Query(query) => Some((**node.id(), query, model)),
+                QueryKind::TableQuery(_) => None,
+            }
+        })
+    }
+
+    /// The statements building each of [`BuiltProject::materialized`], by node id, from the
+    /// SQL [`BuiltProject::show`] gives. Tables are rebuilt and views replaced. Incremental
+    /// models and snapshots are created in full while `exists` says their relation, as
+    /// [`ResourceConfig::relation_parts`], is missing, and merged into after that, which
+    /// needs their output columns. An incremental model with a `watermark` only merges rows
+    /// past the one `watermarks` has for it, if any. A model that can't be compiled doesn't
+    /// stop the rest; each failure is returned as a `<name>: <error>` line.
+    pub fn run_statements(
+        &self,
+        watermarks: Option<&WatermarkStore>,
+        mut exists: impl FnMut(u64, &[String; 3]) -> Result<bool, BackendError>,
+    ) -> (FnvHashMap<u64, Vec<String>>, Vec<String>) {
+        let collection = self.meta.collection();
+        let (ephemeral, aliases) = (self.ephemeral(), self.relation_aliases());
+        let dialect = self.project.dialect();
+        let (mut statements, mut failures) = (FnvHashMap::default(), Vec::new());
+        for (node_id, query, model) in self.models() {
+            let name = QueryName::from(model.name.as_ref().as_str());
+            if ephemeral.contains(&name) {
+                continue;
+            }
+            let built = collection
+                .show(&name, &ephemeral, &aliases, &dialect)
+                .map_err(|e| e.to_string())
+                .and_then(|select| {
+                    let target = self.project.relation_sql(model);
+                    let mut merge_into = || {
+                        let columns = query.output_columns().ok_or(
+                            "can't tell which columns to merge; end the query with a select",
+                        )?;
+                        let parts = model.relation_parts(
+                            &self.project.schema_naming,
+                            &self.project.target_name,
+                            self.project.identifier_case,
+                        );
+                        let exists = exists(node_id, &parts).map_err(|e| e.to_string())?;
+                        Ok::<_, String>((columns, exists))
+                    };
+                    let dialect = query.dialect(&dialect);
+                    Ok(match &model.materialized {
+                        Materialization::Incremental {
+                            unique_key,
+                            filter,
+                            watermark,
+                        } => {
+                            let (columns, exists) = merge_into()?;
+                            let watermark = watermark.as_deref().and_then(|column| {
+                                Some((column, watermarks?.get(model.name.as_ref())?))
+                            });
+                            IncrementalSql {
+                                target: &target,
+                                select: &select,
+                                columns: &as_strs(&columns),
+                                unique_key: &as_strs(unique_key),
+                                filter: filter.as_deref(),
+                                watermark,
+                            }
+                            .statements(dialect, exists)
+                        }
+                        Materialization::Snapshot {
+                            strategy,
+                            unique_key,
+                            updated_at,
+                        } => {
+                            let (columns, exists) = merge_into()?;
+                            SnapshotSql {
+                                target: &target,
+                                select: &select,
+                                columns: &as_strs(&columns),
+                                unique_key: &as_strs(unique_key),
+                                updated_at: updated_at.as_str(),
+                                strategy: *strategy,
+                            }
+                            .statements(dialect, exists)
+                        }
+                        materialized => create_statements(&target, &select, materialized),
+                    })
+                });
+            match built {
+                Ok(sql) => {
+                    statements.insert(node_id, sql);
+                }
+                Err(e) => failures.push(format!("{}: {e}", name.as_str())),
+            }
+        }
+        (statements, failures)
+    }
+
+    /// `executor` with the project's settings for a run applied: `analysis_only`,
+    /// `on_run_start`/`on_run_end`, and each model's `pre_hook`/`post_hook`, `weight`,
+    /// `expected_duration`/`expected_rows` and `tests.row_count`, checked against its relation.
+    pub fn configure<'a>(&self, executor: Executor<'a>) -> Executor<'a> {
+        let hooks = self
+            .models()
+            .map(|(node_id, _, model)| (node_id, model.hooks()))
+            .collect();
+        let weights = self
+            .models()
+            .map(|(node_id, _, model)| (node_id, model.weight))
+            .collect();
+        let thresholds = self
+            .models()
+            .map(|(node_id, _, model)| (node_id, model.thresholds()))
+            .collect();
+        let row_counts = self
+            .models()
+            .filter(|(_, _, model)| model.materialized != Materialization::Ephemeral)
+            .filter_map(|(node_id, _, model)| {
+                let bounds = model.tests.row_count?;
+                Some((node_id, (self.project.relation_sql(model), bounds)))
+            })
+            .collect();
+        executor
+            .analysis_only(self.project.analysis_only)
+            .with_run_hooks(&self.project.on_run_start, &self.project.on_run_end)
+            .with_node_hooks(hooks)
+            .with_weights(weights)
+            .with_thresholds(thresholds)
+            .with_row_counts(row_counts)
+    }
+
+    /// Moves each incremental model's stored watermark up to the largest value of its
+    /// `watermark` column, fetched by running the given query through `max`, if the model
+    /// succeeded in `report`. One that can't be read or recorded doesn't stop the rest; each
+    /// failure is returned as a `<name>: <error>` line.
+    pub fn advance_watermarks(
+        &self,
+        store: &mut WatermarkStore,
+        report: &RunReport,
+        mut max: impl FnMut(u64, &str) -> Result<Option<String>, BackendError>,
+    ) -> Vec<String> {
+        let dialect = self.project.dialect();
+        let mut failures = Vec::new();
+        for (node_id, query, model) in self.models() {
+            let Materialization::Incremental {
+                watermark: Some(column),
+                ..
+            } = &model.materialized
+            else {
+                continue;
+            };
+            if report.status(node_id) != Some(&NodeStatus::Succeeded) {
+                continue;
+            }
+            let sql = format!(
+                "SELECT max({}) FROM {}",
+                quote_ident(column, query.dialect(&dialect)),
+                self.project.relation_sql(model)
+            );
+            let name = model.name.as_ref().as_str();
+            let advanced = max(node_id, &sql)
+                .map_err(|e| e.to_string())
+                .and_then(|value| match value {
+                    Some(value) => store
+                        .advance(report, node_id, name, &value)
+                        .map_err(|e| e.to_string()),
+                    // Nothing merged yet.
+                    None => Ok(false),
+                });
+            if let Err(e) = advanced {
+                failures.push(format!("{name}: {e}"));
+            }
+        }
+        failures
+    }
+
+    /// Pools for the connections `nodes` are built through, one for each database with its
+    /// own entry in `connections` and one for `connection` covering the rest, with no more
+    /// connections in use at once between them than `limit` allows. A backend is opened from
+    /// each up front, so a connection that can't be made fails here rather than mid-run, as
+    /// does a model with no connection at all.
+    pub fn pools<'p>(
+        &'p self,
+        factory: &'p BackendFactory,
+        nodes: &FnvHashSet<u64>,
+        limit: Option<&'p ConnectionLimit>,
+    ) -> Result<ConnectionPools<'p>, BackendError> {
+        // Keyed by the database when it has its own entry in `connections`, else `None` for
+        // the project's `connection`.
+        let mut groups: Vec<(Option<&str>, Vec<u64>)> = Vec::new();
+        for model in &self.project.models {
+            let Some(query) = self
+                .meta
+                .collection()
+                .get(&QueryName::from(model.name.as_ref().as_str()))
+            else {
+                continue;
+            };
+            if !nodes.contains(&**query.id()) {
+                continue;
+            }
+            let database = model.database.as_ref().as_str();
+            if self.project.connection_for(database).is_none() {
+                return Err(BackendError::Config(format!(
+                    "no connection for database {database:?}"
+                )));
+            }
+            let key = self
+                .project
+                .connections
+                .contains_key(database)
+                .then_some(database);
+            match groups.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, nodes)) => nodes.push(**query.id()),
+                None => groups.push((key, vec![**query.id()])),
+            }
+        }
+        let pools: Vec<_> = groups
+            .into_iter()
+            .map(|(key, nodes)| {
+                // Checked above.
+                let connection = match key {
+                    Some(database) => &self.project.connections[database],
+                    None => self.project.connection.as_ref().unwrap(),
+                };
+                let pool = BackendPool::for_connection(factory, connection);
+                let pool = match limit {
+                    Some(limit) => pool.with_limit(limit),
+                    None => pool,
+                };
+                (pool, nodes)
+            })
+            .collect();
+        for (pool, _) in &pools {
+            pool.acquire()?;
+        }
+        Ok(ConnectionPools { pools })
+    }
+
+    /// Runs `statements` in teardown order, each model's through a pool for the connection
+    /// of its database. Models in the same layer are dropped in parallel, with no more
+    /// connections in use at once than `limit` allows. Fails before anything runs if a model
+    /// has no connection or a backend can't be created.
+    pub fn teardown(
+        &self,
+        factory: &BackendFactory,
+        statements: &FnvHashMap<u64, Vec<String>>,
+        limit: Option<&ConnectionLimit>,
+    ) -> Result<RunReport, BackendError> {
+        let pools = self.pools(factory, &statements.keys().copied().collect(), limit)?;
+        let Some(executor) = pools.executor(FailurePolicy::KeepGoing) else {
+            return Ok(RunReport::default());
+        };
+        Ok(executor
+            .analysis_only(self.project.analysis_only)
+            .teardown(self.meta.graph(), statements))
+    }
+}
+
+// Borrowed views of owned names, for the SQL builders.
+fn as_strs<S: AsRef<str>>(names: &[S]) -> Vec<&str> {
+    names.iter().map(AsRef::as_ref).collect()
+}
+
+/// A [`BackendPool`] for each connection a set of models goes through, from
+/// [`BuiltProject::pools`].
+pub struct ConnectionPools<'p> {
+    pools: Vec<(BackendPool<'p>, Vec<u64>)>, // With the nodes each one builds
+}
+
+impl<'p> ConnectionPools<'p> {
+    /// A backend from `node_id`'s connection, e.g. to probe its relation.
+    pub fn backend_for(&self, node_id: u64) -> Result<PooledBackend<'_, 'p>, BackendError> {
+        let (pool, _) = self
+            .pools
+            .iter()
+            .find(|(_, nodes)| nodes.contains(&node_id))
+            .ok_or_else(|| BackendError::Config(format!("no connection for node {node_id}")))?;
+        pool.acquire()
+    }
+
+    /// An executor running each node through its connection's pool, the first pool taking
+    /// the run hooks. It builds no more nodes at once than any pool may open or the machine
+    /// has cores. `None` if there are no pools because there were no nodes.
+    pub fn executor(&self, policy: FailurePolicy) -> Option<Executor<'_>> {
+        let ((first, _), routed) = self.pools.split_first()?;
+        // No pool is asked for more backends at once than it may open.
+        let threads = self
+            .pools
+            .iter()
+            .map(|(pool, _)| pool.max_size())
+            .chain(std::thread::available_parallelism().map(usize::from))
+            .min()
+            .unwrap_or(1);
+        let mut executor = Executor::pooled(first, policy).with_threads(threads);
+        for (pool, nodes) in routed {
+            executor = executor.with_pool_for(nodes.iter().copied(), pool);
+        }
+        Some(executor)
+    }
+}
+
+/// One problem found while building a project.
+#[derive(Debug)]
+pub enum BuildIssue {
+    /// The project file couldn't be read or deserialized, on its own or with the models'
+    /// config blocks merged in. Nothing else is checked after the first; after the second,
+    /// the project is checked without the blocks.
+    Load(Box<figment::Error>),
+    /// A model's `# orbital-config:` block isn't a TOML inline table.
+    ConfigBlock {
+        model: String,
+        error: Box<figment::Error>,
+    },
+    Config(ConfigError),
+    Source(SourceError),
+    Extends(ExtendsError),
+    Var {
+        model: String,
+        error: VarError,
+    },
+    Prepare(PrepareError),
+    Undeclared(UndeclaredReference),
+    DuplicateId(DuplicateId),
+    Graph(GraphError),
+}
+
+impl fmt::Display for BuildIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildIssue::Load(e) => write!(f, "{e}"),
+            BuildIssue::ConfigBlock { model, error } => {
+                write!(f, "{model}: invalid orbital-config block: {error}")
+            }
+            BuildIssue::Config(e) => write!(f, "{e}"),
+            BuildIssue::Source(e) => write!(f, "{e}"),
+            BuildIssue::Extends(e) => write!(f, "{e}"),
+            BuildIssue::Var { model, error } => write!(f, "{model}: {error}"),
+            BuildIssue::Prepare(e) => write!(f, "{e}"),
+            BuildIssue::Undeclared(e) => write!(f, "{e}"),
+            BuildIssue::DuplicateId(e) => write!(f, "{e}"),
+            BuildIssue::Graph(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Everything wrong with a project, in the order it was found: config first, then models,
+/// then the graph.
+#[derive(Debug)]
+pub struct BuildError {
+    pub issues: Vec<BuildIssue>,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, issue) in self.issues.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{issue}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl Project {
+    /// Loads the project file at `path` and every enabled model under its model paths, with
+    /// `extends` resolved and vars substituted, then builds the graph. Models' config blocks
+    /// are merged into the project as in [`Project::from_file_with_blocks`]. Every check runs
+    /// even after one fails, so the error lists all the problems found rather than the first.
+    pub fn build(path: impl AsRef<Path>) -> Result<BuiltProject, BuildError> {
+        let load_error = |e| BuildError {
+            issues: vec![BuildIssue::Load(e)],
+        };
+        let path = path.as_ref();
+        let project = Project::from_file(path).map_err(load_error)?;
+        // Missing model paths are reported by `validate` below.
+        let roots = project.model_path.paths().iter().filter(|p| p.is_dir());
+        let mut issues = Vec::new();
+        let (queries, skipped) = match FilesystemSource::from_roots(roots).fetch_readable() {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                issues.push(BuildIssue::Source(e));
+                (Vec::new(), Vec::new())
+            }
+        };
+        // Likewise a missing seed path.
+        let seed_files = if project.seed_path.is_dir() {
+            seed_names(&project.seed_path).unwrap_or_else(|e| {
+                issues.push(BuildIssue::Source(e));
+                Vec::new()
+            })
+        } else {
+            Vec::new()
+        };
+        let blocks: Vec<_> = queries
+            .iter()
+            .filter_map(|q| {
+                let model = q.name().to_string();
+                match parse_config_block(q.config_block()?) {
+                    Ok(block) => Some((q.name().into(), block)),
+                    Err(error) => {
+                        issues.push(BuildIssue::ConfigBlock { model, error });
+                        None
+                    }
+                }
+            })
+            .collect();
+        let project = if blocks.is_empty() {
+            project
+        } else {
+            Project::from_file_with_blocks(path, &blocks).unwrap_or_else(|e| {
+                issues.push(BuildIssue::Load(e));
+                project
+            })
+        };
+        let mut issues: Vec<_> = project
+            .validate()
+            .into_iter()
+            .map(BuildIssue::Config)
+            .chain(issues)
+            .collect();
+        let mut seeds: Vec<_> = project
+            .seeds
+            .iter()
+            .map(|s| s.name.as_ref().to_string())
+            .chain(seed_files)
+            .collect();
+        seeds.sort();
+        seeds.dedup();
+        let collection = project.load_collection(queries, &seeds, &mut issues);
+        match GraphMeta::new_with_limits(collection, project.fail_on_orphan, &project.limits) {
+            Ok(mut meta) if issues.is_empty() => {
+                let names: Vec<_> = seeds.iter().map(|s| QueryName::from(s.as_str())).collect();
+                meta.mark_seeds(&names);
+                Ok(BuiltProject {
+                    project,
+                    meta,
+                    skipped,
+                    seeds,
+                    selected: None,
+                })
+            }
+            Ok(_) => Err(BuildError { issues }),
+            // Nothing was left to build a graph from because of what's already reported.
+            Err(GraphError::Empty) if !issues.is_empty() => Err(BuildError { issues }),
+            Err(e) => {
+                issues.push(BuildIssue::Graph(e));
+                Err(BuildError { issues })
+            }
+        }
+    }
+
+    // The enabled `queries` that survive every check, with each failure added to `issues`,
+    // and a table for each of `seeds` whether or not anything reads it.
+    fn load_collection(
+        &self,
+        queries: Vec<RawQuery>,
+        seeds: &[String],
+        issues: &mut Vec<BuildIssue>,
+    ) -> QueryCollection {
+        let queries: Vec<_> = queries
+            .into_iter()
+            .filter_map(|q| {
+                let config = self.models.iter().find(|m| m.name.as_ref() == q.name());
+                if !q.is_enabled(config) {
+                    return None;
+                }
+                // The project's key wins over one annotated in the file, as `enabled` does.
+                match config.and_then(|c| c.id_key.as_ref()) {
+                    Some(key) => Some(q.with_id_key(key.as_str())),
+                    None => Some(q),
+                }
+            })
+            .collect();
+        let extends: HashMap<_, _> = self
+            .models
+            .iter()
+            .filter_map(|m| {
+                let base = m.extends.as_ref()?;
+                Some((
+                    QueryName::from(m.name.as_ref().as_str()),
+                    QueryName::from(base.as_ref().as_str()),
+                ))
+            })
+            .collect();
+        let mut collection = QueryCollection::new();
+        // Before vars, which check that the query still parses: a model extending another
+        // isn't a whole query until its base is prepended.
+        let queries = match resolve_extends(queries, &extends) {
+            Ok(queries) => queries,
+            Err(e) => {
+                issues.push(BuildIssue::Extends(e));
+                return collection;
+            }
+        };
+        let vars = self.vars.clone().unwrap_or_default();
+        let queries: Vec<_> = queries
+            .into_iter()
+            .filter_map(|q| {
+                let model = q.name().to_string();
+                q.with_vars(&vars)
+                    .map_err(|error| issues.push(BuildIssue::Var { model, error }))
+                    .ok()
+            })
+            .collect();
+        let declared: Vec<_> = self
+            .sources
+            .iter()
+            .map(|s| s.name.as_ref().as_str())
+            .chain(seeds.iter().map(String::as_str))
+            .collect();
+        let added = if self.strict_sources {
+            collection.add_queries_strict(queries.clone(), &declared)
+        } else {
+            collection.add_queries(queries.clone());
+            Ok(())
+        };
+        // Queries that don't parse are left out of the collection silently, so ask again
+        // for the reason of each one missing.
+        for q in &queries {
+            if !matches!(
+                collection.get(&QueryName::from(q.name())),
+                Some(QueryKind::Query(_))
+            ) {
+                if let Err(e) = collection.prepare_query(q.query_string(), q.name()) {
+                    issues.push(BuildIssue::Prepare(e));
+                }
+            }
+        }
+        if let Err(undeclared) = added {
+            issues.extend(undeclared.into_iter().map(BuildIssue::Undeclared));
+        }
+        issues.extend(
+            collection
+                .duplicate_ids()
+                .iter()
+                .cloned()
+                .map(BuildIssue::DuplicateId),
+        );
+        for (model, base) in &extends {
+            collection.add_manual_dependencies(model, [base.clone()]);
+        }
+        // Only for models that made it in, the same as the dependencies parsed from them.
+        for model in &self.models {
+            let name = QueryName::from(model.name.as_ref().as_str());
+            if !matches!(collection.get(&name), Some(QueryKind::Query(_))) {
+                continue;
+            }
+            let depends_on: Vec<_> = model
+                .depends_on
+                .iter()
+                .map(|upstream| QueryName::from(upstream.as_ref().as_str()))
+                .collect();
+            for upstream in &depends_on {
+                let is_model = matches!(collection.get(upstream), Some(QueryKind::Query(_)));
+                if self.strict_sources && !is_model && !declared.contains(&upstream.as_str()) {
+                    issues.push(BuildIssue::Undeclared(UndeclaredReference {
+                        query: name.clone(),
+                        table: upstream.clone(),
+                    }));
+                }
+            }
+            collection.add_manual_dependencies(&name, depends_on);
+        }
+        collection.add_tables(seeds.iter().map(|seed| QueryName::from(seed.as_str())));
+        collection
+    }
+}
+
+#[cfg(test)]
+mod test_build {
+    use super::*;
+    use crate::{
+        backend::Backend,
+        executor::{NodeStatus, ThresholdWarning},
+        testing::{InMemoryBackend, StubBackend},
+    };
+    use std::{fs, path::PathBuf};
+
+    #[test]
+    fn test_config_block_sets_materialization() {
+        let files = [
+            (
+                "stg_orders.prql",
+                "# orbital-config: { materialized = \"view\", tags = [\"nightly\"] }\nfrom raw_orders",
+            ),
+            (
+                "orders.prql",
+                "# orbital-config: { materialized = \"view\", alias = \"all_orders\" }\nfrom stg_orders",
+            ),
+        ];
+        let dir = write_project(
+            "blocks",
+            Some(DEFAULTS),
+            "[{ name: orders, materialized: table }]",
+            &files,
+        );
+        let built = Project::build(dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let BuiltProject { project, meta, .. } = built.unwrap();
+        let manifest = Manifest::from_collection(meta.collection()).with_materializations(|name| {
+            let model = project.models.iter().find(|m| m.name.as_ref() == name)?;
+            Some(model.materialized.name().to_string())
+        });
+        let materialized = |name| manifest.node(name).unwrap().materialized.as_deref();
+        assert_eq!(materialized("stg_orders"), Some("view"));
+        // The project file's own setting wins; the block fills in the rest.
+        assert_eq!(materialized("orders"), Some("table"));
+        assert_eq!(materialized("raw_orders"), None);
+        let orders = project.models.iter().find(|m| m.name.as_ref() == "orders");
+        assert_eq!(orders.unwrap().relation_name().as_ref(), "all_orders");
+        let stg_orders = project
+            .models
+            .iter()
+            .find(|m| m.name.as_ref() == "stg_orders");
+        assert_eq!(stg_orders.unwrap().tags, vec![String::from("nightly")]);
+    }
+
+    // Model defaults for the projects that don't test what happens without them.
+    const DEFAULTS: &str = "{ database: analytics, schema: marts }";
+
+    // A project under a fresh temp directory with a `models` and `seeds` dir, `defaults` as
+    // its resource defaults if given, `models` as its model entries and each of `files`
+    // written under `models`.
+    fn write_project(
+        name: &str,
+        defaults: Option<&str>,
+        models: &str,
+        files: &[(&str, &str)],
+    ) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("orbital-{name}-{}", std::process::id()));
+        fs::create_dir_all(dir.join("models")).unwrap();
+        fs::create_dir_all(dir.join("seeds")).unwrap();
+        for (file, text) in files {
+            fs::write(dir.join("models").join(file), text).unwrap();
+        }
+        fs::write(
+            dir.join("orbital.yml"),
+            format!(
+                r#"
+name: shop
+version: "0.1.0"
+model_path: {models_dir}
+seed_path: {seeds_dir}
+clean_targets: target
+log_path: logs
+{defaults}models: {models}
+seeds: []
+sources: []
+"#,
+                models_dir = dir.join("models").display(),
+                seeds_dir = dir.join("seeds").display(),
+                defaults = defaults
+                    .map(|defaults| format!("defaults: {defaults}\n"))
+                    .unwrap_or_default(),
+            ),
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_depends_on_adds_ordered_edges() {
+        let files = [
+            ("audit_log.prql", "from raw_audit"),
+            // Reads audit_log through a macro the parser can't see into.
+            ("orders.prql", "from raw_orders"),
+        ];
+        let dir = write_project(
+            "depends-on",
+            Some(DEFAULTS),
+            "[{ name: orders, depends_on: [audit_log] }]",
+            &files,
+        );
+        let built = Project::build(dir.join("orbital.yml"));
+        let cyclic_dir = write_project(
+            "depends-on-cycle",
+            Some(DEFAULTS),
+            "[{ name: orders, depends_on: [audit_log] }, { name: audit_log, depends_on: [orders] }]",
+            &files,
+        );
+        let cyclic = Project::build(cyclic_dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&cyclic_dir).unwrap();
+
+        let meta = built.unwrap().meta;
+        let (audit_log, orders) = (QueryName::from("audit_log"), QueryName::from("orders"));
+        assert!(meta.edge_kind(&audit_log, &orders).is_some());
+        let order = meta.topological_named();
+        let position = |name| order.iter().position(|n| *n == name).unwrap();
+        assert!(position(&audit_log) < position(&orders));
+        let issues = match cyclic {
+            Err(BuildError { issues }) => issues,
+            Ok(_) => panic!("expected the cycle to fail the build"),
+        };
+        assert!(matches!(
+            issues.as_slice(),
+            [BuildIssue::Graph(GraphError::ContainsCycle(_))]
+        ));
+    }
+
+    #[test]
+    fn test_compiled_downstream_reads_an_aliased_model_from_its_relation() {
+        let dir = write_project(
+            "alias",
+            Some(DEFAULTS),
+            "[{ name: stg_orders, alias: staging_orders }]",
+            &[
+                ("stg_orders.prql", "from raw_orders | select [id, amount]"),
+                ("orders.prql", "from stg_orders | select [id]"),
+            ],
+        );
+        let built = Project::build(dir.join("orbital.yml")).unwrap();
+        let target = TargetDir::new(dir.join("target"));
+        let failures = built.compile(&target, false, None);
+        let compiled = |model| fs::read_to_string(dir.join(format!("target/compiled/{model}.sql")));
+        let (orders, stg_orders) = (compiled("orders"), compiled("stg_orders"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(failures.is_empty(), "{failures:?}");
+        // Still named, and depended on, by the model's own name.
+        assert!(stg_orders.is_ok());
+        let stg_orders = QueryName::from("stg_orders");
+        assert!(built
+            .meta
+            .edge_kind(&stg_orders, &QueryName::from("orders"))
+            .is_some());
+        let orders = orders.unwrap();
+        assert!(orders.contains("staging_orders AS stg_orders"), "{orders}");
+        let model = &built.project.models[0];
+        assert_eq!(
+            built.project.relation_sql(model),
+            r#""analytics"."marts"."staging_orders""#
+        );
+    }
+
+    #[test]
+    fn test_compiled_references_follow_the_identifier_case() {
+        let dir = write_project(
+            "case",
+            Some(DEFAULTS),
+            "[{ name: stg_orders }]",
+            &[
+                ("stg_orders.prql", "from raw_orders | select [id, amount]"),
+                ("orders.prql", "from stg_orders | select [id]"),
+            ],
+        );
+        let config = dir.join("orbital.yml");
+        let mut text = fs::read_to_string(&config).unwrap();
+        text.push_str("identifier_case: upper\n");
+        fs::write(&config, text).unwrap();
+        let built = Project::build(&config).unwrap();
+        let failures = built.compile(&TargetDir::new(dir.join("target")), false, None);
+        let compiled = |model| fs::read_to_string(dir.join(format!("target/compiled/{model}.sql")));
+        let (orders, stg_orders) = (compiled("orders").unwrap(), compiled("stg_orders").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(failures.is_empty(), "{failures:?}");
+        let relation = built.project.relation_sql(&built.project.models[0]);
+        assert!(relation.ends_with(r#"."STG_ORDERS""#), "{relation}");
+        assert!(orders.contains(r#""STG_ORDERS" AS stg_orders"#), "{orders}");
+        assert!(
+            stg_orders.contains(r#""RAW_ORDERS" AS raw_orders"#),
+            "{stg_orders}"
+        );
+    }
+
+    #[test]
+    fn test_compiled_files_start_with_the_sql_header_unless_disabled() {
+        let dir = write_project(
+            "header",
+            Some(DEFAULTS),
+            "[]",
+            &[("staff.prql", "from employees")],
+        );
+        let built = Project::build(dir.join("orbital.yml")).unwrap();
+        let generated_at = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let with_header = TargetDir::new(dir.join("with"));
+        let without_header = TargetDir::new(dir.join("without"));
+        let failures = [
+            built.compile(&with_header, false, Some(generated_at)),
+            built.compile(&without_header, false, None),
+        ];
+        let compiled = |target: &TargetDir| {
+            fs::read_to_string(target.root().join("compiled/staff.sql")).unwrap()
+        };
+        let (with_header, without_header) = (compiled(&with_header), compiled(&without_header));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(failures.iter().all(Vec::is_empty), "{failures:?}");
+        let id = built.meta.collection()[&QueryName::from("staff")].id();
+        let header = format!(
+            "-- orbital: model=staff id={} generated_at=1700000000\n",
+            **id
+        );
+        assert_eq!(with_header, format!("{header}{without_header}"));
+        assert!(without_header.starts_with("SELECT"), "{without_header}");
+    }
+
+    #[test]
+    fn test_seed_files_are_loaded_as_seed_tables() {
+        use crate::query_graph::graph::EdgeKind;
+
+        let dir = write_project(
+            "seeds",
+            Some(DEFAULTS),
+            "[]",
+            &[("orders.prql", "from raw_orders | join rates [==currency]")],
+        );
+        fs::create_dir_all(dir.join("seeds/finance")).unwrap();
+        fs::write(dir.join("seeds/finance/rates.csv"), "currency,rate\n").unwrap();
+        fs::write(dir.join("seeds/countries.csv"), "code,name\n").unwrap();
+        fs::write(dir.join("seeds/scratch.csv"), "").unwrap();
+        fs::write(dir.join("seeds/.orbitalignore"), "scratch.csv\n").unwrap();
+        let built = Project::build(dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        let built = built.unwrap();
+
+        assert_eq!(built.seeds, vec!["countries", "rates"]);
+        let name = QueryName::from;
+        assert_eq!(
+            built.meta.edge_kind(&name("rates"), &name("orders")),
+            Some(EdgeKind::SeedRef)
+        );
+        assert_eq!(
+            built.meta.edge_kind(&name("raw_orders"), &name("orders")),
+            Some(EdgeKind::SourceRef)
+        );
+        // Read by nothing yet, but still part of the project.
+        assert!(matches!(
+            built.meta.collection().get(&name("countries")),
+            Some(QueryKind::TableQuery(_))
+        ));
+        assert!(built.meta.collection().get(&name("scratch")).is_none());
+    }
+
+    #[test]
+    fn test_refs_are_traced_after_disabled_models_are_left_out() {
+        use crate::query_graph::query::RefResolution;
+
+        let dir = write_project(
+            "trace-ref",
+            Some(DEFAULTS),
+            "[{ name: legacy_orders, enabled: false }]",
+            &[
+                ("stg_orders.prql", "from raw_orders"),
+                ("legacy_orders.prql", "from raw_orders"),
+                ("orders.prql", "from stg_orders | join legacy_orders [id]"),
+            ],
+        );
+        let config = dir.join("orbital.yml");
+        let built = Project::build(&config);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let meta = built.unwrap().meta;
+        let trace = |table| {
+            meta.collection()
+                .trace_ref(
+                    &QueryName::from("orders"),
+                    &QueryName::from(table),
+                    &[],
+                    &[],
+                )
+                .unwrap()
+        };
+        assert_eq!(trace("stg_orders"), RefResolution::Model);
+        assert_eq!(trace("legacy_orders"), RefResolution::Placeholder);
+    }
+
+    #[test]
+    fn test_run_builds_models_then_merges_into_incremental_ones() {
+        let dir = write_project(
+            "run",
+            Some(DEFAULTS),
+            "[{ name: stg_orders, materialized: view }, \
+             { name: orders, materialized: { incremental: { unique_key: [id] } } }]",
+            &[
+                ("stg_orders.prql", "from raw_orders | select [id, amount]"),
+                ("orders.prql", "from stg_orders | select [id, amount]"),
+            ],
+        );
+        let built = Project::build(dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        let built = built.unwrap();
+        let id = |name| {
+            **built
+                .meta
+                .collection()
+                .get(&QueryName::from(name))
+                .unwrap()
+                .id()
+        };
+        let (stg_orders, orders) = (id("stg_orders"), id("orders"));
+        assert_eq!(
+            built.materialized(),
+            FnvHashSet::from_iter([stg_orders, orders])
+        );
+
+        let mut backend = InMemoryBackend::new();
+        for run in 0..2 {
+            let (statements, failures) = built
+                .run_statements(None, |_, [database, schema, table]| {
+                    backend.probe_table(database, schema, table)
+                });
+            assert!(failures.is_empty(), "{failures:?}");
+            let report = built
+                .configure(Executor::new(&mut backend, FailurePolicy::FailFast))
+                .run(built.meta.graph(), &statements);
+            assert!(!report.has_failures());
+            let first = |node_id| statements[&node_id][0].as_str();
+            assert!(first(stg_orders)
+                .starts_with(r#"CREATE OR REPLACE VIEW "analytics"."marts"."stg_orders" AS "#));
+            // Created on the first run, then merged into.
+            let orders_sql = if run == 0 {
+                r#"CREATE TABLE "analytics"."marts"."orders" AS "#
+            } else {
+                r#"DELETE FROM "analytics"."marts"."orders" WHERE ("id") IN "#
+            };
+            assert!(first(orders).starts_with(orders_sql), "{}", first(orders));
+        }
+        assert_eq!(
+            backend.relations().collect::<Vec<_>>(),
+            ["analytics.marts.orders", "analytics.marts.stg_orders"]
+        );
+    }
+
+    #[test]
+    fn test_run_wraps_each_model_in_its_hooks() {
+        let dir = write_project(
+            "hooks",
+            Some(DEFAULTS),
+            "[{ name: orders, pre_hook: [SET search_path TO marts], \
+             post_hook: [GRANT SELECT ON orders TO reporter] }]",
+            &[("orders.prql", "from raw_orders")],
+        );
+        let built = Project::build(dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        let built = built.unwrap();
+        let (statements, _) = built.run_statements(None, |_, _| Ok(false));
+
+        let mut backend = InMemoryBackend::new();
+        let report = built
+            .configure(Executor::new(&mut backend, FailurePolicy::FailFast))
+            .run(built.meta.graph(), &statements);
+
+        assert!(!report.has_failures());
+        let executed = backend.executed();
+        assert_eq!(executed.len(), 4, "{executed:?}");
+        assert_eq!(executed[0], "SET search_path TO marts");
+        assert!(executed[2].starts_with(r#"CREATE TABLE "analytics"."marts"."orders" AS "#));
+        assert_eq!(executed[3], "GRANT SELECT ON orders TO reporter");
+    }
+
+    #[test]
+    fn test_run_leaves_out_excluded_models() {
+        let dir = write_project(
+            "exclude",
+            Some(DEFAULTS),
+            "[{ name: stg_orders }, \
+             { name: orders, post_hook: [GRANT SELECT ON orders TO reporter] }, \
+             { name: events, tags: [slow] }]",
+            &[
+                ("stg_orders.prql", "from raw_orders"),
+                ("orders.prql", "from stg_orders"),
+                ("events.prql", "from raw_events"),
+            ],
+        );
+        let built = Project::build(dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        let exclude = "tag:slow orders".parse().unwrap();
+        let built = built.unwrap().with_selection(None, Some(&exclude));
+        let (statements, _) = built.run_statements(None, |_, _| Ok(false));
+        assert_eq!(statements.len(), 1);
+        assert_eq!(built.materialized().len(), 1);
+
+        let mut backend = InMemoryBackend::new();
+        let report = built
+            .configure(Executor::new(&mut backend, FailurePolicy::FailFast))
+            .run(built.meta.graph(), &statements);
+
+        assert!(!report.has_failures());
+        let executed = backend.executed();
+        assert_eq!(executed.len(), 2, "{executed:?}");
+        assert!(executed[1].starts_with(r#"CREATE TABLE "analytics"."marts"."stg_orders" AS "#));
+    }
+
+    #[test]
+    fn test_run_starts_heavier_models_first() {
+        let dir = write_project(
+            "weights",
+            Some(DEFAULTS),
+            "[{ name: light }, { name: heavy, weight: 10 }]",
+            &[
+                ("light.prql", "from raw_orders"),
+                ("heavy.prql", "from raw_events"),
+            ],
+        );
+        let built = Project::build(dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        let built = built.unwrap();
+        let (statements, _) = built.run_statements(None, |_, _| Ok(false));
+
+        let mut backend = InMemoryBackend::new();
+        built
+            .configure(Executor::new(&mut backend, FailurePolicy::FailFast))
+            .run(built.meta.graph(), &statements);
+
+        let created: Vec<_> = backend
+            .executed()
+            .iter()
+            .filter(|sql| sql.starts_with("CREATE"))
+            .collect();
+        assert!(created[0].contains(r#"."heavy" AS"#), "{created:?}");
+        assert!(created[1].contains(r#"."light" AS"#), "{created:?}");
+    }
+
+    #[test]
+    fn test_run_hooks_bracket_the_models() {
+        let dir = write_project(
+            "run-hooks",
+            Some(DEFAULTS),
+            "[{ name: stg_orders }, { name: orders }]",
+            &[
+                ("stg_orders.prql", "from raw_orders"),
+                ("orders.prql", "from stg_orders"),
+            ],
+        );
+        let config = dir.join("orbital.yml");
+        let mut text = fs::read_to_string(&config).unwrap();
+        text.push_str("on_run_start: [BEGIN]\non_run_end: [COMMIT, ANALYZE]\n");
+        fs::write(&config, text).unwrap();
+        let built = Project::build(&config);
+        fs::remove_dir_all(&dir).unwrap();
+        let built = built.unwrap();
+        let (statements, _) = built.run_statements(None, |_, _| Ok(false));
+
+        let mut backend = InMemoryBackend::new();
+        let report = built
+            .configure(Executor::new(&mut backend, FailurePolicy::FailFast))
+            .run(built.meta.graph(), &statements);
+
+        assert!(!report.has_failures());
+        let executed = backend.executed();
+        assert_eq!(executed.len(), 7, "{executed:?}");
+        assert_eq!(executed[0], "BEGIN");
+        assert!(executed[1..5].iter().all(|sql| sql.contains("TABLE")));
+        assert_eq!(executed[5..], ["COMMIT", "ANALYZE"]);
+    }
+
+    #[test]
+    fn test_run_warns_about_models_past_their_expected_rows() {
+        let dir = write_project(
+            "thresholds",
+            Some(DEFAULTS),
+            "[{ name: orders, expected_rows: 5 }, { name: events }]",
+            &[
+                ("orders.prql", "from raw_orders"),
+                ("events.prql", "from raw_events"),
+            ],
+        );
+        let built = Project::build(dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        let built = built.unwrap();
+        let (statements, _) = built.run_statements(None, |_, _| Ok(false));
+
+        let mut backend = StubBackend::new("warehouse").with_rows(10);
+        let report = built
+            .configure(Executor::new(&mut backend, FailurePolicy::FailFast))
+            .run(built.meta.graph(), &statements);
+
+        let orders = **built
+            .meta
+            .collection()
+            .get(&QueryName::from("orders"))
+            .unwrap()
+            .id();
+        assert!(!report.has_failures());
+        assert_eq!(
+            report.threshold_warnings,
+            [ThresholdWarning::Rows {
+                node_id: orders,
+                rows: 20,
+                expected: 5,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_run_fails_a_model_outside_its_row_count() {
+        let dir = write_project(
+            "row-count",
+            Some(DEFAULTS),
+            "[{ name: orders, tests: { row_count: { min: 1 } } }, { name: revenue }]",
+            &[
+                ("orders.prql", "from raw_orders"),
+                ("revenue.prql", "from orders"),
+            ],
+        );
+        let built = Project::build(dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        let built = built.unwrap();
+        let (statements, _) = built.run_statements(None, |_, _| Ok(false));
+        let id = |name| {
+            **built
+                .meta
+                .collection()
+                .get(&QueryName::from(name))
+                .unwrap()
+                .id()
+        };
+
+        let mut backend = StubBackend::new("warehouse").with_count(0);
+        let report = built
+            .configure(Executor::new(&mut backend, FailurePolicy::KeepGoing))
+            .run(built.meta.graph(), &statements);
+
+        assert!(matches!(
+            report.status(id("orders")),
+            Some(NodeStatus::Failed(e)) if e.starts_with(r#""analytics"."marts"."orders" has 0 rows"#)
+        ));
+        assert_eq!(report.status(id("revenue")), Some(&NodeStatus::Skipped));
+    }
+
+    #[test]
+    fn test_run_merges_past_the_stored_watermark_and_advances_it() {
+        let dir = write_project(
+            "run-watermark",
+            Some(DEFAULTS),
+            "[{ name: orders, materialized: { incremental: { unique_key: [id], \
+             filter: \"status = 'new' OR status = 'late'\", watermark: updated_at } } }]",
+            &[(
+                "orders.prql",
+                "from raw_orders | select [id, status, updated_at]",
+            )],
+        );
+        let built = Project::build(dir.join("orbital.yml")).unwrap();
+        let mut store = WatermarkStore::open(dir.join("logs")).unwrap();
+        store.set("orders", "2024-03-01").unwrap();
+        let (statements, failures) = built.run_statements(Some(&store), |_, _| Ok(true));
+        assert!(failures.is_empty(), "{failures:?}");
+
+        let mut backend = StubBackend::new("warehouse").with_value("max(", Ok(Some("2024-03-02")));
+        let report = built
+            .configure(Executor::new(&mut backend, FailurePolicy::FailFast))
+            .run(built.meta.graph(), &statements);
+        let failures =
+            built.advance_watermarks(&mut store, &report, |_, sql| backend.fetch_value(sql));
+        let reopened = WatermarkStore::open(dir.join("logs"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(failures.is_empty(), "{failures:?}");
+        let executed = backend.executed();
+        assert!(
+            executed[0].contains(
+                r#"WHERE (status = 'new' OR status = 'late') AND ("updated_at" > '2024-03-01')"#
+            ),
+            "{executed:?}"
+        );
+        assert_eq!(
+            executed.last().unwrap(),
+            r#"SELECT max("updated_at") FROM "analytics"."marts"."orders""#
+        );
+        assert_eq!(reopened.unwrap().get("orders"), Some("2024-03-02"));
+    }
+
+    #[test]
+    fn test_drop_uses_schema_naming_and_each_database_connection() {
+        let dir = write_project(
+            "drop",
+            Some(DEFAULTS),
+            "[{ name: orders, materialized: view }, { name: revenue, database: finance }]",
+            &[
+                ("orders.prql", "from raw_orders"),
+                ("revenue.prql", "from orders"),
+            ],
+        );
+        let config = dir.join("orbital.yml");
+        let mut text = fs::read_to_string(&config).unwrap();
+        text.push_str(
+            "schema_naming: { template: \"{target}_{schema}\" }\ntarget_name: prod\n\
+             connection: { adapter: warehouse }\nconnections: { finance: { adapter: ledger } }\n",
+        );
+        fs::write(&config, text).unwrap();
+        let built = Project::build(&config);
+        fs::remove_dir_all(&dir).unwrap();
+        let built = built.unwrap();
+
+        let (warehouse, ledger) = (StubBackend::new("warehouse"), StubBackend::new("ledger"));
+        let mut factory = BackendFactory::new();
+        for (adapter, backend) in [("warehouse", &warehouse), ("ledger", &ledger)] {
+            let backend = backend.clone();
+            factory.register_backend(adapter, move |_| Ok(Box::new(backend.clone())));
+        }
+        let limit = ConnectionLimit::new(1);
+        let report = built
+            .teardown(&factory, &built.drop_statements(), Some(&limit))
+            .unwrap();
+
+        assert!(!report.has_failures());
+        assert_eq!(
+            ledger.executed(),
+            [r#"DROP TABLE IF EXISTS "finance"."prod_marts"."revenue""#]
+        );
+        assert_eq!(
+            warehouse.executed(),
+            [r#"DROP VIEW IF EXISTS "analytics"."prod_marts"."orders""#]
+        );
+    }
+
+    #[test]
+    fn test_config_and_parse_errors_are_reported_together() {
+        let files = [
+            ("orders.prql", "from raw_orders"),
+            ("broken.prql", "from raw_orders | select [a,, b]"),
+        ];
+        let dir = write_project("build", None, "[]", &files);
+        fs::remove_dir(dir.join("seeds")).unwrap();
+        let built = Project::build(dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let issues = match built {
+            Err(BuildError { issues }) => issues,
+            Ok(_) => panic!("expected the build to fail"),
+        };
+        assert_eq!(issues.len(), 2, "{issues:?}");
+        assert!(matches!(
+            &issues[0],
+            BuildIssue::Config(ConfigError::MissingPath {
+                field: "seed_path",
+                ..
+            })
+        ));
+        assert!(matches!(
+            &issues[1],
+            BuildIssue::Prepare(PrepareError::Prql(_))
+        ));
+    }
+
+    #[test]
+    fn test_block_that_breaks_the_project_is_reported_with_the_rest() {
+        let files = [
+            (
+                "orders.prql",
+                "# orbital-config: { materialized = \"bogus\" }\nfrom raw_orders",
+            ),
+            ("broken.prql", "from raw_orders | select [a,, b]"),
+        ];
+        let dir = write_project("bad-block", Some(DEFAULTS), "[]", &files);
+        let built = Project::build(dir.join("orbital.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let issues = match built {
+            Err(BuildError { issues }) => issues,
+            Ok(_) => panic!("expected the build to fail"),
+        };
+        assert_eq!(issues.len(), 2, "{issues:?}");
+        assert!(matches!(&issues[0], BuildIssue::Load(_)));
+        assert!(matches!(
+            &issues[1],
+            BuildIssue::Prepare(PrepareError::Prql(_))
+        ));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::settings::{ResourceConfig, ResourceProperties};
+use std::fmt::Write;
+
+/// How many models are documented and tested. A model is documented when its properties
+/// give it a non-empty description, and tested when its project config or its properties
+/// configure at least one test.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    pub models: usize,
+    pub undocumented: Vec<String>, // Sorted
+    pub untested: Vec<String>,     // Sorted
+}
+
+impl Coverage {
+    pub fn measure<'a>(
+        models: impl IntoIterator<Item = &'a str>,
+        configs: &[ResourceConfig],
+        properties: &[ResourceProperties],
+    ) -> Self {
+        let mut coverage = Self::default();
+        for model in models {
+            coverage.models += 1;
+            let props = properties.iter().find(|p| p.name.as_ref() == model);
+            let documented = props
+                .and_then(|p| p.description.as_deref())
+                .is_some_and(|d| !d.trim().is_empty());
+            let tested = configs
+                .iter()
+                .filter(|c| c.name.as_ref() == model)
+                .chain(props.map(|p| &p.config))
+                .any(|c| !c.tests.is_empty());
+            if !documented {
+                coverage.undocumented.push(model.to_string());
+            }
+            if !tested {
+                coverage.untested.push(model.to_string());
+            }
+        }
+        coverage.undocumented.sort();
+        coverage.untested.sort();
+        coverage
+    }
+
+    /// Share of models with a description, from 0 to 100. A project without models is fully
+    /// covered.
+    pub fn doc_percent(&self) -> f64 {
+        percent(self.models - self.undocumented.len(), self.models)
+    }
+
+    /// Share of models with at least one test, from 0 to 100.
+    pub fn test_percent(&self) -> f64 {
+        percent(self.models - self.untested.len(), self.models)
+    }
+
+    /// Whether both documentation and test coverage reach `min_percent`.
+    pub fn meets(&self, min_percent: f64) -> bool {
+        self.doc_percent() >= min_percent && self.test_percent() >= min_percent
+    }
+
+    /// Counts, then the models missing each, one per line.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (what, percent, missing) in [
+            ("documented", self.doc_percent(), &self.undocumented),
+            ("tested", self.test_percent(), &self.untested),
+        ] {
+            let covered = self.models - missing.len();
+            let _ = writeln!(out, "{what}: {covered}/{} ({percent:.1}%)", self.models);
+            for model in missing {
+                let _ = writeln!(out, "  not {what}: {model}");
+            }
+        }
+        out
+    }
+}
+
+fn percent(covered: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        covered as f64 * 100.0 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod test_coverage {
+    use super::*;
+    use figment::providers::{Format, Yaml};
+
+    #[test]
+    fn test_half_documented_fixture() {
+        let properties: Vec<ResourceProperties> = figment::Figment::from(Yaml::string(
+            r#"
+models:
+  - name: orders
+    description: One row per order, deduplicated.
+    config: { name: orders, database: analytics, schema: marts, tests: { row_count: { min: 1 } } }
+    columns: []
+  - name: customers
+    description: "  "
+    config: { name: customers, database: analytics, schema: marts }
+    columns: []
+"#,
+        ))
+        .extract_inner("models")
+        .unwrap();
+        let coverage = Coverage::measure(["orders", "customers"], &[], &properties);
+        assert_eq!(coverage.doc_percent(), 50.0);
+        assert_eq!(coverage.undocumented, vec!["customers"]);
+        assert_eq!(coverage.untested, vec!["customers"]);
+        assert!(coverage.meets(50.0));
+        assert!(!coverage.meets(75.0));
+        assert_eq!(
+            coverage.render(),
+            "documented: 1/2 (50.0%)\n  not documented: customers\n\
+             tested: 1/2 (50.0%)\n  not tested: customers\n"
+        );
+    }
+}
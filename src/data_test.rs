@@ -0,0 +1,104 @@
+use crate::{
+    backend::{Backend, BackendError},
+    settings::RowCount,
+};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DataTestError {
+    /// The test query itself couldn't be run.
+    Backend(BackendError),
+    RowCountOutOfBounds {
+        relation: String,
+        count: u64,
+        bounds: RowCount,
+    },
+}
+
+impl fmt::Display for DataTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataTestError::Backend(e) => write!(f, "could not run test: {e}"),
+            DataTestError::RowCountOutOfBounds {
+                relation,
+                count,
+                bounds,
+            } => {
+                write!(
+                    f,
+                    "{relation} has {count} rows, expected at least {}",
+                    bounds.min
+                )?;
+                match bounds.max {
+                    Some(max) => write!(f, " and at most {max}"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for DataTestError {}
+
+impl From<BackendError> for DataTestError {
+    fn from(value: BackendError) -> Self {
+        DataTestError::Backend(value)
+    }
+}
+
+/// Counts the rows of an already materialized `relation` (quoted and qualified as needed) and
+/// checks the count falls within `bounds`.
+pub fn check_row_count(
+    backend: &mut dyn Backend,
+    relation: &str,
+    bounds: &RowCount,
+) -> Result<(), DataTestError> {
+    let count = backend.fetch_count(&format!("SELECT count(*) FROM {relation}"))?;
+    if bounds.contains(count) {
+        Ok(())
+    } else {
+        Err(DataTestError::RowCountOutOfBounds {
+            relation: relation.to_string(),
+            count,
+            bounds: *bounds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_data_test {
+    use super::*;
+    use crate::testing::StubBackend;
+
+    fn check(count: u64, bounds: RowCount) -> Result<(), DataTestError> {
+        let mut backend = StubBackend::new("counting").with_count(count);
+        let result = check_row_count(&mut backend, "marts.orders", &bounds);
+        assert_eq!(backend.executed(), ["SELECT count(*) FROM marts.orders"]);
+        result
+    }
+
+    #[test]
+    fn test_row_count_bounds() {
+        let bounded = RowCount {
+            min: 1,
+            max: Some(100),
+        };
+        assert!(check(1, bounded).is_ok());
+        assert!(check(100, bounded).is_ok());
+        assert_eq!(
+            check(0, bounded),
+            Err(DataTestError::RowCountOutOfBounds {
+                relation: String::from("marts.orders"),
+                count: 0,
+                bounds: bounded,
+            })
+        );
+        assert!(check(101, bounded).is_err());
+        let open_ended = RowCount { min: 1, max: None };
+        assert!(check(u64::MAX, open_ended).is_ok());
+        assert_eq!(
+            check(0, open_ended).unwrap_err().to_string(),
+            "marts.orders has 0 rows, expected at least 1"
+        );
+    }
+}
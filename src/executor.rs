@@ -0,0 +1,1164 @@
+use crate::{
+    backend::{pool::BackendPool, Backend, BackendError},
+    data_test::check_row_count,
+    progress::ProgressReporter,
+    query_graph::graph::QueryGraph,
+    settings::{FreshnessStatus, RowCount},
+};
+use fnv::{FnvHashMap, FnvHashSet};
+use serde::Serialize;
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// What happens to the rest of a run once a node fails. Either way, nothing downstream of a
+/// failed node is attempted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Finish every branch that doesn't depend on the failure.
+    #[default]
+    KeepGoing,
+    /// Stop dispatching anything after the first failure.
+    FailFast,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeStatus {
+    Succeeded,
+    Failed(String),
+    /// Not attempted: an upstream failed, or the run was stopped by [`FailurePolicy::FailFast`].
+    Skipped,
+    /// Would have been built, but the run is in analysis mode.
+    Planned,
+}
+
+/// What a node is expected to stay within, from its `expected_duration`/`expected_rows`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Thresholds {
+    pub duration: Option<Duration>,
+    pub rows: Option<u64>, // Summed over the rows each statement reports affecting
+}
+
+/// A model's `pre_hook`/`post_hook` statements, run with its own on the same backend.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NodeHooks {
+    pub pre: Vec<String>,
+    pub post: Vec<String>,
+}
+
+/// A node that was built, but went past one of its [`Thresholds`]. Reported, never failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThresholdWarning {
+    Duration {
+        node_id: u64,
+        took: Duration,
+        expected: Duration,
+    },
+    Rows {
+        node_id: u64,
+        rows: u64,
+        expected: u64,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct RunReport {
+    pub statuses: FnvHashMap<u64, NodeStatus>,
+    pub hook_error: Option<String>, // First failing on-run-start or on-run-end statement
+    pub threshold_warnings: Vec<ThresholdWarning>, // In the order nodes finished
+    pub analysis_only: bool,        // Nothing was written; see `NodeStatus::Planned`
+}
+
+impl RunReport {
+    pub fn status(&self, node_id: u64) -> Option<&NodeStatus> {
+        self.statuses.get(&node_id)
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.hook_error.is_some()
+            || self
+                .statuses
+                .values()
+                .any(|s| matches!(s, NodeStatus::Failed(_)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeEvent {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Skipped,
+    Planned,
+}
+
+impl From<&NodeStatus> for NodeEvent {
+    fn from(status: &NodeStatus) -> Self {
+        match status {
+            NodeStatus::Succeeded => NodeEvent::Succeeded,
+            NodeStatus::Failed(_) => NodeEvent::Failed,
+            NodeStatus::Skipped => NodeEvent::Skipped,
+            NodeStatus::Planned => NodeEvent::Planned,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EventRecord<'e> {
+    event: NodeEvent,
+    node_id: u64,
+    name: Option<&'e str>,
+    timestamp_ms: u128,
+}
+
+/// Newline-delimited JSON stream of node state transitions, written as they happen so
+/// external orchestrators can follow a run live.
+pub struct EventLog<'w> {
+    sink: &'w mut dyn Write,
+    names: FnvHashMap<u64, String>,
+}
+
+impl<'w> EventLog<'w> {
+    /// `names` labels node ids in the events; unnamed nodes get a null name.
+    pub fn new(sink: &'w mut dyn Write, names: FnvHashMap<u64, String>) -> Self {
+        Self { sink, names }
+    }
+
+    fn emit(&mut self, node_id: u64, event: NodeEvent) {
+        let record = EventRecord {
+            event,
+            node_id,
+            name: self.names.get(&node_id).map(String::as_str),
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis()),
+        };
+        // Losing the event stream shouldn't abort the warehouse work it describes.
+        if let Ok(line) = serde_json::to_string(&record) {
+            let _ = writeln!(self.sink, "{line}");
+        }
+    }
+}
+
+// Where a node's statements run.
+enum Target<'a> {
+    Backend(&'a mut dyn Backend),
+    Pool(&'a BackendPool<'a>), // A backend is checked out for each node
+}
+
+// A node that's due to be built, with everything needed to build it on another thread.
+struct Job<'s> {
+    node_id: u64,
+    hooks: NodeHooks,
+    statements: &'s [String],
+    row_count: Option<(String, RowCount)>,
+}
+
+impl Job<'_> {
+    fn is_empty(&self) -> bool {
+        self.statements.is_empty() && self.hooks.pre.is_empty() && self.hooks.post.is_empty()
+    }
+}
+
+enum Progress {
+    Running(u64),
+    Finished {
+        node_id: u64,
+        status: NodeStatus,
+        rows: u64,
+        took: Duration,
+    },
+}
+
+/// Runs each node's statements against a backend, one execution layer at a time.
+pub struct Executor<'a> {
+    backend: Target<'a>,
+    // Backends for nodes routed away from the default one, e.g. by database
+    routed: Vec<Target<'a>>,
+    routes: FnvHashMap<u64, usize>, // node id -> index into `routed`
+    threads: usize,
+    policy: FailurePolicy,
+    events: Option<EventLog<'a>>,
+    progress: Option<ProgressReporter<'a>>,
+    on_run_start: Vec<String>,
+    on_run_end: Vec<String>,
+    analysis_only: bool,
+    thresholds: FnvHashMap<u64, Thresholds>,
+    hooks: FnvHashMap<u64, NodeHooks>,
+    weights: FnvHashMap<u64, u32>,
+    row_counts: FnvHashMap<u64, (String, RowCount)>,
+}
+
+impl<'a> Executor<'a> {
+    pub fn new(backend: &'a mut dyn Backend, policy: FailurePolicy) -> Self {
+        Self::with_target(Target::Backend(backend), policy)
+    }
+
+    /// Checks a backend out of `pool` for each node, and for the run hooks.
+    pub fn pooled(pool: &'a BackendPool<'a>, policy: FailurePolicy) -> Self {
+        Self::with_target(Target::Pool(pool), policy)
+    }
+
+    fn with_target(backend: Target<'a>, policy: FailurePolicy) -> Self {
+        Self {
+            backend,
+            routed: Vec::new(),
+            routes: FnvHashMap::default(),
+            threads: 1,
+            policy,
+            events: None,
+            progress: None,
+            on_run_start: Vec::new(),
+            on_run_end: Vec::new(),
+            analysis_only: false,
+            thresholds: FnvHashMap::default(),
+            hooks: FnvHashMap::default(),
+            weights: FnvHashMap::default(),
+            row_counts: FnvHashMap::default(),
+        }
+    }
+
+    /// Statements run once before the first node and once after the last, e.g. the
+    /// project's `on_run_start`/`on_run_end`. A failing start hook skips every node and the
+    /// end hooks; end hooks otherwise run whether or not nodes failed.
+    pub fn with_run_hooks<S: AsRef<str>>(mut self, on_run_start: &[S], on_run_end: &[S]) -> Self {
+        let owned = |hooks: &[S]| hooks.iter().map(|h| h.as_ref().to_string()).collect();
+        self.on_run_start = owned(on_run_start);
+        self.on_run_end = owned(on_run_end);
+        self
+    }
+
+    /// Never touch the warehouse: nodes that would be built are marked
+    /// [`NodeStatus::Planned`] instead, and run hooks are skipped, since any of them may
+    /// issue DDL or DML.
+    pub fn analysis_only(mut self, analysis_only: bool) -> Self {
+        self.analysis_only = analysis_only;
+        self
+    }
+
+    /// Runs `nodes` against `backend` instead of the default one, e.g. every model in a
+    /// database with its own connection. Run hooks always go to the default backend.
+    pub fn with_backend_for(
+        self,
+        nodes: impl IntoIterator<Item = u64>,
+        backend: &'a mut dyn Backend,
+    ) -> Self {
+        self.route(nodes, Target::Backend(backend))
+    }
+
+    /// [`Executor::with_backend_for`], checking a backend out of `pool` for each node.
+    pub fn with_pool_for(
+        self,
+        nodes: impl IntoIterator<Item = u64>,
+        pool: &'a BackendPool<'a>,
+    ) -> Self {
+        self.route(nodes, Target::Pool(pool))
+    }
+
+    fn route(mut self, nodes: impl IntoIterator<Item = u64>, target: Target<'a>) -> Self {
+        let index = self.routed.len();
+        self.routed.push(target);
+        self.routes
+            .extend(nodes.into_iter().map(|node_id| (node_id, index)));
+        self
+    }
+
+    /// Builds up to `threads` nodes of a layer at once. Only nodes whose backend comes from
+    /// a pool are built in parallel, as each needs a connection of its own; how many of
+    /// those run statements at the same time is up to the pools' [`ConnectionLimit`].
+    ///
+    /// [`ConnectionLimit`]: crate::backend::pool::ConnectionLimit
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    fn target_for(&mut self, node_id: Option<u64>) -> &mut Target<'a> {
+        match node_id.and_then(|node_id| self.routes.get(&node_id)) {
+            Some(index) => &mut self.routed[*index],
+            None => &mut self.backend,
+        }
+    }
+
+    fn pool_for(&self, node_id: u64) -> Option<&'a BackendPool<'a>> {
+        let target = match self.routes.get(&node_id) {
+            Some(index) => &self.routed[*index],
+            None => &self.backend,
+        };
+        match target {
+            Target::Pool(pool) => Some(*pool),
+            Target::Backend(_) => None,
+        }
+    }
+
+    // Runs `f` on the backend for `node_id`, checked out for the call if it's from a pool.
+    fn with_backend<T>(
+        &mut self,
+        node_id: Option<u64>,
+        f: impl FnOnce(&mut dyn Backend) -> T,
+    ) -> Result<T, BackendError> {
+        match self.target_for(node_id) {
+            Target::Backend(backend) => Ok(f(&mut **backend)),
+            Target::Pool(pool) => Ok(f(&mut *pool.acquire()?)),
+        }
+    }
+
+    /// Edges `(from, to)` where `to` reads a model in a different database, per
+    /// `databases`, through a backend that can't read across databases. Nodes missing from
+    /// `databases`, such as sources, are never reported.
+    pub fn unsupported_cross_database(
+        &mut self,
+        graph: &QueryGraph,
+        databases: &FnvHashMap<u64, &str>,
+    ) -> Vec<(u64, u64)> {
+        let mut unsupported = Vec::new();
+        for to in graph.execution_layers().into_iter().flatten() {
+            let Some(to_database) = databases.get(&to) else {
+                continue;
+            };
+            for from in graph.get_parents(to) {
+                let crosses = databases
+                    .get(&from)
+                    .is_some_and(|from_database| from_database != to_database);
+                // A pool that can't connect fails the node when it's built instead.
+                if crosses
+                    && !self
+                        .with_backend(Some(to), |backend| backend.supports_cross_database())
+                        .unwrap_or(true)
+                {
+                    unsupported.push((from, to));
+                }
+            }
+        }
+        unsupported.sort_unstable();
+        unsupported
+    }
+
+    /// Nodes that succeed but take longer or write more rows than their thresholds allow
+    /// get a [`ThresholdWarning`] in the report.
+    pub fn with_thresholds(mut self, thresholds: FnvHashMap<u64, Thresholds>) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Each node's hooks run immediately before and after its statements, in order. A
+    /// failing hook fails the node, even if its statements already went through.
+    pub fn with_node_hooks(mut self, hooks: FnvHashMap<u64, NodeHooks>) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Within each execution layer, heavier nodes are dispatched first, so the longest builds
+    /// start earliest. Nodes without a weight count as 0; ties keep id order.
+    pub fn with_weights(mut self, weights: FnvHashMap<u64, u32>) -> Self {
+        self.weights = weights;
+        self
+    }
+
+    /// Once a node is built and its post-hooks have run, the relation given for it, as
+    /// written in SQL, has its rows counted on the same backend. The node fails if the count
+    /// is out of bounds, as for a model's `tests.row_count`.
+    pub fn with_row_counts(mut self, row_counts: FnvHashMap<u64, (String, RowCount)>) -> Self {
+        self.row_counts = row_counts;
+        self
+    }
+
+    pub fn with_event_log(mut self, events: EventLog<'a>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Every node event [`Executor::run`] would log is also counted and shown by `progress`.
+    pub fn with_progress(mut self, progress: ProgressReporter<'a>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn progress(&self) -> Option<&ProgressReporter<'a>> {
+        self.progress.as_ref()
+    }
+
+    fn emit(&mut self, node_id: u64, event: NodeEvent) {
+        if let Some(events) = self.events.as_mut() {
+            events.emit(node_id, event);
+        }
+        if let Some(progress) = self.progress.as_mut() {
+            progress.observe(node_id, event);
+        }
+    }
+
+    /// Nodes without an entry in `statements` (sources and seeds) have nothing to build and
+    /// count as succeeded.
+    pub fn run(
+        &mut self,
+        graph: &QueryGraph,
+        statements: &FnvHashMap<u64, Vec<String>>,
+    ) -> RunReport {
+        let mut report = RunReport {
+            analysis_only: self.analysis_only,
+            ..RunReport::default()
+        };
+        if let (NodeStatus::Failed(e), _) = self.build(None, &self.on_run_start.clone()) {
+            report.hook_error = Some(e);
+        }
+        let mut stopped = report.hook_error.is_some();
+        let layers = graph.execution_layers();
+        for node_id in layers.iter().flatten() {
+            self.emit(*node_id, NodeEvent::Queued);
+        }
+        for mut layer in layers {
+            layer.sort_by_key(|node_id| {
+                std::cmp::Reverse(self.weights.get(node_id).copied().unwrap_or(0))
+            });
+            let mut jobs = Vec::new();
+            for node_id in layer {
+                let upstream_ok = graph.get_parents(node_id).iter().all(|p| {
+                    matches!(
+                        report.status(*p),
+                        Some(NodeStatus::Succeeded | NodeStatus::Planned)
+                    )
+                });
+                let to_build = statements.get(&node_id).map_or(&[][..], Vec::as_slice);
+                let status = if stopped || !upstream_ok {
+                    NodeStatus::Skipped
+                } else if self.analysis_only && !to_build.is_empty() {
+                    NodeStatus::Planned
+                } else {
+                    jobs.push(Job {
+                        node_id,
+                        hooks: self.hooks.get(&node_id).cloned().unwrap_or_default(),
+                        statements: to_build,
+                        row_count: self.row_counts.get(&node_id).cloned(),
+                    });
+                    continue;
+                };
+                self.emit(node_id, NodeEvent::from(&status));
+                self.record(node_id, status, &mut report, &mut stopped);
+            }
+            self.dispatch(jobs, |executor, progress| match progress {
+                Progress::Running(node_id) => executor.emit(node_id, NodeEvent::Running),
+                Progress::Finished {
+                    node_id,
+                    status,
+                    rows,
+                    took,
+                } => {
+                    if status == NodeStatus::Succeeded {
+                        executor.check_thresholds(node_id, took, rows, &mut report);
+                    }
+                    executor.emit(node_id, NodeEvent::from(&status));
+                    executor.record(node_id, status, &mut report, &mut stopped);
+                }
+            });
+        }
+        if let Some(progress) = self.progress.as_mut() {
+            progress.finish();
+        }
+        if report.hook_error.is_none() {
+            if let (NodeStatus::Failed(e), _) = self.build(None, &self.on_run_end.clone()) {
+                report.hook_error = Some(e);
+            }
+        }
+        report
+    }
+
+    /// Runs each node's statements, e.g. `DROP`s, dependents first, so nothing is removed
+    /// while something built on it remains. A node with a dependent that failed is skipped.
+    /// Run hooks aren't involved.
+    pub fn teardown(
+        &mut self,
+        graph: &QueryGraph,
+        statements: &FnvHashMap<u64, Vec<String>>,
+    ) -> RunReport {
+        let mut report = RunReport {
+            analysis_only: self.analysis_only,
+            ..RunReport::default()
+        };
+        let mut stopped = false;
+        // The layers of `QueryGraph::teardown_order`, so each can be dropped in parallel.
+        for layer in graph.execution_layers().into_iter().rev() {
+            let mut jobs = Vec::new();
+            for node_id in layer {
+                let downstream_ok = graph.get_children(node_id).iter().all(|c| {
+                    !matches!(
+                        report.status(*c),
+                        Some(NodeStatus::Failed(_) | NodeStatus::Skipped)
+                    )
+                });
+                let to_drop = statements.get(&node_id).map_or(&[][..], Vec::as_slice);
+                let status = if stopped || !downstream_ok {
+                    NodeStatus::Skipped
+                } else if self.analysis_only && !to_drop.is_empty() {
+                    NodeStatus::Planned
+                } else {
+                    jobs.push(Job {
+                        node_id,
+                        hooks: NodeHooks::default(),
+                        statements: to_drop,
+                        row_count: None,
+                    });
+                    continue;
+                };
+                self.record(node_id, status, &mut report, &mut stopped);
+            }
+            self.dispatch(jobs, |executor, progress| {
+                if let Progress::Finished {
+                    node_id, status, ..
+                } = progress
+                {
+                    executor.record(node_id, status, &mut report, &mut stopped);
+                }
+            });
+        }
+        report
+    }
+
+    fn record(&self, node_id: u64, status: NodeStatus, report: &mut RunReport, stopped: &mut bool) {
+        if matches!(status, NodeStatus::Failed(_)) && self.policy == FailurePolicy::FailFast {
+            *stopped = true;
+        }
+        report.statuses.insert(node_id, status);
+    }
+
+    // Builds `jobs`, reporting each as it starts and finishes through `progress` on this
+    // thread. Jobs routed to a pool go first, up to `threads` at once, then the rest in
+    // order. Under `FailFast`, jobs not yet started once one fails finish as skipped.
+    fn dispatch(&mut self, jobs: Vec<Job<'_>>, mut progress: impl FnMut(&mut Self, Progress)) {
+        let parallel = self.threads > 1 && !self.analysis_only;
+        let (pooled, in_order): (Vec<_>, Vec<_>) = jobs
+            .into_iter()
+            .map(|job| (self.pool_for(job.node_id).filter(|_| parallel), job))
+            .partition(|(pool, job)| pool.is_some() && !job.is_empty());
+        let fail_fast = self.policy == FailurePolicy::FailFast;
+        let halted = AtomicBool::new(false);
+        let skipped = |node_id| Progress::Finished {
+            node_id,
+            status: NodeStatus::Skipped,
+            rows: 0,
+            took: Duration::ZERO,
+        };
+        if !pooled.is_empty() {
+            let workers = self.threads.min(pooled.len());
+            let queue = Mutex::new(pooled.into_iter());
+            let (sender, received) = mpsc::channel();
+            std::thread::scope(|scope| {
+                for _ in 0..workers {
+                    let (queue, halted, sender) = (&queue, &halted, sender.clone());
+                    scope.spawn(move || loop {
+                        let next = queue.lock().unwrap_or_else(PoisonError::into_inner).next();
+                        let Some((Some(pool), job)) = next else {
+                            break;
+                        };
+                        if halted.load(Ordering::SeqCst) {
+                            let _ = sender.send(skipped(job.node_id));
+                            continue;
+                        }
+                        let _ = sender.send(Progress::Running(job.node_id));
+                        let started = Instant::now();
+                        let (status, rows) = match pool.acquire_waiting() {
+                            Ok(mut backend) => build_checked(&mut *backend, &job),
+                            Err(e) => (NodeStatus::Failed(e.to_string()), 0),
+                        };
+                        if fail_fast && matches!(status, NodeStatus::Failed(_)) {
+                            halted.store(true, Ordering::SeqCst);
+                        }
+                        let _ = sender.send(Progress::Finished {
+                            node_id: job.node_id,
+                            status,
+                            rows,
+                            took: started.elapsed(),
+                        });
+                    });
+                }
+                drop(sender);
+                for update in received {
+                    progress(self, update);
+                }
+            });
+        }
+        for (_, job) in in_order {
+            if halted.load(Ordering::SeqCst) {
+                progress(self, skipped(job.node_id));
+                continue;
+            }
+            progress(self, Progress::Running(job.node_id));
+            let started = Instant::now();
+            let (status, rows) = if self.analysis_only || job.is_empty() {
+                (NodeStatus::Succeeded, 0)
+            } else {
+                self.with_backend(Some(job.node_id), |backend| build_checked(backend, &job))
+                    .unwrap_or_else(|e| (NodeStatus::Failed(e.to_string()), 0))
+            };
+            if fail_fast && matches!(status, NodeStatus::Failed(_)) {
+                halted.store(true, Ordering::SeqCst);
+            }
+            progress(
+                self,
+                Progress::Finished {
+                    node_id: job.node_id,
+                    status,
+                    rows,
+                    took: started.elapsed(),
+                },
+            );
+        }
+    }
+
+    fn check_thresholds(&self, node_id: u64, took: Duration, rows: u64, report: &mut RunReport) {
+        let Some(thresholds) = self.thresholds.get(&node_id) else {
+            return;
+        };
+        if let Some(expected) = thresholds.duration.filter(|expected| took > *expected) {
+            report.threshold_warnings.push(ThresholdWarning::Duration {
+                node_id,
+                took,
+                expected,
+            });
+        }
+        if let Some(expected) = thresholds.rows.filter(|expected| rows > *expected) {
+            report.threshold_warnings.push(ThresholdWarning::Rows {
+                node_id,
+                rows,
+                expected,
+            });
+        }
+    }
+
+    fn build(&mut self, node_id: Option<u64>, statements: &[String]) -> (NodeStatus, u64) {
+        if self.analysis_only || statements.is_empty() {
+            return (NodeStatus::Succeeded, 0);
+        }
+        self.with_backend(node_id, |backend| build(backend, statements))
+            .unwrap_or_else(|e| (NodeStatus::Failed(e.to_string()), 0))
+    }
+}
+
+// The status, and how many rows the statements reported affecting between them.
+fn build(backend: &mut dyn Backend, statements: &[String]) -> (NodeStatus, u64) {
+    let mut rows = 0;
+    for sql in statements {
+        match backend.execute(sql) {
+            Ok(affected) => rows += affected,
+            Err(e) => return (NodeStatus::Failed(e.to_string()), rows),
+        }
+    }
+    (NodeStatus::Succeeded, rows)
+}
+
+// `build`, with the node's pre-hooks first and post-hooks after, stopping at the first
+// failure. The hooks' rows aren't counted.
+fn build_hooked(
+    backend: &mut dyn Backend,
+    hooks: &NodeHooks,
+    statements: &[String],
+) -> (NodeStatus, u64) {
+    if let (failed @ NodeStatus::Failed(_), _) = build(backend, &hooks.pre) {
+        return (failed, 0);
+    }
+    let (status, rows) = build(backend, statements);
+    if status != NodeStatus::Succeeded {
+        return (status, rows);
+    }
+    (build(backend, &hooks.post).0, rows)
+}
+
+// `build_hooked` for `job`, then its row count check if it has one and something was built.
+fn build_checked(backend: &mut dyn Backend, job: &Job<'_>) -> (NodeStatus, u64) {
+    let (status, rows) = build_hooked(backend, &job.hooks, job.statements);
+    match &job.row_count {
+        Some((relation, bounds))
+            if status == NodeStatus::Succeeded && !job.statements.is_empty() =>
+        {
+            match check_row_count(backend, relation, bounds) {
+                Ok(()) => (status, rows),
+                Err(e) => (NodeStatus::Failed(e.to_string()), rows),
+            }
+        }
+        _ => (status, rows),
+    }
+}
+
+/// Every model downstream of a source whose freshness check came back `Warn` or `Error`,
+/// i.e. everything that needs rebuilding because its inputs moved.
+pub fn invalidate_stale_downstream(
+    graph: &QueryGraph,
+    freshness: &FnvHashMap<u64, FreshnessStatus>,
+) -> FnvHashSet<u64> {
+    freshness
+        .iter()
+        .filter(|(_, status)| **status != FreshnessStatus::Fresh)
+        .flat_map(|(source, _)| graph.get_downstream(*source))
+        .collect()
+}
+
+#[cfg(test)]
+mod test_executor {
+    use super::*;
+    use crate::{progress::ProgressCounts, testing::StubBackend};
+
+    // Fails any statement containing `boom`.
+    fn failing() -> StubBackend {
+        StubBackend::new("failing").failing_on("boom")
+    }
+
+    // Source 0 feeds 1 -> 2 and 3 -> 4; node 1 fails. Source 5 feeds 6.
+    fn fixture() -> (QueryGraph, FnvHashMap<u64, Vec<String>>) {
+        let graph =
+            QueryGraph::new_from_edges(vec![(0, 1), (1, 2), (0, 3), (3, 4), (5, 6)]).unwrap();
+        let statements = [
+            (1, "boom"),
+            (2, "build 2"),
+            (3, "build 3"),
+            (4, "build 4"),
+            (6, "build 6"),
+        ]
+        .into_iter()
+        .map(|(id, sql)| (id, vec![sql.to_string()]))
+        .collect();
+        (graph, statements)
+    }
+
+    #[test]
+    fn test_row_count_out_of_bounds_fails_the_node() {
+        let (graph, statements) = fixture();
+        let mut backend = failing().with_count(0);
+        let bounds = RowCount { min: 1, max: None };
+        let row_counts = [(1, "marts.one"), (3, "marts.three")]
+            .into_iter()
+            .map(|(id, relation)| (id, (relation.to_string(), bounds)))
+            .collect();
+        let report = Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .with_row_counts(row_counts)
+            .run(&graph, &statements);
+
+        assert!(matches!(
+            report.status(3),
+            Some(NodeStatus::Failed(e)) if e == "marts.three has 0 rows, expected at least 1"
+        ));
+        assert_eq!(report.status(4), Some(&NodeStatus::Skipped));
+        // Node 1 failed to build, so it was never counted.
+        assert_eq!(
+            backend.executed(),
+            [
+                "boom",
+                "build 3",
+                "SELECT count(*) FROM marts.three",
+                "build 6"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_going_finishes_independent_branches() {
+        let (graph, statements) = fixture();
+        let mut backend = failing();
+        let report = Executor::new(&mut backend, FailurePolicy::KeepGoing).run(&graph, &statements);
+        assert!(matches!(report.status(1), Some(NodeStatus::Failed(_))));
+        assert_eq!(report.status(2), Some(&NodeStatus::Skipped));
+        assert_eq!(report.status(4), Some(&NodeStatus::Succeeded));
+        assert_eq!(report.status(6), Some(&NodeStatus::Succeeded));
+        assert!(!backend.executed().contains(&"build 2".to_string()));
+    }
+
+    #[test]
+    fn test_fail_fast_stops_dispatch() {
+        let (graph, statements) = fixture();
+        let mut backend = failing();
+        let report = Executor::new(&mut backend, FailurePolicy::FailFast).run(&graph, &statements);
+        assert!(report.has_failures());
+        assert_eq!(report.status(4), Some(&NodeStatus::Skipped));
+        // Layers run in id order, so 3 and 6 come after the failing 1 within the same layer.
+        assert_eq!(report.status(3), Some(&NodeStatus::Skipped));
+        assert_eq!(report.status(6), Some(&NodeStatus::Skipped));
+        assert_eq!(backend.executed(), vec!["boom"]);
+    }
+
+    #[test]
+    fn test_heavier_nodes_are_dispatched_first_within_a_layer() {
+        use crate::testing::InMemoryBackend;
+        // Source 0 feeds 1, 2 and 3, which all feed 4.
+        let graph =
+            QueryGraph::new_from_edges(vec![(0, 1), (0, 2), (0, 3), (1, 4), (2, 4), (3, 4)])
+                .unwrap();
+        let statements: FnvHashMap<_, _> = (1..=4)
+            .map(|id| (id, vec![format!("build {id}")]))
+            .collect();
+        let mut backend = InMemoryBackend::new();
+        Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .with_weights(FnvHashMap::from_iter([(2, 5), (3, 10), (4, 100)]))
+            .run(&graph, &statements);
+        assert_eq!(
+            backend.executed(),
+            ["build 3", "build 2", "build 1", "build 4"]
+        );
+
+        let mut unweighted = InMemoryBackend::new();
+        Executor::new(&mut unweighted, FailurePolicy::KeepGoing).run(&graph, &statements);
+        assert_eq!(
+            unweighted.executed(),
+            ["build 1", "build 2", "build 3", "build 4"]
+        );
+    }
+
+    #[test]
+    fn test_run_hooks_wrap_every_node() {
+        let (graph, mut statements) = fixture();
+        statements.insert(1, vec!["build 1".to_string()]);
+        let start = vec!["SET search_path TO analytics".to_string()];
+        let end = vec!["INSERT INTO audit.runs VALUES (now())".to_string()];
+        let mut backend = failing();
+        let report = Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .with_run_hooks(&start, &end)
+            .run(&graph, &statements);
+        assert!(!report.has_failures());
+        assert_eq!(backend.executed().len(), 7);
+        assert_eq!(backend.executed().first(), start.first());
+        assert_eq!(backend.executed().last(), end.last());
+    }
+
+    #[test]
+    fn test_node_hooks_run_around_their_statements() {
+        let graph = QueryGraph::new_from_edges(vec![(0, 1), (1, 2)]).unwrap();
+        let statements = [(1, "CREATE TABLE orders"), (2, "CREATE TABLE revenue")]
+            .into_iter()
+            .map(|(id, sql)| (id, vec![sql.to_string()]))
+            .collect();
+        let hooks = |pre: &[&str], post: &[&str]| NodeHooks {
+            pre: pre.iter().map(|h| h.to_string()).collect(),
+            post: post.iter().map(|h| h.to_string()).collect(),
+        };
+        let mut backend = failing();
+        let report = Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .with_node_hooks(FnvHashMap::from_iter([(
+                1,
+                hooks(
+                    &["SET role loader", "LOCK raw"],
+                    &["ANALYZE orders", "GRANT orders"],
+                ),
+            )]))
+            .run(&graph, &statements);
+        assert!(!report.has_failures());
+        assert_eq!(
+            backend.executed(),
+            vec![
+                "SET role loader",
+                "LOCK raw",
+                "CREATE TABLE orders",
+                "ANALYZE orders",
+                "GRANT orders",
+                "CREATE TABLE revenue"
+            ]
+        );
+
+        let mut backend = failing();
+        let report = Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .with_node_hooks(FnvHashMap::from_iter([(
+                1,
+                hooks(&[], &["GRANT boom", "ANALYZE orders"]),
+            )]))
+            .run(&graph, &statements);
+        // The table was built before the post-hook failed, but the node still counts as failed.
+        assert!(matches!(report.status(1), Some(NodeStatus::Failed(_))));
+        assert_eq!(report.status(2), Some(&NodeStatus::Skipped));
+        assert_eq!(
+            backend.executed(),
+            vec!["CREATE TABLE orders", "GRANT boom"]
+        );
+    }
+
+    #[test]
+    fn test_failing_start_hook_prevents_model_execution() {
+        let (graph, statements) = fixture();
+        let start = vec!["boom".to_string()];
+        let end = vec!["INSERT INTO audit.runs VALUES (now())".to_string()];
+        let mut backend = failing();
+        let report = Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .with_run_hooks(&start, &end)
+            .run(&graph, &statements);
+        assert!(report.has_failures());
+        assert!(report.hook_error.is_some());
+        assert!(report.statuses.values().all(|s| *s == NodeStatus::Skipped));
+        assert_eq!(backend.executed(), vec!["boom"]);
+    }
+
+    #[test]
+    fn test_analysis_mode_plans_without_touching_the_warehouse() {
+        let (graph, statements) = fixture();
+        let start = vec!["GRANT SELECT ON ALL TABLES IN SCHEMA marts TO bi".to_string()];
+        let mut backend = failing();
+        let report = Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .with_run_hooks(&start, &[])
+            .analysis_only(true)
+            .run(&graph, &statements);
+        assert!(backend.executed().is_empty());
+        assert!(report.analysis_only);
+        assert!(!report.has_failures());
+        for node_id in statements.keys() {
+            assert_eq!(report.status(*node_id), Some(&NodeStatus::Planned));
+        }
+        assert_eq!(report.status(0), Some(&NodeStatus::Succeeded));
+    }
+
+    #[test]
+    fn test_stale_sources_invalidate_union_of_downstream() {
+        // Sources 0 and 5 both feed 3; 7 is fresh and feeds 8.
+        let graph =
+            QueryGraph::new_from_edges(vec![(0, 1), (1, 3), (5, 3), (5, 6), (3, 4), (7, 8)])
+                .unwrap();
+        let freshness = [
+            (0, FreshnessStatus::Warn),
+            (5, FreshnessStatus::Error),
+            (7, FreshnessStatus::Fresh),
+        ]
+        .into_iter()
+        .collect();
+        let mut invalidated: Vec<_> = invalidate_stale_downstream(&graph, &freshness)
+            .into_iter()
+            .collect();
+        invalidated.sort_unstable();
+        assert_eq!(invalidated, vec![1, 3, 4, 6]);
+    }
+
+    #[test]
+    fn test_event_log_is_ordered_ndjson() {
+        let (graph, statements) = fixture();
+        let mut backend = failing();
+        let mut sink = Vec::new();
+        let names = [(1, "stg_orders".to_string())].into_iter().collect();
+        Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .with_event_log(EventLog::new(&mut sink, names))
+            .run(&graph, &statements);
+
+        let events: Vec<serde_json::Value> = String::from_utf8(sink)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let events_for = |id: u64| {
+            events
+                .iter()
+                .filter(|e| e["node_id"] == id)
+                .map(|e| e["event"].as_str().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(events_for(1), vec!["queued", "running", "failed"]);
+        assert_eq!(events_for(2), vec!["queued", "skipped"]);
+        assert_eq!(events_for(4), vec!["queued", "running", "succeeded"]);
+        // Seven nodes queued and finished; all but the skipped node 2 ran.
+        assert_eq!(events.len(), 7 + 6 + 7);
+        assert!(events.iter().any(|e| e["name"] == "stg_orders"));
+        let timestamps: Vec<_> = events.iter().map(|e| e["timestamp_ms"].as_u64()).collect();
+        assert!(timestamps.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn test_progress_counts_nodes_as_they_start_and_finish() {
+        let (graph, statements) = fixture();
+        let mut backend = failing();
+        let mut sink = Vec::new();
+        let names = [(1, "stg_orders".to_string())].into_iter().collect();
+        let mut executor = Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .with_progress(ProgressReporter::new(&mut sink, false, names));
+        executor.run(&graph, &statements);
+        // Seven nodes; the skipped node 2 never ran, but is done with all the same.
+        assert_eq!(
+            executor.progress().unwrap().counts(),
+            ProgressCounts {
+                total: 7,
+                started: 7,
+                finished: 7,
+                failed: 1,
+            }
+        );
+        drop(executor);
+
+        let lines = String::from_utf8(sink).unwrap();
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(lines.len(), 6 + 7);
+        assert_eq!(lines[0], "[0/7] started node 0");
+        assert!(lines.contains(&"[2/7] started stg_orders"));
+        assert!(lines.iter().any(|l| l.ends_with("failed stg_orders")));
+        assert!(lines.iter().any(|l| l.ends_with("skipped node 2")));
+    }
+
+    #[test]
+    fn test_teardown_drops_dependents_first() {
+        // Source 0 feeds 1, which feeds 2 and 3; 3 also reads 2.
+        let graph = QueryGraph::new_from_edges(vec![(0, 1), (1, 2), (1, 3), (2, 3)]).unwrap();
+        let statements: FnvHashMap<_, _> = [(1, "stg_orders"), (2, "orders"), (3, "revenue")]
+            .into_iter()
+            .map(|(id, table)| (id, vec![format!("DROP TABLE IF EXISTS {table}")]))
+            .collect();
+        let mut backend = failing();
+        let report =
+            Executor::new(&mut backend, FailurePolicy::KeepGoing).teardown(&graph, &statements);
+        assert!(!report.has_failures());
+        assert_eq!(
+            backend.executed(),
+            vec![
+                "DROP TABLE IF EXISTS revenue",
+                "DROP TABLE IF EXISTS orders",
+                "DROP TABLE IF EXISTS stg_orders"
+            ]
+        );
+
+        let mut statements = statements;
+        statements.insert(2, vec!["DROP TABLE boom".to_string()]);
+        let mut backend = failing();
+        let report =
+            Executor::new(&mut backend, FailurePolicy::KeepGoing).teardown(&graph, &statements);
+        assert!(matches!(report.status(2), Some(NodeStatus::Failed(_))));
+        assert_eq!(report.status(1), Some(&NodeStatus::Skipped));
+        assert_eq!(backend.executed().len(), 2);
+
+        let mut backend = failing();
+        let report = Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .analysis_only(true)
+            .teardown(&graph, &statements);
+        assert!(report.analysis_only);
+        assert_eq!(report.status(2), Some(&NodeStatus::Planned));
+        assert!(backend.executed().is_empty());
+    }
+
+    #[test]
+    fn test_models_route_to_their_database_connection() {
+        use crate::testing::InMemoryBackend;
+        // Model 1 lives in `analytics`, model 2 in `finance` and reads model 1.
+        let graph = QueryGraph::new_from_edges(vec![(0, 1), (1, 2)]).unwrap();
+        let statements: FnvHashMap<_, _> = [
+            (1, "CREATE TABLE analytics.marts.orders AS SELECT 1"),
+            (2, "CREATE TABLE finance.marts.revenue AS SELECT 2"),
+        ]
+        .into_iter()
+        .map(|(id, sql)| (id, vec![sql.to_string()]))
+        .collect();
+        let databases = FnvHashMap::from_iter([(1, "analytics"), (2, "finance")]);
+        let (mut analytics, mut finance) = (InMemoryBackend::new(), InMemoryBackend::new());
+
+        let mut executor = Executor::new(&mut analytics, FailurePolicy::FailFast)
+            .with_backend_for([2], &mut finance);
+        assert!(executor
+            .unsupported_cross_database(&graph, &databases)
+            .is_empty());
+        let report = executor.run(&graph, &statements);
+
+        assert!(!report.has_failures());
+        assert_eq!(analytics.executed(), statements[&1].as_slice());
+        assert_eq!(finance.executed(), statements[&2].as_slice());
+
+        let mut single_database = failing();
+        let mut executor = Executor::new(&mut analytics, FailurePolicy::FailFast)
+            .with_backend_for([2], &mut single_database);
+        assert_eq!(
+            executor.unsupported_cross_database(&graph, &databases),
+            vec![(1, 2)]
+        );
+    }
+
+    #[test]
+    fn test_connection_limit_caps_concurrent_statements() {
+        use crate::{
+            backend::pool::{BackendPool, ConnectionLimit},
+            settings::PoolConfig,
+        };
+        // Six models in a single layer, all reading source 0.
+        let graph = QueryGraph::new_from_edges((1..=6).map(|id| (0, id)).collect()).unwrap();
+        let statements = (1..=6)
+            .map(|id| (id, vec![format!("build {id}")]))
+            .collect();
+        let counting = StubBackend::new("counting").slow_on("", Duration::from_millis(20));
+        let connect = {
+            let counting = counting.clone();
+            move || Ok(Box::new(counting.clone()) as Box<dyn Backend>)
+        };
+        let limit = ConnectionLimit::new(2);
+        let config = PoolConfig {
+            max_size: 6,
+            idle_timeout: None,
+        };
+        let pool = BackendPool::new(connect, config).with_limit(&limit);
+        let report = Executor::pooled(&pool, FailurePolicy::KeepGoing)
+            .with_threads(6)
+            .run(&graph, &statements);
+
+        assert!(!report.has_failures());
+        assert!((1..=6).all(|id| report.status(id) == Some(&NodeStatus::Succeeded)));
+        // Built in parallel, but never more than two at a time.
+        assert_eq!(counting.peak(), 2);
+        assert!(pool.open() <= 2);
+    }
+
+    #[test]
+    fn test_more_threads_than_pooled_backends_wait_their_turn() {
+        use crate::{backend::pool::BackendPool, settings::PoolConfig};
+
+        // Six models in a single layer, all reading source 0.
+        let graph = QueryGraph::new_from_edges((1..=6).map(|id| (0, id)).collect()).unwrap();
+        let statements = (1..=6)
+            .map(|id| (id, vec![format!("build {id}")]))
+            .collect();
+        let counting = StubBackend::new("counting").slow_on("", Duration::from_millis(20));
+        let connect = {
+            let counting = counting.clone();
+            move || Ok(Box::new(counting.clone()) as Box<dyn Backend>)
+        };
+        let config = PoolConfig {
+            max_size: 2,
+            idle_timeout: None,
+        };
+        let pool = BackendPool::new(connect, config);
+        let report = Executor::pooled(&pool, FailurePolicy::KeepGoing)
+            .with_threads(6)
+            .run(&graph, &statements);
+
+        assert!((1..=6).all(|id| report.status(id) == Some(&NodeStatus::Succeeded)));
+        assert_eq!(counting.peak(), 2);
+        assert_eq!(pool.open(), 2);
+    }
+
+    #[test]
+    fn test_slow_model_gets_a_threshold_warning() {
+        let graph = QueryGraph::new_from_edges(vec![(0, 1), (1, 2)]).unwrap();
+        let statements = [(1, "build slow 1"), (2, "build 2")]
+            .into_iter()
+            .map(|(id, sql)| (id, vec![sql.to_string()]))
+            .collect();
+        let thresholds = FnvHashMap::from_iter([
+            (
+                1,
+                Thresholds {
+                    duration: Some(Duration::from_millis(5)),
+                    rows: None,
+                },
+            ),
+            (
+                2,
+                Thresholds {
+                    duration: Some(Duration::from_secs(60)),
+                    rows: Some(100),
+                },
+            ),
+        ]);
+        let mut backend = StubBackend::new("slow")
+            .slow_on("slow", Duration::from_millis(20))
+            .with_rows(10);
+        let report = Executor::new(&mut backend, FailurePolicy::KeepGoing)
+            .with_thresholds(thresholds)
+            .run(&graph, &statements);
+
+        assert!(!report.has_failures());
+        assert_eq!(report.status(1), Some(&NodeStatus::Succeeded));
+        assert!(matches!(
+            report.threshold_warnings.as_slice(),
+            [ThresholdWarning::Duration { node_id: 1, took, expected }]
+                if *took > *expected && *expected == Duration::from_millis(5)
+        ));
+    }
+}
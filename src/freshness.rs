@@ -0,0 +1,404 @@
+use crate::{
+    backend::Backend,
+    settings::{FreshnessCheck, FreshnessStatus},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const FRESHNESS_FILE_NAME: &str = "freshness.json";
+
+/// How one source fared in [`check_freshness`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SourceFreshness {
+    pub source: String,
+    pub status: FreshnessStatus,
+    pub loaded_at: Option<String>, // As the warehouse returned it; `None` if not probed or empty
+    pub age_secs: Option<u64>,
+    pub error: Option<String>, // Why the check couldn't run or be read; counts as `Error`
+}
+
+/// Runs each of `checks` against `backend` and classifies the source by how long before
+/// `now` it was last loaded. Excluded sources aren't probed and count as fresh. A check
+/// that fails, or returns something that isn't a timestamp, counts as an error rather than
+/// stopping the others.
+pub fn check_freshness(
+    checks: &[FreshnessCheck<'_>],
+    backend: &mut dyn Backend,
+    now: SystemTime,
+) -> Vec<SourceFreshness> {
+    checks
+        .iter()
+        .map(|check| {
+            let mut result = SourceFreshness {
+                source: check.source.as_ref().to_string(),
+                status: FreshnessStatus::Fresh,
+                loaded_at: None,
+                age_secs: None,
+                error: None,
+            };
+            let Some(sql) = &check.sql else {
+                return result;
+            };
+            let age = match backend.fetch_value(sql) {
+                Ok(Some(loaded_at)) => {
+                    let age = parse_timestamp(&loaded_at)
+                        .map(|at| now.duration_since(at).unwrap_or(Duration::ZERO));
+                    if age.is_none() {
+                        result.error = Some(format!("{loaded_at:?} is not a timestamp"));
+                    }
+                    result.loaded_at = Some(loaded_at);
+                    age
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    result.error = Some(e.to_string());
+                    None
+                }
+            };
+            result.age_secs = age.map(|age| age.as_secs());
+            result.status = match result.error {
+                Some(_) => FreshnessStatus::Error,
+                None => check.freshness.classify(age),
+            };
+            result
+        })
+        .collect()
+}
+
+/// One line per source, aligned: its name, status and how long ago it was loaded.
+pub fn render_table(results: &[SourceFreshness]) -> String {
+    let width = results
+        .iter()
+        .map(|r| r.source.len())
+        .chain(["source".len()])
+        .max()
+        .unwrap_or(0);
+    let mut out = format!("{:width$}  status  last loaded\n", "source");
+    for result in results {
+        let loaded = match (&result.error, result.age_secs, &result.loaded_at) {
+            (Some(error), _, _) => error.clone(),
+            (None, Some(age), _) => format!("{}h {:02}m ago", age / 3600, age % 3600 / 60),
+            (None, None, None) if result.status == FreshnessStatus::Fresh => {
+                "not probed".to_string()
+            }
+            (None, None, _) => "never".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "{:width$}  {:6}  {loaded}",
+            result.source,
+            result.status.name()
+        );
+    }
+    out
+}
+
+#[derive(Serialize)]
+struct FreshnessReport<'r> {
+    status: FreshnessStatus,
+    sources: &'r [SourceFreshness],
+}
+
+/// The worst status and every source's result, as a JSON object.
+pub fn render_json(results: &[SourceFreshness]) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(&FreshnessReport {
+        status: FreshnessStatus::worst(results.iter().map(|r| r.status)),
+        sources: results,
+    })
+}
+
+/// What the last freshness run found for a source.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceState {
+    pub status: FreshnessStatus,
+    pub loaded_at: Option<String>,
+    pub checked_at: u64, // Unix seconds
+}
+
+/// Each source's latest freshness, kept in a file under the target directory so that later
+/// commands, and whatever monitors the project, can read it without probing again.
+#[derive(Debug)]
+pub struct FreshnessState {
+    path: PathBuf,
+    sources: BTreeMap<String, SourceState>,
+}
+
+impl FreshnessState {
+    /// Reads the state in `dir`, starting empty if nothing has been recorded yet.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let path = dir.as_ref().join(FRESHNESS_FILE_NAME);
+        let sources = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, sources })
+    }
+
+    pub fn get(&self, source: &str) -> Option<&SourceState> {
+        self.sources.get(source)
+    }
+
+    /// Records every result as checked at `now` and writes the state out. Sources missing
+    /// from `results` keep what they had. The file is replaced whole, as a
+    /// [`WatermarkStore`](crate::watermark::WatermarkStore) is.
+    pub fn record(&mut self, results: &[SourceFreshness], now: SystemTime) -> io::Result<()> {
+        let checked_at = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        for result in results {
+            self.sources.insert(
+                result.source.clone(),
+                SourceState {
+                    status: result.status,
+                    loaded_at: result.loaded_at.clone(),
+                    checked_at,
+                },
+            );
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let staged = self.path.with_extension("json.tmp");
+        fs::write(&staged, serde_json::to_string_pretty(&self.sources)?)?;
+        fs::rename(&staged, &self.path)
+    }
+}
+
+/// Parses a timestamp as warehouses print them: `2024-03-01`, `2024-03-01 12:30:00`, with
+/// `T` in place of the space, fractional seconds, and a trailing `Z` or `+hh[:mm]` offset
+/// all allowed. Without an offset it's taken as UTC.
+pub fn parse_timestamp(text: &str) -> Option<SystemTime> {
+    let text = text.trim();
+    let date = text.get(..10)?;
+    let rest = &text[10..];
+    let mut ymd = date.split('-');
+    let (year, month, day): (i64, i64, i64) = (
+        ymd.next()?.parse().ok()?,
+        ymd.next()?.parse().ok()?,
+        ymd.next()?.parse().ok()?,
+    );
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut secs = days_from_civil(year, month, day) * 86_400;
+    if !rest.is_empty() {
+        let rest = rest.strip_prefix(['T', ' '])?;
+        let split = rest.find(['Z', '+', '-']).unwrap_or(rest.len());
+        let (time, offset) = rest.split_at(split);
+        let mut hms = time.trim().split(':');
+        let hours: i64 = hms.next()?.parse().ok()?;
+        let minutes: i64 = hms.next()?.parse().ok()?;
+        let seconds: f64 = hms.next().unwrap_or("0").parse().ok()?;
+        secs += hours * 3600 + minutes * 60 + seconds as i64;
+        secs -= parse_offset(offset)?;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+// Seconds east of UTC from `Z`, `+hh`, `+hhmm` or `+hh:mm`; nothing means UTC.
+fn parse_offset(offset: &str) -> Option<i64> {
+    let (sign, digits) = match offset.chars().next() {
+        None => return Some(0),
+        Some('Z') if offset.len() == 1 => return Some(0),
+        Some('+') => (1, &offset[1..]),
+        Some('-') => (-1, &offset[1..]),
+        _ => return None,
+    };
+    let digits = digits.replace(':', "");
+    let hours: i64 = digits.get(..2)?.parse().ok()?;
+    let minutes: i64 = match digits.get(2..) {
+        Some("") | None => 0,
+        Some(minutes) => minutes.parse().ok()?,
+    };
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+// Days from 1970-01-01 to a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod test_freshness {
+    use super::*;
+    use crate::{backend::BackendError, settings::Project, testing::StubBackend};
+    use figment::providers::{Format, Yaml};
+    use prql_compiler::ast::pl::Dialect;
+
+    // Answers each freshness query with the timestamp configured for the table it reads.
+    fn load_times(times: Vec<(&str, Result<Option<&str>, BackendError>)>) -> StubBackend {
+        times.into_iter().fold(
+            StubBackend::new("load-times"),
+            |backend, (table, loaded_at)| backend.with_value(&format!("\"{table}\""), loaded_at),
+        )
+    }
+
+    fn project(sources: &[&str]) -> Project {
+        let sources: String = sources
+            .iter()
+            .map(|name| {
+                format!(
+                    r#"
+  - name: {name}
+    enabled: true
+    freshness:
+      loaded_at_field:
+        table: {{ database: raw, schema: public, table: {name} }}
+        column: loaded_at
+      warn_after: {{ count: 12, period: Hour }}
+      error_after: {{ count: 1, period: Day }}"#
+                )
+            })
+            .collect();
+        let yaml = format!(
+            "name: shop\nversion: \"0.1.0\"\nmodel_path: .\nseed_path: .\nclean_targets: target\n\
+             log_path: logs\nmodels: []\nseeds: []\nsources:{sources}\n"
+        );
+        figment::Figment::from(Yaml::string(&yaml))
+            .extract()
+            .unwrap()
+    }
+
+    fn statuses(results: &[SourceFreshness]) -> Vec<(&str, FreshnessStatus)> {
+        results
+            .iter()
+            .map(|r| (r.source.as_str(), r.status))
+            .collect()
+    }
+
+    #[test]
+    fn test_mixed_statuses_set_the_exit_code() {
+        let now = parse_timestamp("2024-03-02 00:00:00").unwrap();
+        let project = project(&["orders", "customers", "events", "refunds"]);
+        let checks = project
+            .freshness_checks(&Dialect::PostgreSql, &["refunds"])
+            .unwrap();
+        let mut backend = load_times(vec![
+            ("orders", Ok(Some("2024-03-01 20:00:00+00"))),
+            ("customers", Ok(Some("2024-03-01T10:30:00Z"))),
+            ("events", Ok(None)),
+        ]);
+        let results = check_freshness(&checks, &mut backend, now);
+        assert_eq!(
+            statuses(&results),
+            vec![
+                ("orders", FreshnessStatus::Fresh),
+                ("customers", FreshnessStatus::Warn),
+                ("events", FreshnessStatus::Error),
+                ("refunds", FreshnessStatus::Fresh),
+            ]
+        );
+        assert_eq!(results[0].age_secs, Some(4 * 60 * 60));
+        let worst = FreshnessStatus::worst(results.iter().map(|r| r.status));
+        assert_eq!(worst.exit_code(), 2);
+        assert_eq!(
+            FreshnessStatus::worst(results[..2].iter().map(|r| r.status)).exit_code(),
+            1
+        );
+
+        let mut backend = load_times(vec![
+            (
+                "orders",
+                Err(BackendError::Execution(String::from("timeout"))),
+            ),
+            ("customers", Ok(Some("yesterday"))),
+            ("events", Ok(Some("2024-03-01 23:59:59.5"))),
+        ]);
+        let results = check_freshness(&checks, &mut backend, now);
+        assert_eq!(
+            statuses(&results)[..3],
+            [
+                ("orders", FreshnessStatus::Error),
+                ("customers", FreshnessStatus::Error),
+                ("events", FreshnessStatus::Fresh),
+            ]
+        );
+        assert_eq!(
+            results[1].error.as_deref(),
+            Some("\"yesterday\" is not a timestamp")
+        );
+        let table = render_table(&results);
+        assert!(
+            table.starts_with("source     status  last loaded\n"),
+            "{table}"
+        );
+        assert!(table.contains("events     fresh   0h 00m ago\n"), "{table}");
+        assert!(table.contains("refunds    fresh   not probed\n"), "{table}");
+        let json: serde_json::Value =
+            serde_json::from_str(&render_json(&results).unwrap()).unwrap();
+        assert_eq!(json["status"], "error");
+        assert_eq!(json["sources"][2]["source"], "events");
+    }
+
+    #[test]
+    fn test_state_keeps_each_sources_latest_result() {
+        let dir = std::env::temp_dir().join(format!("orbital-freshness-{}", std::process::id()));
+        let result = |source: &str, status| SourceFreshness {
+            source: source.to_string(),
+            status,
+            loaded_at: Some("2024-03-01".to_string()),
+            age_secs: None,
+            error: None,
+        };
+        let mut state = FreshnessState::open(&dir).unwrap();
+        let first = UNIX_EPOCH + Duration::from_secs(100);
+        state
+            .record(
+                &[
+                    result("orders", FreshnessStatus::Fresh),
+                    result("events", FreshnessStatus::Warn),
+                ],
+                first,
+            )
+            .unwrap();
+        state
+            .record(
+                &[result("orders", FreshnessStatus::Error)],
+                first + Duration::from_secs(1),
+            )
+            .unwrap();
+        let reopened = FreshnessState::open(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let orders = reopened.get("orders").unwrap();
+        assert_eq!(
+            (orders.status, orders.checked_at),
+            (FreshnessStatus::Error, 101)
+        );
+        assert_eq!(
+            reopened.get("events").unwrap().status,
+            FreshnessStatus::Warn
+        );
+        assert!(reopened.get("refunds").is_none());
+    }
+
+    #[test]
+    fn test_timestamps_in_warehouse_formats() {
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(parse_timestamp("1970-01-02"), at(86_400));
+        assert_eq!(parse_timestamp("2024-03-01 12:30:00"), at(1_709_296_200));
+        assert_eq!(
+            parse_timestamp("2024-03-01T12:30:00.123456Z"),
+            at(1_709_296_200)
+        );
+        assert_eq!(
+            parse_timestamp("2024-03-01 14:30:00+02:00"),
+            at(1_709_296_200)
+        );
+        assert_eq!(parse_timestamp("2024-03-01 07:30:00-05"), at(1_709_296_200));
+        assert_eq!(parse_timestamp("2024-02-29 00:00:00"), at(1_709_164_800));
+        assert_eq!(parse_timestamp("yesterday"), None);
+        assert_eq!(parse_timestamp("2024-13-01"), None);
+        assert_eq!(parse_timestamp("2024-03-01 noon"), None);
+    }
+}
@@ -0,0 +1,18 @@
+pub mod backend;
+pub mod build;
+pub mod coverage;
+pub mod data_test;
+pub mod executor;
+pub mod freshness;
+pub mod lock;
+pub mod manifest;
+pub mod progress;
+pub mod query_graph;
+pub mod server;
+pub mod settings;
+pub mod sql;
+pub mod target;
+#[cfg(any(test, feature = "memory"))]
+pub mod testing;
+pub mod warning;
+pub mod watermark;
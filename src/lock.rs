@@ -0,0 +1,330 @@
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub const LOCK_FILE_NAME: &str = "orbital.lock";
+
+// Times to try taking over a stale lock while another process is doing the same.
+const STALE_ATTEMPTS: u32 = 5;
+
+// How long a lock without a readable PID, or a guard taken to remove a stale lock, may sit
+// untouched before it's taken to be left over from a crash. Both are normally gone within
+// milliseconds.
+const STALE_AFTER: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum LockError {
+    /// Another live orbital process holds the lock. `pid` is `None` while the owner has yet
+    /// to write it.
+    Held {
+        pid: Option<u32>,
+        path: PathBuf,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Held {
+                pid: Some(pid),
+                path,
+            } => write!(
+                f,
+                "another orbital run (pid {pid}) holds {}; wait for it to finish",
+                path.display()
+            ),
+            LockError::Held { pid: None, path } => write!(
+                f,
+                "another orbital run is starting and holds {}; wait for it to finish",
+                path.display()
+            ),
+            LockError::Io(e) => write!(f, "could not take the run lock: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+impl From<io::Error> for LockError {
+    fn from(value: io::Error) -> Self {
+        LockError::Io(value)
+    }
+}
+
+/// Exclusive lock over a project's warehouse for the length of a run, held as a file under
+/// `log_path` containing the owner's PID. Released when dropped, unless taken over by then.
+#[derive(Debug)]
+pub struct RunLock {
+    path: PathBuf,
+}
+
+impl RunLock {
+    /// Takes the lock, replacing it if the process that left it behind is gone, or if it
+    /// never got as far as writing its PID and the lock is older than [`STALE_AFTER`].
+    pub fn acquire(dir: impl AsRef<Path>) -> Result<Self, LockError> {
+        fs::create_dir_all(dir.as_ref())?;
+        let path = dir.as_ref().join(LOCK_FILE_NAME);
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match Self::create(&path) {
+                Err(LockError::Held { pid, path }) if Self::is_stale(&path, pid)? => {
+                    if !Self::remove_stale(&path, pid)? {
+                        if attempts >= STALE_ATTEMPTS {
+                            return Err(LockError::Held { pid, path });
+                        }
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn is_stale(path: &Path, pid: Option<u32>) -> io::Result<bool> {
+        match pid {
+            Some(pid) => Ok(!process_is_alive(pid)),
+            None => older_than(path, STALE_AFTER),
+        }
+    }
+
+    // Removes the lock at `path` if it still belongs to `pid`, returning false if another
+    // process is already doing so. The check and the removal happen under a second lock
+    // file, so two processes finding the same stale lock can't have one remove the fresh
+    // lock the other just took in its place. A guard older than `STALE_AFTER` was left by a
+    // process that crashed while holding it, and is cleared. Either way, the caller tries
+    // again to create.
+    fn remove_stale(path: &Path, pid: Option<u32>) -> Result<bool, LockError> {
+        let guard = path.with_extension("lock.stale");
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&guard)
+        {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if older_than(&guard, STALE_AFTER)? {
+                    remove_if_present(&guard)?;
+                }
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let removed = Self::remove_if_owned(path, pid);
+        let _ = fs::remove_file(&guard);
+        removed?;
+        Ok(true)
+    }
+
+    // Re-checks the lock under the guard: the PID must be unchanged, and a lock still
+    // without one must still be past the grace period.
+    fn remove_if_owned(path: &Path, pid: Option<u32>) -> io::Result<()> {
+        let owner = match fs::read_to_string(path) {
+            Ok(owner) => owner,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if owner.trim().parse().ok() == pid && Self::is_stale(path, pid)? {
+            remove_if_present(path)?;
+        }
+        Ok(())
+    }
+
+    fn create(path: &Path) -> Result<Self, LockError> {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(mut file) => {
+                write!(file, "{}", std::process::id())?;
+                Ok(Self {
+                    path: path.to_path_buf(),
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                // An unreadable PID means the owner is mid-write, or crashed before writing.
+                let pid = match fs::read_to_string(path) {
+                    Ok(owner) => owner.trim().parse().ok(),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e.into()),
+                };
+                Err(LockError::Held {
+                    pid,
+                    path: path.to_path_buf(),
+                })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Only removes the lock if it still holds this process's PID. Another process may have
+// judged it stale, e.g. while this one was paused, and taken it over.
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let ours = fs::read_to_string(&self.path)
+            .is_ok_and(|owner| owner.trim().parse() == Ok(std::process::id()));
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Whether `path` was last written at least `age` ago. A file that's already gone counts, as
+// there's nothing left to wait for.
+fn older_than(path: &Path, age: Duration) -> io::Result<bool> {
+    match fs::metadata(path).and_then(|m| m.modified()) {
+        Ok(modified) => Ok(modified.elapsed().is_ok_and(|elapsed| elapsed >= age)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+fn remove_if_present(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Without /proc there's no cheap portable check, so assume the owner is still running.
+fn process_is_alive(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        Path::new(&format!("/proc/{pid}")).exists()
+    } else {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test_lock {
+    use super::*;
+
+    fn lock_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("orbital-lock-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn test_second_acquisition_fails_while_held() {
+        let dir = lock_dir("held");
+        let lock = RunLock::acquire(&dir).unwrap();
+        match RunLock::acquire(&dir) {
+            Err(LockError::Held { pid, .. }) => assert_eq!(pid, Some(std::process::id())),
+            other => panic!("expected the lock to be held, got {other:?}"),
+        }
+        drop(lock);
+        assert!(!dir.join(LOCK_FILE_NAME).exists());
+        let relocked = RunLock::acquire(&dir);
+        assert!(relocked.is_ok());
+        drop(relocked);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dropping_a_lock_taken_over_leaves_it_in_place() {
+        let dir = lock_dir("taken-over");
+        let lock = RunLock::acquire(&dir).unwrap();
+        let path = dir.join(LOCK_FILE_NAME);
+        // Another process judged this one dead and took the lock.
+        fs::write(&path, "4194305").unwrap();
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "4194305");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_stale_lock_is_replaced() {
+        let dir = lock_dir("stale");
+        fs::create_dir_all(&dir).unwrap();
+        // Above the kernel's pid_max, so no process can have it.
+        fs::write(dir.join(LOCK_FILE_NAME), "4194305").unwrap();
+        let lock = RunLock::acquire(&dir).unwrap();
+        let owner = fs::read_to_string(dir.join(LOCK_FILE_NAME)).unwrap();
+        assert_eq!(owner, std::process::id().to_string());
+        drop(lock);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_stale_lock_removal_spares_a_fresh_lock() {
+        let dir = lock_dir("raced");
+        let lock = RunLock::acquire(&dir).unwrap();
+        let path = dir.join(LOCK_FILE_NAME);
+        // Another process saw a dead owner, but this one has since taken the lock.
+        assert!(RunLock::remove_stale(&path, Some(4194305)).unwrap());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+        drop(lock);
+
+        // While someone else is removing a stale lock, it isn't removed twice.
+        fs::write(&path, "4194305").unwrap();
+        fs::write(path.with_extension("lock.stale"), "").unwrap();
+        assert!(!RunLock::remove_stale(&path, Some(4194305)).unwrap());
+        assert!(path.exists());
+        assert!(matches!(
+            RunLock::acquire(&dir),
+            Err(LockError::Held {
+                pid: Some(4194305),
+                ..
+            })
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn backdate(path: &Path) {
+        let file = fs::File::options().write(true).open(path).unwrap();
+        let modified = std::time::SystemTime::now() - STALE_AFTER * 2;
+        file.set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn test_lock_without_a_pid_is_replaced_once_it_is_old() {
+        let dir = lock_dir("empty");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(LOCK_FILE_NAME);
+        // The owner crashed between creating the file and writing its PID.
+        fs::write(&path, "").unwrap();
+        assert!(matches!(
+            RunLock::acquire(&dir),
+            Err(LockError::Held { pid: None, .. })
+        ));
+        backdate(&path);
+        let lock = RunLock::acquire(&dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+        drop(lock);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_removal_guard_left_by_a_crash_expires() {
+        let dir = lock_dir("guard");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(LOCK_FILE_NAME);
+        let guard = path.with_extension("lock.stale");
+        // A process crashed while removing this stale lock, leaving its guard behind.
+        fs::write(&path, "4194305").unwrap();
+        fs::write(&guard, "").unwrap();
+        backdate(&guard);
+        let lock = RunLock::acquire(&dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+        assert!(!guard.exists());
+        drop(lock);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,879 @@
-mod query_graph;
-mod settings;
+use fnv::FnvHashMap;
+use orbital::{
+    backend::{
+        pool::{BackendPool, ConnectionLimit},
+        BackendFactory,
+    },
+    build::BuiltProject,
+    coverage::Coverage,
+    executor::{EventLog, FailurePolicy, NodeStatus, RunReport, ThresholdWarning},
+    freshness::{check_freshness, render_json, render_table, FreshnessState},
+    lock::RunLock,
+    manifest::Manifest,
+    progress::ProgressReporter,
+    query_graph::{
+        query::{QueryKind, QueryName},
+        selector::{render_selection, SelectorSet},
+    },
+    server::RpcServer,
+    settings::{FreshnessStatus, Project, ResourceProperties},
+    target::{self, TargetDir},
+    watermark::WatermarkStore,
+};
+use std::{
+    env,
+    fs::File,
+    io::{self, IsTerminal, Write},
+    net::TcpListener,
+    path::PathBuf,
+    process::ExitCode,
+    time::SystemTime,
+};
 
-fn main() {
-    println!("Hello, world!");
-    query_graph::query::QueryCollection::new();
+const DEFAULT_PROJECT_FILE: &str = "orbital.yml";
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1).peekable();
+    match args.next().as_deref() {
+        Some("validate-config") => {
+            validate_config(args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE))
+        }
+        Some("show") => match args.next() {
+            Some(model) => show(
+                &model,
+                args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE),
+            ),
+            None => {
+                eprintln!("usage: orbital show <model> [project file]");
+                ExitCode::FAILURE
+            }
+        },
+        Some("ls") => {
+            let select = match (args.next().as_deref(), args.next()) {
+                (Some("--select"), Some(selector)) => Some(selector),
+                _ => None,
+            };
+            let exclude = match args.next_if(|arg| arg == "--exclude") {
+                Some(_) => args.next().map(Some),
+                None => Some(None),
+            };
+            match (select, exclude) {
+                (Some(select), Some(exclude)) => ls(
+                    &select,
+                    exclude.as_deref(),
+                    args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE),
+                ),
+                _ => {
+                    eprintln!(
+                        "usage: orbital ls --select <selector> [--exclude <selector>] [project file]"
+                    );
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Some("compile") => {
+            let (mut layered, mut sql_header) = (false, true);
+            while let Some(flag) =
+                args.next_if(|arg| arg == "--layered" || arg == "--no-sql-header")
+            {
+                layered |= flag == "--layered";
+                sql_header &= flag != "--no-sql-header";
+            }
+            match target_path_override(&mut args) {
+                Ok(target_path) => compile(
+                    target_path,
+                    layered,
+                    sql_header,
+                    args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE),
+                ),
+                Err(()) => {
+                    eprintln!(
+                        "usage: orbital compile [--layered] [--no-sql-header] [--target-path <dir>] [project file]"
+                    );
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Some("clean") => {
+            let (mut drop, mut yes) = (false, false);
+            while let Some(flag) = args.next_if(|arg| arg == "--drop" || arg == "--yes") {
+                drop |= flag == "--drop";
+                yes |= flag == "--yes";
+            }
+            match max_connections(&mut args) {
+                Ok(limit) => clean(
+                    drop,
+                    yes,
+                    limit,
+                    args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE),
+                ),
+                Err(()) => {
+                    eprintln!(
+                        "usage: orbital clean [--drop] [--yes] [--max-connections <n>] [project file]"
+                    );
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Some("coverage") => {
+            let min_coverage = match args.next_if(|arg| arg == "--min-coverage") {
+                Some(_) => match args.next().map(|pct| pct.parse::<f64>()) {
+                    Some(Ok(pct)) => Some(pct),
+                    _ => {
+                        eprintln!(
+                            "usage: orbital coverage [--min-coverage <percent>] [project file]"
+                        );
+                        return ExitCode::FAILURE;
+                    }
+                },
+                None => None,
+            };
+            coverage(
+                min_coverage,
+                args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE),
+            )
+        }
+        Some("check-drift") => match target_path_override(&mut args) {
+            Ok(target_path) => check_drift(
+                target_path,
+                args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE),
+            ),
+            Err(()) => {
+                eprintln!("usage: orbital check-drift [--target-path <dir>] [project file]");
+                ExitCode::FAILURE
+            }
+        },
+        Some("freshness") => {
+            let (mut json, mut exclude) = (false, Vec::new());
+            while let Some(flag) = args.next_if(|arg| arg == "--json" || arg == "--exclude-sources")
+            {
+                if flag == "--json" {
+                    json = true;
+                    continue;
+                }
+                let Some(names) = args.next() else {
+                    eprintln!(
+                        "usage: orbital freshness [--json] [--exclude-sources <name,...>] [project file]"
+                    );
+                    return ExitCode::FAILURE;
+                };
+                exclude.extend(
+                    names
+                        .split(',')
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string),
+                );
+            }
+            freshness(
+                json,
+                &exclude,
+                args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE),
+            )
+        }
+        Some("serve") => match (max_connections(&mut args), args.next()) {
+            (Ok(limit), Some(addr)) => serve(
+                &addr,
+                limit,
+                args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE),
+            ),
+            _ => {
+                eprintln!("usage: orbital serve [--max-connections <n>] <address> [project file]");
+                ExitCode::FAILURE
+            }
+        },
+        Some("trace-ref") => match (args.next(), args.next()) {
+            (Some(query), Some(table)) => trace_ref(
+                &query,
+                &table,
+                args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE),
+            ),
+            _ => {
+                eprintln!("usage: orbital trace-ref <query> <table> [project file]");
+                ExitCode::FAILURE
+            }
+        },
+        Some("run") => match run_flags(&mut args) {
+            Ok(flags) => run(
+                &flags,
+                args.next().as_deref().unwrap_or(DEFAULT_PROJECT_FILE),
+            ),
+            Err(()) => {
+                eprintln!(
+                    "usage: orbital run [--fail-fast|--keep-going] [--events <path|->] [--analysis] \
+                     [--threads <n>] [--max-connections <n>] [--no-progress] \
+                     [--select <selector>] [--exclude <selector>] [project file]"
+                );
+                ExitCode::FAILURE
+            }
+        },
+        command => {
+            if let Some(command) = command {
+                eprintln!("unknown command: {command}");
+            }
+            eprintln!(
+                "usage: orbital <validate-config|show|ls|compile|run|clean|coverage|check-drift|\
+                 freshness|serve|trace-ref> ..."
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Takes a leading `--target-path <dir>` off `args`, failing if the flag has no value. The
+/// directory is relative to the working directory, as usual for command-line paths.
+fn target_path_override(
+    args: &mut std::iter::Peekable<impl Iterator<Item = String>>,
+) -> Result<Option<PathBuf>, ()> {
+    if args.next_if(|arg| arg == "--target-path").is_none() {
+        return Ok(None);
+    }
+    args.next().map(|dir| Some(PathBuf::from(dir))).ok_or(())
+}
+
+// `--max-connections <n>`, if given, as the limit on backends in use at once. Fails on a
+// missing or zero count.
+fn max_connections(
+    args: &mut std::iter::Peekable<impl Iterator<Item = String>>,
+) -> Result<Option<ConnectionLimit>, ()> {
+    if args.next_if(|arg| arg == "--max-connections").is_none() {
+        return Ok(None);
+    }
+    match args.next().map(|n| n.parse::<usize>()) {
+        Some(Ok(n)) if n > 0 => Ok(Some(ConnectionLimit::new(n))),
+        _ => Err(()),
+    }
+}
+
+/// How `run` was asked to go about it.
+#[derive(Default)]
+struct RunFlags {
+    policy: FailurePolicy,
+    events: Option<String>, // `--events`: a file to stream node events to, or `-` for stdout
+    analysis: bool,         // `--analysis`: as if the project set `analysis_only`
+    threads: Option<usize>, // `--threads`: models built at once, instead of the pools' size
+    limit: Option<ConnectionLimit>, // `--max-connections`
+    no_progress: bool,      // `--no-progress`: nothing on stderr as models build
+    select: Option<SelectorSet>, // `--select`: only these models are built
+    exclude: Option<SelectorSet>, // `--exclude`: nor are these
+}
+
+// The flags leading `args` for `run`, failing on one it doesn't know or one missing its
+// value.
+fn run_flags(args: &mut std::iter::Peekable<impl Iterator<Item = String>>) -> Result<RunFlags, ()> {
+    let mut flags = RunFlags::default();
+    while let Some(flag) = args.next_if(|arg| arg.starts_with("--")) {
+        match flag.as_str() {
+            "--fail-fast" => flags.policy = FailurePolicy::FailFast,
+            "--keep-going" => flags.policy = FailurePolicy::KeepGoing,
+            "--events" => flags.events = Some(args.next().ok_or(())?),
+            "--analysis" => flags.analysis = true,
+            "--no-progress" => flags.no_progress = true,
+            "--select" | "--exclude" => {
+                let selectors = match args.next().map(|raw| raw.parse::<SelectorSet>()) {
+                    Some(Ok(selectors)) => selectors,
+                    Some(Err(e)) => {
+                        eprintln!("{e}");
+                        return Err(());
+                    }
+                    None => return Err(()),
+                };
+                if flag == "--select" {
+                    flags.select = Some(selectors);
+                } else {
+                    flags.exclude = Some(selectors);
+                }
+            }
+            "--threads" | "--max-connections" => {
+                let n = match args.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => return Err(()),
+                };
+                if flag == "--threads" {
+                    flags.threads = Some(n);
+                } else {
+                    flags.limit = Some(ConnectionLimit::new(n));
+                }
+            }
+            _ => return Err(()),
+        }
+    }
+    Ok(flags)
+}
+
+// `--target-path` when given, otherwise the project's own.
+fn target_dir(project: &Project, target_path: Option<PathBuf>) -> TargetDir {
+    TargetDir::new(target_path.unwrap_or_else(|| project.target_dir().to_path_buf()))
+}
+
+/// Loads and checks the project file only: no queries are parsed. Connections must use an
+/// adapter this binary has a backend for. If they do, the project's `connection` is asked
+/// whether every source's `loaded_at_field` columns exist.
+fn validate_config(path: &str) -> ExitCode {
+    let project = match Project::from_file(path) {
+        Ok(project) => project,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let factory = backends();
+    let mut errors = project.validate();
+    let unsupported = project.validate_adapters(&factory);
+    if let (true, Some(connection)) = (unsupported.is_empty(), &project.connection) {
+        let columns = factory
+            .create(connection)
+            .and_then(|mut backend| project.validate_loaded_at_fields(backend.as_mut()));
+        match columns {
+            Ok(missing) => errors.extend(missing),
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    errors.extend(unsupported);
+    for error in &errors {
+        eprintln!("{path}: {error}");
+    }
+    if errors.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Loads the project and every enabled model, printing each problem found (and each model
+/// file skipped as unreadable, and each graph warning) to stderr. `None` if there were problems.
+fn build(path: &str) -> Option<BuiltProject> {
+    match Project::build(path) {
+        Ok(built) => {
+            for (file, e) in &built.skipped {
+                eprintln!("warning: skipping {}: {e}", file.display());
+            }
+            for warning in built.meta.warnings() {
+                eprintln!("warning: {warning}");
+            }
+            Some(built)
+        }
+        Err(e) => {
+            for issue in &e.issues {
+                eprintln!("{path}: {issue}");
+            }
+            None
+        }
+    }
+}
+
+/// Prints the names `select` resolves to, less those `exclude` does, without building
+/// anything.
+fn ls(select: &str, exclude: Option<&str>, path: &str) -> ExitCode {
+    let parsed = select.parse::<SelectorSet>().and_then(|select| {
+        let exclude = exclude.map(str::parse::<SelectorSet>).transpose()?;
+        Ok((select, exclude))
+    });
+    let (select, exclude) = match parsed {
+        Ok(selectors) => selectors,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(built) = build(path) else {
+        return ExitCode::FAILURE;
+    };
+    let selected = built
+        .meta
+        .select_excluding(Some(&select), exclude.as_ref(), &built.tags());
+    print!("{}", render_selection(&selected));
+    ExitCode::SUCCESS
+}
+
+/// Prints one model's compiled SQL, with vars substituted, ephemeral upstreams inlined and
+/// aliased upstreams read from their relation. Nothing is written to disk.
+fn show(model: &str, path: &str) -> ExitCode {
+    let Some(built) = build(path) else {
+        return ExitCode::FAILURE;
+    };
+    match built.show(&QueryName::from(model)) {
+        Ok(sql) => {
+            println!("{sql}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Writes every non-ephemeral model's compiled SQL, then the manifest, under the target
+/// directory. Nothing is run. With `layered`, each file is named after its execution layer
+/// as well, so running them in lexical order respects dependencies. With `sql_header`, each
+/// file starts with a comment naming the model it came from.
+fn compile(target_path: Option<PathBuf>, layered: bool, sql_header: bool, path: &str) -> ExitCode {
+    let Some(built) = build(path) else {
+        return ExitCode::FAILURE;
+    };
+    let target = target_dir(&built.project, target_path);
+    let failures = built.compile(&target, layered, sql_header.then(SystemTime::now));
+    for failure in &failures {
+        eprintln!("{failure}");
+    }
+    if failures.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Builds every enabled model that isn't ephemeral, a layer at a time, each through the
+/// connection for its database. With `select` or `exclude`, only the models they leave are
+/// built, hooked and checked, reading the rest as they stand. Under `FailFast` nothing more is started once a model fails;
+/// otherwise every branch not downstream of a failure is finished. With `events`, each node's
+/// state changes are streamed there as JSON lines as they happen. With `analysis`, or the
+/// project's `analysis_only`, models are compiled and planned but nothing is written to the
+/// warehouse. Incremental models' watermarks are read from under `log_path` and moved up
+/// after the models that succeed. Up to `threads` models of a layer are built at once, with
+/// no more connections in use than `limit` allows. Progress goes to stderr as a redrawn
+/// status line on a terminal, or a line per model otherwise, unless `no_progress` is set.
+/// Holds the run lock throughout. Fails if any model can't be compiled, before anything
+/// runs, or fails to build.
+fn run(flags: &RunFlags, path: &str) -> ExitCode {
+    let Some(built) = build(path) else {
+        return ExitCode::FAILURE;
+    };
+    let built = match (&flags.select, &flags.exclude) {
+        (None, None) => built,
+        (select, exclude) => built.with_selection(select.as_ref(), exclude.as_ref()),
+    };
+    let Some(_lock) = run_lock(&built.project) else {
+        return ExitCode::FAILURE;
+    };
+    let mut events: Option<Box<dyn Write>> = match flags.events.as_deref() {
+        None => None,
+        Some("-") => Some(Box::new(io::stdout())),
+        Some(file) => match File::create(file) {
+            Ok(file) => Some(Box::new(file)),
+            Err(e) => {
+                eprintln!("{file}: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+    let Some(factory) = backends_for(&built.project, path) else {
+        return ExitCode::FAILURE;
+    };
+    let pools = match built.pools(&factory, &built.materialized(), flags.limit.as_ref()) {
+        Ok(pools) => pools,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut watermarks = match WatermarkStore::open(&built.project.log_path) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("{}: {e}", built.project.log_path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let (statements, failures) =
+        built.run_statements(Some(&watermarks), |node_id, [database, schema, table]| {
+            pools
+                .backend_for(node_id)?
+                .probe_table(database, schema, table)
+        });
+    for failure in &failures {
+        eprintln!("{failure}");
+    }
+    if !failures.is_empty() {
+        return ExitCode::FAILURE;
+    }
+    let Some(mut executor) = pools.executor(flags.policy) else {
+        println!("nothing to run");
+        return ExitCode::SUCCESS;
+    };
+    let names: FnvHashMap<u64, String> = built
+        .meta
+        .collection()
+        .values()
+        .map(|node| (**node.id(), node.name().as_str().to_string()))
+        .collect();
+    if let Some(events) = events.as_mut() {
+        executor = executor.with_event_log(EventLog::new(events.as_mut(), names.clone()));
+    }
+    let mut stderr = io::stderr();
+    if !flags.no_progress {
+        let redraw = stderr.is_terminal();
+        executor = executor.with_progress(ProgressReporter::new(&mut stderr, redraw, names));
+    }
+    if let Some(threads) = flags.threads {
+        executor = executor.with_threads(threads);
+    }
+    let report = built
+        .configure(executor)
+        .analysis_only(flags.analysis || built.project.analysis_only)
+        .run(built.meta.graph(), &statements);
+    report_threshold_warnings(&built, &report);
+    let unrecorded = built.advance_watermarks(&mut watermarks, &report, |node_id, sql| {
+        pools.backend_for(node_id)?.fetch_value(sql)
+    });
+    for line in &unrecorded {
+        eprintln!("warning: could not advance watermark: {line}");
+    }
+    if report_failures(&built, &report) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Removes `clean_targets` and the target directory. With `drop`, every enabled model's
+/// relation is first dropped from the warehouse, dependents before dependencies, after
+/// asking for confirmation unless `yes` is set, using no more connections at once than
+/// `limit` allows. Files are kept if any drop fails. Holds the run lock throughout, so
+/// nothing is removed from under a running `serve`.
+fn clean(drop: bool, yes: bool, limit: Option<ConnectionLimit>, path: &str) -> ExitCode {
+    let project = match Project::from_file(path) {
+        Ok(project) => project,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(_lock) = run_lock(&project) else {
+        return ExitCode::FAILURE;
+    };
+    if drop && !drop_models(path, yes, limit.as_ref()) {
+        return ExitCode::FAILURE;
+    }
+    match target::clean(&[&project.clean_targets, project.target_dir()]) {
+        Ok(removed) => {
+            for path in removed {
+                println!("removed {}", path.display());
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Drops every enabled model's relation in teardown order, each through the connection for
+// its database, returning whether all went.
+fn drop_models(path: &str, yes: bool, limit: Option<&ConnectionLimit>) -> bool {
+    let Some(built) = build(path) else {
+        return false;
+    };
+    let statements = built.drop_statements();
+    if statements.is_empty() {
+        return true;
+    }
+    if !yes {
+        for node_id in built.meta.graph().teardown_order() {
+            for sql in statements.get(&node_id).into_iter().flatten() {
+                println!("{sql}");
+            }
+        }
+        print!("Run these {} statements? [y/N] ", statements.len());
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err() || !answer.trim().eq_ignore_ascii_case("y") {
+            eprintln!("nothing dropped");
+            return false;
+        }
+    }
+    let Some(factory) = backends_for(&built.project, path) else {
+        return false;
+    };
+    let report = match built.teardown(&factory, &statements, limit) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{e}");
+            return false;
+        }
+    };
+    report_failures(&built, &report)
+}
+
+// Prints each model that ran past its `expected_duration` or `expected_rows` in `report`.
+fn report_threshold_warnings(built: &BuiltProject, report: &RunReport) {
+    let name = |node_id: u64| {
+        built
+            .meta
+            .collection()
+            .get_by_id(&node_id.into())
+            .map_or_else(|| node_id.to_string(), |q| q.name().as_str().to_string())
+    };
+    for warning in &report.threshold_warnings {
+        match warning {
+            ThresholdWarning::Duration {
+                node_id,
+                took,
+                expected,
+            } => eprintln!(
+                "warning: {} took {:.1}s, expected at most {}s",
+                name(*node_id),
+                took.as_secs_f64(),
+                expected.as_secs()
+            ),
+            ThresholdWarning::Rows {
+                node_id,
+                rows,
+                expected,
+            } => eprintln!(
+                "warning: {} wrote {rows} rows, expected at most {expected}",
+                name(*node_id)
+            ),
+        }
+    }
+}
+
+// Prints each model that failed in `report`, by name, returning whether none did.
+fn report_failures(built: &BuiltProject, report: &RunReport) -> bool {
+    if report.analysis_only {
+        println!("analysis mode: skipping materialization");
+    }
+    let mut failed: Vec<_> = report
+        .statuses
+        .iter()
+        .filter_map(|(id, status)| match status {
+            NodeStatus::Failed(e) => Some((built.meta.collection().get_by_id(&(*id).into())?, e)),
+            _ => None,
+        })
+        .map(|(query, e)| format!("{}: {e}", query.name().as_str()))
+        .collect();
+    failed.sort();
+    for line in &failed {
+        eprintln!("{line}");
+    }
+    if let Some(e) = &report.hook_error {
+        eprintln!("run hook failed: {e}");
+    }
+    !report.has_failures()
+}
+
+// The project's run lock, printing why if it can't be taken.
+fn run_lock(project: &Project) -> Option<RunLock> {
+    RunLock::acquire(&project.log_path)
+        .map_err(|e| eprintln!("{e}"))
+        .ok()
+}
+
+// The backends this binary can build from a project's `connection`. None are built in yet;
+// the `memory` feature adds one that runs nothing.
+fn backends() -> BackendFactory {
+    #[cfg_attr(not(feature = "memory"), allow(unused_mut))]
+    let mut factory = BackendFactory::new();
+    #[cfg(feature = "memory")]
+    factory.register_backend("memory", |_| {
+        Ok(Box::new(orbital::testing::InMemoryBackend::new()))
+    });
+    factory
+}
+
+// `backends()`, if it covers every connection in the project, printing each one it doesn't.
+fn backends_for(project: &Project, path: &str) -> Option<BackendFactory> {
+    let factory = backends();
+    let unsupported = project.validate_adapters(&factory);
+    for error in &unsupported {
+        eprintln!("{path}: {error}");
+    }
+    unsupported.is_empty().then_some(factory)
+}
+
+/// Reports which loaded models lack a description (from properties files under the model
+/// paths) or any configured test. With `min_coverage`, fails when either share is below it.
+fn coverage(min_coverage: Option<f64>, path: &str) -> ExitCode {
+    let Some(BuiltProject { project, meta, .. }) = build(path) else {
+        return ExitCode::FAILURE;
+    };
+    let properties = match ResourceProperties::load_all(project.model_path.paths()) {
+        Ok(properties) => properties,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let models = meta
+        .collection()
+        .sorted_values()
+        .into_iter()
+        .filter(|node| matches!(node, QueryKind::Query(_)))
+        .map(|node| node.name().as_str());
+    let coverage = Coverage::measure(models, &project.models, &properties);
+    print!("{}", coverage.render());
+    match min_coverage {
+        Some(min) if !coverage.meets(min) => {
+            eprintln!("coverage is below the minimum of {min}%");
+            ExitCode::FAILURE
+        }
+        _ => ExitCode::SUCCESS,
+    }
+}
+
+/// Compares the project's current lineage against the manifest last written to the target
+/// directory, listing each model whose dependencies changed. Nothing is compiled or run.
+fn check_drift(target_path: Option<PathBuf>, path: &str) -> ExitCode {
+    let Some(BuiltProject { project, meta, .. }) = build(path) else {
+        return ExitCode::FAILURE;
+    };
+    let manifest_path = target_dir(&project, target_path).manifest_path();
+    let recorded = match Manifest::read_from(&manifest_path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!("{}: {e}", manifest_path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let drift = recorded.dependency_drift(&Manifest::from_collection(meta.collection()));
+    for model in &drift {
+        println!("{model}");
+    }
+    if drift.is_empty() {
+        println!("no dependency drift");
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Probes every enabled source's freshness, records the results under the target directory
+/// and prints them, as a table or with `json` as JSON. Sources in `exclude` are skipped on
+/// top of the project's `freshness_exclude`, and naming one that isn't declared is an error.
+/// Exits 0 when every source is fresh, 1 when the worst is a warning and 2 on any error.
+/// Models aren't loaded.
+fn freshness(json: bool, exclude: &[String], path: &str) -> ExitCode {
+    let project = match Project::from_file(path) {
+        Ok(project) => project,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let exclude: Vec<&str> = exclude.iter().map(String::as_str).collect();
+    let checks = match project.freshness_checks(&project.dialect(), &exclude) {
+        Ok(checks) => checks,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{path}: {error}");
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(connection) = &project.connection else {
+        eprintln!("no connection configured to check freshness against");
+        return ExitCode::FAILURE;
+    };
+    let Some(factory) = backends_for(&project, path) else {
+        return ExitCode::FAILURE;
+    };
+    let mut backend = match factory.create(connection) {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let now = SystemTime::now();
+    let results = check_freshness(&checks, backend.as_mut(), now);
+    let recorded = FreshnessState::open(project.target_dir())
+        .and_then(|mut state| state.record(&results, now));
+    if let Err(e) = recorded {
+        eprintln!("warning: could not record freshness: {e}");
+    }
+    if json {
+        match render_json(&results) {
+            Ok(out) => println!("{out}"),
+            Err(e) => eprintln!("{e}"),
+        }
+    } else {
+        print!("{}", render_table(&results));
+    }
+    let worst = FreshnessStatus::worst(results.iter().map(|r| r.status));
+    ExitCode::from(worst.exit_code() as u8)
+}
+
+/// Answers JSON-RPC requests about the project on `addr` until interrupted, for editors.
+/// Source freshness is checked through a pool for the project's `connection`, using no more
+/// connections at once than `limit` allows. Holds the run lock while serving.
+fn serve(addr: &str, limit: Option<ConnectionLimit>, path: &str) -> ExitCode {
+    let Some(built) = build(path) else {
+        return ExitCode::FAILURE;
+    };
+    let Some(_lock) = run_lock(&built.project) else {
+        return ExitCode::FAILURE;
+    };
+    let dialect = built.project.dialect();
+    let checks = match built.project.freshness_checks(&dialect, &[]) {
+        Ok(checks) => checks,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{path}: {error}");
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(factory) = backends_for(&built.project, path) else {
+        return ExitCode::FAILURE;
+    };
+    let pool = built.project.connection.as_ref().map(|connection| {
+        let pool = BackendPool::for_connection(&factory, connection);
+        match &limit {
+            Some(limit) => pool.with_limit(limit),
+            None => pool,
+        }
+    });
+    let aliases = built.relation_aliases();
+    let mut server = RpcServer::new(built.meta, dialect).with_aliases(aliases);
+    if let Some(pool) = &pool {
+        server = server.with_freshness(checks, pool);
+    }
+    let served = TcpListener::bind(addr).and_then(|listener| server.serve(&listener));
+    match served {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{addr}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Explains what `table` means inside `query`: a model, a declared source, a seed, or a
+/// placeholder created because nothing else matched.
+fn trace_ref(query: &str, table: &str, path: &str) -> ExitCode {
+    let Some(BuiltProject {
+        project,
+        meta,
+        seeds,
+        ..
+    }) = build(path)
+    else {
+        return ExitCode::FAILURE;
+    };
+    let sources: Vec<_> = project
+        .sources
+        .iter()
+        .map(|s| s.name.as_ref().as_str())
+        .collect();
+    let seeds: Vec<_> = seeds.iter().map(String::as_str).collect();
+    match meta.collection().trace_ref(
+        &QueryName::from(query),
+        &QueryName::from(table),
+        &sources,
+        &seeds,
+    ) {
+        Ok(resolution) => {
+            println!("{query}: {table} resolved to {resolution}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
 }
 
 /*
@@ -0,0 +1,318 @@
+use crate::query_graph::query::{QueryCollection, QueryKind, QueryMap, QueryName};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// How one model's dependencies differ between a recorded manifest and the current project.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DependencyDrift {
+    pub model: String,
+    pub added: Vec<String>, // Read now, but not when the manifest was written
+    pub removed: Vec<String>, // Read when the manifest was written, but not any more
+}
+
+impl fmt::Display for DependencyDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.model)?;
+        for added in &self.added {
+            write!(f, " +{added}")?;
+        }
+        for removed in &self.removed {
+            write!(f, " -{removed}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Query,
+    Table,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestNode {
+    pub id: u64,
+    pub name: String,
+    pub kind: NodeKind,
+    pub dependencies: Vec<String>,
+    pub fingerprint: Option<u64>, // Only queries have source text to fingerprint
+    #[serde(default)]
+    pub relation: Option<String>, // Schema-qualified relation the node was built into, if known
+    #[serde(default)]
+    pub columns: Option<Vec<String>>, // Output column names, when the query fixes them statically
+    #[serde(default)]
+    pub materialized: Option<String>, // `table`, `view`... for models the project configures
+}
+
+/// Every node the project parsed, in a form external tools can consume. Nodes and their
+/// dependencies are sorted by name so the file only changes when the project does.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub nodes: Vec<ManifestNode>,
+}
+
+impl Manifest {
+    pub fn from_collection(collection: &QueryCollection) -> Self {
+        let nodes = collection
+            .sorted_values()
+            .into_iter()
+            .map(|kind| {
+                let mut dependencies: Vec<_> =
+                    kind.dependencies().iter().map(|d| d.to_string()).collect();
+                dependencies.sort();
+                let (node_kind, fingerprint, columns) = match kind {
+                    QueryKind::Query(q) => (
+                        NodeKind::Query,
+                        Some(q.fingerprint()),
+                        q.output_columns()
+                            .map(|cols| cols.iter().map(|c| c.to_string()).collect()),
+                    ),
+                    QueryKind::TableQuery(_) => (NodeKind::Table, None, None),
+                };
+                ManifestNode {
+                    id: **kind.id(),
+                    name: kind.name().to_string(),
+                    kind: node_kind,
+                    dependencies,
+                    fingerprint,
+                    relation: None,
+                    columns,
+                    materialized: None,
+                }
+            })
+            .collect();
+        Self { nodes }
+    }
+
+    pub fn node(&self, name: &str) -> Option<&ManifestNode> {
+        self.nodes.iter().find(|n| n.name == name)
+    }
+
+    /// Every query whose dependencies in `current` differ from the ones recorded here, sorted
+    /// by name. Queries only one side knows about are compared against no dependencies.
+    pub fn dependency_drift(&self, current: &Manifest) -> Vec<DependencyDrift> {
+        let deps_of = |manifest: &'_ Manifest, name: &str| -> Vec<String> {
+            manifest
+                .node(name)
+                .filter(|node| node.kind == NodeKind::Query)
+                .map_or_else(Vec::new, |node| node.dependencies.clone())
+        };
+        let mut models: Vec<_> = self
+            .nodes
+            .iter()
+            .chain(&current.nodes)
+            .filter(|node| node.kind == NodeKind::Query)
+            .map(|node| node.name.as_str())
+            .collect();
+        models.sort_unstable();
+        models.dedup();
+        models
+            .into_iter()
+            .filter_map(|model| {
+                let (before, now) = (deps_of(self, model), deps_of(current, model));
+                let added: Vec<_> = now
+                    .iter()
+                    .filter(|d| !before.contains(d))
+                    .cloned()
+                    .collect();
+                let removed: Vec<_> = before
+                    .iter()
+                    .filter(|d| !now.contains(d))
+                    .cloned()
+                    .collect();
+                (!added.is_empty() || !removed.is_empty()).then(|| DependencyDrift {
+                    model: model.to_string(),
+                    added,
+                    removed,
+                })
+            })
+            .collect()
+    }
+
+    /// Records where each node was built, e.g. `"prod_marts"."orders"`, so that a later run
+    /// can defer to it.
+    pub fn with_relations(mut self, relation_of: impl Fn(&str) -> Option<String>) -> Self {
+        for node in &mut self.nodes {
+            node.relation = relation_of(&node.name);
+        }
+        self
+    }
+
+    /// Records how each node is materialized, as named in config, e.g. `view`.
+    pub fn with_materializations(
+        mut self,
+        materialization_of: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        for node in &mut self.nodes {
+            node.materialized = materialization_of(&node.name);
+        }
+        self
+    }
+
+    /// For a run deferring to this (production) manifest: every model in `collection` that
+    /// isn't `selected` for rebuilding is read from the relation this manifest built it into,
+    /// as aliases for [`Query::compile_with_aliases`](crate::query_graph::query::Query::compile_with_aliases).
+    /// Models this manifest doesn't know the relation of are left to build locally.
+    pub fn deferred_aliases(
+        &self,
+        collection: &QueryCollection,
+        selected: &[&QueryName],
+    ) -> QueryMap<QueryName, smartstring::alias::String> {
+        collection
+            .sorted_values()
+            .into_iter()
+            .filter(|kind| matches!(kind, QueryKind::Query(_)))
+            .map(|kind| kind.name())
+            .filter(|name| !selected.contains(name))
+            .filter_map(|name| {
+                let relation = self.node(&name.to_string())?.relation.as_deref()?;
+                Some((name.clone(), relation.into()))
+            })
+            .collect()
+    }
+
+    /// Writes `manifest.json` into `dir` (normally the project's target directory), returning
+    /// its path.
+    pub fn write_to(&self, dir: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let path = dir.as_ref().join(MANIFEST_FILE_NAME);
+        fs::create_dir_all(dir.as_ref())?;
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(path)
+    }
+
+    pub fn read_from(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod test_manifest {
+    use super::*;
+    use crate::query_graph::query::RawQuery;
+    use prql_compiler::ast::pl::Dialect;
+
+    fn collection() -> QueryCollection {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+        ]);
+        collection
+    }
+
+    #[test]
+    fn test_manifest_records_output_columns() {
+        let mut collection = collection();
+        collection.add_queries(vec![RawQuery::new(
+            "adults",
+            "from employees | filter age > 35 | select name",
+        )]);
+        let manifest = Manifest::from_collection(&collection);
+        assert_eq!(
+            manifest.node("adults").unwrap().columns,
+            Some(vec![String::from("name")])
+        );
+        // Never narrowed with a `select`, so its columns are whatever `rituals` has.
+        assert_eq!(manifest.node("q2").unwrap().columns, None);
+        assert_eq!(manifest.node("employees").unwrap().columns, None);
+    }
+
+    #[test]
+    fn test_rewired_dependency_is_reported_as_drift() {
+        let recorded = Manifest::from_collection(&collection());
+        let mut rewired = QueryCollection::new();
+        rewired.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from grimoires | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+        ]);
+        let drift = recorded.dependency_drift(&Manifest::from_collection(&rewired));
+        assert_eq!(
+            drift,
+            vec![DependencyDrift {
+                model: String::from("q2"),
+                added: vec![String::from("grimoires")],
+                removed: vec![String::from("rituals")],
+            }]
+        );
+        assert_eq!(drift[0].to_string(), "q2: +grimoires -rituals");
+        assert!(recorded.dependency_drift(&recorded).is_empty());
+    }
+
+    #[test]
+    fn test_manifest_round_trips() {
+        let manifest = Manifest::from_collection(&collection());
+        let q2 = manifest.node("q2").unwrap();
+        assert_eq!(q2.kind, NodeKind::Query);
+        assert_eq!(q2.dependencies, vec!["q1", "rituals"]);
+        assert!(q2.fingerprint.is_some());
+        assert_eq!(manifest.node("rituals").unwrap().kind, NodeKind::Table);
+
+        let dir = std::env::temp_dir().join(format!("orbital-manifest-{}", std::process::id()));
+        let path = manifest.write_to(&dir).unwrap();
+        let read_back = Manifest::read_from(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read_back, manifest);
+    }
+
+    #[test]
+    fn test_deferred_upstream_reads_from_prod_relation() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("stg_orders", "from raw_orders | select [id, amount]"),
+            RawQuery::new("orders", "from stg_orders | select [id, amount]"),
+        ]);
+        let prod = Manifest::from_collection(&collection)
+            .with_relations(|name| Some(format!("prod_marts.{name}")));
+        let orders = QueryName::from("orders");
+
+        let aliases = prod.deferred_aliases(&collection, &[&orders]);
+        assert_eq!(aliases.len(), 1);
+        let Some(QueryKind::Query(query)) = collection.get(&orders) else {
+            panic!("orders should be a query");
+        };
+        let sql = query
+            .compile_with_aliases(&Dialect::PostgreSql, &aliases)
+            .unwrap();
+        assert!(sql.contains("prod_marts.stg_orders"), "{sql}");
+
+        let stg_orders = QueryName::from("stg_orders");
+        assert!(prod
+            .deferred_aliases(&collection, &[&orders, &stg_orders])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_manifest_is_deterministic() {
+        let first = serde_json::to_string(&Manifest::from_collection(&collection())).unwrap();
+        let second = serde_json::to_string(&Manifest::from_collection(&collection())).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_manifest_bytes_ignore_insertion_order() {
+        let mut reversed = QueryCollection::new();
+        reversed.add_queries(vec![RawQuery::new(
+            "q3",
+            "from q2 | filter something == 'blah'",
+        )]);
+        reversed.add_queries(vec![RawQuery::new(
+            "q2",
+            "from rituals | join side:inner q1 [==source]",
+        )]);
+        reversed.add_queries(vec![RawQuery::new(
+            "q1",
+            "from arcana | filter source != 'necronomicron'",
+        )]);
+        let expected = serde_json::to_vec(&Manifest::from_collection(&collection())).unwrap();
+        let actual = serde_json::to_vec(&Manifest::from_collection(&reversed)).unwrap();
+        assert_eq!(actual, expected);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::executor::NodeEvent;
+use fnv::FnvHashMap;
+use std::io::Write;
+
+/// How far a run has got, counted from the executor's node events.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressCounts {
+    pub total: usize,    // Queued
+    pub started: usize,  // Running, or already past it
+    pub finished: usize, // Succeeded, failed, skipped or planned
+    pub failed: usize,
+}
+
+impl ProgressCounts {
+    /// Started but not yet finished.
+    pub fn running(&self) -> usize {
+        self.started.saturating_sub(self.finished)
+    }
+
+    fn observe(&mut self, event: NodeEvent) {
+        match event {
+            NodeEvent::Queued => self.total += 1,
+            NodeEvent::Running => self.started += 1,
+            NodeEvent::Failed => {
+                self.finished += 1;
+                self.failed += 1;
+            }
+            // Skipped and planned nodes are never started, so count them in both.
+            NodeEvent::Skipped | NodeEvent::Planned => {
+                self.started += 1;
+                self.finished += 1;
+            }
+            NodeEvent::Succeeded => self.finished += 1,
+        }
+    }
+}
+
+/// Shows a run's progress as it happens. On a terminal, one status line is redrawn after
+/// every node event; otherwise, e.g. in CI logs, each node gets a line as it starts and
+/// finishes.
+pub struct ProgressReporter<'w> {
+    counts: ProgressCounts,
+    sink: &'w mut dyn Write,
+    redraw: bool,
+    names: FnvHashMap<u64, String>,
+}
+
+impl<'w> ProgressReporter<'w> {
+    /// With `redraw`, `sink` is treated as a terminal. `names` labels node ids in the line
+    /// log; unnamed nodes are shown by id.
+    pub fn new(sink: &'w mut dyn Write, redraw: bool, names: FnvHashMap<u64, String>) -> Self {
+        Self {
+            counts: ProgressCounts::default(),
+            sink,
+            redraw,
+            names,
+        }
+    }
+
+    pub fn counts(&self) -> ProgressCounts {
+        self.counts
+    }
+
+    pub(crate) fn observe(&mut self, node_id: u64, event: NodeEvent) {
+        self.counts.observe(event);
+        let ProgressCounts {
+            total,
+            finished,
+            failed,
+            ..
+        } = self.counts;
+        // Progress output going missing shouldn't abort the run it describes.
+        if self.redraw {
+            let running = self.counts.running();
+            let _ = write!(
+                self.sink,
+                "\r\x1b[2K[{finished}/{total}] {running} running, {failed} failed"
+            );
+            return;
+        }
+        let what = match event {
+            NodeEvent::Queued => return,
+            NodeEvent::Running => "started",
+            NodeEvent::Succeeded => "succeeded",
+            NodeEvent::Failed => "failed",
+            NodeEvent::Skipped => "skipped",
+            NodeEvent::Planned => "planned",
+        };
+        let _ = match self.names.get(&node_id) {
+            Some(name) => writeln!(self.sink, "[{finished}/{total}] {what} {name}"),
+            None => writeln!(self.sink, "[{finished}/{total}] {what} node {node_id}"),
+        };
+    }
+
+    /// Ends the status line once the run is over, so later output starts on its own.
+    pub(crate) fn finish(&mut self) {
+        if self.redraw && self.counts.total > 0 {
+            let _ = writeln!(self.sink);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_progress {
+    use super::*;
+
+    #[test]
+    fn test_status_line_is_redrawn_in_place() {
+        let mut sink = Vec::new();
+        let mut progress = ProgressReporter::new(&mut sink, true, FnvHashMap::default());
+        for event in [NodeEvent::Queued, NodeEvent::Running, NodeEvent::Succeeded] {
+            progress.observe(1, event);
+        }
+        progress.finish();
+        assert_eq!(
+            String::from_utf8(sink).unwrap(),
+            "\r\x1b[2K[0/1] 0 running, 0 failed\
+             \r\x1b[2K[0/1] 1 running, 0 failed\
+             \r\x1b[2K[1/1] 0 running, 0 failed\n"
+        );
+    }
+}
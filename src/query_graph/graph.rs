@@ -1,31 +1,167 @@
 use fnv::{FnvHashMap, FnvHashSet};
+use petgraph::visit::{Bfs, Reversed, Topo};
 use petgraph::Direction;
 use petgraph::{
-    algo,
+    algo, dot,
     prelude::DiGraph,
     stable_graph::{IndexType, NodeIndex},
     Directed, Graph,
 };
+use serde::Deserialize;
+use std::{cmp::Reverse, fmt};
 
 type NodeId = u64;
 type IxType = u8;
 type IdLookupTable = FnvHashMap<NodeId, NodeIndex<IxType>>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GraphError {
+    /// A dependency refers to a node that was never added.
+    UnknownNode(NodeId),
+    /// There were no nodes left to build a graph from.
+    Empty,
+    /// The dependencies contain a cycle. Holds the ids along one such cycle, in edge order.
+    ContainsCycle(Vec<NodeId>),
+    /// Nodes with neither dependencies nor dependents, when those are configured to fail.
+    OrphanNodes(Vec<NodeId>),
+    /// The project is larger than a configured [`GraphLimits`] allows.
+    LimitExceeded {
+        limit: &'static str,
+        max: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::UnknownNode(id) => write!(f, "dependency refers to unknown node {id}"),
+            GraphError::Empty => write!(f, "no nodes to build a graph from"),
+            GraphError::ContainsCycle(ids) => write!(f, "cycle detected: {ids:?}"),
+            GraphError::OrphanNodes(ids) => write!(f, "orphan nodes detected: {ids:?}"),
+            GraphError::LimitExceeded { limit, max, found } => {
+                write!(f, "{limit} is {max}, but the project has {found}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+/// Upper bounds on project size, checked before a graph is built so that a misconfigured or
+/// pathological project fails fast. Unlimited by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct GraphLimits {
+    #[serde(default)]
+    pub max_models: Option<usize>,
+    #[serde(default)]
+    pub max_edges: Option<usize>,
+}
+
+impl GraphLimits {
+    pub fn check(&self, models: usize, edges: usize) -> Result<(), GraphError> {
+        for (limit, max, found) in [
+            ("max_models", self.max_models, models),
+            ("max_edges", self.max_edges, edges),
+        ] {
+            match max {
+                Some(max) if found > max => {
+                    return Err(GraphError::LimitExceeded { limit, max, found })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A cycle found while building a graph, and the edge dropped to break it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenCycle {
+    /// Node ids around the cycle, each depending on the one before it.
+    pub path: Vec<NodeId>,
+    pub removed_edge: (NodeId, NodeId),
+}
+
+/// What an edge's upstream end is to the node that reads it. Edges are built as
+/// [`EdgeKind::ModelRef`] and reclassified once it's known what the upstream is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Read from another model.
+    #[default]
+    ModelRef,
+    /// Read from a source table.
+    SourceRef,
+    /// Read from a seed.
+    SeedRef,
+    /// Declared in config rather than read in the query, e.g. `depends_on`.
+    Manual,
+}
+
+#[derive(Debug)]
 pub struct QueryGraph {
-    pub inner: DiGraph<NodeId, (), IxType>,
+    pub inner: DiGraph<NodeId, EdgeKind, IxType>,
     lookup_table: IdLookupTable,
 }
 
 impl QueryGraph {
     pub fn new_from_valid_data(valid_data: ValidGraphData) -> Option<Self> {
+        QueryGraph::try_from_valid_data(valid_data).ok()
+    }
+
+    pub(crate) fn try_from_valid_data(valid_data: ValidGraphData) -> Result<Self, GraphError> {
         let dag: DiAcylcicGraph = valid_data.into();
-        if algo::is_cyclic_directed(&dag.raw_graph) {
-            return None;
+        if let Err(cycle) = algo::toposort(&dag.raw_graph, None) {
+            return Err(GraphError::ContainsCycle(find_cycle_through(
+                &dag.raw_graph,
+                cycle.node_id(),
+            )));
         };
-        Some(QueryGraph {
+        Ok(QueryGraph {
             inner: dag.raw_graph,
             lookup_table: dag.lookup_table,
         })
     }
+    /// Like [`QueryGraph::new_from_valid_data`], but instead of refusing cyclic data, breaks
+    /// each cycle by dropping one of its edges and hands the dropped edges back as warnings.
+    /// The edge dropped from a cycle is the last one inserted, which given
+    /// [`ValidGraphData`]'s ordering is the one with the greatest `(source, target)` ids.
+    pub fn new_from_valid_data_breaking_cycles(
+        valid_data: ValidGraphData,
+    ) -> (Self, Vec<(NodeId, NodeId)>) {
+        let (graph, broken) = Self::break_cycles(valid_data);
+        (graph, broken.into_iter().map(|c| c.removed_edge).collect())
+    }
+
+    pub(crate) fn break_cycles(valid_data: ValidGraphData) -> (Self, Vec<BrokenCycle>) {
+        let mut dag: DiAcylcicGraph = valid_data.into();
+        let mut broken = Vec::new();
+        while let Err(cycle) = algo::toposort(&dag.raw_graph, None) {
+            let g = &dag.raw_graph;
+            let cycle_ids = find_cycle_through(g, cycle.node_id());
+            let path: Vec<_> = cycle_ids.iter().map(|id| dag.lookup_table[id]).collect();
+            let last_added = path
+                .iter()
+                .zip(path.iter().cycle().skip(1))
+                .filter_map(|(&src, &dest)| g.find_edge(src, dest))
+                .max()
+                .expect("a cycle always has at least one edge");
+            let (src, dest) = g
+                .edge_endpoints(last_added)
+                .expect("edge was just found in the graph");
+            broken.push(BrokenCycle {
+                path: cycle_ids,
+                removed_edge: (g[src], g[dest]),
+            });
+            dag.raw_graph.remove_edge(last_added);
+        }
+        let graph = QueryGraph {
+            inner: dag.raw_graph,
+            lookup_table: dag.lookup_table,
+        };
+        (graph, broken)
+    }
+
     pub fn new_from_edges(edges: Vec<(NodeId, NodeId)>) -> Option<Self> {
         ValidGraphData::new_from_edges(&edges).and_then(QueryGraph::new_from_valid_data)
     }
@@ -56,15 +192,354 @@ impl QueryGraph {
             .filter_map(|n_idx| self.get_id(n_idx))
             .collect()
     }
+
+    pub fn get_leaf_nodes(&self) -> Vec<NodeId> {
+        self.inner
+            .externals(Direction::Outgoing)
+            .filter_map(|n_idx| self.get_id(n_idx))
+            .collect()
+    }
+
+    /// The kind of the edge from `from` to `to`, if there is one.
+    pub fn edge_kind(&self, from: NodeId, to: NodeId) -> Option<EdgeKind> {
+        let edge = self
+            .inner
+            .find_edge(self.get_index(from)?, self.get_index(to)?)?;
+        Some(self.inner[edge])
+    }
+
+    /// Reclassifies the edge from `from` to `to`, returning whether there was one.
+    pub fn set_edge_kind(&mut self, from: NodeId, to: NodeId, kind: EdgeKind) -> bool {
+        let (Some(from), Some(to)) = (self.get_index(from), self.get_index(to)) else {
+            return false;
+        };
+        match self.inner.find_edge(from, to) {
+            Some(edge) => {
+                self.inner[edge] = kind;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The nodes `node_id` directly depends on. Unknown ids have none.
+    pub fn get_parents(&self, node_id: NodeId) -> Vec<NodeId> {
+        let Some(n_idx) = self.get_index(node_id) else {
+            return Vec::new();
+        };
+        self.inner
+            .neighbors_directed(n_idx, Direction::Incoming)
+            .map(|parent| self.inner[parent])
+            .collect()
+    }
+
+    /// The nodes that directly depend on `node_id`. Unknown ids have none.
+    pub fn get_children(&self, node_id: NodeId) -> Vec<NodeId> {
+        let Some(n_idx) = self.get_index(node_id) else {
+            return Vec::new();
+        };
+        self.inner
+            .neighbors_directed(n_idx, Direction::Outgoing)
+            .map(|child| self.inner[child])
+            .collect()
+    }
+
+    /// Every node `node_id` transitively depends on, not including itself. Unknown ids have
+    /// no ancestors.
+    pub fn get_ancestors(&self, node_id: NodeId) -> Vec<NodeId> {
+        let Some(start) = self.get_index(node_id) else {
+            return Vec::new();
+        };
+        let reversed = Reversed(&self.inner);
+        let mut bfs = Bfs::new(reversed, start);
+        let mut ancestors = Vec::new();
+        while let Some(n_idx) = bfs.next(reversed) {
+            if n_idx != start {
+                ancestors.push(self.inner[n_idx]);
+            }
+        }
+        ancestors
+    }
+
+    /// Whether `node` transitively depends on `potential_ancestor`. As with
+    /// [`QueryGraph::get_ancestors`] a node is not its own ancestor, and unknown ids have none.
+    pub fn is_ancestor_of(&self, potential_ancestor: NodeId, node: NodeId) -> bool {
+        match (self.get_index(potential_ancestor), self.get_index(node)) {
+            (Some(from), Some(to)) if from != to => {
+                algo::has_path_connecting(&self.inner, from, to, None)
+            }
+            _ => false,
+        }
+    }
+
+    /// Every node that transitively depends on `node_id`, not including itself. Unknown ids
+    /// have no downstream.
+    pub fn get_downstream(&self, node_id: NodeId) -> Vec<NodeId> {
+        let Some(start) = self.get_index(node_id) else {
+            return Vec::new();
+        };
+        let mut bfs = Bfs::new(&self.inner, start);
+        let mut downstream = Vec::new();
+        while let Some(n_idx) = bfs.next(&self.inner) {
+            if n_idx != start {
+                downstream.push(self.inner[n_idx]);
+            }
+        }
+        downstream
+    }
+
+    /// How many nodes transitively depend on `node_id`: a rough measure of how much a
+    /// failure or change there would ripple. Unknown ids have none.
+    pub fn descendants_count(&self, node_id: NodeId) -> usize {
+        self.get_downstream(node_id).len()
+    }
+
+    /// Nodes that are neither one of `outputs` nor upstream of one, sorted: work whose result
+    /// no final product consumes. Unlike orphans these can still have dependents, e.g. when
+    /// their only consumer is disabled and so left out of `outputs`. Unknown output ids are
+    /// ignored.
+    pub fn get_unreachable_nodes(&self, outputs: &[NodeId]) -> Vec<NodeId> {
+        let live: FnvHashSet<NodeId> = outputs
+            .iter()
+            .filter(|id| self.get_index(**id).is_some())
+            .flat_map(|id| self.get_ancestors(*id).into_iter().chain([*id]))
+            .collect();
+        let mut unreachable: Vec<_> = self
+            .inner
+            .node_weights()
+            .filter(|id| !live.contains(id))
+            .copied()
+            .collect();
+        unreachable.sort_unstable();
+        unreachable
+    }
+
+    /// Every node, each after all of its dependencies, computed in full up front.
+    pub fn topological_order(&self) -> Vec<NodeId> {
+        algo::toposort(&self.inner, None)
+            .expect("QueryGraph is only ever constructed from acyclic data")
+            .into_iter()
+            .map(|n_idx| self.inner[n_idx])
+            .collect()
+    }
+
+    /// Like [`QueryGraph::topological_order`], but each node is found only when asked for,
+    /// so a consumer can start on the first nodes without the whole order being built. The
+    /// order may differ from `topological_order`'s; both put dependencies first.
+    pub fn topo_iter(&self) -> impl Iterator<Item = NodeId> + '_ {
+        let mut topo = Topo::new(&self.inner);
+        std::iter::from_fn(move || topo.next(&self.inner).map(|n_idx| self.inner[n_idx]))
+    }
+
+    /// Length of the longest path from any root to each node. Roots (sources) have depth 0.
+    pub fn node_depths(&self) -> FnvHashMap<NodeId, usize> {
+        let order = algo::toposort(&self.inner, None)
+            .expect("QueryGraph is only ever constructed from acyclic data");
+        let mut depths: FnvHashMap<NodeIndex<IxType>, usize> = FnvHashMap::default();
+        for n_idx in order {
+            let depth = self
+                .inner
+                .neighbors_directed(n_idx, Direction::Incoming)
+                .filter_map(|parent| depths.get(&parent))
+                .map(|d| d + 1)
+                .max()
+                .unwrap_or(0);
+            depths.insert(n_idx, depth);
+        }
+        depths
+            .into_iter()
+            .map(|(n_idx, depth)| (self.inner[n_idx], depth))
+            .collect()
+    }
+
+    /// Nodes grouped by [`QueryGraph::node_depths`]: every node's dependencies sit in
+    /// earlier layers, so the nodes of a layer can be built in any order. Each layer is sorted.
+    pub fn execution_layers(&self) -> Vec<Vec<NodeId>> {
+        let depths = self.node_depths();
+        let layer_count = depths.values().max().map_or(0, |d| d + 1);
+        let mut layers = vec![Vec::new(); layer_count];
+        for (node_id, depth) in depths {
+            layers[depth].push(node_id);
+        }
+        layers.iter_mut().for_each(|layer| layer.sort_unstable());
+        layers
+    }
+
+    /// The longest chain of dependencies, root first, counting each node as one step: the
+    /// chain that bounds a run however much else runs in parallel. Empty for an empty graph.
+    pub fn critical_path(&self) -> Vec<NodeId> {
+        self.critical_path_by(|_| 1)
+    }
+
+    /// Like [`QueryGraph::critical_path`], but the chain with the greatest total `weight`,
+    /// e.g. each model's last run time. Ties go to the lower node id.
+    pub fn critical_path_by(&self, weight: impl Fn(NodeId) -> u64) -> Vec<NodeId> {
+        let order = algo::toposort(&self.inner, None)
+            .expect("QueryGraph is only ever constructed from acyclic data");
+        // The heaviest chain ending at each node, and the parent it continues from.
+        let mut best: FnvHashMap<NodeIndex<IxType>, (u64, Option<NodeIndex<IxType>>)> =
+            FnvHashMap::default();
+        for n_idx in order {
+            let parent = self
+                .inner
+                .neighbors_directed(n_idx, Direction::Incoming)
+                .max_by_key(|parent| (best[parent].0, Reverse(self.inner[*parent])));
+            let cost = parent.map_or(0, |parent| best[&parent].0) + weight(self.inner[n_idx]);
+            best.insert(n_idx, (cost, parent));
+        }
+        let mut next = best
+            .iter()
+            .max_by_key(|(n_idx, (cost, _))| (*cost, Reverse(self.inner[**n_idx])))
+            .map(|(n_idx, _)| *n_idx);
+        let mut path = Vec::new();
+        while let Some(n_idx) = next {
+            path.push(self.inner[n_idx]);
+            next = best[&n_idx].1;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Every node, dependents before their dependencies: the order to drop things in. The
+    /// reverse of [`QueryGraph::execution_layers`], flattened.
+    pub fn teardown_order(&self) -> Vec<NodeId> {
+        self.execution_layers()
+            .into_iter()
+            .rev()
+            .flatten()
+            .collect()
+    }
+
+    /// The graph as a stable document for external consumers, all lists sorted ascending:
+    ///
+    /// ```json
+    /// {
+    ///   "nodes": [<id>, ...],
+    ///   "edges": [{"from": <id>, "to": <id>}, ...],
+    ///   "roots": [<id>, ...],
+    ///   "leaves": [<id>, ...]
+    /// }
+    /// ```
+    ///
+    /// Edges point from a dependency to its dependent. Roots have no dependencies and leaves
+    /// have no dependents. Unlike the internal serde form, this layout is a public contract.
+    pub fn to_json_schema_instance(&self) -> serde_json::Value {
+        let sorted = |mut ids: Vec<NodeId>| {
+            ids.sort_unstable();
+            ids
+        };
+        let mut edges: Vec<_> = self
+            .inner
+            .raw_edges()
+            .iter()
+            .map(|e| (self.inner[e.source()], self.inner[e.target()]))
+            .collect();
+        edges.sort_unstable();
+        serde_json::json!({
+            "nodes": sorted(self.inner.node_weights().copied().collect()),
+            "edges": edges
+                .into_iter()
+                .map(|(from, to)| serde_json::json!({ "from": from, "to": to }))
+                .collect::<Vec<_>>(),
+            "roots": sorted(self.get_root_nodes()),
+            "leaves": sorted(self.get_leaf_nodes()),
+        })
+    }
+
+    /// Graphviz rendering of the graph, labelled by node id. Stable across runs for the same
+    /// set of nodes and edges.
+    pub fn to_dot(&self) -> String {
+        format!(
+            "{:?}",
+            dot::Dot::with_config(&self.inner, &[dot::Config::EdgeNoLabel])
+        )
+    }
 }
+
+/// Incrementally assembles a [`QueryGraph`] from node ids and dependencies, without going
+/// through PRQL parsing. Mostly useful for fixtures in tests.
+#[derive(Debug, Default)]
+pub struct QueryGraphBuilder {
+    nodes: Vec<NodeId>,
+    node_set: FnvHashSet<NodeId>,
+    edges: Vec<(NodeId, NodeId)>,
+}
+
+impl QueryGraphBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_node(&mut self, node_id: NodeId) -> &mut Self {
+        if self.node_set.insert(node_id) {
+            self.nodes.push(node_id);
+        }
+        self
+    }
+
+    /// Records that `child` depends on `parent`. Both must already have been added.
+    pub fn add_dependency(
+        &mut self,
+        child: NodeId,
+        parent: NodeId,
+    ) -> Result<&mut Self, GraphError> {
+        if let Some(&unknown) = [parent, child]
+            .iter()
+            .find(|id| !self.node_set.contains(id))
+        {
+            return Err(GraphError::UnknownNode(unknown));
+        }
+        self.edges.push((parent, child));
+        Ok(self)
+    }
+
+    pub fn build(&self) -> Result<QueryGraph, GraphError> {
+        ValidGraphData::new_from_id_edge_pairs(&self.nodes, &self.edges)
+            .ok_or(GraphError::Empty)
+            .and_then(QueryGraph::try_from_valid_data)
+    }
+}
+
+/// Walks forward from `start` (which must sit on a cycle) until it gets back to `start`,
+/// returning the node ids along the way.
+fn find_cycle_through(
+    g: &DiGraph<NodeId, EdgeKind, IxType>,
+    start: NodeIndex<IxType>,
+) -> Vec<NodeId> {
+    let mut predecessors: FnvHashMap<NodeIndex<IxType>, NodeIndex<IxType>> = FnvHashMap::default();
+    let mut bfs = Bfs::new(g, start);
+    let mut closing_node = None;
+    'search: while let Some(node) = bfs.next(g) {
+        for next in g.neighbors_directed(node, Direction::Outgoing) {
+            if next == start {
+                closing_node = Some(node);
+                break 'search;
+            }
+            predecessors.entry(next).or_insert(node);
+        }
+    }
+    let mut cycle = Vec::new();
+    let mut current = closing_node;
+    while let Some(node) = current {
+        cycle.push(g[node]);
+        current = if node == start {
+            None
+        } else {
+            predecessors.get(&node).copied()
+        };
+    }
+    cycle.reverse();
+    cycle
+}
+
 struct DiAcylcicGraph {
-    raw_graph: DiGraph<NodeId, (), IxType>,
+    raw_graph: DiGraph<NodeId, EdgeKind, IxType>,
     lookup_table: IdLookupTable,
 }
 
 impl From<ValidGraphData> for DiAcylcicGraph {
     fn from(v: ValidGraphData) -> Self {
-        let mut g: Graph<NodeId, (), Directed, IxType> =
+        let mut g: Graph<NodeId, EdgeKind, Directed, IxType> =
             DiGraph::with_capacity(v.nodes.len(), v.edges.len());
         let node_id_map: FnvHashMap<_, _> = v
             .nodes
@@ -77,7 +552,7 @@ impl From<ValidGraphData> for DiAcylcicGraph {
         v.edges.into_iter().for_each(|(src, dest)| {
             let idx_a = node_id_map[&src];
             let idx_b = node_id_map[&dest];
-            g.update_edge(idx_a, idx_b, ());
+            g.update_edge(idx_a, idx_b, EdgeKind::default());
         });
         Self {
             raw_graph: g,
@@ -93,6 +568,32 @@ pub struct ValidGraphData {
 
 impl ValidGraphData {
     pub fn new_from_id_edge_pairs(node_ids: &[NodeId], edges: &[(NodeId, NodeId)]) -> Option<Self> {
+        Self::from_id_edge_pairs(node_ids, edges, false).ok()
+    }
+
+    /// As [`ValidGraphData::new_from_id_edge_pairs`], but nodes without any edges are kept.
+    /// A query that reads no tables (e.g. a literal select) is such a node, not a mistake.
+    pub fn new_from_id_edge_pairs_retaining_orphans(
+        node_ids: &[NodeId],
+        edges: &[(NodeId, NodeId)],
+    ) -> Option<Self> {
+        Self::from_id_edge_pairs(node_ids, edges, true).ok()
+    }
+
+    /// As [`ValidGraphData::new_from_id_edge_pairs_retaining_orphans`], but says why the
+    /// data was rejected.
+    pub fn try_from_id_edge_pairs_retaining_orphans(
+        node_ids: &[NodeId],
+        edges: &[(NodeId, NodeId)],
+    ) -> Result<Self, GraphError> {
+        Self::from_id_edge_pairs(node_ids, edges, true)
+    }
+
+    fn from_id_edge_pairs(
+        node_ids: &[NodeId],
+        edges: &[(NodeId, NodeId)],
+        retain_orphans: bool,
+    ) -> Result<Self, GraphError> {
         // Ensure edges only refer to nodes present in the node_ids
         let distinct_edge_ids = edges
             .iter()
@@ -106,9 +607,14 @@ impl ValidGraphData {
         if !mismatches.is_empty() {
             println!("Invalid dataset provided. Edge data refers to un-referenced nodes!");
             println!("Offending nodes found in edges: {:?}", mismatches);
-            return None;
+            let first = mismatches.iter().map(|&&&id| id).min().unwrap_or_default();
+            return Err(GraphError::UnknownNode(first));
         }
-        let orphan_nodes: FnvHashSet<_> = node_id_set.difference(&distinct_edge_ids).collect();
+        let orphan_nodes: FnvHashSet<_> = if retain_orphans {
+            FnvHashSet::default()
+        } else {
+            node_id_set.difference(&distinct_edge_ids).collect()
+        };
         if !orphan_nodes.is_empty() {
             println!("Orphan nodes detected - removing. {:?}", orphan_nodes);
         }
@@ -123,13 +629,21 @@ impl ValidGraphData {
         };
         if valid_nodes.is_empty() || node_id_set.is_empty() {
             println!("Invalid data");
-            return None;
+            return Err(GraphError::Empty);
+        };
+        // Sorting both nodes and edges keeps petgraph's insertion (and so index) order
+        // independent of whatever order the caller happened to produce them in.
+        let valid_edges = {
+            let mut edges = edges.to_vec();
+            edges.sort_unstable();
+            edges.dedup();
+            edges
         };
         let valid_graph_data = Self {
             nodes: valid_nodes,
-            edges: edges.to_vec(),
+            edges: valid_edges,
         };
-        Some(valid_graph_data)
+        Ok(valid_graph_data)
     }
 
     pub fn new_from_edges(edges: &[(NodeId, NodeId)]) -> Option<Self> {
@@ -147,7 +661,7 @@ impl ValidGraphData {
     }
 }
 
-fn find_orphan_nodes<X, Y: IndexType>(g: &Graph<X, (), Directed, Y>) -> Vec<NodeIndex<Y>> {
+pub fn find_orphan_nodes<X, E, Y: IndexType>(g: &Graph<X, E, Directed, Y>) -> Vec<NodeIndex<Y>> {
     let orphan_nodes: Vec<_> = g
         .externals(Direction::Outgoing)
         .filter(|ext| g.edges_directed(*ext, Direction::Incoming).next().is_none())
@@ -168,7 +682,7 @@ mod test_graphs {
 
     #[test]
     fn test_dag_construction() {
-        let g = DiGraph::<u16, ()>::from_edges(&[(0, 1), (0, 2), (3, 2), (2, 4), (4, 5)]);
+        let g = DiGraph::<u16, ()>::from_edges([(0, 1), (0, 2), (3, 2), (2, 4), (4, 5)]);
         let output = format!(
             "{:?}",
             dot::Dot::with_config(&g, &[dot::Config::NodeIndexLabel, dot::Config::EdgeNoLabel])
@@ -195,21 +709,44 @@ mod test_graphs {
     #[test]
     fn test_detecting_cycles() {
         use petgraph::algo;
-        let g = DiGraph::<u16, ()>::from_edges(&[(0, 1), (0, 2), (3, 2), (2, 4), (4, 5)]);
+        let g = DiGraph::<u16, ()>::from_edges([(0, 1), (0, 2), (3, 2), (2, 4), (4, 5)]);
         let contains_cycle = algo::is_cyclic_directed(&g);
-        assert_eq!(contains_cycle, false);
+        assert!(!contains_cycle);
     }
 
     #[test]
     fn test_topological_sorted_dag() {
-        let g = DiGraph::<u16, ()>::from_edges(&[(0, 1), (0, 2), (3, 2), (2, 4), (4, 5)]);
+        let g = DiGraph::<u16, ()>::from_edges([(0, 1), (0, 2), (3, 2), (2, 4), (4, 5)]);
         let g_sorted = algo::toposort(&g, None);
         assert!(g_sorted.is_ok())
     }
 
+    #[test]
+    fn test_topo_iter_matches_topological_order() {
+        let chain = QueryGraph::new_from_edges(vec![(2, 3), (0, 1), (1, 2)]).unwrap();
+        assert_eq!(
+            chain.topo_iter().collect::<Vec<_>>(),
+            chain.topological_order()
+        );
+        assert_eq!(chain.topological_order(), vec![0, 1, 2, 3]);
+
+        let graph =
+            QueryGraph::new_from_edges(vec![(0, 1), (0, 2), (3, 2), (2, 4), (4, 5)]).unwrap();
+        let lazy: Vec<_> = graph.topo_iter().collect();
+        let mut sorted = lazy.clone();
+        sorted.sort_unstable();
+        let mut eager = graph.topological_order();
+        eager.sort_unstable();
+        assert_eq!(sorted, eager);
+        let position = |id| lazy.iter().position(|n| *n == id).unwrap();
+        for (from, to) in [(0, 1), (0, 2), (3, 2), (2, 4), (4, 5)] {
+            assert!(position(from) < position(to));
+        }
+    }
+
     #[test]
     fn test_get_dependent_nodes() {
-        let g = DiGraph::<u16, ()>::from_edges(&[(0, 1), (0, 2), (3, 2), (2, 4), (4, 5)]);
+        let g = DiGraph::<u16, ()>::from_edges([(0, 1), (0, 2), (3, 2), (2, 4), (4, 5)]);
         let n_idx = node_index(0);
         let outgoing = {
             let mut n: Vec<_> = g.neighbors_directed(n_idx, Direction::Outgoing).collect();
@@ -221,8 +758,7 @@ mod test_graphs {
 
     #[test]
     fn test_can_find_orphan_nodes() {
-        let mut g =
-            DiGraph::<u8, ()>::from_edges(&[(0, 1), (0, 2), (3, 2), (2, 4), (4, 5), (7, 5)]);
+        let mut g = DiGraph::<u8, ()>::from_edges([(0, 1), (0, 2), (3, 2), (2, 4), (4, 5), (7, 5)]);
         g.add_node(6);
         println!(
             "{:?}",
@@ -276,6 +812,16 @@ mod test_graphs {
         assert_eq!(data.unwrap().nodes, expected_nodes);
     }
 
+    #[test]
+    fn test_retains_orphan_nodes_on_request() {
+        let nodes = [0, 1, 2, 3, 4];
+        let edges = [(0, 1), (0, 2), (1, 3)];
+        let data = ValidGraphData::new_from_id_edge_pairs_retaining_orphans(&nodes, &edges);
+        assert_eq!(data.unwrap().nodes, nodes);
+        let edgeless = ValidGraphData::new_from_id_edge_pairs_retaining_orphans(&[4], &[]);
+        assert_eq!(edgeless.unwrap().nodes, [4]);
+    }
+
     #[test]
     fn test_graph_generates_on_valid_edge_data() {
         let edges = [(0, 1), (0, 2), (3, 2), (2, 4), (4, 5), (7, 5)];
@@ -356,12 +902,12 @@ mod test_graphs {
     4 [ label = "86" ]
     5 [ label = "109" ]
     6 [ label = "243" ]
-    3 -> 2 [ ]
-    3 -> 0 [ ]
-    6 -> 0 [ ]
     0 -> 1 [ ]
     1 -> 4 [ ]
+    3 -> 0 [ ]
+    3 -> 2 [ ]
     5 -> 4 [ ]
+    6 -> 0 [ ]
 }
 "#
         )
@@ -385,4 +931,162 @@ mod test_graphs {
         // This way, the specific index values are completely meaningless and be compressed at will
         // and we don't have to traverse the map to "find" the "weights" (ids)
     }
+
+    #[test]
+    fn test_builder_matches_edge_based_construction() {
+        let nodes = [31, 18, 9, 243, 11, 86, 109];
+        let edges = [(31, 18), (31, 9), (243, 9), (9, 11), (11, 86), (109, 86)];
+        let from_edges =
+            QueryGraph::new_from_ids_and_edges(nodes.to_vec(), edges.to_vec()).unwrap();
+        let mut builder = QueryGraphBuilder::new();
+        nodes.iter().for_each(|&n| {
+            builder.add_node(n);
+        });
+        for (parent, child) in edges {
+            builder.add_dependency(child, parent).unwrap();
+        }
+        let from_builder = builder.build().unwrap();
+        assert_eq!(from_builder.get_root_nodes(), from_edges.get_root_nodes());
+    }
+
+    #[test]
+    fn test_builder_rejects_unknown_nodes() {
+        let mut builder = QueryGraphBuilder::new();
+        builder.add_node(0).add_node(1);
+        assert_eq!(
+            builder.add_dependency(2, 0).unwrap_err(),
+            GraphError::UnknownNode(2)
+        );
+        assert_eq!(
+            QueryGraphBuilder::new().build().unwrap_err(),
+            GraphError::Empty
+        );
+    }
+
+    #[test]
+    fn test_builder_reports_cycle() {
+        let mut builder = QueryGraphBuilder::new();
+        builder.add_node(0).add_node(1).add_node(2).add_node(3);
+        builder.add_dependency(1, 0).unwrap();
+        builder.add_dependency(2, 1).unwrap();
+        builder.add_dependency(3, 2).unwrap();
+        builder.add_dependency(1, 3).unwrap();
+        match builder.build() {
+            Err(GraphError::ContainsCycle(mut cycle)) => {
+                cycle.sort_unstable();
+                assert_eq!(cycle, vec![1, 2, 3]);
+            }
+            _ => panic!("expected a cycle"),
+        }
+    }
+
+    #[test]
+    fn test_dot_output_independent_of_edge_order() {
+        let nodes = [31, 18, 9, 243, 11, 86, 109];
+        let edges = [(31, 18), (31, 9), (243, 9), (9, 11), (11, 86), (109, 86)];
+        let mut reversed_edges = edges.to_vec();
+        reversed_edges.reverse();
+        let graph = QueryGraph::new_from_ids_and_edges(nodes.to_vec(), edges.to_vec()).unwrap();
+        let reversed = QueryGraph::new_from_ids_and_edges(nodes.to_vec(), reversed_edges).unwrap();
+        assert_eq!(graph.to_dot(), reversed.to_dot());
+    }
+
+    #[test]
+    fn test_node_depths_follow_longest_path() {
+        let edges = [(0, 1), (0, 2), (3, 2), (2, 4), (4, 5), (7, 5)];
+        let graph = QueryGraph::new_from_edges(edges.to_vec()).unwrap();
+        let depths = graph.node_depths();
+        assert_eq!(depths[&0], 0);
+        assert_eq!(depths[&7], 0);
+        assert_eq!(depths[&2], 1);
+        assert_eq!(depths[&5], 3);
+    }
+
+    #[test]
+    fn test_breaking_cycles_removes_last_added_edge() {
+        let nodes = [0, 1, 2, 3, 4, 5, 7];
+        let edges = [(0, 1), (0, 2), (3, 2), (2, 4), (4, 5), (7, 5), (5, 0)];
+        let data = ValidGraphData::new_from_id_edge_pairs(&nodes, &edges).unwrap();
+        let (graph, removed) = QueryGraph::new_from_valid_data_breaking_cycles(data);
+        assert_eq!(removed, vec![(5, 0)]);
+        assert!(!algo::is_cyclic_directed(&graph.inner));
+        assert_eq!(graph.inner.edge_count(), edges.len() - 1);
+    }
+
+    #[test]
+    fn test_breaking_cycles_leaves_acyclic_data_alone() {
+        let edges = [(0, 1), (0, 2), (3, 2), (2, 4), (4, 5), (7, 5)];
+        let data = ValidGraphData::new_from_edges(&edges).unwrap();
+        let (graph, removed) = QueryGraph::new_from_valid_data_breaking_cycles(data);
+        assert!(removed.is_empty());
+        assert_eq!(graph.inner.edge_count(), edges.len());
+    }
+
+    #[test]
+    fn test_get_ancestors() {
+        let edges = [(0, 1), (0, 2), (3, 2), (2, 4), (4, 5), (7, 5)];
+        let graph = QueryGraph::new_from_edges(edges.to_vec()).unwrap();
+        let mut ancestors = graph.get_ancestors(4);
+        ancestors.sort_unstable();
+        assert_eq!(ancestors, vec![0, 2, 3]);
+        assert!(graph.get_ancestors(0).is_empty());
+        assert!(graph.get_ancestors(42).is_empty());
+    }
+
+    #[test]
+    fn test_descendants_count_of_fan_out() {
+        // 0 feeds 1, 2 and 3 directly and 4 through both 1 and 2.
+        let edges = [(0, 1), (0, 2), (0, 3), (1, 4), (2, 4), (5, 3)];
+        let graph = QueryGraph::new_from_edges(edges.to_vec()).unwrap();
+        assert_eq!(graph.descendants_count(0), 4);
+        assert_eq!(graph.descendants_count(1), 1);
+        assert_eq!(graph.descendants_count(5), 1);
+        assert_eq!(graph.descendants_count(4), 0);
+        assert_eq!(graph.descendants_count(42), 0);
+    }
+
+    #[test]
+    fn test_unreachable_nodes_feed_no_output() {
+        // 2 only feeds 6, which is disabled and so not an output; 5 is the live output.
+        let edges = [(0, 1), (1, 5), (0, 2), (2, 6), (3, 5)];
+        let graph = QueryGraph::new_from_edges(edges.to_vec()).unwrap();
+        assert_eq!(graph.get_unreachable_nodes(&[5]), vec![2, 6]);
+        assert!(graph
+            .get_unreachable_nodes(&graph.get_leaf_nodes())
+            .is_empty());
+        assert_eq!(graph.get_unreachable_nodes(&[42]).len(), 6);
+    }
+
+    #[test]
+    fn test_critical_path_is_the_longest_chain() {
+        let graph = QueryGraph::new_from_edges(vec![(0, 1), (0, 2), (2, 4), (4, 5)]).unwrap();
+        assert_eq!(graph.critical_path(), vec![0, 2, 4, 5]);
+
+        // 1 is slow enough to outweigh the longer chain through 2.
+        let weights: FnvHashMap<_, _> = [(1, 10)].into_iter().collect();
+        let weighted = graph.critical_path_by(|id| weights.get(&id).copied().unwrap_or(1));
+        assert_eq!(weighted, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_execution_layers_group_by_depth() {
+        let edges = [(0, 1), (0, 2), (3, 2), (2, 4), (4, 5), (7, 5)];
+        let graph = QueryGraph::new_from_edges(edges.to_vec()).unwrap();
+        assert_eq!(
+            graph.execution_layers(),
+            vec![vec![0, 3, 7], vec![1, 2], vec![4], vec![5]]
+        );
+    }
+
+    #[test]
+    fn test_json_schema_instance_lists_roots_and_leaves() {
+        let edges = [(0, 1), (0, 2), (3, 2), (2, 4), (4, 5), (7, 5)];
+        let graph = QueryGraph::new_from_edges(edges.to_vec()).unwrap();
+        let json = graph.to_json_schema_instance();
+        assert_eq!(json["nodes"], serde_json::json!([0, 1, 2, 3, 4, 5, 7]));
+        assert_eq!(json["roots"], serde_json::json!([0, 3, 7]));
+        assert_eq!(json["leaves"], serde_json::json!([1, 5]));
+        assert_eq!(json["edges"][0], serde_json::json!({ "from": 0, "to": 1 }));
+        assert_eq!(json["edges"].as_array().unwrap().len(), edges.len());
+    }
 }
@@ -1,43 +1,476 @@
+use self::selector::{SelectionMethod, Selector, SelectorSet};
 use self::{
-    graph::{QueryGraph, ValidGraphData},
-    query::{QueryCollection, QueryId, RawQuery},
+    graph::{BrokenCycle, EdgeKind, GraphError, GraphLimits, QueryGraph, ValidGraphData},
+    query::{QueryCollection, QueryId, QueryKind, QueryName},
 };
-use std::ops::Deref;
+use crate::{
+    settings::FreshnessStatus,
+    warning::{Warning, WarningCollector},
+};
+use fnv::{FnvHashMap, FnvHashSet};
+use petgraph::dot;
 
 pub mod graph;
 pub mod query;
+pub mod selector;
+pub mod source;
+
+/// Everything a change to one node would ripple into.
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct BlastRadius<'a> {
+    /// Models that read from the node itself, sorted.
+    pub direct: Vec<&'a QueryName>,
+    /// Every model downstream of the node, directly or not, sorted.
+    pub affected: Vec<&'a QueryName>,
+    /// Edges on the longest path from the node to an affected model; 0 if nothing is affected.
+    pub max_depth: usize,
+}
+
+impl BlastRadius<'_> {
+    /// How many models are affected, e.g. for "changing this table affects 47 models".
+    pub fn count(&self) -> usize {
+        self.affected.len()
+    }
+}
 
 pub struct GraphMeta {
     graph: QueryGraph,
     query: QueryCollection,
+    // Cycles dropped by `new_breaking_cycles`; always empty otherwise
+    broken_cycles: Vec<BrokenCycle>,
+    warnings: Vec<Warning>, // Sorted, as `WarningCollector::sorted` gives them
 }
 
 impl GraphMeta {
-    pub fn new(query_collection: QueryCollection) -> Option<Self> {
-        generate_graph_from_collection(&query_collection).and_then(|graph| {
-            Some(Self {
-                graph,
-                query: query_collection,
-            })
+    pub fn new(query_collection: QueryCollection) -> Result<Self, GraphError> {
+        Self::new_with_orphan_check(query_collection, false)
+    }
+
+    /// Like [`GraphMeta::new`], but orphans (queries that neither read from nor feed any
+    /// other node, usually a broken reference) are reported, and with `fail_on_orphan` set
+    /// they fail construction instead of just being warned about.
+    pub fn new_with_orphan_check(
+        query_collection: QueryCollection,
+        fail_on_orphan: bool,
+    ) -> Result<Self, GraphError> {
+        Self::new_with_limits(query_collection, fail_on_orphan, &GraphLimits::default())
+    }
+
+    /// Like [`GraphMeta::new_with_orphan_check`], but fails with
+    /// [`GraphError::LimitExceeded`] before building anything if the collection is larger
+    /// than `limits` allow. Only queries count as models; tables don't.
+    pub fn new_with_limits(
+        query_collection: QueryCollection,
+        fail_on_orphan: bool,
+        limits: &GraphLimits,
+    ) -> Result<Self, GraphError> {
+        let mut graph = generate_graph_from_collection(&query_collection, limits)?;
+        classify_edges(&mut graph, &query_collection);
+        let mut meta = Self {
+            graph,
+            query: query_collection,
+            broken_cycles: Vec::new(),
+            warnings: Vec::new(),
+        };
+        let orphans = orphan_ids(&meta.graph);
+        if !orphans.is_empty() {
+            if fail_on_orphan {
+                return Err(GraphError::OrphanNodes(orphans));
+            }
+            let mut warnings = WarningCollector::new();
+            for name in meta.orphans_named() {
+                warnings.push(Warning::OrphanQuery(name.to_string().into()));
+            }
+            meta.warnings = warnings.sorted();
+        }
+        Ok(meta)
+    }
+
+    /// Like [`GraphMeta::new`], but a cyclic collection still yields a graph, with each cycle
+    /// broken as in [`QueryGraph::new_from_valid_data_breaking_cycles`].
+    pub fn new_breaking_cycles(query_collection: QueryCollection) -> Result<Self, GraphError> {
+        let (mut graph, broken_cycles) = QueryGraph::break_cycles(valid_data_from_collection(
+            &query_collection,
+            &GraphLimits::default(),
+        )?);
+        classify_edges(&mut graph, &query_collection);
+        Ok(Self {
+            graph,
+            query: query_collection,
+            broken_cycles,
+            warnings: Vec::new(),
         })
     }
+
+    /// What was found wrong with the graph without failing it, e.g. orphans, for the caller
+    /// to report.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn graph(&self) -> &QueryGraph {
+        &self.graph
+    }
+
+    pub fn collection(&self) -> &QueryCollection {
+        &self.query
+    }
+
+    fn name_of(&self, id: u64) -> Option<&QueryName> {
+        self.query
+            .get_by_id(&QueryId::from(id))
+            .map(|kind| kind.name())
+    }
+
+    /// Every node's name, dependencies before dependents. Follows
+    /// [`QueryGraph::execution_layers`], so the order is stable.
+    pub fn topological_named(&self) -> Vec<&QueryName> {
+        self.graph
+            .execution_layers()
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.name_of(id))
+            .collect()
+    }
+
+    /// [`QueryGraph::execution_layers`] by name: each inner list can be built in parallel once
+    /// the ones before it are done. Names within a layer are sorted.
+    pub fn named_execution_layers(&self) -> Vec<Vec<String>> {
+        self.graph
+            .execution_layers()
+            .into_iter()
+            .map(|layer| {
+                let mut names: Vec<_> = layer
+                    .into_iter()
+                    .filter_map(|id| self.name_of(id))
+                    .map(|name| name.to_string())
+                    .collect();
+                names.sort();
+                names
+            })
+            .collect()
+    }
+
+    /// Names of every node `selector` picks, sorted. No model carries a tag, so `tag:`
+    /// selectors pick nothing; see [`GraphMeta::select_with_tags`].
+    pub fn select(&self, selector: &Selector) -> Vec<&QueryName> {
+        self.select_with_tags(selector, &FnvHashMap::default())
+    }
+
+    /// [`GraphMeta::select`], with `tags` giving each model's configured tags for `tag:`
+    /// selectors.
+    pub fn select_with_tags(
+        &self,
+        selector: &Selector,
+        tags: &FnvHashMap<QueryName, Vec<String>>,
+    ) -> Vec<&QueryName> {
+        let matched: Vec<_> = match &selector.method {
+            SelectionMethod::Name(name) => self.query.get(name).into_iter().collect(),
+            SelectionMethod::Tag(tag) => self
+                .query
+                .sorted_values()
+                .into_iter()
+                .filter(|kind| {
+                    tags.get(kind.name())
+                        .is_some_and(|model_tags| model_tags.iter().any(|t| t == tag))
+                })
+                .collect(),
+            SelectionMethod::Path(dir) => self
+                .query
+                .values()
+                .filter(|kind| match kind {
+                    QueryKind::Query(q) => q.source_path().is_some_and(|p| p.starts_with(dir)),
+                    QueryKind::TableQuery(_) => false,
+                })
+                .collect(),
+        };
+        let mut ids: Vec<_> = matched.iter().map(|kind| **kind.id()).collect();
+        for kind in &matched {
+            let id = **kind.id();
+            if selector.upstream {
+                ids.extend(self.graph.get_ancestors(id));
+            }
+            if selector.downstream {
+                ids.extend(self.graph.get_downstream(id));
+            }
+        }
+        let mut names: Vec<_> = ids.into_iter().filter_map(|id| self.name_of(id)).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Names of every node any of `set`'s alternatives picks, sorted, where an alternative
+    /// picks the nodes every one of its selectors does. `tags` is as for
+    /// [`GraphMeta::select_with_tags`].
+    pub fn select_set_with_tags(
+        &self,
+        set: &SelectorSet,
+        tags: &FnvHashMap<QueryName, Vec<String>>,
+    ) -> Vec<&QueryName> {
+        let mut names: Vec<_> = set
+            .unions
+            .iter()
+            .flat_map(|selectors| {
+                let mut picked = selectors.iter().map(|s| self.select_with_tags(s, tags));
+                let first = picked.next().unwrap_or_default();
+                picked.fold(first, |mut kept, next| {
+                    kept.retain(|name| next.contains(name));
+                    kept
+                })
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// [`GraphMeta::select_set_with_tags`] for `select`, or every node without one, less
+    /// every node `exclude` picks. Dependents of an excluded node are only dropped if
+    /// `exclude` asks for them with `+`.
+    pub fn select_excluding(
+        &self,
+        select: Option<&SelectorSet>,
+        exclude: Option<&SelectorSet>,
+        tags: &FnvHashMap<QueryName, Vec<String>>,
+    ) -> Vec<&QueryName> {
+        let mut names = match select {
+            Some(select) => self.select_set_with_tags(select, tags),
+            None => self
+                .query
+                .sorted_values()
+                .into_iter()
+                .map(QueryKind::name)
+                .collect(),
+        };
+        if let Some(exclude) = exclude {
+            let excluded = self.select_set_with_tags(exclude, tags);
+            names.retain(|name| !excluded.contains(name));
+        }
+        names
+    }
+
+    /// The kind of the edge from `from` to `to`, if `to` reads from `from`.
+    pub fn edge_kind(&self, from: &QueryName, to: &QueryName) -> Option<EdgeKind> {
+        let id_of = |name| self.query.get(name).map(|kind| **kind.id());
+        self.graph.edge_kind(id_of(from)?, id_of(to)?)
+    }
+
+    /// Retags edges out of the named tables as [`EdgeKind::SeedRef`]. The collection only
+    /// knows them as tables, so which are seeds has to come from the project.
+    pub fn mark_seeds<'a>(&mut self, seeds: impl IntoIterator<Item = &'a QueryName>) {
+        for seed in seeds {
+            let Some(table @ QueryKind::TableQuery(_)) = self.query.get(seed) else {
+                continue;
+            };
+            let from = **table.id();
+            for to in self.graph.get_downstream(from) {
+                if self.graph.edge_kind(from, to) == Some(EdgeKind::SourceRef) {
+                    self.graph.set_edge_kind(from, to, EdgeKind::SeedRef);
+                }
+            }
+        }
+    }
+
+    /// Names of the nodes that neither read from nor feed any other node, sorted.
+    pub fn orphans_named(&self) -> Vec<&QueryName> {
+        let mut orphans: Vec<_> = orphan_ids(&self.graph)
+            .into_iter()
+            .filter_map(|id| self.name_of(id))
+            .collect();
+        orphans.sort();
+        orphans
+    }
+
+    /// Names of the nodes with no dependencies, sorted.
+    pub fn roots_named(&self) -> Vec<&QueryName> {
+        let mut roots: Vec<_> = self
+            .graph
+            .get_root_nodes()
+            .into_iter()
+            .filter_map(|id| self.name_of(id))
+            .collect();
+        roots.sort();
+        roots
+    }
+
+    /// The first cycle that had to be broken, spelled out by query name:
+    /// `cycle detected: orders -> order_items -> orders`.
+    pub fn describe_cycle(&self) -> Option<String> {
+        let cycle = self.broken_cycles.first()?;
+        let name_of = |id: &u64| match self.name_of(*id) {
+            Some(name) => name.to_string(),
+            None => id.to_string(),
+        };
+        let names: Vec<_> = cycle
+            .path
+            .iter()
+            .chain(cycle.path.first())
+            .map(name_of)
+            .collect();
+        Some(format!("cycle detected: {}", names.join(" -> ")))
+    }
+
+    /// The sources and seeds (`TableQuery`s) a model ultimately reads from, skipping any
+    /// intermediate models. Sorted by name.
+    pub fn sources_of(&self, model: &QueryName) -> Vec<&QueryName> {
+        let Some(model) = self.query.get(model) else {
+            return Vec::new();
+        };
+        let mut sources: Vec<_> = self
+            .graph
+            .get_ancestors(**model.id())
+            .iter()
+            .filter_map(|id| self.query.get_by_id(&QueryId::from(*id)))
+            .filter(|kind| matches!(kind, QueryKind::TableQuery(_)))
+            .map(|kind| kind.name())
+            .collect();
+        sources.sort();
+        sources
+    }
+
+    /// What changing `source_name` would affect. Unknown names affect nothing.
+    pub fn blast_radius(&self, source_name: &str) -> BlastRadius<'_> {
+        let Some(source) = self.query.get(&QueryName::from(source_name)) else {
+            return BlastRadius::default();
+        };
+        let source = **source.id();
+        let downstream: FnvHashSet<_> = self.graph.get_downstream(source).into_iter().collect();
+        // Longest distance from the source, filled in dependency order so every parent is
+        // settled before its children.
+        let mut depths = FnvHashMap::default();
+        depths.insert(source, 0);
+        for id in self.graph.execution_layers().into_iter().flatten() {
+            if !downstream.contains(&id) {
+                continue;
+            }
+            let depth = self
+                .graph
+                .get_parents(id)
+                .iter()
+                .filter_map(|parent| depths.get(parent))
+                .max()
+                .map_or(0, |d| d + 1);
+            depths.insert(id, depth);
+        }
+        let sorted_names = |ids: Vec<u64>| {
+            let mut names: Vec<_> = ids.into_iter().filter_map(|id| self.name_of(id)).collect();
+            names.sort();
+            names
+        };
+        let direct = downstream
+            .iter()
+            .copied()
+            .filter(|id| self.graph.get_parents(*id).contains(&source))
+            .collect();
+        BlastRadius {
+            direct: sorted_names(direct),
+            affected: sorted_names(downstream.iter().copied().collect()),
+            max_depth: depths.values().copied().max().unwrap_or(0),
+        }
+    }
+
+    /// Like [`QueryGraph::to_dot`], but sources and seeds are filled differently from models,
+    /// and nodes with a known status are filled by it instead.
+    pub fn to_dot_styled(
+        &self,
+        statuses: Option<&FnvHashMap<QueryName, FreshnessStatus>>,
+    ) -> String {
+        let node_attributes = |_, (_, id): (_, &u64)| {
+            let Some(kind) = self.query.get_by_id(&QueryId::from(*id)) else {
+                return String::new();
+            };
+            let status = statuses.and_then(|s| s.get(kind.name()));
+            let fill = match (status, kind) {
+                (Some(FreshnessStatus::Fresh), _) => "green",
+                (Some(FreshnessStatus::Warn), _) => "yellow",
+                (Some(FreshnessStatus::Error), _) => "red",
+                (None, QueryKind::TableQuery(_)) => "lightblue",
+                (None, QueryKind::Query(_)) => "white",
+            };
+            format!("style=filled fillcolor={fill}")
+        };
+        format!(
+            "{:?}",
+            dot::Dot::with_attr_getters(
+                &self.graph.inner,
+                &[dot::Config::EdgeNoLabel],
+                &|_, edge| {
+                    match edge.weight() {
+                        EdgeKind::ModelRef => "",
+                        EdgeKind::SourceRef | EdgeKind::SeedRef => "color=lightblue",
+                        EdgeKind::Manual => "style=dashed",
+                    }
+                    .to_string()
+                },
+                &node_attributes,
+            )
+        )
+    }
 }
 
-fn generate_graph_from_collection(c: &QueryCollection) -> Option<QueryGraph> {
-    let edges: Vec<_> = c
-        .values()
-        .map(|node| (node.id(), c.get_query_depedencies(node.name())))
-        .flat_map(|(id, deps)| gen_edge_pairs(id, &deps))
+fn orphan_ids(graph: &QueryGraph) -> Vec<u64> {
+    let mut orphans: Vec<_> = graph::find_orphan_nodes(&graph.inner)
+        .into_iter()
+        .filter_map(|n_idx| graph.get_id(n_idx))
         .collect();
-    ValidGraphData::new_from_edges(&edges).and_then(QueryGraph::new_from_valid_data)
+    orphans.sort_unstable();
+    orphans
+}
+
+// Every edge starts out a `ModelRef`; retag the ones from tables and the hand-declared ones.
+fn classify_edges(graph: &mut QueryGraph, c: &QueryCollection) {
+    let ids = &c.query_id_map.inner;
+    for node in c.sorted_values() {
+        let manual = c.manual_dependencies(node.name());
+        for dep in node.dependency_names() {
+            let Some(src) = ids.get(dep) else {
+                continue;
+            };
+            let kind = if manual.contains(dep) {
+                EdgeKind::Manual
+            } else if matches!(c.get(dep), Some(QueryKind::TableQuery(_))) {
+                EdgeKind::SourceRef
+            } else {
+                EdgeKind::ModelRef
+            };
+            graph.set_edge_kind(**src, **node.id(), kind);
+        }
+    }
+}
+
+fn generate_graph_from_collection(
+    c: &QueryCollection,
+    limits: &GraphLimits,
+) -> Result<QueryGraph, GraphError> {
+    valid_data_from_collection(c, limits).and_then(QueryGraph::try_from_valid_data)
 }
 
-fn gen_edge_pairs(src_node: &QueryId, node_deps: &[QueryId]) -> Vec<(u64, u64)> {
-    node_deps
+fn valid_data_from_collection(
+    c: &QueryCollection,
+    limits: &GraphLimits,
+) -> Result<ValidGraphData, GraphError> {
+    let nodes = c.sorted_values();
+    // Each query already carries its dependency names; resolve them straight to ids rather
+    // than looking every node back up by name first.
+    let ids = &c.query_id_map.inner;
+    let edges: Vec<_> = nodes
         .iter()
-        .map(|n| (n, src_node))
-        .map(|(src, dst)| (src.deref().to_owned(), dst.deref().to_owned()))
-        .collect()
+        .flat_map(|node| {
+            let dst = **node.id();
+            node.dependency_names()
+                .iter()
+                .filter_map(move |dep| ids.get(dep))
+                .map(move |src| (**src, dst))
+        })
+        .collect();
+    let models = nodes
+        .iter()
+        .filter(|node| matches!(node, QueryKind::Query(_)))
+        .count();
+    limits.check(models, edges.len())?;
+    let node_ids: Vec<_> = nodes.iter().map(|node| **node.id()).collect();
+    ValidGraphData::try_from_id_edge_pairs_retaining_orphans(&node_ids, &edges)
 }
 
 #[cfg(test)]
@@ -45,6 +478,7 @@ mod test_query_graph {
     use petgraph::dot;
 
     use super::*;
+    use crate::query_graph::query::{QueryName, RawQuery};
 
     #[test]
     fn test_can_generate_graph_from_queries() {
@@ -59,11 +493,576 @@ mod test_query_graph {
         ];
         let mut collection = QueryCollection::new();
         collection.add_queries(queries);
-        let query_graph = generate_graph_from_collection(&collection).unwrap();
+        let query_graph =
+            generate_graph_from_collection(&collection, &GraphLimits::default()).unwrap();
         dbg!(collection.query_id_map.inner);
         println!(
             "{:?}",
             petgraph::dot::Dot::with_config(&query_graph.inner, &[dot::Config::EdgeNoLabel])
         );
     }
+
+    #[test]
+    fn test_edges_match_per_node_dependency_lookup() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+            RawQuery::new(
+                "q4",
+                "from q3 | join side:inner rituals [==source] | join side:inner q1 [==other]",
+            ),
+        ]);
+        let mut expected: Vec<_> = collection
+            .values()
+            .flat_map(|node| {
+                collection
+                    .get_query_depedencies(node.name())
+                    .into_iter()
+                    .map(|dep| (*dep, **node.id()))
+            })
+            .collect();
+        expected.sort_unstable();
+        let graph = generate_graph_from_collection(&collection, &GraphLimits::default()).unwrap();
+        let mut edges: Vec<_> = graph
+            .inner
+            .raw_edges()
+            .iter()
+            .map(|e| (graph.inner[e.source()], graph.inner[e.target()]))
+            .collect();
+        edges.sort_unstable();
+        assert_eq!(edges, expected);
+        assert_eq!(edges.len(), 7);
+    }
+
+    #[test]
+    fn test_is_ancestor_of() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+            RawQuery::new(
+                "q4",
+                "from q3 | join side:inner rituals [==source] | join side:inner q1 [==other]",
+            ),
+        ]);
+        let id = |name: &str| **collection.get(&QueryName::from(name)).unwrap().id();
+        let graph = generate_graph_from_collection(&collection, &GraphLimits::default()).unwrap();
+        assert!(graph.is_ancestor_of(id("q1"), id("q4")));
+        assert!(graph.is_ancestor_of(id("q2"), id("q4")));
+        assert!(!graph.is_ancestor_of(id("q4"), id("q1")));
+        assert!(!graph.is_ancestor_of(id("q1"), id("q1")));
+        assert!(!graph.is_ancestor_of(id("q1"), 42));
+    }
+
+    #[test]
+    fn test_dot_output_is_stable_across_builds() {
+        let build_dot = || {
+            let mut collection = QueryCollection::new();
+            collection.add_queries(vec![
+                RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+                RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+                RawQuery::new("q3", "from q2 | join side:inner grimoires [==source]"),
+                RawQuery::new("q4", "from q3 | join side:inner familiars [==source]"),
+            ]);
+            generate_graph_from_collection(&collection, &GraphLimits::default())
+                .unwrap()
+                .to_dot()
+        };
+        let first = build_dot();
+        (0..10).for_each(|_| assert_eq!(build_dot(), first));
+    }
+
+    #[test]
+    fn test_query_depths() {
+        let queries = vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+            RawQuery::new(
+                "q4",
+                "from q3 | join side:inner rituals [==source] | join side:inner q1 [==other]",
+            ),
+        ];
+        let mut collection = QueryCollection::new();
+        collection.add_queries(queries);
+        let depths = generate_graph_from_collection(&collection, &GraphLimits::default())
+            .unwrap()
+            .node_depths();
+        let depth_of = |name: &str| depths[collection[&QueryName::from(name)].id()];
+        assert_eq!(depth_of("arcana"), 0);
+        assert_eq!(depth_of("rituals"), 0);
+        assert_eq!(depth_of("q4"), 4);
+        assert!(depths.values().all(|&d| d <= depth_of("q4")));
+    }
+
+    #[test]
+    fn test_sources_of_skips_intermediate_models() {
+        let queries = vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+            RawQuery::new(
+                "q4",
+                "from q3 | join side:inner rituals [==source] | join side:inner q1 [==other]",
+            ),
+        ];
+        let mut collection = QueryCollection::new();
+        collection.add_queries(queries);
+        let meta = GraphMeta::new(collection).unwrap();
+        assert_eq!(
+            meta.sources_of(&"q4".into()),
+            vec![&QueryName::from("arcana"), &QueryName::from("rituals")]
+        );
+        assert!(meta.sources_of(&"arcana".into()).is_empty());
+    }
+
+    #[test]
+    fn test_blast_radius_of_a_source() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+            RawQuery::new(
+                "q4",
+                "from q3 | join side:inner rituals [==source] | join side:inner q1 [==other]",
+            ),
+            RawQuery::new("q5", "from rituals"),
+        ]);
+        let meta = GraphMeta::new(collection).unwrap();
+        let names = |names: &[&str]| -> Vec<QueryName> {
+            names.iter().map(|n| QueryName::from(*n)).collect()
+        };
+        let rituals = meta.blast_radius("rituals");
+        // q4 reads rituals itself, however far down the q2 chain it also sits.
+        assert_eq!(
+            rituals.direct,
+            names(&["q2", "q4", "q5"]).iter().collect::<Vec<_>>()
+        );
+        assert_eq!(rituals.max_depth, 3);
+        let radius = meta.blast_radius("arcana");
+        assert_eq!(radius.direct, names(&["q1"]).iter().collect::<Vec<_>>());
+        assert_eq!(
+            radius.affected,
+            names(&["q1", "q2", "q3", "q4"]).iter().collect::<Vec<_>>()
+        );
+        assert_eq!(radius.count(), 4);
+        // arcana -> q1 -> q2 -> q3 -> q4, even though q1 also feeds q4 directly.
+        assert_eq!(radius.max_depth, 4);
+        assert_eq!(meta.blast_radius("q4"), BlastRadius::default());
+        assert_eq!(meta.blast_radius("nope").count(), 0);
+    }
+
+    #[test]
+    fn test_styled_dot_colors_nodes_by_kind_and_status() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+        ]);
+        let id_of = |name: &str| **collection.get(&QueryName::from(name)).unwrap().id();
+        let (q1, q2, arcana) = (id_of("q1"), id_of("q2"), id_of("arcana"));
+        let meta = GraphMeta::new(collection).unwrap();
+        let mut statuses = FnvHashMap::default();
+        statuses.insert(QueryName::from("q1"), FreshnessStatus::Error);
+
+        let fill_of = |dot: &str, id: u64| {
+            dot.lines()
+                .find(|l| l.contains(&format!("label = \"{id}\"")))
+                .and_then(|l| l.split("fillcolor=").nth(1))
+                .map(|rest| rest.trim_end_matches(']').to_string())
+        };
+        let styled = meta.to_dot_styled(Some(&statuses));
+        assert_eq!(fill_of(&styled, q1).as_deref(), Some("red"));
+        assert_eq!(fill_of(&styled, q2).as_deref(), Some("white"));
+        assert_eq!(fill_of(&styled, arcana).as_deref(), Some("lightblue"));
+        let unstatused = meta.to_dot_styled(None);
+        assert_eq!(fill_of(&unstatused, q1).as_deref(), Some("white"));
+        assert!(!meta.graph.to_dot().contains("fillcolor"));
+    }
+
+    #[test]
+    fn test_manual_dependency_adds_ordered_edge() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("load_orders", "from raw_orders"),
+            RawQuery::new("report", "from orders | select [id]"),
+        ]);
+        let id_of = |c: &QueryCollection, name: &str| **c.get(&QueryName::from(name)).unwrap().id();
+        let (load, report) = (
+            id_of(&collection, "load_orders"),
+            id_of(&collection, "report"),
+        );
+        let unlinked =
+            generate_graph_from_collection(&collection, &GraphLimits::default()).unwrap();
+        assert!(!unlinked.get_ancestors(report).contains(&load));
+
+        collection
+            .add_manual_dependencies(&QueryName::from("report"), [QueryName::from("load_orders")]);
+        let graph = generate_graph_from_collection(&collection, &GraphLimits::default()).unwrap();
+        assert!(graph.get_ancestors(report).contains(&load));
+        let depths = graph.node_depths();
+        assert!(depths[&load] < depths[&report]);
+    }
+
+    #[test]
+    fn test_sourceless_query_is_kept_as_root() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("constants", "from s\"SELECT 1 AS a\""),
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+        ]);
+        let constants = collection.get(&QueryName::from("constants")).unwrap();
+        assert!(constants.dependencies().is_empty());
+        let id = **constants.id();
+
+        let graph = generate_graph_from_collection(&collection, &GraphLimits::default()).unwrap();
+        assert!(graph.get_index(id).is_some());
+        assert!(graph.get_root_nodes().contains(&id));
+    }
+
+    #[test]
+    fn test_cycle_is_described_by_name() {
+        let queries = || {
+            let mut collection = QueryCollection::new();
+            collection.add_queries(vec![
+                RawQuery::new("orders", "from order_items | select [id]"),
+                RawQuery::new("order_items", "from orders | select [id]"),
+                RawQuery::new("q1", "from arcana"),
+            ]);
+            collection
+        };
+        assert!(matches!(
+            GraphMeta::new(queries()),
+            Err(GraphError::ContainsCycle(_))
+        ));
+        let meta = GraphMeta::new_breaking_cycles(queries()).unwrap();
+        let description = meta.describe_cycle().unwrap();
+        assert!(
+            description == "cycle detected: orders -> order_items -> orders"
+                || description == "cycle detected: order_items -> orders -> order_items",
+            "{description}"
+        );
+        let mut acyclic = QueryCollection::new();
+        acyclic.add_queries(vec![RawQuery::new("q1", "from arcana")]);
+        let meta = GraphMeta::new_breaking_cycles(acyclic).unwrap();
+        assert_eq!(meta.describe_cycle(), None);
+    }
+
+    #[test]
+    fn test_named_roots_and_order() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+        ]);
+        let meta = GraphMeta::new(collection).unwrap();
+        assert_eq!(
+            meta.roots_named(),
+            vec![&QueryName::from("arcana"), &QueryName::from("rituals")]
+        );
+        let order = meta.topological_named();
+        let position = |name: &str| order.iter().position(|n| **n == QueryName::from(name));
+        assert!(position("q1") < position("q2"));
+        assert!(position("q2") < position("q3"));
+        assert_eq!(order.len(), meta.collection().len());
+        assert_eq!(meta.graph().inner.node_count(), 5);
+    }
+
+    #[test]
+    fn test_graph_meta_reports_why_generation_failed() {
+        let mut cyclic = QueryCollection::new();
+        cyclic.add_queries(vec![
+            RawQuery::new("orders", "from order_items | select [id]"),
+            RawQuery::new("order_items", "from orders | select [id]"),
+        ]);
+        let ids: Vec<_> = ["orders", "order_items"]
+            .iter()
+            .map(|name| **cyclic.get(&QueryName::from(*name)).unwrap().id())
+            .collect();
+        match GraphMeta::new(cyclic) {
+            Err(GraphError::ContainsCycle(mut cycle)) => {
+                cycle.sort_unstable();
+                let mut expected = ids.clone();
+                expected.sort_unstable();
+                assert_eq!(cycle, expected);
+            }
+            _ => panic!("expected a cycle"),
+        }
+        assert!(matches!(
+            GraphMeta::new(QueryCollection::new()),
+            Err(GraphError::Empty)
+        ));
+    }
+
+    #[test]
+    fn test_orphans_fail_only_when_configured() {
+        let queries = || {
+            let mut collection = QueryCollection::new();
+            collection.add_queries(vec![
+                RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+                RawQuery::new("stray", "from s\"SELECT 1 AS a\""),
+            ]);
+            collection
+        };
+        let stray = **queries().get(&QueryName::from("stray")).unwrap().id();
+        assert_eq!(
+            GraphMeta::new_with_orphan_check(queries(), true).err(),
+            Some(GraphError::OrphanNodes(vec![stray]))
+        );
+        let lenient = GraphMeta::new_with_orphan_check(queries(), false).unwrap();
+        assert!(lenient.graph().get_index(stray).is_some());
+        assert_eq!(lenient.warnings(), [Warning::OrphanQuery("stray".into())]);
+    }
+
+    #[test]
+    fn test_model_limit_fails_construction() {
+        let queries = || {
+            let mut collection = QueryCollection::new();
+            collection.add_queries(vec![
+                RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+                RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+                RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+            ]);
+            collection
+        };
+        let limits = GraphLimits {
+            max_models: Some(2),
+            max_edges: None,
+        };
+        assert_eq!(
+            GraphMeta::new_with_limits(queries(), false, &limits).err(),
+            Some(GraphError::LimitExceeded {
+                limit: "max_models",
+                max: 2,
+                found: 3,
+            })
+        );
+        let roomy = GraphLimits {
+            max_models: Some(3),
+            max_edges: Some(4),
+        };
+        assert!(GraphMeta::new_with_limits(queries(), false, &roomy).is_ok());
+    }
+
+    #[test]
+    fn test_named_execution_layers() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("stg_orders", "from raw_orders"),
+            RawQuery::new("int_orders", "from stg_orders"),
+            RawQuery::new(
+                "orders",
+                "from int_orders | join side:left raw_customers [==customer_id]",
+            ),
+        ]);
+        let meta = GraphMeta::new(collection).unwrap();
+        assert_eq!(
+            meta.named_execution_layers(),
+            vec![
+                vec!["raw_customers", "raw_orders"],
+                vec!["stg_orders"],
+                vec!["int_orders"],
+                vec!["orders"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ls_lists_selected_names() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from raw"),
+            RawQuery::new("q2", "from q1"),
+            RawQuery::new("q3", "from q1"),
+            RawQuery::new("q4", "from q2 | join side:inner q3 [==id]"),
+        ]);
+        let meta = GraphMeta::new(collection).unwrap();
+        let tags: FnvHashMap<_, _> = [("q2", vec!["x"]), ("q3", vec!["x", "y"])]
+            .into_iter()
+            .map(|(name, tags)| {
+                (
+                    QueryName::from(name),
+                    tags.into_iter().map(String::from).collect(),
+                )
+            })
+            .collect();
+        let ls = |raw: &str| {
+            selector::render_selection(&meta.select_with_tags(&raw.parse().unwrap(), &tags))
+        };
+        assert_eq!(ls("+q4"), "q1\nq2\nq3\nq4\nraw\n5 models selected\n");
+        assert_eq!(ls("q1+"), "q1\nq2\nq3\nq4\n4 models selected\n");
+        assert_eq!(ls("tag:x"), "q2\nq3\n2 models selected\n");
+        assert_eq!(ls("tag:y"), "q3\n1 model selected\n");
+        assert_eq!(ls("tag:missing"), "0 models selected\n");
+        assert_eq!(ls("nope"), "0 models selected\n");
+
+        let ls_set = |raw: &str| {
+            selector::render_selection(&meta.select_set_with_tags(&raw.parse().unwrap(), &tags))
+        };
+        assert_eq!(ls_set("tag:x,+q4"), "q2\nq3\n2 models selected\n");
+        assert_eq!(ls_set("q1+,+q2"), "q1\nq2\n2 models selected\n");
+        assert_eq!(ls_set("tag:y,q2+"), "0 models selected\n");
+        assert_eq!(ls_set("tag:y,q1+ raw"), "q3\nraw\n2 models selected\n");
+    }
+
+    #[test]
+    fn test_exclude_removes_selected_nodes() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("stg_orders", "from raw_orders"),
+            RawQuery::new("stg_events", "from raw_events"),
+            RawQuery::new("sessions", "from stg_events"),
+            RawQuery::new("final", "from stg_orders | join side:left sessions [==id]"),
+        ]);
+        let meta = GraphMeta::new(collection).unwrap();
+        let tags =
+            FnvHashMap::from_iter([(QueryName::from("sessions"), vec![String::from("slow")])]);
+        let ls = |select: &str, exclude: Option<&str>| {
+            let exclude = exclude.map(|raw| raw.parse().unwrap());
+            selector::render_selection(&meta.select_excluding(
+                Some(&select.parse().unwrap()),
+                exclude.as_ref(),
+                &tags,
+            ))
+        };
+        assert_eq!(
+            ls("+final", Some("tag:slow")),
+            "final\nraw_events\nraw_orders\nstg_events\nstg_orders\n5 models selected\n"
+        );
+        // With `+`, the exclusion takes the slow model's dependents along with it.
+        assert_eq!(
+            ls("+final", Some("tag:slow+ raw_events")),
+            "raw_orders\nstg_events\nstg_orders\n3 models selected\n"
+        );
+        assert_eq!(ls("+final", None), ls("+final", Some("nope")));
+        assert_eq!(
+            selector::render_selection(&meta.select_excluding(
+                None,
+                Some(&"stg_events+".parse().unwrap()),
+                &tags
+            )),
+            "raw_events\nraw_orders\nstg_orders\n3 models selected\n"
+        );
+    }
+
+    #[test]
+    fn test_select_by_path() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("stg_orders", "from raw_orders")
+                .with_source_path("models/staging/stg_orders.prql"),
+            RawQuery::new("stg_customers", "from raw_customers")
+                .with_source_path("models/staging/crm/stg_customers.prql"),
+            RawQuery::new(
+                "orders",
+                "from stg_orders | join side:inner stg_customers [==id]",
+            )
+            .with_source_path("models/marts/orders.prql"),
+            RawQuery::new("staging_report", "from orders")
+                .with_source_path("models/staging_reports/report.prql"),
+        ]);
+        let meta = GraphMeta::new(collection).unwrap();
+        let select = |raw: &str| -> Vec<String> {
+            meta.select(&raw.parse().unwrap())
+                .iter()
+                .map(|n| n.to_string())
+                .collect()
+        };
+        assert_eq!(
+            select("path:models/staging"),
+            vec!["stg_customers", "stg_orders"]
+        );
+        assert_eq!(
+            select("path:models/staging+"),
+            vec!["orders", "staging_report", "stg_customers", "stg_orders"]
+        );
+        assert_eq!(
+            select("+orders"),
+            vec![
+                "orders",
+                "raw_customers",
+                "raw_orders",
+                "stg_customers",
+                "stg_orders"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_edges_are_tagged_by_upstream_kind() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("stg_orders", "from raw_orders"),
+            RawQuery::new(
+                "orders",
+                "from stg_orders | join side:left rates [==currency]",
+            ),
+        ]);
+        collection.add_manual_dependencies(&QueryName::from("orders"), [QueryName::from("audit")]);
+        let mut meta = GraphMeta::new(collection).unwrap();
+        let kind = |meta: &GraphMeta, from: &str, to: &str| {
+            meta.edge_kind(&QueryName::from(from), &QueryName::from(to))
+        };
+        assert_eq!(
+            kind(&meta, "raw_orders", "stg_orders"),
+            Some(EdgeKind::SourceRef)
+        );
+        assert_eq!(
+            kind(&meta, "stg_orders", "orders"),
+            Some(EdgeKind::ModelRef)
+        );
+        assert_eq!(kind(&meta, "audit", "orders"), Some(EdgeKind::Manual));
+        assert_eq!(kind(&meta, "raw_orders", "orders"), None);
+
+        meta.mark_seeds([&QueryName::from("rates"), &QueryName::from("stg_orders")]);
+        assert_eq!(kind(&meta, "rates", "orders"), Some(EdgeKind::SeedRef));
+        assert_eq!(
+            kind(&meta, "stg_orders", "orders"),
+            Some(EdgeKind::ModelRef)
+        );
+    }
+
+    #[test]
+    fn test_extends_continues_the_base_pipeline() {
+        use crate::query_graph::query::{resolve_extends, ExtendsError};
+        use std::collections::HashMap;
+        let name = QueryName::from;
+        let queries = || {
+            vec![
+                RawQuery::new("paid_orders", "from orders | filter status == 'paid'"),
+                RawQuery::new("big_paid_orders", "filter amount > 100"),
+            ]
+        };
+        let extends = HashMap::from([(name("big_paid_orders"), name("paid_orders"))]);
+        let mut collection = QueryCollection::new();
+        collection.add_queries(resolve_extends(queries(), &extends).unwrap());
+        for (model, base) in &extends {
+            collection.add_manual_dependencies(model, [base.clone()]);
+        }
+        let meta = GraphMeta::new(collection).unwrap();
+        let sql = match meta.collection().get(&name("big_paid_orders")) {
+            Some(QueryKind::Query(q)) => q.compile(&Default::default()).unwrap(),
+            other => panic!("expected a query, got {other:?}"),
+        };
+        assert!(sql.contains("status = 'paid'"), "{sql}");
+        assert!(sql.contains("amount > 100"), "{sql}");
+        assert_eq!(
+            meta.edge_kind(&name("paid_orders"), &name("big_paid_orders")),
+            Some(EdgeKind::Manual)
+        );
+
+        let cyclic = HashMap::from([
+            (name("big_paid_orders"), name("paid_orders")),
+            (name("paid_orders"), name("big_paid_orders")),
+        ]);
+        assert!(matches!(
+            resolve_extends(queries(), &cyclic),
+            Err(ExtendsError::Cycle(chain)) if chain.len() == 3
+        ));
+    }
 }
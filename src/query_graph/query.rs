@@ -1,12 +1,32 @@
+use crate::settings::{substitute_vars, IdentifierCase, ResourceConfig, VarError, VarValue};
 use fnv::FnvHashMap;
-use prql_compiler::{parse, semantic::resolve, translate};
+use prql_compiler::{
+    ast::{
+        pl::InterpolateItem,
+        pl::{Dialect, TableExternRef},
+        rq::{CId, ColumnDeclKind, Expr, ExprKind, Relation, TableRef, Transform},
+    },
+    parse,
+    semantic::resolve,
+    translate, ErrorMessage, IntoErrorMessage, SourceLocation,
+};
+use semver::Version;
+use serde::Deserialize;
 use shrinkwraprs::Shrinkwrap;
 use smartstring::alias::String;
+use std::borrow::Cow;
 use std::collections::HashSet;
+use std::fmt;
 use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, ops::Deref};
 use xxhash_rust::xxh3::{xxh3_64, Xxh3Builder};
 
+/// The PRQL version the bundled compiler implements, matched against `prql version:`
+/// headers. Kept in step with the `prql-compiler` dependency.
+pub const PRQL_VERSION: &str = "0.3.1";
+
 #[derive(Debug, Shrinkwrap, Eq, PartialEq, Hash, Clone, Copy, Default, PartialOrd, Ord)]
 pub struct QueryId(u64);
 
@@ -27,6 +47,14 @@ impl QueryKind {
         }
     }
 
+    /// [`QueryKind::dependencies`] without the copy.
+    pub fn dependency_names(&self) -> &[QueryName] {
+        match self {
+            QueryKind::Query(q) => &q.dependencies,
+            QueryKind::TableQuery(_) => &[],
+        }
+    }
+
     pub fn id(&self) -> &QueryId {
         match self {
             QueryKind::Query(q) => &q.id,
@@ -55,6 +83,11 @@ pub struct Query {
     name: QueryName,
     resolved_query: prql_compiler::ast::rq::Query,
     dependencies: Vec<QueryName>,
+    // Set when the query carries its own `prql dialect:...` header
+    pinned_dialect: Option<Dialect>,
+    // xxh3 of the raw query text
+    fingerprint: u64,
+    source_path: Option<PathBuf>,
 }
 
 impl PartialEq for Query {
@@ -63,6 +96,9 @@ impl PartialEq for Query {
             && self.name == other.name
             && self.resolved_query == other.resolved_query
             && self.dependencies == other.dependencies
+            && self.pinned_dialect == other.pinned_dialect
+            && self.fingerprint == other.fingerprint
+            && self.source_path == other.source_path
     }
 }
 
@@ -77,10 +113,23 @@ impl Hash for Query {
     }
 }
 
-#[derive(Debug)]
+/// Counts from [`QueryCollection::stats`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CollectionStats {
+    pub queries: usize,
+    pub tables: usize,
+    pub distinct_dependencies: usize, // Names read by at least one query
+    pub most_dependencies: Option<(QueryName, usize)>, // First by name on a tie
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct RawQuery {
     query_string: String,
     name: String,
+    #[serde(skip)]
+    source_path: Option<PathBuf>,
+    #[serde(default)]
+    id_key: Option<String>, // Hashed for the id instead of `name`, so renames keep it
 }
 
 impl RawQuery {
@@ -88,8 +137,413 @@ impl RawQuery {
         Self {
             query_string: query_string.into(),
             name: name.into(),
+            source_path: None,
+            id_key: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn query_string(&self) -> &str {
+        &self.query_string
+    }
+
+    pub fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    /// The value of an `# orbital: key = value` comment in the query text, if it has one.
+    /// The first matching annotation wins.
+    pub fn annotation(&self, key: &str) -> Option<&str> {
+        self.query_string.lines().find_map(|line| {
+            let (k, v) = line
+                .trim()
+                .strip_prefix('#')?
+                .trim_start()
+                .strip_prefix("orbital:")?
+                .split_once('=')?;
+            (k.trim() == key).then(|| v.trim())
+        })
+    }
+
+    /// The text after `# orbital-config:` on the first line starting with it: the model's
+    /// own settings, parsed by [`parse_config_block`](crate::settings::parse_config_block).
+    pub fn config_block(&self) -> Option<&str> {
+        self.query_string.lines().find_map(|line| {
+            let block = line
+                .trim()
+                .strip_prefix('#')?
+                .trim_start()
+                .strip_prefix("orbital-config:")?;
+            Some(block.trim())
+        })
+    }
+
+    /// Whether the model should be loaded at all. `config`, when the project configures this
+    /// model, always wins; otherwise an `# orbital: enabled = false` annotation disables it.
+    pub fn is_enabled(&self, config: Option<&ResourceConfig>) -> bool {
+        match config {
+            Some(config) => config.enabled,
+            None => self.annotation("enabled") != Some("false"),
+        }
+    }
+
+    /// Substitutes project vars into the query text, see [`substitute_vars`]. If anything was
+    /// substituted the result is parsed and resolved again, so a value that breaks the query
+    /// is reported here instead of the model quietly failing to load.
+    pub fn with_vars(mut self, vars: &HashMap<String, VarValue>) -> Result<Self, VarError> {
+        let rendered = substitute_vars(&self.query_string, vars)?;
+        if rendered != self.query_string.as_str() {
+            let text = normalize_line_endings(&rendered);
+            parse(&text).and_then(resolve).map_err(|e| {
+                VarError::Invalid(PrepareError::from_prql(e, &self.name, &text).to_string())
+            })?;
+        }
+        self.query_string = rendered.into();
+        Ok(self)
+    }
+
+    /// Records the file the query was loaded from, for path-based selection.
+    pub fn with_source_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.source_path = Some(path.into());
+        self
+    }
+
+    /// Gives the query a stable key, e.g. a UUID, to derive its id from instead of its name,
+    /// so the model can be renamed without losing its identity. Keys must be unique.
+    pub fn with_id_key(mut self, key: impl Into<String>) -> Self {
+        self.id_key = Some(key.into());
+        self
+    }
+
+    /// The id the query is added under: from [`RawQuery::with_id_key`], else an
+    /// `# orbital: id_key = ...` annotation, else the name.
+    pub fn id(&self) -> QueryId {
+        let key = self.id_key.as_deref().or_else(|| self.annotation("id_key"));
+        QueryId(xxh3_64(key.unwrap_or(&self.name).as_bytes()))
+    }
+}
+
+#[derive(Debug)]
+pub enum PrepareError {
+    /// The PRQL failed to parse or resolve. The message is rendered against the query source,
+    /// so it already points at the offending line.
+    Prql(ErrorMessage),
+    /// The query's `prql version:` header asks for a PRQL the bundled compiler doesn't match.
+    UnsupportedPrqlVersion {
+        query: std::string::String,
+        required: std::string::String,
+    },
+}
+
+impl PrepareError {
+    fn from_prql(e: impl IntoErrorMessage, query_name: &str, raw_query: &str) -> Self {
+        PrepareError::Prql(e.into_error_message(query_name, raw_query, false))
+    }
+
+    /// Line and column (both 0-based) of the start and end of the offending span, if the
+    /// compiler could attribute the error to one.
+    pub fn location(&self) -> Option<&SourceLocation> {
+        match self {
+            PrepareError::Prql(e) => e.location.as_ref(),
+            PrepareError::UnsupportedPrqlVersion { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for PrepareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrepareError::Prql(e) => write!(f, "{e}"),
+            PrepareError::UnsupportedPrqlVersion { query, required } => write!(
+                f,
+                "{query} requires PRQL {required}, but orbital compiles PRQL {PRQL_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PrepareError {}
+
+/// Which side of a `join` a relation is on: the pipeline being joined into, or the relation
+/// named by the `join`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum JoinKeySide {
+    Left,
+    Right,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ColumnError {
+    /// `query` reads `column` from `upstream`, but `upstream` never produces it.
+    MissingColumn {
+        query: QueryName,
+        upstream: QueryName,
+        column: String,
+    },
+    /// `query` joins on `column` of `upstream`, on `side` of the join, but `upstream` never
+    /// produces it.
+    MissingJoinKey {
+        query: QueryName,
+        upstream: QueryName,
+        column: String,
+        side: JoinKeySide,
+    },
+}
+
+impl fmt::Display for ColumnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColumnError::MissingColumn {
+                query,
+                upstream,
+                column,
+            } => write!(
+                f,
+                "{} selects column `{column}` from {}, which does not produce it",
+                query.deref(),
+                upstream.deref()
+            ),
+            ColumnError::MissingJoinKey {
+                query,
+                upstream,
+                column,
+                side,
+            } => write!(
+                f,
+                "{} joins on column `{column}` of {} ({} side), which does not produce it",
+                query.deref(),
+                upstream.deref(),
+                match side {
+                    JoinKeySide::Left => "left",
+                    JoinKeySide::Right => "right",
+                }
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ColumnError {}
+
+#[derive(Debug)]
+pub enum ShowError {
+    /// No query by that name; tables have no SQL of their own to show.
+    UnknownModel(QueryName),
+    Compile(std::string::String),
+}
+
+impl fmt::Display for ShowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShowError::UnknownModel(name) => write!(f, "no model named {}", name.deref()),
+            ShowError::Compile(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ShowError {}
+
+/// What a table reference in a query was taken to mean.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RefResolution {
+    /// Built by another query in the project.
+    Model,
+    /// Declared under the project's `sources`.
+    Source,
+    /// Declared under the project's `seeds`.
+    Seed,
+    /// Declared nowhere. A placeholder table was created just because a query reads from it,
+    /// which usually means a typo or a missing source declaration.
+    Placeholder,
+}
+
+impl fmt::Display for RefResolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefResolution::Model => write!(f, "a model, built by the query of the same name"),
+            RefResolution::Source => write!(f, "a declared source"),
+            RefResolution::Seed => write!(f, "a declared seed"),
+            RefResolution::Placeholder => write!(
+                f,
+                "an auto-created placeholder table: no model, source or seed has that name"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TraceError {
+    UnknownQuery(QueryName),
+    /// The query exists but never reads from the table.
+    NotReferenced {
+        query: QueryName,
+        table: QueryName,
+    },
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::UnknownQuery(query) => write!(f, "no query named {}", query.deref()),
+            TraceError::NotReferenced { query, table } => {
+                write!(f, "{} does not read from {}", query.deref(), table.deref())
+            }
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+/// A table read by a query that is neither a model nor a declared source or seed, which in
+/// strict mode is an error rather than a placeholder. Usually a typo.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UndeclaredReference {
+    pub query: QueryName,
+    pub table: QueryName,
+}
+
+impl fmt::Display for UndeclaredReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} reads from {}, which is not a model, source or seed",
+            self.query.deref(),
+            self.table.deref()
+        )
+    }
+}
+
+impl std::error::Error for UndeclaredReference {}
+
+/// Two queries with different names that would be added under the same id, e.g. from a
+/// copied `id_key`. The second is left out of the collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateId {
+    pub id: QueryId,
+    pub first: QueryName,
+    pub second: QueryName,
+}
+
+impl fmt::Display for DuplicateId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} has the same id as {}; give one of them a different id_key",
+            self.second.deref(),
+            self.first.deref()
+        )
+    }
+}
+
+impl std::error::Error for DuplicateId {}
+
+/// Why a model's `extends` couldn't be resolved.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExtendsError {
+    /// The base isn't one of the queries being loaded.
+    UnknownBase { model: QueryName, base: QueryName },
+    /// Models that extend each other, in order, starting and ending with the same one.
+    Cycle(Vec<QueryName>),
+}
+
+impl fmt::Display for ExtendsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtendsError::UnknownBase { model, base } => write!(
+                f,
+                "{} extends {}, which is not a model",
+                model.deref(),
+                base.deref()
+            ),
+            ExtendsError::Cycle(chain) => {
+                let chain: Vec<_> = chain.iter().map(|name| name.as_str()).collect();
+                write!(
+                    f,
+                    "models extend each other in a cycle: {}",
+                    chain.join(" -> ")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExtendsError {}
+
+/// Prepends the (resolved) PRQL of each model's base to its own, so a model declaring
+/// `extends` continues its base's pipeline. `extends` maps models to their bases. Bases can
+/// themselves extend other models; the chain is resolved once per model.
+///
+/// Only the text changes: record the inheritance as a dependency with
+/// [`QueryCollection::add_manual_dependencies`] once the queries are added.
+pub fn resolve_extends(
+    queries: Vec<RawQuery>,
+    extends: &HashMap<QueryName, QueryName>,
+) -> Result<Vec<RawQuery>, ExtendsError> {
+    let own: HashMap<_, _> = queries
+        .iter()
+        .map(|q| (QueryName::from(q.name()), q.query_string.clone()))
+        .collect();
+    let mut resolved: HashMap<QueryName, String> = HashMap::new();
+    for query in &queries {
+        let mut chain = vec![QueryName::from(query.name())];
+        // Walk up to the first model with nothing (left) to extend, then resolve back down.
+        while let Some(base) = chain.last().and_then(|model| extends.get(model)) {
+            if resolved.contains_key(base) {
+                break;
+            }
+            if let Some(start) = chain.iter().position(|model| model == base) {
+                let mut cycle = chain[start..].to_vec();
+                cycle.push(base.clone());
+                return Err(ExtendsError::Cycle(cycle));
+            }
+            if !own.contains_key(base) {
+                return Err(ExtendsError::UnknownBase {
+                    model: chain.last().unwrap().clone(),
+                    base: base.clone(),
+                });
+            }
+            chain.push(base.clone());
+        }
+        for model in chain.into_iter().rev() {
+            if resolved.contains_key(&model) {
+                continue;
+            }
+            let text = match extends.get(&model).and_then(|base| resolved.get(base)) {
+                Some(base_text) => format!("{base_text}\n{}", own[&model]).into(),
+                None => own[&model].clone(),
+            };
+            resolved.insert(model, text);
         }
     }
+    Ok(queries
+        .into_iter()
+        .map(|mut q| {
+            if let Some(text) = resolved.remove(&QueryName::from(q.name())) {
+                if text != q.query_string {
+                    // Pinned first, or the base's `id_key` annotation, now earlier in the
+                    // text, would be found instead of the model's own.
+                    let key = q
+                        .id_key
+                        .clone()
+                        .or_else(|| q.annotation("id_key").map(Into::into));
+                    q.id_key = Some(key.unwrap_or_else(|| q.name.clone()));
+                }
+                q.query_string = text;
+            }
+            q
+        })
+        .collect())
+}
+
+// `\r\n` and lone `\r` become `\n`; text that has neither is borrowed as-is.
+fn normalize_line_endings(text: &str) -> Cow<'_, str> {
+    if text.contains('\r') {
+        Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Cow::Borrowed(text)
+    }
 }
 
 pub type QueryMap<K, V> = HashMap<K, V, Xxh3Builder>;
@@ -105,6 +559,15 @@ pub struct QueryCollection {
     // Project? Scope? Might be better names
     query_map: QueryMap<QueryName, QueryKind>,
     pub(crate) query_id_map: ResourceIdMap<QueryId>,
+    // name -> xxh3 of the raw query string, for every query in `query_map`. Lets incremental
+    // reloads skip re-parsing queries whose source hasn't changed; the parsed query, with its
+    // dependencies, is already in `query_map`.
+    prepared_hashes: QueryMap<QueryName, u64>,
+    // query -> the dependencies added by hand that parsing didn't already find
+    manual_dependencies: QueryMap<QueryName, Vec<QueryName>>,
+    duplicate_ids: Vec<DuplicateId>, // Queries refused for an id another name already has
+    #[cfg(test)]
+    prepare_calls: std::cell::Cell<usize>,
 }
 
 impl Query {
@@ -113,12 +576,268 @@ impl Query {
         name: &str,
         parsed_query: prql_compiler::ast::rq::Query,
         dependencies: Vec<QueryName>,
+        pinned_dialect: Option<Dialect>,
+        fingerprint: u64,
     ) -> Self {
         Self {
             id,
             name: QueryName(name.into()),
             resolved_query: parsed_query,
             dependencies,
+            pinned_dialect,
+            fingerprint,
+            source_path: None,
+        }
+    }
+
+    /// The file this query was loaded from, if it came from one.
+    pub fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    /// Hash of the raw query text this query was prepared from.
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// The dialect named in the query's own `prql dialect:...` header, if it has one.
+    pub fn pinned_dialect(&self) -> Option<&Dialect> {
+        self.pinned_dialect.as_ref()
+    }
+
+    /// The dialect this query compiles to: its pinned dialect, falling back to `default`.
+    pub fn dialect<'a>(&'a self, default: &'a Dialect) -> &'a Dialect {
+        self.pinned_dialect.as_ref().unwrap_or(default)
+    }
+
+    pub fn compile(&self, default_dialect: &Dialect) -> prql_compiler::Result<std::string::String> {
+        self.compile_with_aliases(default_dialect, &QueryMap::default())
+    }
+
+    /// Comment identifying where compiled SQL came from, so a relation found in the warehouse
+    /// can be traced back to its model: `-- orbital: model=<name> id=<id> generated_at=<ts>`,
+    /// with the timestamp in Unix seconds.
+    pub fn sql_header(&self, generated_at: SystemTime) -> std::string::String {
+        let ts = generated_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        format!(
+            "-- orbital: model={} id={} generated_at={ts}",
+            self.name.deref(),
+            self.id.deref()
+        )
+    }
+
+    /// Compile, reading from the materialized relation of any upstream query listed in
+    /// `aliases` instead of from a table named after the query itself.
+    pub fn compile_with_aliases(
+        &self,
+        default_dialect: &Dialect,
+        aliases: &QueryMap<QueryName, String>,
+    ) -> prql_compiler::Result<std::string::String> {
+        let mut query = self.resolved_query.clone();
+        query.def.dialect = self.dialect(default_dialect).clone();
+        for table in query.tables.iter_mut() {
+            if let (Relation::ExternRef(..), Some(name)) = (&table.relation, &mut table.name) {
+                if let Some(alias) = aliases.get(&QueryName(name.as_str().into())) {
+                    *name = alias.to_string();
+                }
+            }
+        }
+        translate(query)
+    }
+
+    /// Aliases for [`Query::compile_with_aliases`] renaming every upstream relation this query
+    /// reads under the `case` policy, so references agree with how the upstream was created.
+    pub fn case_aliases(&self, case: IdentifierCase) -> QueryMap<QueryName, String> {
+        self.resolved_query
+            .tables
+            .iter()
+            .filter_map(|t| match (&t.name, &t.relation) {
+                (Some(name), Relation::ExternRef(..)) => Some(name),
+                _ => None,
+            })
+            .map(|name| (QueryName(name.as_str().into()), case.apply(name).into()))
+            .collect()
+    }
+
+    /// Compile into a single statement with every `ephemeral` upstream, direct or through
+    /// other ephemerals, inlined as a CTE ahead of the query instead of read from a table.
+    /// Every other upstream is read from its relation in `aliases`, if it has one, as in
+    /// [`Query::compile_with_aliases`].
+    pub fn compile_inlining(
+        &self,
+        collection: &QueryCollection,
+        ephemeral: &HashSet<QueryName>,
+        aliases: &QueryMap<QueryName, String>,
+        default_dialect: &Dialect,
+    ) -> prql_compiler::Result<std::string::String> {
+        let dialect = self.dialect(default_dialect);
+        let mut seen = HashSet::new();
+        let mut inlined = Vec::new();
+        for dependency in &self.dependencies {
+            collect_ephemerals(collection, ephemeral, dependency, &mut seen, &mut inlined);
+        }
+        // Inlined upstreams are read from their CTE, which keeps the query's name.
+        let aliases: QueryMap<_, _> = aliases
+            .iter()
+            .filter(|(name, _)| !ephemeral.contains(*name))
+            .map(|(name, alias)| (name.clone(), alias.clone()))
+            .collect();
+        let sql = self.compile_with_aliases(dialect, &aliases)?;
+        if inlined.is_empty() {
+            return Ok(sql);
+        }
+        let ctes = inlined
+            .iter()
+            .map(|q| {
+                let sql = q.compile_with_aliases(dialect, &aliases)?;
+                Ok(format!("{} AS ({sql})", q.name.deref()))
+            })
+            .collect::<prql_compiler::Result<Vec<_>>>()?
+            .join(",\n");
+        Ok(match sql.strip_prefix("WITH ") {
+            Some(rest) => format!("WITH {ctes},\n{rest}"),
+            None => format!("WITH {ctes}\n{sql}"),
+        })
+    }
+
+    /// Columns this query reads from each upstream relation, by name.
+    pub fn referenced_columns(&self) -> Vec<(QueryName, String)> {
+        self.resolved_query
+            .tables
+            .iter()
+            .filter_map(|t| match (&t.name, &t.relation) {
+                (Some(name), Relation::ExternRef(_, decls)) => Some((name, decls)),
+                _ => None,
+            })
+            .flat_map(|(name, decls)| {
+                decls.iter().filter_map(move |d| match &d.kind {
+                    ColumnDeclKind::ExternRef(col) => {
+                        Some((QueryName(name.as_str().into()), col.as_str().into()))
+                    }
+                    _ => None,
+                })
+            })
+            .collect()
+    }
+
+    /// Columns each `join` matches on that are read straight from an upstream relation, by
+    /// that relation's name, with the side of the join it's on. Keys computed within the
+    /// query are left out.
+    pub fn join_keys(&self) -> Vec<(QueryName, String, JoinKeySide)> {
+        let Relation::Pipeline(pipeline) = &self.resolved_query.relation else {
+            return Vec::new();
+        };
+        let mut joined: Vec<&TableRef> = Vec::new();
+        let mut keys = Vec::new();
+        for transform in pipeline {
+            match transform {
+                Transform::From(table) => joined.push(table),
+                Transform::Join { with, filter, .. } => {
+                    let mut refs = Vec::new();
+                    column_refs(filter, &mut refs);
+                    for cid in refs {
+                        let left = joined.iter().map(|t| (*t, JoinKeySide::Left));
+                        let found =
+                            left.chain([(with, JoinKeySide::Right)])
+                                .find_map(|(table, side)| {
+                                    Some((self.extern_column(table, cid)?, side))
+                                });
+                        if let Some(((upstream, column), side)) = found {
+                            keys.push((upstream, column, side));
+                        }
+                    }
+                    joined.push(with);
+                }
+                _ => {}
+            }
+        }
+        keys
+    }
+
+    // The upstream relation and column `cid` reads, if it's one of `table`'s columns taken
+    // unchanged from an external relation.
+    fn extern_column(&self, table: &TableRef, cid: CId) -> Option<(QueryName, String)> {
+        let decl = table.columns.iter().find(|decl| decl.id == cid)?;
+        let ColumnDeclKind::Expr { expr, .. } = &decl.kind else {
+            return None;
+        };
+        let ExprKind::ColumnRef(extern_cid) = &expr.kind else {
+            return None;
+        };
+        let source = self
+            .resolved_query
+            .tables
+            .iter()
+            .find(|t| t.id == table.source)?;
+        let Relation::ExternRef(_, decls) = &source.relation else {
+            return None;
+        };
+        let column = decls.iter().find_map(|d| match &d.kind {
+            ColumnDeclKind::ExternRef(name) if d.id == *extern_cid => Some(name),
+            _ => None,
+        })?;
+        Some((
+            QueryName(source.name.as_deref()?.into()),
+            column.as_str().into(),
+        ))
+    }
+
+    /// Names of the columns this query produces. `None` when they can't be determined
+    /// statically, e.g. the query never narrows its input with a `select`.
+    pub fn output_columns(&self) -> Option<Vec<String>> {
+        let Relation::Pipeline(pipeline) = &self.resolved_query.relation else {
+            return None;
+        };
+        let mut names = FnvHashMap::default();
+        let mut selected = None;
+        for transform in pipeline {
+            let decls = match transform {
+                Transform::From(table) | Transform::Join { with: table, .. } => {
+                    table.columns.iter().collect()
+                }
+                Transform::Compute(decl) => vec![decl],
+                Transform::Select(cids) => {
+                    selected = Some(cids);
+                    continue;
+                }
+                _ => continue,
+            };
+            for decl in decls {
+                if let Some(name) = decl.get_name() {
+                    names.insert(decl.id, String::from(name.as_str()));
+                }
+            }
+        }
+        selected?
+            .iter()
+            .map(|cid| names.get(cid).cloned())
+            .collect()
+    }
+}
+
+// Every column `expr` refers to, in order.
+fn column_refs(expr: &Expr, refs: &mut Vec<CId>) {
+    match &expr.kind {
+        ExprKind::ColumnRef(cid) => refs.push(*cid),
+        ExprKind::Literal(_) => {}
+        ExprKind::Range(range) => {
+            for bound in [&range.start, &range.end].into_iter().flatten() {
+                column_refs(bound, refs);
+            }
+        }
+        ExprKind::Binary { left, right, .. } => {
+            column_refs(left, refs);
+            column_refs(right, refs);
+        }
+        ExprKind::Unary { expr, .. } => column_refs(expr, refs),
+        ExprKind::SString(items) | ExprKind::FString(items) => {
+            for item in items {
+                if let InterpolateItem::Expr(expr) = item {
+                    column_refs(expr, refs);
+                }
+            }
         }
     }
 }
@@ -136,6 +855,10 @@ impl<T: Eq + Hash + Copy + Default + Ord> ResourceIdMap<T> {
         self.reverse.insert(resource_id, resource_name);
     }
 
+    pub fn get_resource_name(&self, resource_id: &T) -> Option<&QueryName> {
+        self.reverse.get(resource_id)
+    }
+
     // pub fn remove_query(&mut self, resource_name: &impl AsRef<str>, resource_id: impl AsRef<T>) {
     //     self.inner.remove(resource_name.as_ref());
     //     self.reverse.remove(resource_id.as_ref());
@@ -161,6 +884,12 @@ impl<T: Eq + Hash + Copy + Default + Ord> ResourceIdMap<T> {
     // }
 }
 
+impl<T: Eq + Hash + Copy + Default + Ord> Default for ResourceIdMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // impl ResourceIdMap<QueryId> {}
 
 impl QueryCollection {
@@ -168,20 +897,162 @@ impl QueryCollection {
         Self {
             query_map: QueryMap::default(),
             query_id_map: ResourceIdMap::new(),
+            prepared_hashes: QueryMap::default(),
+            manual_dependencies: QueryMap::default(),
+            duplicate_ids: Vec::new(),
+            #[cfg(test)]
+            prepare_calls: std::cell::Cell::new(0),
         }
     }
 
+    /// Adds `queries`, leaving out any whose id another name already has, in the collection
+    /// or earlier in `queries`; see [`QueryCollection::duplicate_ids`].
     pub fn add_queries(&mut self, queries: Vec<RawQuery>) {
-        let parsed_queries: Vec<_> = queries
+        let queries = self.reject_duplicate_ids(queries);
+        let parsed_queries = self.parse_new(queries);
+        self.insert_parsed(parsed_queries);
+    }
+
+    /// Every query left out so far for having the id of another, in the order added.
+    pub fn duplicate_ids(&self) -> &[DuplicateId] {
+        &self.duplicate_ids
+    }
+
+    fn reject_duplicate_ids(&mut self, queries: Vec<RawQuery>) -> Vec<RawQuery> {
+        let mut taken: FnvHashMap<QueryId, QueryName> = FnvHashMap::default();
+        queries
+            .into_iter()
+            .filter(|q| {
+                let id = q.id();
+                let name = QueryName(q.name.clone());
+                let first = match self.query_id_map.get_resource_name(&id) {
+                    Some(first) if *first != name => first.clone(),
+                    _ => match taken.get(&id) {
+                        Some(first) if *first != name => first.clone(),
+                        _ => {
+                            taken.insert(id, name);
+                            return true;
+                        }
+                    },
+                };
+                self.duplicate_ids.push(DuplicateId {
+                    id,
+                    first,
+                    second: name,
+                });
+                false
+            })
+            .collect()
+    }
+
+    /// Like [`QueryCollection::add_queries`], but a query reading a table that is neither a
+    /// model nor one of `declared` sources and seeds fails instead of creating a placeholder
+    /// for it. On failure nothing is added; every offending reference is returned, sorted.
+    pub fn add_queries_strict(
+        &mut self,
+        queries: Vec<RawQuery>,
+        declared: &[&str],
+    ) -> Result<(), Vec<UndeclaredReference>> {
+        let queries = self.reject_duplicate_ids(queries);
+        let parsed_queries = self.parse_new(queries);
+        let models: HashSet<_> = parsed_queries.iter().map(|(q, _)| &q.name).collect();
+        let mut undeclared: Vec<_> = parsed_queries
             .iter()
-            .filter_map(|q| self.prepare_query(&q.query_string, &q.name).ok())
+            .flat_map(|(q, _)| q.dependencies.iter().map(move |dep| (q, dep)))
+            .filter(|(_, dep)| {
+                !models.contains(dep)
+                    && !matches!(self.query_map.get(*dep), Some(QueryKind::Query(_)))
+                    && !declared.contains(&dep.as_str())
+            })
+            .map(|(q, dep)| UndeclaredReference {
+                query: q.name.clone(),
+                table: dep.clone(),
+            })
             .collect();
+        if !undeclared.is_empty() {
+            undeclared.sort_by(|a, b| (&a.query, &a.table).cmp(&(&b.query, &b.table)));
+            return Err(undeclared);
+        }
+        self.insert_parsed(parsed_queries);
+        Ok(())
+    }
+
+    // Parses every query whose text isn't already cached, keeping the hash it was cached under.
+    fn parse_new(&self, queries: Vec<RawQuery>) -> Vec<(Query, u64)> {
+        queries
+            .iter()
+            .map(|q| {
+                (
+                    q,
+                    xxh3_64(normalize_line_endings(&q.query_string).as_bytes()),
+                )
+            })
+            .filter(|(q, raw_hash)| {
+                let name = QueryName(q.name.clone());
+                self.prepared_hashes.get(&name) != Some(raw_hash)
+                    || self.query_map.get(&name).map(|kind| *kind.id()) != Some(q.id())
+            })
+            .filter_map(|(q, raw_hash)| {
+                self.prepare_query(&q.query_string, &q.name)
+                    .ok()
+                    .map(|mut parsed| {
+                        parsed.id = q.id();
+                        parsed.source_path = q.source_path.clone();
+                        (parsed, raw_hash)
+                    })
+            })
+            .collect()
+    }
+
+    fn insert_parsed(&mut self, parsed_queries: Vec<(Query, u64)>) {
         // 1st Iteration to build query-name -> query, query_name <--> query_id lookups
-        for q in parsed_queries {
-            self.query_id_map
-                .insert_resource(q.name.clone(), q.id.clone());
+        for (q, raw_hash) in parsed_queries {
+            self.manual_dependencies.remove(&q.name);
+            self.prepared_hashes.insert(q.name.clone(), raw_hash);
+            self.query_id_map.insert_resource(q.name.clone(), q.id);
             self.query_map.insert(q.name.clone(), QueryKind::Query(q));
         }
+        self.add_missing_tables();
+    }
+
+    /// Declares dependencies of `query` that PRQL parsing can't see, e.g. through macros or
+    /// dynamic references. They're unioned with the parsed ones; unknown names become tables.
+    pub fn add_manual_dependencies(
+        &mut self,
+        query: &QueryName,
+        dependencies: impl IntoIterator<Item = QueryName>,
+    ) {
+        let Some(QueryKind::Query(q)) = self.query_map.get_mut(query) else {
+            return;
+        };
+        let manual = self.manual_dependencies.entry(query.clone()).or_default();
+        for dependency in dependencies {
+            if !q.dependencies.contains(&dependency) {
+                q.dependencies.push(dependency.clone());
+                manual.push(dependency);
+            }
+        }
+        self.add_missing_tables();
+    }
+
+    /// Adds a table for each of `names` not already in the collection, e.g. seeds loaded
+    /// from files whether or not a model reads them yet. A model of the same name wins.
+    pub fn add_tables(&mut self, names: impl IntoIterator<Item = QueryName>) {
+        for name in names {
+            if self.query_map.contains_key(&name) {
+                continue;
+            }
+            let id = QueryId(xxh3_64(name.deref().as_bytes()));
+            let tbl = TableQuery {
+                name: name.clone(),
+                id,
+            };
+            self.query_id_map.insert_resource(name.clone(), id);
+            self.query_map.insert(name, QueryKind::TableQuery(tbl));
+        }
+    }
+
+    fn add_missing_tables(&mut self) {
         let table_names: HashSet<_> = self
             .query_map
             .values()
@@ -196,28 +1067,38 @@ impl QueryCollection {
             })
             .map(|x| x.to_owned())
             .collect();
-        table_names.into_iter().for_each(|t| {
-            // let name = t.deref();
-            let id = QueryId(xxh3_64(t.deref().as_bytes()));
-            let tbl = TableQuery {
-                name: t.clone(),
-                id: id.clone(),
-            };
-            self.query_id_map.insert_resource(t.clone(), id.clone());
-            self.query_map.insert(t, QueryKind::TableQuery(tbl));
-        });
+        self.add_tables(table_names);
     }
 
     /*
     raw query info --> collection of raw queries --> collection of parsed + identified queries
     ---> collection of queries + dag built
     */
+    /// Parses and resolves `raw_query`. Line endings are normalized to `\n` first, so a file
+    /// checked out with CRLF endings fingerprints and reports locations the same as with LF.
     pub fn prepare_query(
         &self,
         raw_query: &str,
         query_name: impl AsRef<str>,
-    ) -> Result<Query, Box<dyn std::error::Error>> {
-        let parsed_query = resolve(parse(raw_query)?)?;
+    ) -> Result<Query, PrepareError> {
+        let raw_query = &*normalize_line_endings(raw_query);
+        #[cfg(test)]
+        self.prepare_calls.set(self.prepare_calls.get() + 1);
+        let name = query_name.as_ref();
+        let statements =
+            parse(raw_query).map_err(|e| PrepareError::from_prql(e, name, raw_query))?;
+        let query_def = statements.iter().find_map(|stmt| stmt.kind.as_query_def());
+        if let Some(required) = query_def.and_then(|def| def.version.as_ref()) {
+            if !required.matches(&Version::parse(PRQL_VERSION).expect("PRQL_VERSION is valid")) {
+                return Err(PrepareError::UnsupportedPrqlVersion {
+                    query: name.to_string(),
+                    required: required.to_string(),
+                });
+            }
+        }
+        let pinned_dialect = query_def.map(|def| def.dialect.clone());
+        let parsed_query =
+            resolve(statements).map_err(|e| PrepareError::from_prql(e, name, raw_query))?;
         let dependent_table_names = extract_dependent_tables(&parsed_query);
         let query_id = QueryId(xxh3_64(query_name.as_ref().as_bytes()));
         let query = Query::new(
@@ -225,10 +1106,147 @@ impl QueryCollection {
             query_name.as_ref(),
             parsed_query,
             dependent_table_names,
+            pinned_dialect,
+            xxh3_64(raw_query.as_bytes()),
         );
         Ok(query)
     }
 
+    /// Check every column a query reads from an upstream query against the columns that
+    /// upstream produces. A missing column a `join` matches on is reported as a
+    /// [`ColumnError::MissingJoinKey`]. Best-effort: upstreams whose output can't be
+    /// determined, and external tables, are skipped.
+    pub fn validate_columns(&self) -> Vec<ColumnError> {
+        let queries = self.sorted_values().into_iter().filter_map(|q| match q {
+            QueryKind::Query(q) => Some(q),
+            QueryKind::TableQuery(_) => None,
+        });
+        let mut errors = Vec::new();
+        for query in queries {
+            let join_keys = query.join_keys();
+            for (upstream, column) in query.referenced_columns() {
+                let Some(QueryKind::Query(upstream_query)) = self.query_map.get(&upstream) else {
+                    continue;
+                };
+                let Some(produced) = upstream_query.output_columns() else {
+                    continue;
+                };
+                if produced.contains(&column) {
+                    continue;
+                }
+                let side = join_keys
+                    .iter()
+                    .find(|(table, key, _)| *table == upstream && *key == column)
+                    .map(|(_, _, side)| *side);
+                errors.push(match side {
+                    Some(side) => ColumnError::MissingJoinKey {
+                        query: query.name.clone(),
+                        upstream,
+                        column,
+                        side,
+                    },
+                    None => ColumnError::MissingColumn {
+                        query: query.name.clone(),
+                        upstream,
+                        column,
+                    },
+                });
+            }
+        }
+        errors
+    }
+
+    /// The SQL `model` would run, with `ephemeral` upstreams inlined and the rest read from
+    /// their relation in `aliases`, for reading or pasting into a console.
+    pub fn show(
+        &self,
+        model: &QueryName,
+        ephemeral: &HashSet<QueryName>,
+        aliases: &QueryMap<QueryName, String>,
+        default_dialect: &Dialect,
+    ) -> Result<std::string::String, ShowError> {
+        let Some(QueryKind::Query(query)) = self.query_map.get(model) else {
+            return Err(ShowError::UnknownModel(model.clone()));
+        };
+        query
+            .compile_inlining(self, ephemeral, aliases, default_dialect)
+            .map_err(|e| ShowError::Compile(e.to_string()))
+    }
+
+    /// How `table`, as read by `query`, was resolved. Models take precedence over declared
+    /// sources and seeds of the same name, as they do when the graph is built.
+    pub fn trace_ref(
+        &self,
+        query: &QueryName,
+        table: &QueryName,
+        sources: &[&str],
+        seeds: &[&str],
+    ) -> Result<RefResolution, TraceError> {
+        let Some(QueryKind::Query(q)) = self.query_map.get(query) else {
+            return Err(TraceError::UnknownQuery(query.clone()));
+        };
+        if !q.dependencies.contains(table) {
+            return Err(TraceError::NotReferenced {
+                query: query.clone(),
+                table: table.clone(),
+            });
+        }
+        Ok(match self.query_map.get(table) {
+            Some(QueryKind::Query(_)) => RefResolution::Model,
+            _ if sources.contains(&table.as_str()) => RefResolution::Source,
+            _ if seeds.contains(&table.as_str()) => RefResolution::Seed,
+            _ => RefResolution::Placeholder,
+        })
+    }
+
+    /// A summary that doesn't need a graph, so it's available even when building one fails,
+    /// e.g. because of a cycle.
+    pub fn stats(&self) -> CollectionStats {
+        let mut stats = CollectionStats::default();
+        let mut dependencies = HashSet::new();
+        for node in self.sorted_values() {
+            match node {
+                QueryKind::TableQuery(_) => stats.tables += 1,
+                QueryKind::Query(q) => {
+                    stats.queries += 1;
+                    dependencies.extend(&q.dependencies);
+                    let count = q.dependencies.len();
+                    if stats
+                        .most_dependencies
+                        .as_ref()
+                        .is_none_or(|(_, most)| count > *most)
+                    {
+                        stats.most_dependencies = Some((q.name.clone(), count));
+                    }
+                }
+            }
+        }
+        stats.distinct_dependencies = dependencies.len();
+        stats
+    }
+
+    /// Every node, sorted by name. The underlying map iterates in an order that depends on
+    /// insertion history, so anything serialized or order-sensitive should go through this.
+    pub fn sorted_values(&self) -> Vec<&QueryKind> {
+        let mut nodes: Vec<_> = self.query_map.values().collect();
+        nodes.sort_by(|a, b| a.name().cmp(b.name()));
+        nodes
+    }
+
+    /// The dependencies of `query` that came from
+    /// [`QueryCollection::add_manual_dependencies`] rather than from parsing it.
+    pub fn manual_dependencies(&self, query: &QueryName) -> &[QueryName] {
+        self.manual_dependencies
+            .get(query)
+            .map_or(&[], Vec::as_slice)
+    }
+
+    pub fn get_by_id(&self, id: &QueryId) -> Option<&QueryKind> {
+        self.query_id_map
+            .get_resource_name(id)
+            .and_then(|name| self.query_map.get(name))
+    }
+
     pub fn get_query_depedencies(&self, q: &QueryName) -> Vec<QueryId> {
         if let Some(e) = self.query_map.get(q) {
             e.dependencies()
@@ -242,6 +1260,12 @@ impl QueryCollection {
     }
 }
 
+impl Default for QueryCollection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Deref for QueryCollection {
     type Target = QueryMap<QueryName, QueryKind>;
 
@@ -250,15 +1274,45 @@ impl Deref for QueryCollection {
     }
 }
 
+// Post-order walk, so an ephemeral always lands after the ephemerals it reads from.
+fn collect_ephemerals<'a>(
+    collection: &'a QueryCollection,
+    ephemeral: &HashSet<QueryName>,
+    name: &QueryName,
+    seen: &mut HashSet<QueryName>,
+    inlined: &mut Vec<&'a Query>,
+) {
+    if !ephemeral.contains(name) || !seen.insert(name.clone()) {
+        return;
+    }
+    if let Some(QueryKind::Query(q)) = collection.get(name) {
+        for dependency in &q.dependencies {
+            collect_ephemerals(collection, ephemeral, dependency, seen, inlined);
+        }
+        inlined.push(q);
+    }
+}
+
+// Keyed on the referenced relation rather than the declaration's name, so join aliases and
+// tables defined inside the query itself never show up as dependencies.
 fn extract_dependent_tables(query: &prql_compiler::ast::rq::Query) -> Vec<QueryName> {
     query
         .tables
         .iter()
-        .filter_map(|t| t.name.clone())
-        .map(|x| QueryName(x.into()))
+        .filter_map(|t| match &t.relation {
+            Relation::ExternRef(TableExternRef::LocalTable(name), _) => Some(name),
+            _ => None,
+        })
+        .map(|x| QueryName(x.as_str().into()))
         .collect()
 }
 
+impl From<u64> for QueryId {
+    fn from(value: u64) -> Self {
+        QueryId(value)
+    }
+}
+
 impl From<&str> for QueryName {
     fn from(value: &str) -> Self {
         QueryName(value.into())
@@ -351,18 +1405,44 @@ mod test_super {
         println!("{:?}", translate(resolved));
     }
 
+    #[test]
+    fn test_unsupported_prql_version_is_reported() {
+        let collection = QueryCollection::new();
+        let err = collection
+            .prepare_query("prql version:\"99\"\nfrom employees", "future")
+            .unwrap_err();
+        assert!(
+            matches!(&err, PrepareError::UnsupportedPrqlVersion { query, required }
+                if query == "future" && required == "^99"),
+            "{err}"
+        );
+        assert!(collection
+            .prepare_query("prql version:\"0.3\"\nfrom employees", "current")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_prepare_error_carries_source_location() {
+        let prql = "from employees\nfilter age > 35\nselect [name,, age]";
+        let collection = QueryCollection::new();
+        let err = collection.prepare_query(prql, "bad_query").unwrap_err();
+        let location = err.location().expect("error should carry a location");
+        assert_eq!(location.start.0, 2);
+        assert!(err.to_string().contains("bad_query"));
+    }
+
     #[test]
     fn test_can_add_queries() {
-        let queries = vec![RawQuery{name:"q1".into(), query_string: "from arcana | filter source != 'necronomicron'".into()},
-        RawQuery{name:"q2".into(), query_string: "from rituals | join side:inner q1 [==source]".into(),},
-        RawQuery{name:"q3".into(), query_string: "from rituals | derive [ritual_cost = component_count + price]  | sort ritual_cost".into(),}];
+        let queries = vec![RawQuery{name:"q1".into(), query_string: "from arcana | filter source != 'necronomicron'".into(), source_path: None, id_key: None,},
+        RawQuery{name:"q2".into(), query_string: "from rituals | join side:inner q1 [==source]".into(), source_path: None, id_key: None,},
+        RawQuery{name:"q3".into(), query_string: "from rituals | derive [ritual_cost = component_count + price]  | sort ritual_cost".into(), source_path: None, id_key: None,}];
         let mut collection = QueryCollection::new();
         collection.add_queries(queries);
         dbg!(&collection.query_id_map);
         assert_eq!(collection.query_map.len(), 5);
         assert_eq!(
             collection.query_id_map.inner.keys().collect::<HashSet<_>>(),
-            vec![
+            [
                 "q1".into(),
                 "arcana".into(),
                 "q3".into(),
@@ -376,9 +1456,9 @@ mod test_super {
 
     #[test]
     fn test_query_dependency_registers_properly() {
-        let queries = vec![RawQuery{name:"q1".into(), query_string: "from arcana | filter source != 'necronomicron'".into()},
-        RawQuery{name:"q2".into(), query_string: "from rituals | join side:inner q1 [==source]".into(),},
-        RawQuery{name:"q3".into(), query_string: "from rituals | derive [ritual_cost = component_count + price]  | sort ritual_cost".into(),}];
+        let queries = vec![RawQuery{name:"q1".into(), query_string: "from arcana | filter source != 'necronomicron'".into(), source_path: None, id_key: None,},
+        RawQuery{name:"q2".into(), query_string: "from rituals | join side:inner q1 [==source]".into(), source_path: None, id_key: None,},
+        RawQuery{name:"q3".into(), query_string: "from rituals | derive [ritual_cost = component_count + price]  | sort ritual_cost".into(), source_path: None, id_key: None,}];
         let mut collection = QueryCollection::new();
         collection.add_queries(queries);
         dbg!(&collection.query_id_map);
@@ -395,15 +1475,127 @@ mod test_super {
         assert_eq!(q2_dependencies, expected_deps);
     }
 
+    #[test]
+    fn test_vars_are_revalidated_after_substitution() {
+        let query = || RawQuery::new("q1", "from arcana | filter source != {{ banned_source }}");
+        let vars = |value: &str| {
+            HashMap::from([(
+                String::from("banned_source"),
+                VarValue::String(value.into()),
+            )])
+        };
+        let valid = query().with_vars(&vars("necronomicron")).unwrap();
+        assert_eq!(
+            valid.query_string(),
+            "from arcana | filter source != 'necronomicron'"
+        );
+        // Neither quote can hold this value, so the literal ends early.
+        assert!(matches!(
+            query().with_vars(&vars(r#"it's "cursed""#)),
+            Err(VarError::Invalid(e)) if e.contains("q1")
+        ));
+    }
+
+    #[test]
+    fn test_show_compiles_a_single_model() {
+        let vars = [(
+            String::from("banned_source"),
+            VarValue::String(String::from("necronomicron")),
+        )]
+        .into_iter()
+        .collect();
+        let queries = vec![
+            RawQuery::new("q1", "from arcana | filter source != {{ banned_source }}"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+        ];
+        let mut collection = QueryCollection::new();
+        collection.add_queries(
+            queries
+                .into_iter()
+                .map(|q| q.with_vars(&vars).unwrap())
+                .collect(),
+        );
+        let (ephemeral, aliases) = (HashSet::new(), QueryMap::default());
+        let sql = collection
+            .show(
+                &QueryName::from("q2"),
+                &ephemeral,
+                &aliases,
+                &Dialect::Generic,
+            )
+            .unwrap();
+        assert!(sql.contains("JOIN q1"), "{sql}");
+
+        let inlined = collection
+            .show(
+                &QueryName::from("q2"),
+                &HashSet::from([QueryName::from("q1")]),
+                &aliases,
+                &Dialect::Generic,
+            )
+            .unwrap();
+        assert!(inlined.contains("source <> 'necronomicron'"), "{inlined}");
+        assert!(matches!(
+            collection.show(
+                &QueryName::from("rituals"),
+                &ephemeral,
+                &aliases,
+                &Dialect::Generic
+            ),
+            Err(ShowError::UnknownModel(_))
+        ));
+    }
+
+    #[test]
+    fn test_trace_ref_distinguishes_models_from_placeholders() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("stg_orders", "from raw_orders"),
+            RawQuery::new(
+                "orders",
+                "from stg_orders | join side:left countries [==country_code] | join side:left ordrs [==id]",
+            ),
+        ]);
+        let trace = |table: &str| {
+            collection.trace_ref(
+                &QueryName::from("orders"),
+                &QueryName::from(table),
+                &["raw_orders"],
+                &["countries"],
+            )
+        };
+        assert_eq!(trace("stg_orders"), Ok(RefResolution::Model));
+        assert_eq!(trace("countries"), Ok(RefResolution::Seed));
+        assert_eq!(trace("ordrs"), Ok(RefResolution::Placeholder));
+        assert_eq!(
+            trace("raw_orders"),
+            Err(TraceError::NotReferenced {
+                query: QueryName::from("orders"),
+                table: QueryName::from("raw_orders"),
+            })
+        );
+        assert_eq!(
+            collection.trace_ref(
+                &QueryName::from("stg_orders"),
+                &QueryName::from("raw_orders"),
+                &["raw_orders"],
+                &[],
+            ),
+            Ok(RefResolution::Source)
+        );
+    }
+
     #[test]
     fn test_can_add_queries_incrementally() {
         let queries = vec![RawQuery {
             name: "q1".into(),
             query_string: "from arcana | filter source != 'necronomicron'".into(),
+            source_path: None,
+            id_key: None,
         }];
         let queries2 = vec![
-        RawQuery{name:"q2".into(), query_string: "from rituals | join side:inner q1 [==source]".into(),},
-        RawQuery{name:"q3".into(), query_string: "from rituals | derive [ritual_cost = component_count + price]  | sort ritual_cost".into(),}];
+        RawQuery{name:"q2".into(), query_string: "from rituals | join side:inner q1 [==source]".into(), source_path: None, id_key: None,},
+        RawQuery{name:"q3".into(), query_string: "from rituals | derive [ritual_cost = component_count + price]  | sort ritual_cost".into(), source_path: None, id_key: None,}];
         let mut collection = QueryCollection::new();
         collection.add_queries(queries);
         dbg!(&collection.query_id_map);
@@ -412,7 +1604,7 @@ mod test_super {
         assert_eq!(collection.query_map.len(), 5);
         assert_eq!(
             collection.query_id_map.inner.keys().collect::<HashSet<_>>(),
-            vec![
+            [
                 "q1".into(),
                 "arcana".into(),
                 "q3".into(),
@@ -423,4 +1615,483 @@ mod test_super {
             .collect::<HashSet<_>>()
         );
     }
+
+    #[test]
+    fn test_unchanged_queries_are_not_re_parsed() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+        ]);
+        assert_eq!(collection.prepare_calls.get(), 2);
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+        ]);
+        assert_eq!(collection.prepare_calls.get(), 2);
+        collection.add_queries(vec![RawQuery::new("q2", "from rituals")]);
+        assert_eq!(collection.prepare_calls.get(), 3);
+        assert_eq!(
+            collection.get_query_depedencies(&"q2".into()),
+            vec![QueryId(xxh3_64(b"rituals"))]
+        );
+        assert_eq!(collection.prepared_hashes.len(), 2);
+    }
+
+    #[test]
+    fn test_dialect_header_overrides_default() {
+        let collection = QueryCollection::new();
+        let pinned = collection
+            .prepare_query(
+                "prql dialect:clickhouse\nfrom employees | select [`first name`]",
+                "pinned",
+            )
+            .unwrap();
+        let unpinned = collection
+            .prepare_query("from employees | select [`first name`]", "unpinned")
+            .unwrap();
+        assert_eq!(pinned.pinned_dialect(), Some(&Dialect::ClickHouse));
+        assert_eq!(unpinned.pinned_dialect(), None);
+        assert!(pinned
+            .compile(&Dialect::PostgreSql)
+            .unwrap()
+            .contains("`first name`"));
+        assert!(unpinned
+            .compile(&Dialect::PostgreSql)
+            .unwrap()
+            .contains("\"first name\""));
+    }
+
+    #[test]
+    fn test_aliased_upstream_is_read_from_its_relation() {
+        let collection = QueryCollection::new();
+        let downstream = collection
+            .prepare_query("from stg_orders | select [id, amount]", "orders")
+            .unwrap();
+        let mut aliases = QueryMap::default();
+        aliases.insert(
+            QueryName::from("stg_orders"),
+            String::from("staging_orders"),
+        );
+
+        assert_eq!(downstream.dependencies, vec![QueryName::from("stg_orders")]);
+        let sql = downstream
+            .compile_with_aliases(&Dialect::PostgreSql, &aliases)
+            .unwrap();
+        assert!(sql.contains("staging_orders AS stg_orders"), "{sql}");
+        assert!(!downstream
+            .compile(&Dialect::PostgreSql)
+            .unwrap()
+            .contains("staging_orders"));
+    }
+
+    #[test]
+    fn test_enabled_annotation_yields_to_config() {
+        use figment::providers::{Format, Yaml};
+        let queries = || {
+            vec![
+                RawQuery::new("stg_orders", "from raw_orders"),
+                RawQuery::new(
+                    "orders_backfill",
+                    "# orbital: enabled = false\nfrom stg_orders | filter amount > 0",
+                ),
+            ]
+        };
+        let loaded = |config: Option<&ResourceConfig>| {
+            let mut collection = QueryCollection::new();
+            collection.add_queries(
+                queries()
+                    .into_iter()
+                    .filter(|q| q.is_enabled(config.filter(|c| c.name.as_ref() == q.name())))
+                    .collect(),
+            );
+            collection.contains_key(&QueryName::from("orders_backfill"))
+        };
+        let config: ResourceConfig = figment::Figment::from(Yaml::string(
+            "name: orders_backfill\nenabled: true\ndatabase: analytics\nschema: marts\nexclude_full_refresh: false",
+        ))
+        .extract()
+        .unwrap();
+
+        assert_eq!(queries()[1].annotation("enabled"), Some("false"));
+        assert_eq!(queries()[0].annotation("enabled"), None);
+        assert!(!loaded(None));
+        assert!(loaded(Some(&config)));
+    }
+
+    #[test]
+    fn test_line_endings_do_not_change_fingerprint() {
+        let collection = QueryCollection::new();
+        let lf = "from employees\nfilter age > 35\nselect name";
+        let crlf = lf.replace('\n', "\r\n");
+        let prepare = |text: &str| collection.prepare_query(text, "adults").unwrap();
+        assert_eq!(prepare(lf).fingerprint(), prepare(&crlf).fingerprint());
+        assert_eq!(
+            prepare(lf).compile(&Dialect::Generic).unwrap(),
+            prepare(&crlf).compile(&Dialect::Generic).unwrap()
+        );
+
+        let mut reloaded = QueryCollection::new();
+        reloaded.add_queries(vec![RawQuery::new("adults", lf)]);
+        reloaded.add_queries(vec![RawQuery::new("adults", crlf)]);
+        assert_eq!(reloaded.prepare_calls.get(), 1);
+    }
+
+    #[test]
+    fn test_strict_sources_rejects_undeclared_tables() {
+        let queries = || {
+            vec![
+                RawQuery::new("stg_orders", "from ordrs"),
+                RawQuery::new("orders", "from stg_orders | join side:left refunds [==id]"),
+            ]
+        };
+        let mut strict = QueryCollection::new();
+        assert_eq!(
+            strict.add_queries_strict(queries(), &["orders_raw", "refunds"]),
+            Err(vec![UndeclaredReference {
+                query: QueryName::from("stg_orders"),
+                table: QueryName::from("ordrs"),
+            }])
+        );
+        assert!(strict.get(&QueryName::from("orders")).is_none());
+        assert!(strict
+            .add_queries_strict(queries(), &["ordrs", "refunds"])
+            .is_ok());
+        assert!(matches!(
+            strict.get(&QueryName::from("ordrs")),
+            Some(QueryKind::TableQuery(_))
+        ));
+
+        let mut lenient = QueryCollection::new();
+        lenient.add_queries(queries());
+        assert!(matches!(
+            lenient.get(&QueryName::from("ordrs")),
+            Some(QueryKind::TableQuery(_))
+        ));
+    }
+
+    #[test]
+    fn test_lowercase_policy_applies_to_create_and_references() {
+        let collection = QueryCollection::new();
+        let downstream = collection
+            .prepare_query("from StgOrders | select [id, amount]", "orders")
+            .unwrap();
+        let case = IdentifierCase::Lower;
+        let target = case.apply("StgOrders");
+        let create = &crate::sql::IncrementalSql {
+            target: &target,
+            select: "SELECT 1 AS id",
+            columns: &[],
+            unique_key: &[],
+            filter: None,
+            watermark: None,
+        }
+        .statements(&Dialect::PostgreSql, false)[0];
+        let reference = downstream
+            .compile_with_aliases(&Dialect::PostgreSql, &downstream.case_aliases(case))
+            .unwrap();
+
+        assert_eq!(create, "CREATE TABLE stgorders AS SELECT 1 AS id");
+        // The upstream's name survives only as the alias columns are qualified with.
+        assert!(
+            reference.contains("stgorders AS \"StgOrders\""),
+            "{reference}"
+        );
+        assert_eq!(IdentifierCase::Preserve.apply("StgOrders"), "StgOrders");
+        assert_eq!(IdentifierCase::Upper.apply("StgOrders"), "STGORDERS");
+    }
+
+    #[test]
+    fn test_missing_upstream_column_is_reported() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("stg_orders", "from orders | select [id, amount]"),
+            RawQuery::new("orders_ok", "from stg_orders | select [id, amount]"),
+            RawQuery::new("orders_bad", "from stg_orders | select [id, order_id]"),
+        ]);
+
+        assert_eq!(
+            collection.validate_columns(),
+            vec![ColumnError::MissingColumn {
+                query: QueryName::from("orders_bad"),
+                upstream: QueryName::from("stg_orders"),
+                column: String::from("order_id"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_id_key_keeps_the_id_across_renames() {
+        let id_of = |queries: Vec<RawQuery>, name: &str| {
+            let mut collection = QueryCollection::new();
+            collection.add_queries(queries);
+            *collection.get(&QueryName::from(name)).unwrap().id()
+        };
+        let key = "0b7e4a52-orders";
+        let before = id_of(
+            vec![RawQuery::new("orders", "from raw_orders").with_id_key(key)],
+            "orders",
+        );
+        let renamed = id_of(
+            vec![RawQuery::new("fct_orders", "from raw_orders").with_id_key(key)],
+            "fct_orders",
+        );
+        let annotated = id_of(
+            vec![RawQuery::new(
+                "orders_v2",
+                format!("# orbital: id_key = {key}\nfrom raw_orders"),
+            )],
+            "orders_v2",
+        );
+        let unkeyed = id_of(vec![RawQuery::new("orders", "from raw_orders")], "orders");
+
+        assert_eq!(before, renamed);
+        assert_eq!(before, annotated);
+        assert_eq!(unkeyed, QueryId(xxh3_64(b"orders")));
+        assert_ne!(before, unkeyed);
+
+        // Adding a key to an already loaded query re-keys it even though its text is cached.
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![RawQuery::new("orders", "from raw_orders")]);
+        collection.add_queries(vec![
+            RawQuery::new("orders", "from raw_orders").with_id_key(key)
+        ]);
+        let orders = collection.get(&QueryName::from("orders")).unwrap();
+        assert_eq!(*orders.id(), before);
+    }
+
+    #[test]
+    fn test_queries_sharing_an_id_key_are_refused() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("orders", "from raw_orders").with_id_key("orders-key"),
+            RawQuery::new("fct_orders", "from raw_orders").with_id_key("orders-key"),
+            RawQuery::new("customers", "from raw_customers"),
+        ]);
+        // Reloading a query under its own id is fine; a later batch reusing it is not.
+        collection.add_queries(vec![
+            RawQuery::new("orders", "from raw_orders").with_id_key("orders-key"),
+            RawQuery::new("refunds", "# orbital: id_key = customers\nfrom raw_refunds"),
+        ]);
+
+        let id = |key: &str| QueryId(xxh3_64(key.as_bytes()));
+        assert_eq!(
+            collection.duplicate_ids(),
+            [
+                DuplicateId {
+                    id: id("orders-key"),
+                    first: QueryName::from("orders"),
+                    second: QueryName::from("fct_orders"),
+                },
+                DuplicateId {
+                    id: id("customers"),
+                    first: QueryName::from("customers"),
+                    second: QueryName::from("refunds"),
+                },
+            ]
+        );
+        assert!(collection.get(&QueryName::from("fct_orders")).is_none());
+        assert!(collection.get(&QueryName::from("refunds")).is_none());
+        assert_eq!(
+            collection.query_id_map.get_resource_name(&id("orders-key")),
+            Some(&QueryName::from("orders"))
+        );
+        assert_eq!(
+            collection.duplicate_ids()[0].to_string(),
+            "fct_orders has the same id as orders; give one of them a different id_key"
+        );
+    }
+
+    #[test]
+    fn test_extending_model_keeps_its_own_id() {
+        let extends: HashMap<_, _> = [(QueryName::from("eu_orders"), QueryName::from("orders"))]
+            .into_iter()
+            .collect();
+        let queries = resolve_extends(
+            vec![
+                RawQuery::new("orders", "# orbital: id_key = orders-key\nfrom raw_orders"),
+                RawQuery::new("eu_orders", "filter region == 'eu'"),
+            ],
+            &extends,
+        )
+        .unwrap();
+        let ids: Vec<_> = queries.iter().map(RawQuery::id).collect();
+        assert_eq!(
+            ids,
+            vec![
+                QueryId(xxh3_64(b"orders-key")),
+                QueryId(xxh3_64(b"eu_orders"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_join_on_missing_key_is_reported_with_its_side() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new(
+                "employees",
+                "from raw_employees | select [employee_id, name]",
+            ),
+            RawQuery::new(
+                "other_table",
+                "from raw_other | select [employee_id, title]",
+            ),
+            RawQuery::new("badges", "from raw_badges | select [badge_id, colour]"),
+            RawQuery::new(
+                "titled",
+                "from employees | join side:inner other_table [==employee_id]",
+            ),
+            RawQuery::new(
+                "badged",
+                "from employees | join side:left badges [==employee_id]",
+            ),
+        ]);
+        let Some(QueryKind::Query(titled)) = collection.get(&QueryName::from("titled")) else {
+            panic!("titled should be a query");
+        };
+        assert_eq!(
+            titled.join_keys(),
+            vec![
+                (
+                    QueryName::from("employees"),
+                    String::from("employee_id"),
+                    JoinKeySide::Left
+                ),
+                (
+                    QueryName::from("other_table"),
+                    String::from("employee_id"),
+                    JoinKeySide::Right
+                ),
+            ]
+        );
+
+        assert_eq!(
+            collection.validate_columns(),
+            vec![ColumnError::MissingJoinKey {
+                query: QueryName::from("badged"),
+                upstream: QueryName::from("badges"),
+                column: String::from("employee_id"),
+                side: JoinKeySide::Right,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_ephemeral_chain_is_inlined_as_ctes() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("stg_orders", "from orders | select [id, amount]"),
+            RawQuery::new("paid_orders", "from stg_orders | filter amount > 0"),
+            RawQuery::new("order_ids", "from paid_orders | select [id]"),
+        ]);
+        let ephemeral: HashSet<_> = ["stg_orders", "paid_orders"]
+            .into_iter()
+            .map(QueryName::from)
+            .collect();
+        let Some(QueryKind::Query(order_ids)) = collection.get(&QueryName::from("order_ids"))
+        else {
+            panic!("order_ids was not prepared");
+        };
+
+        let sql = order_ids
+            .compile_inlining(
+                &collection,
+                &ephemeral,
+                &QueryMap::default(),
+                &Dialect::PostgreSql,
+            )
+            .unwrap();
+        assert!(sql.starts_with("WITH stg_orders AS ("), "{sql}");
+        let stg = sql.find("stg_orders AS (").unwrap();
+        let paid = sql.find("paid_orders AS (").unwrap();
+        assert!(stg < paid, "{sql}");
+        assert_eq!(sql.matches("FROM\n  orders").count(), 1, "{sql}");
+        assert!(!sql.contains(';'));
+    }
+
+    #[test]
+    fn test_stats_summarize_the_collection() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+        ]);
+        assert_eq!(
+            collection.stats(),
+            CollectionStats {
+                queries: 3,
+                tables: 2,
+                distinct_dependencies: 4,
+                most_dependencies: Some((QueryName::from("q2"), 2)),
+            }
+        );
+        assert_eq!(QueryCollection::new().stats(), CollectionStats::default());
+    }
+
+    #[test]
+    fn test_manual_dependencies_are_unioned() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("load_orders", "from raw_orders"),
+            RawQuery::new("report", "from orders | select [id]"),
+        ]);
+        collection.add_manual_dependencies(
+            &QueryName::from("report"),
+            [QueryName::from("load_orders"), QueryName::from("orders")],
+        );
+        assert_eq!(
+            collection
+                .get(&QueryName::from("report"))
+                .unwrap()
+                .dependencies(),
+            vec![QueryName::from("orders"), QueryName::from("load_orders")]
+        );
+    }
+
+    #[test]
+    fn test_aliased_join_depends_on_real_tables() {
+        let collection = QueryCollection::new();
+        let query = collection
+            .prepare_query(
+                "from e=employees | join side:inner d=departments [e.dept_id == d.id]",
+                "staff",
+            )
+            .unwrap();
+        let mut dependencies = query.dependencies.clone();
+        dependencies.sort();
+        assert_eq!(
+            dependencies,
+            vec![QueryName::from("departments"), QueryName::from("employees")]
+        );
+    }
+
+    #[test]
+    fn test_query_local_tables_are_not_dependencies() {
+        let collection = QueryCollection::new();
+        let query = collection
+            .prepare_query(
+                "table recent = (from employees | filter age < 30)\nfrom recent | select [name]",
+                "young_staff",
+            )
+            .unwrap();
+        assert_eq!(query.dependencies, vec![QueryName::from("employees")]);
+    }
+
+    #[test]
+    fn test_compiled_sql_header_names_the_model() {
+        let collection = QueryCollection::new();
+        let query = collection
+            .prepare_query("from employees | select [name]", "staff")
+            .unwrap();
+        let generated_at = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            query.sql_header(generated_at),
+            format!(
+                "-- orbital: model=staff id={} generated_at=1700000000",
+                xxh3_64(b"staff")
+            )
+        );
+    }
 }
@@ -0,0 +1,158 @@
+use super::query::QueryName;
+use std::{fmt, path::PathBuf, str::FromStr};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SelectionMethod {
+    /// A single node, by name.
+    Name(QueryName),
+    /// Every query loaded from a file under this directory.
+    Path(PathBuf),
+    /// Every model configured with this tag.
+    Tag(String),
+}
+
+/// A `--select` argument: `orders`, `path:models/staging`, `tag:nightly`, optionally prefixed with `+` to
+/// add everything upstream and/or suffixed with `+` to add everything downstream.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Selector {
+    pub method: SelectionMethod,
+    pub upstream: bool,
+    pub downstream: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SelectorError {
+    /// Nothing left to select once the `+` markers and method prefix were stripped.
+    Empty(String),
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectorError::Empty(raw) => write!(f, "selector {raw:?} does not name anything"),
+        }
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+impl FromStr for Selector {
+    type Err = SelectorError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (upstream, rest) = match raw.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, raw),
+        };
+        let (downstream, rest) = match rest.strip_suffix('+') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let method = if let Some(path) = rest.strip_prefix("path:") {
+            (!path.is_empty()).then(|| SelectionMethod::Path(PathBuf::from(path)))
+        } else if let Some(tag) = rest.strip_prefix("tag:") {
+            (!tag.is_empty()).then(|| SelectionMethod::Tag(tag.to_string()))
+        } else {
+            (!rest.is_empty()).then(|| SelectionMethod::Name(QueryName::from(rest)))
+        };
+        let Some(method) = method else {
+            return Err(SelectorError::Empty(raw.to_string()));
+        };
+        Ok(Self {
+            method,
+            upstream,
+            downstream,
+        })
+    }
+}
+
+/// A whole `--select` value: space-separated alternatives, each a comma-separated list of
+/// selectors that must all match. `tag:nightly,+final orders` picks nightly models that are
+/// also `final` or upstream of it, plus `orders`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SelectorSet {
+    pub unions: Vec<Vec<Selector>>,
+}
+
+impl FromStr for SelectorSet {
+    type Err = SelectorError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let unions = raw
+            .split_whitespace()
+            .map(|group| group.split(',').map(str::parse).collect())
+            .collect::<Result<Vec<Vec<_>>, _>>()?;
+        if unions.is_empty() {
+            return Err(SelectorError::Empty(raw.to_string()));
+        }
+        Ok(Self { unions })
+    }
+}
+
+/// What `orbital ls` prints: one name per line, then how many were selected, so an empty
+/// selection says so instead of printing nothing.
+pub fn render_selection(names: &[&QueryName]) -> String {
+    let mut out: String = names
+        .iter()
+        .map(|name| format!("{}\n", name.as_str()))
+        .collect();
+    let noun = if names.len() == 1 { "model" } else { "models" };
+    out.push_str(&format!("{} {noun} selected\n", names.len()));
+    out
+}
+
+#[cfg(test)]
+mod test_selector {
+    use super::*;
+
+    #[test]
+    fn test_parse_selectors() {
+        assert_eq!(
+            "path:models/staging+".parse(),
+            Ok(Selector {
+                method: SelectionMethod::Path(PathBuf::from("models/staging")),
+                upstream: false,
+                downstream: true,
+            })
+        );
+        assert_eq!(
+            "+orders".parse(),
+            Ok(Selector {
+                method: SelectionMethod::Name(QueryName::from("orders")),
+                upstream: true,
+                downstream: false,
+            })
+        );
+        assert_eq!(
+            "path:".parse::<Selector>(),
+            Err(SelectorError::Empty(String::from("path:")))
+        );
+        assert_eq!(
+            "tag:nightly+".parse(),
+            Ok(Selector {
+                method: SelectionMethod::Tag(String::from("nightly")),
+                upstream: false,
+                downstream: true,
+            })
+        );
+        assert!("tag:".parse::<Selector>().is_err());
+        assert!("+".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn test_parse_selector_sets() {
+        let set: SelectorSet = "tag:nightly,+final orders".parse().unwrap();
+        assert_eq!(
+            set.unions,
+            vec![
+                vec!["tag:nightly".parse().unwrap(), "+final".parse().unwrap()],
+                vec!["orders".parse().unwrap()],
+            ]
+        );
+        assert_eq!(
+            "  ".parse::<SelectorSet>(),
+            Err(SelectorError::Empty(String::from("  ")))
+        );
+        assert!("orders,".parse::<SelectorSet>().is_err());
+    }
+}
@@ -0,0 +1,448 @@
+use super::query::RawQuery;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum SourceError {
+    Io(io::Error),
+    /// The request failed, or the server answered with an error status.
+    Http(String),
+    /// The response wasn't a JSON array of `{name, query_string}` objects.
+    Decode(String),
+    /// Two files, possibly under different roots, would define the same query.
+    DuplicateName {
+        name: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    /// A line in a model root's ignore file isn't a valid pattern.
+    Ignore {
+        path: PathBuf,
+        error: ignore::Error,
+    },
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Io(e) => write!(f, "could not read queries: {e}"),
+            SourceError::Http(e) => write!(f, "could not fetch queries: {e}"),
+            SourceError::Decode(e) => write!(f, "could not decode fetched queries: {e}"),
+            SourceError::DuplicateName {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "query {name} is defined by both {} and {}",
+                first.display(),
+                second.display()
+            ),
+            SourceError::Ignore { path, error } => write!(f, "{}: {error}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<io::Error> for SourceError {
+    fn from(value: io::Error) -> Self {
+        SourceError::Io(value)
+    }
+}
+
+/// A model file that was found but couldn't be read, and why.
+pub type UnreadableFile = (PathBuf, io::Error);
+
+/// Somewhere PRQL models can be loaded from, to populate a
+/// [`QueryCollection`](super::query::QueryCollection).
+pub trait QuerySource {
+    /// Every query the source holds, sorted by name.
+    fn fetch(&self) -> Result<Vec<RawQuery>, SourceError>;
+}
+
+/// The file each model or seed root may hold to keep files out of the project, e.g. drafts.
+pub const IGNORE_FILE_NAME: &str = ".orbitalignore";
+
+/// Patterns from an [`IGNORE_FILE_NAME`] file, in gitignore syntax and matched as git
+/// does, relative to the model root the file is in.
+#[derive(Debug)]
+pub struct IgnoreRules {
+    gitignore: Gitignore,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        Self {
+            gitignore: Gitignore::empty(),
+        }
+    }
+}
+
+impl IgnoreRules {
+    /// Fails on the first line that isn't a valid pattern, such as a backwards range `[9-0]`.
+    pub fn parse(text: &str) -> Result<Self, ignore::Error> {
+        let mut builder = GitignoreBuilder::new("");
+        for line in text.lines() {
+            builder.add_line(None, line)?;
+        }
+        Ok(Self {
+            gitignore: builder.build()?,
+        })
+    }
+
+    /// The rules in `root`'s ignore file, or none if it doesn't have one.
+    pub fn load(root: &Path) -> Result<Self, SourceError> {
+        match fs::read_to_string(root.join(IGNORE_FILE_NAME)) {
+            Ok(text) => Self::parse(&text).map_err(|error| SourceError::Ignore {
+                path: root.join(IGNORE_FILE_NAME),
+                error,
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Whether `relative`, a path under the root the rules were loaded for, is ignored.
+    pub fn is_ignored(&self, relative: &Path, is_dir: bool) -> bool {
+        self.gitignore.matched(relative, is_dir).is_ignore()
+    }
+}
+
+/// Every `.prql` file under one or more directories, recursively, named after its file stem.
+/// Files and directories matched by a root's [`IgnoreRules`] are skipped.
+pub struct FilesystemSource {
+    roots: Vec<PathBuf>,
+}
+
+impl FilesystemSource {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            roots: vec![root.into()],
+        }
+    }
+
+    pub fn from_roots(roots: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        Self {
+            roots: roots.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Like [`QuerySource::fetch`], but a model file that can't be read (bad permissions,
+    /// invalid UTF-8) is set aside with its error instead of failing the whole load, so the
+    /// readable models can still be built. Unreadable directories and duplicate names still
+    /// fail.
+    pub fn fetch_readable(&self) -> Result<(Vec<RawQuery>, Vec<UnreadableFile>), SourceError> {
+        let mut queries = Vec::new();
+        let mut unreadable = Vec::new();
+        for root in &self.roots {
+            let ignore = IgnoreRules::load(root)?;
+            Self::collect(root, root, &ignore, &mut queries, &mut unreadable)?;
+        }
+        queries.sort_by(|a, b| a.name().cmp(b.name()));
+        if let Some(pair) = queries
+            .windows(2)
+            .find(|pair| pair[0].name() == pair[1].name())
+        {
+            let path_of = |q: &RawQuery| q.source_path().map(Path::to_path_buf).unwrap_or_default();
+            return Err(SourceError::DuplicateName {
+                name: pair[0].name().to_string(),
+                first: path_of(&pair[0]),
+                second: path_of(&pair[1]),
+            });
+        }
+        unreadable.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok((queries, unreadable))
+    }
+
+    fn collect(
+        root: &Path,
+        dir: &Path,
+        ignore: &IgnoreRules,
+        queries: &mut Vec<RawQuery>,
+        unreadable: &mut Vec<UnreadableFile>,
+    ) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_dir = path.is_dir();
+            if ignore.is_ignored(path.strip_prefix(root).unwrap_or(&path), is_dir) {
+                continue;
+            }
+            if is_dir {
+                Self::collect(root, &path, ignore, queries, unreadable)?;
+            } else if path.extension().is_some_and(|ext| ext == "prql") {
+                let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+                match fs::read_to_string(&path) {
+                    Ok(text) => queries.push(RawQuery::new(name, text).with_source_path(&path)),
+                    Err(e) => unreadable.push((path, e)),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl QuerySource for FilesystemSource {
+    /// Fails on the first model file that can't be read; see
+    /// [`FilesystemSource::fetch_readable`] to carry on past them.
+    fn fetch(&self) -> Result<Vec<RawQuery>, SourceError> {
+        let (queries, unreadable) = self.fetch_readable()?;
+        match unreadable.into_iter().next() {
+            Some((_, e)) => Err(SourceError::Io(e)),
+            None => Ok(queries),
+        }
+    }
+}
+
+/// Every seed under `root`, recursively: each `.csv` file, named after its file stem and
+/// sorted. Files and directories matched by the root's [`IgnoreRules`] are skipped. Fails on
+/// two files with the same name, as they'd be loaded into the same table.
+pub fn seed_names(root: &Path) -> Result<Vec<String>, SourceError> {
+    fn collect(
+        root: &Path,
+        dir: &Path,
+        ignore: &IgnoreRules,
+        seeds: &mut Vec<(String, PathBuf)>,
+    ) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_dir = path.is_dir();
+            if ignore.is_ignored(path.strip_prefix(root).unwrap_or(&path), is_dir) {
+                continue;
+            }
+            if is_dir {
+                collect(root, &path, ignore, seeds)?;
+            } else if path.extension().is_some_and(|ext| ext == "csv") {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    seeds.push((name.to_string(), path.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    let mut seeds = Vec::new();
+    collect(root, root, &IgnoreRules::load(root)?, &mut seeds)?;
+    seeds.sort();
+    if let Some(pair) = seeds.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(SourceError::DuplicateName {
+            name: pair[0].0.clone(),
+            first: pair[0].1.clone(),
+            second: pair[1].1.clone(),
+        });
+    }
+    Ok(seeds.into_iter().map(|(name, _)| name).collect())
+}
+
+/// A service answering `GET url` with a JSON array of `{"name": ..., "query_string": ...}`.
+pub struct HttpSource {
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+impl HttpSource {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Sent with every request, e.g. `Authorization`.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+impl QuerySource for HttpSource {
+    fn fetch(&self) -> Result<Vec<RawQuery>, SourceError> {
+        let request = self
+            .headers
+            .iter()
+            .fold(ureq::get(&self.url), |request, (name, value)| {
+                request.set(name, value)
+            });
+        let body = request
+            .call()
+            .map_err(|e| SourceError::Http(e.to_string()))?
+            .into_string()?;
+        let mut queries: Vec<RawQuery> =
+            serde_json::from_str(&body).map_err(|e| SourceError::Decode(e.to_string()))?;
+        queries.sort_by(|a, b| a.name().cmp(b.name()));
+        Ok(queries)
+    }
+}
+
+#[cfg(test)]
+mod test_source {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    // Answers a single request with `body`, after checking it carried the auth header.
+    fn serve_once(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/queries", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut authorized = false;
+            loop {
+                let mut line = std::string::String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                authorized |= line.eq_ignore_ascii_case("authorization: Bearer secret\r\n");
+            }
+            let (status, body) = if authorized {
+                ("200 OK", body)
+            } else {
+                ("401 Unauthorized", "")
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        });
+        url
+    }
+
+    fn names_and_text(queries: &[RawQuery]) -> Vec<(&str, &str)> {
+        queries
+            .iter()
+            .map(|q| (q.name(), q.query_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_filesystem_and_http_sources_agree() {
+        let dir = std::env::temp_dir().join(format!("orbital-source-{}", std::process::id()));
+        fs::create_dir_all(dir.join("staging")).unwrap();
+        fs::write(dir.join("staging/stg_orders.prql"), "from raw_orders").unwrap();
+        fs::write(dir.join("orders.prql"), "from stg_orders").unwrap();
+        fs::write(dir.join("README.md"), "not a model").unwrap();
+        let from_files = FilesystemSource::new(&dir).fetch().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let url = serve_once(
+            r#"[{"name": "stg_orders", "query_string": "from raw_orders"},
+                {"name": "orders", "query_string": "from stg_orders"}]"#,
+        );
+        let from_http = HttpSource::new(url)
+            .with_header("Authorization", "Bearer secret")
+            .fetch()
+            .unwrap();
+
+        assert_eq!(
+            names_and_text(&from_files),
+            vec![
+                ("orders", "from stg_orders"),
+                ("stg_orders", "from raw_orders")
+            ]
+        );
+        assert_eq!(names_and_text(&from_http), names_and_text(&from_files));
+    }
+
+    #[test]
+    fn test_filesystem_source_walks_every_root() {
+        let base = std::env::temp_dir().join(format!("orbital-roots-{}", std::process::id()));
+        let (core, finance) = (base.join("core"), base.join("finance"));
+        fs::create_dir_all(&core).unwrap();
+        fs::create_dir_all(&finance).unwrap();
+        fs::write(core.join("orders.prql"), "from raw_orders").unwrap();
+        fs::write(finance.join("revenue.prql"), "from orders").unwrap();
+        let both = FilesystemSource::from_roots([&core, &finance])
+            .fetch()
+            .unwrap();
+        let names: Vec<_> = both.iter().map(RawQuery::name).collect();
+        assert_eq!(names, vec!["orders", "revenue"]);
+
+        fs::write(finance.join("orders.prql"), "from ledger").unwrap();
+        let collision = FilesystemSource::from_roots([&core, &finance]).fetch();
+        fs::remove_dir_all(&base).unwrap();
+        match collision {
+            Err(SourceError::DuplicateName { name, .. }) => assert_eq!(name, "orders"),
+            other => panic!("expected a duplicate name error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_unreadable_file_is_set_aside() {
+        let dir = std::env::temp_dir().join(format!("orbital-unreadable-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("orders.prql"), "from raw_orders").unwrap();
+        fs::write(dir.join("customers.prql"), "from raw_customers").unwrap();
+        fs::write(dir.join("broken.prql"), [0x66, 0x72, 0xff, 0xfe]).unwrap();
+        let source = FilesystemSource::new(&dir);
+        let (queries, unreadable) = source.fetch_readable().unwrap();
+        let strict = source.fetch();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<_> = queries.iter().map(RawQuery::name).collect();
+        assert_eq!(names, vec!["customers", "orders"]);
+        assert_eq!(unreadable.len(), 1);
+        assert_eq!(unreadable[0].0, dir.join("broken.prql"));
+        assert_eq!(unreadable[0].1.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(strict, Err(SourceError::Io(_))));
+    }
+
+    #[test]
+    fn test_ignored_files_are_not_loaded() {
+        let dir = std::env::temp_dir().join(format!("orbital-ignore-{}", std::process::id()));
+        fs::create_dir_all(dir.join("archive")).unwrap();
+        fs::create_dir_all(dir.join("staging")).unwrap();
+        fs::write(
+            dir.join(IGNORE_FILE_NAME),
+            "# work in progress\n*_draft.prql\narchive/\n",
+        )
+        .unwrap();
+        fs::write(dir.join("orders.prql"), "from raw_orders").unwrap();
+        fs::write(dir.join("orders_draft.prql"), "from raw_orders |").unwrap();
+        fs::write(dir.join("staging/stg_draft.prql"), "from raw_orders |").unwrap();
+        fs::write(dir.join("archive/old_orders.prql"), "from legacy").unwrap();
+        let queries = FilesystemSource::new(&dir).fetch();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<_> = queries
+            .as_deref()
+            .unwrap()
+            .iter()
+            .map(RawQuery::name)
+            .collect();
+        assert_eq!(names, vec!["orders"]);
+
+        let rules =
+            IgnoreRules::parse("/drafts/**/*.prql\n*.bak.prql\n!keep.bak.prql\nv[0-9]_*.prql")
+                .unwrap();
+        assert!(rules.is_ignored(Path::new("drafts/a/b/x.prql"), false));
+        assert!(!rules.is_ignored(Path::new("core/drafts/x.prql"), false));
+        assert!(rules.is_ignored(Path::new("core/x.bak.prql"), false));
+        assert!(!rules.is_ignored(Path::new("core/keep.bak.prql"), false));
+        assert!(rules.is_ignored(Path::new("core/v2_orders.prql"), false));
+        assert!(matches!(
+            IgnoreRules::parse("v[9-0]_*.prql"),
+            Err(ignore::Error::Glob { .. })
+        ));
+    }
+
+    #[test]
+    fn test_http_source_reports_error_status() {
+        let url = serve_once("[]");
+        assert!(matches!(
+            HttpSource::new(url).fetch(),
+            Err(SourceError::Http(_))
+        ));
+    }
+}
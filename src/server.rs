@@ -0,0 +1,334 @@
+use crate::{
+    backend::pool::BackendPool,
+    freshness::check_freshness,
+    query_graph::{
+        query::{QueryKind, QueryMap, QueryName},
+        GraphMeta,
+    },
+    settings::{FreshnessCheck, FreshnessStatus},
+};
+use prql_compiler::ast::pl::Dialect;
+use serde_json::{json, Value};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    time::SystemTime,
+};
+
+// Standard JSON-RPC 2.0 error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// Implementation-defined, for requests that were understood but couldn't be carried out.
+const QUERY_ERROR: i64 = -32000;
+
+type RpcResult = Result<Value, (i64, String)>;
+
+/// Answers JSON-RPC 2.0 requests about a loaded project, for editor integrations showing
+/// lineage and compile errors. Requests and responses are one JSON object per line.
+///
+/// Methods: `compile(name)` returns the model's SQL, `dependencies(name)` the names it reads
+/// from, `validate()` every column problem found, `graph()` every node and edge by name, and
+/// `freshness()` each source's freshness with the worst status among them. Parameters can be
+/// given by position or by name.
+pub struct RpcServer<'a> {
+    meta: GraphMeta,
+    dialect: Dialect,
+    aliases: QueryMap<QueryName, smartstring::alias::String>,
+    freshness: Vec<FreshnessCheck<'a>>,
+    pool: Option<&'a BackendPool<'a>>,
+}
+
+impl<'a> RpcServer<'a> {
+    pub fn new(meta: GraphMeta, dialect: Dialect) -> Self {
+        Self {
+            meta,
+            dialect,
+            aliases: QueryMap::default(),
+            freshness: Vec::new(),
+            pool: None,
+        }
+    }
+
+    /// Compiled SQL reads each upstream in `aliases` from its relation there, e.g. the
+    /// project's [`BuiltProject::relation_aliases`](crate::build::BuiltProject::relation_aliases).
+    pub fn with_aliases(
+        mut self,
+        aliases: QueryMap<QueryName, smartstring::alias::String>,
+    ) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    /// `freshness()` runs `checks` through a backend checked out of `pool`, so the
+    /// connection is kept open between requests and replaced once it stops answering.
+    /// Without a pool, `freshness()` fails.
+    pub fn with_freshness(
+        mut self,
+        checks: Vec<FreshnessCheck<'a>>,
+        pool: &'a BackendPool<'a>,
+    ) -> Self {
+        self.freshness = checks;
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Serves connections one after another until accepting one fails.
+    pub fn serve(&self, listener: &TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            self.serve_connection(stream?)?;
+        }
+        Ok(())
+    }
+
+    /// Answers each request line on `stream` until the client disconnects.
+    pub fn serve_connection(&self, stream: TcpStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            writeln!(writer, "{}", self.handle(&line))?;
+        }
+        Ok(())
+    }
+
+    /// The response to a single request.
+    pub fn handle(&self, request: &str) -> Value {
+        let request: Value = match serde_json::from_str(request) {
+            Ok(request) => request,
+            Err(e) => return response(Value::Null, Err((PARSE_ERROR, e.to_string()))),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return response(id, Err((INVALID_REQUEST, "missing method".to_string())));
+        };
+        let params = request.get("params").unwrap_or(&Value::Null);
+        let result = match method {
+            "compile" => self.compile(params),
+            "dependencies" => self.dependencies(params),
+            "validate" => Ok(self.validate()),
+            "graph" => Ok(self.graph()),
+            "freshness" => self.freshness(),
+            _ => Err((METHOD_NOT_FOUND, format!("no method named {method}"))),
+        };
+        response(id, result)
+    }
+
+    fn query(&self, params: &Value) -> Result<&QueryKind, (i64, String)> {
+        let name = params
+            .get(0)
+            .or_else(|| params.get("name"))
+            .and_then(Value::as_str)
+            .ok_or_else(|| (INVALID_PARAMS, "expected a query name".to_string()))?;
+        self.meta
+            .collection()
+            .get(&QueryName::from(name))
+            .ok_or_else(|| (QUERY_ERROR, format!("no query named {name}")))
+    }
+
+    fn compile(&self, params: &Value) -> RpcResult {
+        match self.query(params)? {
+            QueryKind::Query(q) => q
+                .compile_with_aliases(&self.dialect, &self.aliases)
+                .map(Value::from)
+                .map_err(|e| (QUERY_ERROR, e.to_string())),
+            table => Err((
+                QUERY_ERROR,
+                format!("{} is a table, not a model", table.name().as_str()),
+            )),
+        }
+    }
+
+    fn dependencies(&self, params: &Value) -> RpcResult {
+        let mut names: Vec<_> = self
+            .query(params)?
+            .dependency_names()
+            .iter()
+            .map(|name| name.as_str())
+            .collect();
+        names.sort_unstable();
+        Ok(json!(names))
+    }
+
+    fn validate(&self) -> Value {
+        let errors: Vec<_> = self
+            .meta
+            .collection()
+            .validate_columns()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        json!(errors)
+    }
+
+    fn graph(&self) -> Value {
+        let nodes = self.meta.collection().sorted_values();
+        let edges: Vec<_> = nodes
+            .iter()
+            .flat_map(|node| {
+                let mut deps: Vec<_> = node.dependency_names().iter().collect();
+                deps.sort();
+                deps.into_iter()
+                    .map(move |dep| json!({ "from": dep.as_str(), "to": node.name().as_str() }))
+            })
+            .collect();
+        let names: Vec<_> = nodes.iter().map(|node| node.name().as_str()).collect();
+        json!({ "nodes": names, "edges": edges })
+    }
+}
+
+impl RpcServer<'_> {
+    fn freshness(&self) -> RpcResult {
+        let pool = self.pool.ok_or_else(|| {
+            (
+                QUERY_ERROR,
+                "no connection configured to check freshness against".to_string(),
+            )
+        })?;
+        let mut backend = pool.acquire().map_err(|e| (QUERY_ERROR, e.to_string()))?;
+        let results = check_freshness(&self.freshness, &mut *backend, SystemTime::now());
+        Ok(json!({
+            "status": FreshnessStatus::worst(results.iter().map(|r| r.status)),
+            "sources": results,
+        }))
+    }
+}
+
+fn response(id: Value, result: RpcResult) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test_server {
+    use super::*;
+    use crate::query_graph::query::{QueryCollection, RawQuery};
+    use std::thread;
+
+    fn server() -> RpcServer<'static> {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+        ]);
+        RpcServer::new(GraphMeta::new(collection).unwrap(), Dialect::Generic)
+    }
+
+    #[test]
+    fn test_dependencies_round_trip_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = server();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server.serve_connection(stream).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        writeln!(
+            stream,
+            r#"{{"jsonrpc": "2.0", "id": 7, "method": "dependencies", "params": ["q2"]}}"#
+        )
+        .unwrap();
+        let mut line = std::string::String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            response,
+            json!({ "jsonrpc": "2.0", "id": 7, "result": ["q1", "rituals"] })
+        );
+    }
+
+    #[test]
+    fn test_errors_follow_json_rpc_codes() {
+        let server = server();
+        let code = |request: &str| server.handle(request)["error"]["code"].clone();
+        assert_eq!(code("not json"), json!(PARSE_ERROR));
+        assert_eq!(
+            code(r#"{"id": 1, "method": "explode"}"#),
+            json!(METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            code(r#"{"id": 1, "method": "compile"}"#),
+            json!(INVALID_PARAMS)
+        );
+        assert_eq!(
+            code(r#"{"id": 1, "method": "compile", "params": {"name": "arcana"}}"#),
+            json!(QUERY_ERROR)
+        );
+        let graph = server.handle(r#"{"id": 2, "method": "graph"}"#);
+        assert_eq!(
+            graph["result"]["edges"][0],
+            json!({ "from": "arcana", "to": "q1" })
+        );
+        let sql = server.handle(r#"{"id": 3, "method": "compile", "params": ["q3"]}"#);
+        assert!(sql["result"].as_str().unwrap().contains("blah"));
+    }
+
+    #[test]
+    fn test_compile_reads_aliased_upstreams_from_their_relation() {
+        let aliases = [(QueryName::from("q2"), "ritual_sources".into())]
+            .into_iter()
+            .collect();
+        let server = server().with_aliases(aliases);
+        let sql = server.handle(r#"{"id": 1, "method": "compile", "params": ["q3"]}"#);
+        let sql = sql["result"].as_str().unwrap();
+        assert!(sql.contains("ritual_sources AS q2"), "{sql}");
+    }
+
+    #[test]
+    fn test_freshness_reuses_a_pooled_backend() {
+        use crate::{settings::Project, testing::InMemoryBackend};
+        use figment::providers::{Format, Yaml};
+
+        let project: Project = figment::Figment::from(Yaml::string(
+            r#"
+name: shop
+version: "0.1.0"
+model_path: .
+seed_path: .
+clean_targets: target
+log_path: logs
+models: []
+seeds: []
+sources:
+  - name: orders
+    enabled: true
+    freshness:
+      loaded_at_field:
+        table: { database: raw, schema: public, table: orders }
+        column: loaded_at
+      warn_after: { count: 12, period: Hour }
+      error_after: { count: 1, period: Day }
+"#,
+        ))
+        .extract()
+        .unwrap();
+        let checks = project.freshness_checks(&Dialect::PostgreSql, &[]).unwrap();
+        let pool = BackendPool::new(|| Ok(Box::new(InMemoryBackend::new())), Default::default());
+        let request = r#"{"id": 1, "method": "freshness"}"#;
+        assert_eq!(
+            server().handle(request)["error"]["code"],
+            json!(QUERY_ERROR)
+        );
+
+        let server = server().with_freshness(checks, &pool);
+        for _ in 0..2 {
+            let result = &server.handle(request)["result"];
+            // Nothing was ever loaded into the in-memory warehouse.
+            assert_eq!(result["status"], json!("error"));
+            assert_eq!(result["sources"][0]["source"], json!("orders"));
+        }
+        assert_eq!(pool.open(), 1);
+    }
+}
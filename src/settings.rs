@@ -1,106 +1,1862 @@
+use crate::{
+    backend::{Backend, BackendError, BackendFactory},
+    executor::{NodeHooks, Thresholds},
+    query_graph::graph::GraphLimits,
+    sql::quote_ident,
+};
 use figment::{
-    providers::{Format, Yaml},
+    providers::{Format, Serialized, Toml, Yaml},
+    value::Value,
     Figment,
 };
-use serde::Deserialize;
+use prql_compiler::ast::pl::Dialect;
+use serde::{
+    de::{self, Error as _, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 use smartstring::alias::String;
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use version_rs::Version;
 
-type ResourceMetadata = HashMap<String, String>;
+pub type ResourceMetadata = HashMap<String, String>;
 
 #[derive(Debug, Deserialize)]
-struct Project {
-    name: ResourceName,
-    version: Version,
-    model_path: PathBuf,
-    seed_path: PathBuf,
-    clean_targets: PathBuf,
-    log_path: PathBuf,
-    models: Vec<ResourceConfig>,
-    seeds: Vec<ResourceConfig>,
-    sources: Vec<SourceConfig>,
-    vars: Option<HashMap<String, String>>,
+pub struct Project {
+    pub name: ResourceName,
+    #[serde(deserialize_with = "deserialize_version")]
+    pub version: Version,
+    pub model_path: ModelPaths,
+    pub seed_path: PathBuf,
+    pub clean_targets: PathBuf,
+    pub log_path: PathBuf,
+    #[serde(default)]
+    pub target_path: Option<PathBuf>, // Where generated files go; `log_path` if unset
+    pub models: Vec<ResourceConfig>,
+    pub seeds: Vec<ResourceConfig>,
+    pub sources: Vec<SourceConfig>,
+    pub vars: Option<HashMap<String, VarValue>>,
+    #[serde(default)]
+    pub schema_naming: SchemaNaming,
+    #[serde(default = "default_target_name")]
+    pub target_name: String, // What `{target}` stands for in `schema_naming`, e.g. `prod`
+    #[serde(default, deserialize_with = "deserialize_dialect")]
+    pub default_dialect: Dialect, // `postgres`, `snowflake`... Generic if unset
+    #[serde(default)]
+    pub defaults: ResourceDefaults,
+    #[serde(default)]
+    pub identifier_case: IdentifierCase,
+    #[serde(default)]
+    pub connection: Option<ConnectionConfig>,
+    #[serde(default)]
+    pub connections: HashMap<String, ConnectionConfig>, // By database; `connection` covers the rest
+    #[serde(default)]
+    pub fail_on_orphan: bool, // Queries connected to nothing usually mean a broken reference
+    #[serde(default)]
+    pub strict_sources: bool, // Reading an undeclared table is an error, not a placeholder
+    #[serde(default)]
+    pub on_run_start: Vec<String>, // Run in order, once, before the first model
+    #[serde(default)]
+    pub on_run_end: Vec<String>, // Run in order, once, after the last model
+    #[serde(default)]
+    pub freshness_exclude: Vec<String>, // Sources never probed for freshness, treated as fresh
+    #[serde(default)]
+    pub analysis_only: bool, // Compile, validate and check freshness, but never write
+    #[serde(flatten)]
+    pub limits: GraphLimits, // `max_models` and `max_edges`, both unlimited by default
+}
+
+impl Project {
+    /// Loads the project file at `path`. A relative `target_path` is taken relative to the
+    /// file rather than the working directory.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<figment::Error>> {
+        Self::from_file_with_blocks(path, &[])
+    }
+
+    /// Like [`Project::from_file`], but with each model's [`parse_config_block`] settings,
+    /// given by model name, merged into its entry, which is added if the project file has
+    /// none. Anything the entry sets itself wins over its block, which wins over `defaults`.
+    pub fn from_file_with_blocks(
+        path: impl AsRef<Path>,
+        blocks: &[(String, Value)],
+    ) -> Result<Self, Box<figment::Error>> {
+        let figment = Figment::new().merge(Yaml::file(path.as_ref()));
+        let mut project = Self::from_figment_with_blocks(figment, blocks)?;
+        if let (Some(target), Some(dir)) = (&project.target_path, path.as_ref().parent()) {
+            project.target_path = Some(dir.join(target));
+        }
+        Ok(project)
+    }
+
+    /// The directory compiled SQL, the manifest and other generated files are written under:
+    /// `target_path` when set, otherwise `log_path` as before it existed.
+    pub fn target_dir(&self) -> &Path {
+        self.target_path.as_deref().unwrap_or(&self.log_path)
+    }
+
+    /// Extracts the project, first filling each model and seed in from `defaults` wherever
+    /// it leaves a defaulted field out.
+    pub fn from_figment(figment: Figment) -> Result<Self, Box<figment::Error>> {
+        Self::from_figment_with_blocks(figment, &[])
+    }
+
+    fn from_figment_with_blocks(
+        mut figment: Figment,
+        blocks: &[(String, Value)],
+    ) -> Result<Self, Box<figment::Error>> {
+        let defaults: ResourceDefaults = if figment.find_value("defaults").is_ok() {
+            figment.extract_inner("defaults").map_err(Box::new)?
+        } else {
+            ResourceDefaults::default()
+        };
+        for key in ["models", "seeds"] {
+            if let Ok(Value::Array(_, mut resources)) = figment.find_value(key) {
+                if key == "models" {
+                    merge_blocks(&mut resources, blocks);
+                }
+                let filled: Vec<_> = resources
+                    .into_iter()
+                    .map(|resource| defaults.fill(resource))
+                    .collect();
+                figment = figment.merge(Serialized::default(key, filled));
+            }
+        }
+        figment.extract().map_err(Box::new)
+    }
+
+    /// The dialect models without a `prql dialect:` header compile to: the connection's
+    /// adapter when it names a dialect (e.g. `postgres`), falling back to `default_dialect`.
+    pub fn dialect(&self) -> Dialect {
+        self.connection
+            .as_ref()
+            .and_then(|connection| connection.adapter.parse().ok())
+            .unwrap_or_else(|| self.default_dialect.clone())
+    }
+
+    /// Where `resource` is materialized under the project's `schema_naming`, `target_name`
+    /// and `identifier_case`, quoted for its dialect.
+    pub fn relation_sql(&self, resource: &ResourceConfig) -> std::string::String {
+        resource.relation_sql(
+            &self.schema_naming,
+            &self.target_name,
+            self.identifier_case,
+            &self.dialect(),
+        )
+    }
+
+    /// Where models in `database` are built: its entry in `connections`, falling back to
+    /// `connection`.
+    pub fn connection_for(&self, database: &str) -> Option<&ConnectionConfig> {
+        self.connections.get(database).or(self.connection.as_ref())
+    }
+
+    /// The freshness check of every enabled source that configures one, in declaration
+    /// order. Sources in `freshness_exclude` or `exclude` get no SQL and count as fresh.
+    /// Fails with every excluded name that isn't a declared source, which is usually a typo.
+    pub fn freshness_checks(
+        &self,
+        dialect: &Dialect,
+        exclude: &[&str],
+    ) -> Result<Vec<FreshnessCheck<'_>>, Vec<ConfigError>> {
+        let excluded: HashSet<&str> = self
+            .freshness_exclude
+            .iter()
+            .map(|name| name.as_str())
+            .chain(exclude.iter().copied())
+            .collect();
+        let mut unknown: Vec<_> = excluded
+            .iter()
+            .filter(|name| !self.sources.iter().any(|s| s.name.as_ref() == **name))
+            .map(|name| ConfigError::UnknownSource(String::from(*name)))
+            .collect();
+        if !unknown.is_empty() {
+            unknown.sort_by_key(|e| e.to_string());
+            return Err(unknown);
+        }
+        Ok(self
+            .sources
+            .iter()
+            .filter(|source| source.enabled)
+            .filter_map(|source| {
+                let freshness = source.freshness.as_ref()?;
+                let sql = (!excluded.contains(source.name.as_ref().as_str()))
+                    .then(|| freshness.build_check_sql(dialect));
+                Some(FreshnessCheck {
+                    source: &source.name,
+                    freshness,
+                    sql,
+                })
+            })
+            .collect())
+    }
+
+    /// Every connection whose adapter `factory` has no backend for: `connection` first, then
+    /// `connections` by database. Separate from [`Project::validate`], as which adapters
+    /// exist depends on the binary rather than the project.
+    pub fn validate_adapters(&self, factory: &BackendFactory) -> Vec<ConfigError> {
+        let supported: Vec<_> = factory.adapters().into_iter().map(String::from).collect();
+        let mut connections: Vec<_> = self.connections.iter().collect();
+        connections.sort_by_key(|(database, _)| *database);
+        self.connection
+            .iter()
+            .map(|connection| (String::from("connection"), connection))
+            .chain(connections.into_iter().map(|(database, connection)| {
+                (format!("connections.{database}").into(), connection)
+            }))
+            .filter(|(_, connection)| !factory.supports(&connection.adapter))
+            .map(|(name, connection)| ConfigError::UnsupportedAdapter {
+                connection: name,
+                adapter: connection.adapter.clone(),
+                supported: supported.clone(),
+            })
+            .collect()
+    }
+
+    /// Every `loaded_at_field` column of an enabled source that `backend` can't find, in
+    /// declaration order. Separate from [`Project::validate`], as it needs a warehouse.
+    pub fn validate_loaded_at_fields(
+        &self,
+        backend: &mut dyn Backend,
+    ) -> Result<Vec<ConfigError>, BackendError> {
+        let mut errors = Vec::new();
+        for source in self.sources.iter().filter(|s| s.enabled) {
+            let Some(freshness) = &source.freshness else {
+                continue;
+            };
+            let (table, columns) = match &freshness.loaded_at_field {
+                LoadedAtField::Single(field) => (&field.table, std::slice::from_ref(&field.column)),
+                LoadedAtField::Composite { table, columns } => (table, columns.as_slice()),
+            };
+            for column in columns {
+                let found = backend.probe_column(
+                    table.database.as_ref(),
+                    table.schema.as_ref(),
+                    table.table.as_ref(),
+                    column.as_ref(),
+                )?;
+                if !found {
+                    errors.push(ConfigError::MissingColumn {
+                        source: source.name.as_ref().clone(),
+                        column: format!(
+                            "{}.{}.{}.{}",
+                            table.database.as_ref(),
+                            table.schema.as_ref(),
+                            table.table.as_ref(),
+                            column.as_ref()
+                        )
+                        .into(),
+                    });
+                }
+            }
+        }
+        Ok(errors)
+    }
+
+    /// Cross-field checks that deserialization alone can't express. Touches the filesystem
+    /// for path checks but never parses queries or connects to a warehouse.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let model_paths = self.model_path.paths().iter().map(|p| ("model_path", p));
+        for (field, path) in model_paths.chain([("seed_path", &self.seed_path)]) {
+            if !path.is_dir() {
+                errors.push(ConfigError::MissingPath {
+                    field,
+                    path: path.clone(),
+                });
+            }
+        }
+        let mut seen = HashSet::new();
+        for resource in self.models.iter().chain(&self.seeds) {
+            let name = resource.name.as_ref();
+            if !seen.insert(name) {
+                errors.push(ConfigError::DuplicateName(name.clone()));
+            }
+        }
+        for model in &self.models {
+            if let Some(reason) = model.materialization_problem() {
+                errors.push(ConfigError::InvalidMaterialization {
+                    resource: model.name.as_ref().clone(),
+                    reason,
+                });
+            }
+        }
+        let mut targets = HashMap::new();
+        let materialized = self
+            .models
+            .iter()
+            .filter(|m| m.enabled && m.materialized != Materialization::Ephemeral);
+        for model in materialized {
+            let target: String = model
+                .relation_parts(&self.schema_naming, &self.target_name, self.identifier_case)
+                .join(".")
+                .into();
+            let name = model.name.as_ref();
+            if let Some(first) = targets.insert(target.clone(), name) {
+                errors.push(ConfigError::DuplicateTarget {
+                    target,
+                    first: first.clone(),
+                    second: name.clone(),
+                });
+            }
+        }
+        for model in self.models.iter().filter(|m| m.enabled) {
+            let database = model.database.as_ref();
+            if !self.connections.is_empty() && self.connection_for(database).is_none() {
+                errors.push(ConfigError::NoConnection {
+                    resource: model.name.as_ref().clone(),
+                    database: database.clone(),
+                });
+            }
+        }
+        for name in &self.freshness_exclude {
+            if !self.sources.iter().any(|s| s.name.as_ref() == name) {
+                errors.push(ConfigError::UnknownSource(name.clone()));
+            }
+        }
+        for source in &self.sources {
+            if let Some(freshness) = &source.freshness {
+                if freshness.warn_after.as_duration() > freshness.error_after.as_duration() {
+                    errors.push(ConfigError::WarnAfterExceedsErrorAfter(
+                        source.name.as_ref().clone(),
+                    ));
+                }
+            }
+        }
+        errors
+    }
+}
+
+/// One directory of models, or several for projects split across roots.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ModelPaths {
+    Single(PathBuf),
+    Multiple(Vec<PathBuf>),
+}
+
+impl ModelPaths {
+    pub fn paths(&self) -> &[PathBuf] {
+        match self {
+            ModelPaths::Single(path) => std::slice::from_ref(path),
+            ModelPaths::Multiple(paths) => paths,
+        }
+    }
+}
+
+// By the names PRQL headers use (`postgres`, not `PostgreSql`), so config and query agree.
+fn deserialize_dialect<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Dialect, D::Error> {
+    let raw = std::string::String::deserialize(deserializer)?;
+    raw.parse()
+        .map_err(|_| D::Error::custom(format!("unknown dialect {raw:?}")))
+}
+
+// `Version`'s own impl only accepts borrowed strings, which figment never hands out.
+fn deserialize_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Version, D::Error> {
+    let raw = std::string::String::deserialize(deserializer)?;
+    Version::try_from(raw.as_str())
+        .map_err(|_| D::Error::custom(format!("invalid version {raw:?}")))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    MissingPath {
+        field: &'static str,
+        path: PathBuf,
+    },
+    DuplicateName(String),
+    WarnAfterExceedsErrorAfter(String),
+    InvalidMaterialization {
+        resource: String,
+        reason: &'static str,
+    },
+    /// A source named in `freshness_exclude` that isn't declared.
+    UnknownSource(String),
+    /// `connections` is in use, but neither it nor `connection` covers the model's database.
+    NoConnection {
+        resource: String,
+        database: String,
+    },
+    /// Two enabled models, after `alias`, would materialize into the same
+    /// `database.schema.relation`, so one would overwrite the other.
+    DuplicateTarget {
+        target: String,
+        first: String,
+        second: String,
+    },
+    /// A connection names an adapter with no backend registered for it.
+    UnsupportedAdapter {
+        connection: String, // `connection`, or `connections.<database>`
+        adapter: String,
+        supported: Vec<String>,
+    },
+    /// A source's `loaded_at_field` names a column its table doesn't have.
+    MissingColumn {
+        source: String,
+        column: String, // `database.schema.table.column`
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingPath { field, path } => {
+                write!(f, "{field} {} is not a directory", path.display())
+            }
+            ConfigError::DuplicateName(name) => {
+                write!(f, "resource name {name} is used more than once")
+            }
+            ConfigError::WarnAfterExceedsErrorAfter(source) => {
+                write!(f, "source {source} warns after it would already error")
+            }
+            ConfigError::InvalidMaterialization { resource, reason } => {
+                write!(f, "model {resource} {reason}")
+            }
+            ConfigError::UnknownSource(name) => {
+                write!(
+                    f,
+                    "{name} is excluded from freshness checks but is not a source"
+                )
+            }
+            ConfigError::NoConnection { resource, database } => {
+                write!(
+                    f,
+                    "model {resource} is in database {database}, which has no connection"
+                )
+            }
+            ConfigError::DuplicateTarget {
+                target,
+                first,
+                second,
+            } => {
+                write!(
+                    f,
+                    "models {first} and {second} both materialize into {target}"
+                )
+            }
+            ConfigError::UnsupportedAdapter {
+                connection,
+                adapter,
+                supported,
+            } => {
+                write!(f, "{connection} uses unsupported adapter {adapter:?}; ")?;
+                if supported.is_empty() {
+                    write!(f, "no adapters are available in this build")
+                } else {
+                    write!(f, "supported adapters are {}", supported.join(", "))
+                }
+            }
+            ConfigError::MissingColumn { source, column } => {
+                write!(
+                    f,
+                    "source {source} is loaded at {column}, which does not exist"
+                )
+            }
+        }
+    }
+}
+
+/// A project var, typed by how it was written in YAML: `35` is an int, `"35"` a string.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VarValue {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl VarValue {
+    /// The var as a PRQL literal. Only strings are quoted, so numeric comparisons stay
+    /// numeric in the generated SQL.
+    pub fn to_prql_literal(&self) -> std::string::String {
+        match self {
+            // PRQL has no escapes, so pick whichever quote the value doesn't contain.
+            VarValue::String(s) if s.contains('\'') => format!("\"{s}\""),
+            VarValue::String(s) => format!("'{s}'"),
+            VarValue::Int(i) => i.to_string(),
+            VarValue::Float(f) => f.to_string(),
+            VarValue::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for VarValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct VarValueVisitor;
+
+        impl Visitor<'_> for VarValueVisitor {
+            type Value = VarValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a string, number or boolean")
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                Ok(VarValue::Bool(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(VarValue::Int(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                i64::try_from(v)
+                    .map(VarValue::Int)
+                    .map_err(|_| E::custom(format!("{v} is too large for a var")))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                Ok(VarValue::Float(v))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(VarValue::String(v.into()))
+            }
+        }
+
+        deserializer.deserialize_any(VarValueVisitor)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VarError {
+    Undefined(std::string::String),
+    /// A `{{` with no closing `}}`.
+    Unterminated,
+    /// The query no longer parses or resolves once values are substituted in, e.g. a string
+    /// holding both kinds of quote. Carries the compiler's message.
+    Invalid(std::string::String),
+}
+
+impl fmt::Display for VarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VarError::Undefined(name) => write!(f, "var {name} is not defined"),
+            VarError::Unterminated => write!(f, "unterminated {{{{ in query"),
+            VarError::Invalid(e) => write!(f, "substituting vars broke the query: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for VarError {}
+
+/// Replaces each `{{ name }}` in a PRQL query with the var's literal, before parsing.
+pub fn substitute_vars(
+    query: &str,
+    vars: &HashMap<String, VarValue>,
+) -> Result<std::string::String, VarError> {
+    let mut rendered = std::string::String::with_capacity(query.len());
+    let mut rest = query;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or(VarError::Unterminated)?;
+        let name = after[..end].trim();
+        let value = vars
+            .get(name)
+            .ok_or_else(|| VarError::Undefined(name.to_string()))?;
+        rendered.push_str(&value.to_prql_literal());
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
 }
 
 #[derive(Debug, Deserialize)]
-struct ResourceConfig {
-    name: ResourceName,
-    enabled: bool,
-    database: ResourceName,
-    schema: ResourceName,
-    exclude_full_refresh: bool,
-    metadata: Option<ResourceMetadata>,
+pub struct ConnectionConfig {
+    pub adapter: String, // Name a backend was registered under, e.g. "postgres"
+    #[serde(default)]
+    pub options: HashMap<String, String>, // Adapter-specific: host, credentials, file path...
+    #[serde(default)]
+    pub pool: PoolConfig,
+}
+
+/// How a [`BackendPool`](crate::backend::pool::BackendPool) for a connection behaves.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct PoolConfig {
+    #[serde(default = "default_pool_size")]
+    pub max_size: usize, // Connections open at once, in use or idle
+    #[serde(default)]
+    pub idle_timeout: Option<u64>, // Seconds; an idle connection older than this is replaced
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: default_pool_size(),
+            idle_timeout: None,
+        }
+    }
+}
+
+fn default_pool_size() -> usize {
+    4
+}
+
+/// The settings in a model's `# orbital-config: { ... }` line (see
+/// [`RawQuery::config_block`](crate::query_graph::query::RawQuery::config_block)): a TOML
+/// inline table with the same keys as the model's entry in the project file, e.g.
+/// `{ materialized = "view", tags = ["nightly"] }`.
+pub fn parse_config_block(block: &str) -> Result<Value, Box<figment::Error>> {
+    let value = Figment::from(Toml::string(&format!("config = {block}")))
+        .find_value("config")
+        .map_err(Box::new)?;
+    match value {
+        Value::Dict(..) => Ok(value),
+        other => Err(Box::new(figment::Error::from(format!(
+            "expected a table of model settings, found {}",
+            other.to_actual()
+        )))),
+    }
+}
+
+// Fills each model's (still unparsed) entry in from its block, adding an entry for blocks
+// whose model has none.
+fn merge_blocks(models: &mut Vec<Value>, blocks: &[(String, Value)]) {
+    for (name, block) in blocks {
+        let Value::Dict(tag, block) = block else {
+            continue;
+        };
+        let entry = models.iter_mut().find_map(|model| match model {
+            Value::Dict(_, fields)
+                if fields.get("name").and_then(Value::as_str) == Some(name.as_str()) =>
+            {
+                Some(fields)
+            }
+            _ => None,
+        });
+        match entry {
+            Some(fields) => {
+                for (key, value) in block {
+                    fields.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+            None => {
+                let mut fields = block.clone();
+                fields.insert("name".into(), Value::from(name.as_str()));
+                models.push(Value::Dict(*tag, fields));
+            }
+        }
+    }
+}
+
+/// Project-wide values for resource fields, so a model or seed can be declared with just a
+/// name. A resource's own settings always win.
+#[derive(Debug, Default, Deserialize)]
+pub struct ResourceDefaults {
+    pub database: Option<ResourceName>,
+    pub schema: Option<ResourceName>,
+    pub enabled: Option<bool>, // `true` if neither the resource nor the defaults say
+    pub exclude_full_refresh: Option<bool>,
+}
+
+impl ResourceDefaults {
+    // Adds each default the resource doesn't set itself to its (still unparsed) config.
+    fn fill(&self, resource: Value) -> Value {
+        let Value::Dict(tag, mut fields) = resource else {
+            return resource;
+        };
+        let defaults = [
+            (
+                "database",
+                self.database
+                    .as_ref()
+                    .map(|d| Value::from(d.as_ref().as_str())),
+            ),
+            (
+                "schema",
+                self.schema
+                    .as_ref()
+                    .map(|s| Value::from(s.as_ref().as_str())),
+            ),
+            ("enabled", self.enabled.map(Value::from)),
+            (
+                "exclude_full_refresh",
+                self.exclude_full_refresh.map(Value::from),
+            ),
+        ];
+        for (field, value) in defaults {
+            if let Some(value) = value {
+                fields.entry(field.to_string()).or_insert(value);
+            }
+        }
+        Value::Dict(tag, fields)
+    }
 }
 
-prae::define! {
-    #[derive(Debug, Deserialize)]
-    pub ResourceName: String;
-    ensure |name| !name.is_empty();
+fn default_target_name() -> String {
+    String::from("dev")
+}
+
+fn enabled_by_default() -> bool {
+    true
 }
 
 #[derive(Debug, Deserialize)]
-struct ResourceProperties {
-    name: ResourceName,          // Presumably, must match ResourceConfig.nam?
-    description: Option<String>, // Is there a crate for parsed markdown?
-    config: ResourceConfig,
+pub struct ResourceConfig {
+    pub name: ResourceName,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    pub database: ResourceName, // Required, here or in the project's `defaults`
+    pub schema: ResourceName,   // Required, here or in the project's `defaults`
+    #[serde(default)]
+    pub exclude_full_refresh: bool,
+    pub metadata: Option<ResourceMetadata>,
+    #[serde(default)]
+    pub pre_hook: Vec<String>, // Run in order, immediately before the model's main statement
+    #[serde(default)]
+    pub post_hook: Vec<String>, // Run in order, immediately after. A failing hook fails the node.
+    #[serde(default)]
+    pub weight: u32, // Scheduling hint: heavier models are dispatched first within a layer
+    #[serde(default)]
+    pub alias: Option<ResourceName>, // Warehouse relation to materialize into, if not `name`
+    #[serde(default)]
+    pub materialized: Materialization,
+    #[serde(default)]
+    pub depends_on: Vec<ResourceName>, // Extra upstreams the PRQL doesn't name, e.g. via macros
+    #[serde(default)]
+    pub extends: Option<ResourceName>, // Model whose pipeline this one's PRQL continues
+    #[serde(default)]
+    pub tests: ModelTests,
+    #[serde(default)]
+    pub tags: Vec<String>, // Matched by `tag:<name>` selectors
+    #[serde(default)]
+    pub expected_duration: Option<u64>, // Seconds; a run taking longer is warned about
+    #[serde(default)]
+    pub expected_rows: Option<u64>, // A run writing more rows is warned about
+    #[serde(default)]
+    pub id_key: Option<String>, // Stable key the model's id is derived from instead of `name`
+}
+
+/// Sanity checks run against a model after it has been materialized.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct ModelTests {
+    pub row_count: Option<RowCount>,
+}
+
+impl ModelTests {
+    /// Whether no test is configured at all.
+    pub fn is_empty(&self) -> bool {
+        self.row_count.is_none()
+    }
+}
+
+/// Inclusive bounds on a model's row count. Catches models that come out empty or explode.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct RowCount {
+    pub min: u64,
+    #[serde(default)]
+    pub max: Option<u64>, // Unbounded if missing
+}
+
+impl RowCount {
+    pub fn contains(&self, count: u64) -> bool {
+        count >= self.min && self.max.is_none_or(|max| count <= max)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Materialization {
+    #[default]
+    Table,
+    View,
+    /// Never built in the warehouse; inlined as a CTE into each query that reads it.
+    Ephemeral,
+    /// Built once, then only new rows are merged in, replacing rows with the same key.
+    Incremental {
+        unique_key: Vec<String>,
+        #[serde(default)]
+        filter: Option<String>, // Predicate selecting only new rows, e.g. "updated_at > ..."
+        #[serde(default)]
+        watermark: Option<String>, // Column whose last merged value is kept between runs
+    },
+    /// Keeps every version of each row, with `valid_from`/`valid_to` columns marking when it
+    /// was current (a type-2 slowly changing dimension).
+    Snapshot {
+        strategy: SnapshotStrategy,
+        unique_key: Vec<String>,
+        updated_at: String, // Column giving when a row version took effect
+    },
+}
+
+/// How a snapshot decides that a row has changed since its current version.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotStrategy {
+    /// `updated_at` moved forward.
+    Timestamp,
+    /// Any non-key column differs. For sources whose `updated_at` can't be trusted.
+    Check,
+}
+
+impl Materialization {
+    /// As written in config: `table`, `view`, `ephemeral`, `incremental` or `snapshot`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Materialization::Table => "table",
+            Materialization::View => "view",
+            Materialization::Ephemeral => "ephemeral",
+            Materialization::Incremental { .. } => "incremental",
+            Materialization::Snapshot { .. } => "snapshot",
+        }
+    }
+}
+
+impl ResourceConfig {
+    /// The relation this resource materializes to. Lineage and selection keep using `name`.
+    pub fn relation_name(&self) -> &ResourceName {
+        self.alias.as_ref().unwrap_or(&self.name)
+    }
+
+    /// `expected_duration` and `expected_rows`, for the executor.
+    pub fn thresholds(&self) -> Thresholds {
+        Thresholds {
+            duration: self.expected_duration.map(Duration::from_secs),
+            rows: self.expected_rows,
+        }
+    }
+
+    /// `pre_hook` and `post_hook`, for the executor.
+    pub fn hooks(&self) -> NodeHooks {
+        NodeHooks {
+            pre: self.pre_hook.iter().map(|h| h.to_string()).collect(),
+            post: self.post_hook.iter().map(|h| h.to_string()).collect(),
+        }
+    }
+
+    /// The database, schema and relation this resource is materialized into under `target`:
+    /// its schema goes through `naming`, and every part is cased by `case`.
+    pub fn relation_parts(
+        &self,
+        naming: &SchemaNaming,
+        target: &str,
+        case: IdentifierCase,
+    ) -> [std::string::String; 3] {
+        [
+            self.database.as_ref().to_string(),
+            self.target_schema(naming, target),
+            self.relation_name().as_ref().to_string(),
+        ]
+        .map(|part| case.apply(&part))
+    }
+
+    /// [`ResourceConfig::relation_parts`] as `database.schema.relation`, quoted for `dialect`.
+    pub fn relation_sql(
+        &self,
+        naming: &SchemaNaming,
+        target: &str,
+        case: IdentifierCase,
+        dialect: &Dialect,
+    ) -> std::string::String {
+        self.relation_parts(naming, target, case)
+            .iter()
+            .map(|part| quote_ident(part, dialect))
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    /// Why the configured materialization can't work for this resource, if it can't. A view
+    /// with a `unique_key` never gets this far: `view` takes no options, so it fails to parse.
+    pub fn materialization_problem(&self) -> Option<&'static str> {
+        match &self.materialized {
+            Materialization::Incremental { unique_key, .. } if unique_key.is_empty() => {
+                Some("is incremental but has no unique_key to merge on")
+            }
+            Materialization::Snapshot { unique_key, .. } if unique_key.is_empty() => {
+                Some("is a snapshot but has no unique_key to track rows by")
+            }
+            Materialization::Ephemeral
+                if !self.pre_hook.is_empty() || !self.post_hook.is_empty() =>
+            {
+                Some("is ephemeral, so it is never built and its hooks would never run")
+            }
+            _ => None,
+        }
+    }
+
+    /// The schema this resource materializes into under `target`.
+    pub fn target_schema(&self, naming: &SchemaNaming, target: &str) -> std::string::String {
+        naming.schema_for(target, &self.schema)
+    }
+}
+
+/// How a resource's configured schema maps to the schema it is built in, e.g. to keep each
+/// environment's models apart.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaNaming {
+    /// Use the configured schema as-is.
+    #[default]
+    Raw,
+    /// `{target}` and `{schema}` are replaced by the target name and the configured schema.
+    Template(String),
+}
+
+impl SchemaNaming {
+    pub fn schema_for(&self, target: &str, schema: &ResourceName) -> std::string::String {
+        match self {
+            SchemaNaming::Raw => schema.as_ref().to_string(),
+            SchemaNaming::Template(template) => template
+                .replace("{target}", target)
+                .replace("{schema}", schema.as_ref()),
+        }
+    }
+}
+
+/// How generated table and column identifiers are cased before they're emitted, to match the
+/// warehouse's folding of unquoted names: Postgres folds to lower case, Snowflake to upper.
+/// Applying the same policy to a model's create and to every reference to it keeps the two
+/// from disagreeing once quoted.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentifierCase {
+    /// Emit identifiers exactly as written.
+    #[default]
+    Preserve,
+    Lower,
+    Upper,
+}
+
+impl IdentifierCase {
+    pub fn apply(&self, ident: &str) -> std::string::String {
+        match self {
+            IdentifierCase::Preserve => ident.to_string(),
+            IdentifierCase::Lower => ident.to_lowercase(),
+            IdentifierCase::Upper => ident.to_uppercase(),
+        }
+    }
+}
+
+/// Longest identifier any supported warehouse accepts. Dialects with tighter limits are
+/// checked separately by [`ResourceName::validate_for`].
+pub const MAX_RESOURCE_NAME_LEN: usize = 255;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResourceNameError {
+    Empty,
+    TooLong { len: usize, max: usize },
+    LeadingDigit,
+    IllegalCharacter(char),
+}
+
+impl fmt::Display for ResourceNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceNameError::Empty => write!(f, "name is empty"),
+            ResourceNameError::TooLong { len, max } => {
+                write!(f, "name is {len} characters long, the maximum is {max}")
+            }
+            ResourceNameError::LeadingDigit => write!(f, "name starts with a digit"),
+            ResourceNameError::IllegalCharacter(c) => write!(
+                f,
+                "name contains {c:?}, only ASCII letters, digits and '_' are allowed"
+            ),
+        }
+    }
+}
+
+/// Unquoted identifier rules shared by the warehouses we target: ASCII letters, digits and
+/// underscores, not starting with a digit. The length is only checked once the name is known
+/// to be ASCII, so it counts characters and bytes alike.
+fn validate_resource_name(name: &str, max_len: usize) -> Result<(), ResourceNameError> {
+    let first = name.chars().next().ok_or(ResourceNameError::Empty)?;
+    if first.is_ascii_digit() {
+        return Err(ResourceNameError::LeadingDigit);
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_'))
+    {
+        return Err(ResourceNameError::IllegalCharacter(c));
+    }
+    if name.len() > max_len {
+        return Err(ResourceNameError::TooLong {
+            len: name.len(),
+            max: max_len,
+        });
+    }
+    Ok(())
+}
+
+pub use resource_name::ResourceName;
+
+// Kept in its own module so nothing else can reach past validation to the inner value. The
+// lint allowance is for prae's expansion of `validate`, not our code.
+mod resource_name {
+    #![allow(clippy::question_mark)]
+    use super::{validate_resource_name, ResourceNameError, MAX_RESOURCE_NAME_LEN};
+    use smartstring::alias::String;
+
+    prae::define! {
+        #[derive(Debug)]
+        pub ResourceName: String;
+        validate(ResourceNameError) |name| validate_resource_name(name, MAX_RESOURCE_NAME_LEN);
+        plugins: [prae::impl_serde];
+    }
+}
+
+impl ResourceName {
+    /// Re-checks the name against the identifier length limit of a specific dialect.
+    pub fn validate_for(&self, dialect: &Dialect) -> Result<(), ResourceNameError> {
+        let max_len = match dialect {
+            Dialect::PostgreSql => 63,
+            Dialect::MySql => 64,
+            Dialect::MsSql => 128,
+            _ => MAX_RESOURCE_NAME_LEN,
+        };
+        validate_resource_name(self.as_ref(), max_len)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResourceProperties {
+    pub name: ResourceName,          // Presumably, must match ResourceConfig.nam?
+    pub description: Option<String>, // Is there a crate for parsed markdown?
+    pub config: ResourceConfig,
     //tests:,
-    columns: Vec<ColumnMetada>,
+    pub columns: Vec<ColumnMetada>,
+}
+
+impl ResourceProperties {
+    /// The resources listed under `models:` in every `.yml` file under `roots`, in path
+    /// order. Files without a `models` list are skipped; unreadable directories are too.
+    pub fn load_all<P: AsRef<Path>>(roots: &[P]) -> Result<Vec<Self>, Box<figment::Error>> {
+        fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                return;
+            };
+            for path in entries.flatten().map(|entry| entry.path()) {
+                if path.is_dir() {
+                    collect(&path, files);
+                } else if path.extension().is_some_and(|ext| ext == "yml") {
+                    files.push(path);
+                }
+            }
+        }
+        let mut files = Vec::new();
+        for root in roots {
+            collect(root.as_ref(), &mut files);
+        }
+        files.sort();
+        let mut properties = Vec::new();
+        for file in files {
+            let figment = Figment::new().merge(Yaml::file(&file));
+            if figment.find_value("models").is_ok() {
+                let parsed: Vec<Self> = figment.extract_inner("models").map_err(Box::new)?;
+                properties.extend(parsed);
+            }
+        }
+        Ok(properties)
+    }
+
+    /// Metadata from the project-level `project` config overlaid with this file's own; on
+    /// overlapping keys the properties value wins.
+    pub fn effective_metadata(&self, project: &ResourceConfig) -> ResourceMetadata {
+        project
+            .metadata
+            .iter()
+            .chain(&self.config.metadata)
+            .flatten()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
 }
 
 #[derive(Debug, Deserialize)]
-struct ColumnMetada {
-    name: ResourceName,
-    description: Option<String>, // Same point about markdown here too
-    quote: bool,
+pub struct ColumnMetada {
+    pub name: ResourceName,
+    pub description: Option<String>, // Same point about markdown here too
+    pub quote: bool,
     // column_type: ??? // Surely
 }
 
 #[derive(Debug, Deserialize)]
-struct SourceConfig {
-    name: ResourceName,
-    enabled: bool,
+pub struct SourceConfig {
+    pub name: ResourceName,
+    pub enabled: bool,
+    #[serde(default)]
+    pub freshness: Option<Freshness>,
 }
 
 #[derive(Debug, Deserialize)]
-struct SourceProperties {
-    name: ResourceName,
-    database: ResourceName,
-    schema: ResourceName,
+pub struct SourceProperties {
+    pub name: ResourceName,
+    pub database: ResourceName,
+    pub schema: ResourceName,
     // tables: HashMap<String, TableProperties>,
-    meta: Option<ResourceMetadata>,
+    pub meta: Option<ResourceMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Freshness {
+    pub loaded_at_field: LoadedAtField,
+    pub warn_after: FreshnessThreshold,
+    pub error_after: FreshnessThreshold,
+    pub filter: Option<String>, // Filter clause, probably best expressed as a typed "Expression" a-la Polars and co
+}
+
+/// One source's freshness check, from [`Project::freshness_checks`].
+#[derive(Debug)]
+pub struct FreshnessCheck<'a> {
+    pub source: &'a ResourceName,
+    pub freshness: &'a Freshness,
+    pub sql: Option<std::string::String>, // `None` when excluded: not probed, treated as fresh
+}
+
+impl FreshnessCheck<'_> {
+    /// The status of an excluded source; `None` for one that has to be probed.
+    pub fn assumed_status(&self) -> Option<FreshnessStatus> {
+        self.sql.is_none().then_some(FreshnessStatus::Fresh)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FreshnessStatus {
+    Fresh,
+    Warn,
+    Error,
+}
+
+impl FreshnessStatus {
+    /// As reported: `fresh`, `warn` or `error`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FreshnessStatus::Fresh => "fresh",
+            FreshnessStatus::Warn => "warn",
+            FreshnessStatus::Error => "error",
+        }
+    }
+
+    /// Process exit code for a freshness run: 0 fresh, 1 warn, 2 error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FreshnessStatus::Fresh => 0,
+            FreshnessStatus::Warn => 1,
+            FreshnessStatus::Error => 2,
+        }
+    }
+
+    /// The worst of a set of source statuses. No sources at all counts as fresh.
+    pub fn worst(statuses: impl IntoIterator<Item = FreshnessStatus>) -> FreshnessStatus {
+        statuses.into_iter().max().unwrap_or(FreshnessStatus::Fresh)
+    }
 }
 
+/// Where a source's load time is read from.
 #[derive(Debug, Deserialize)]
-struct Freshness {
-    loaded_at_field: FullyQualifiedColumn,
-    warn_after: FreshnessThreshold,
-    error_after: FreshnessThreshold,
-    filter: Option<String>, // Filter clause, probably best expressed as a typed "Expression" a-la Polars and co
+#[serde(untagged)]
+pub enum LoadedAtField {
+    Single(FullyQualifiedColumn),
+    /// Several columns of one table, e.g. one per partition. The source counts as loaded at
+    /// the latest of them.
+    Composite {
+        table: FullyQualifiedTable,
+        columns: Vec<ResourceName>,
+    },
+}
+
+impl Freshness {
+    pub fn build_check_sql(&self, dialect: &Dialect) -> std::string::String {
+        let (table, loaded_at) = match &self.loaded_at_field {
+            LoadedAtField::Single(field) => (
+                &field.table,
+                format!("max({})", quote_ident(field.column.as_ref(), dialect)),
+            ),
+            LoadedAtField::Composite { table, columns } => {
+                let maxes: Vec<_> = columns
+                    .iter()
+                    .map(|c| format!("max({})", quote_ident(c.as_ref(), dialect)))
+                    .collect();
+                (table, format!("greatest({})", maxes.join(", ")))
+            }
+        };
+        let table = table.to_sql(dialect);
+        match &self.filter {
+            Some(filter) => format!("SELECT {loaded_at} FROM {table} WHERE {filter}"),
+            None => format!("SELECT {loaded_at} FROM {table}"),
+        }
+    }
+
+    /// Classifies a source given how long ago it was last loaded. `None` (the check query
+    /// found no rows, so max() was NULL) is treated as maximally stale.
+    pub fn classify(&self, age: Option<Duration>) -> FreshnessStatus {
+        match age {
+            Some(age) if age < self.warn_after.as_duration() => FreshnessStatus::Fresh,
+            Some(age) if age < self.error_after.as_duration() => FreshnessStatus::Warn,
+            _ => FreshnessStatus::Error,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct FullyQualifiedTable {
-    database: ResourceName,
-    schema: ResourceName,
-    table: ResourceName,
+    pub database: ResourceName,
+    pub schema: ResourceName,
+    pub table: ResourceName,
+}
+
+impl FullyQualifiedTable {
+    pub fn to_sql(&self, dialect: &Dialect) -> std::string::String {
+        [&self.database, &self.schema, &self.table]
+            .iter()
+            .map(|part| quote_ident(part.as_ref(), dialect))
+            .collect::<Vec<_>>()
+            .join(".")
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct FullyQualifiedColumn {
-    table: FullyQualifiedTable,
-    column: ResourceName,
+    pub table: FullyQualifiedTable,
+    pub column: ResourceName,
 }
 
 #[derive(Debug, Deserialize)]
-struct FreshnessThreshold {
-    count: u32,
-    period: FreshnessPeriod,
+pub struct FreshnessThreshold {
+    pub count: u32,
+    pub period: FreshnessPeriod,
 }
 
 #[derive(Debug, Deserialize)]
-enum FreshnessPeriod {
+pub enum FreshnessPeriod {
     Hour,
     Day,
     Month,
 }
+
+impl FreshnessThreshold {
+    /// Months are taken as 30 days.
+    pub fn as_duration(&self) -> Duration {
+        let period_secs = match self.period {
+            FreshnessPeriod::Hour => 60 * 60,
+            FreshnessPeriod::Day => 24 * 60 * 60,
+            FreshnessPeriod::Month => 30 * 24 * 60 * 60,
+        };
+        Duration::from_secs(u64::from(self.count) * period_secs)
+    }
+}
+
+#[cfg(test)]
+mod test_settings {
+    use super::*;
+    use prae::Wrapper;
+
+    fn name(n: &str) -> ResourceName {
+        ResourceName::new(n).unwrap()
+    }
+
+    fn orders_freshness(filter: Option<&str>) -> Freshness {
+        Freshness {
+            loaded_at_field: LoadedAtField::Single(FullyQualifiedColumn {
+                table: FullyQualifiedTable {
+                    database: name("raw"),
+                    schema: name("public"),
+                    table: name("orders"),
+                },
+                column: name("loaded_at"),
+            }),
+            warn_after: FreshnessThreshold {
+                count: 12,
+                period: FreshnessPeriod::Hour,
+            },
+            error_after: FreshnessThreshold {
+                count: 1,
+                period: FreshnessPeriod::Day,
+            },
+            filter: filter.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_resource_name_validation() {
+        assert!(ResourceName::new("stg_orders_2").is_ok());
+        assert_eq!(
+            ResourceName::new("stg orders").unwrap_err().original,
+            ResourceNameError::IllegalCharacter(' ')
+        );
+        assert_eq!(
+            ResourceName::new("").unwrap_err().original,
+            ResourceNameError::Empty
+        );
+        assert_eq!(
+            ResourceName::new("2fast").unwrap_err().original,
+            ResourceNameError::LeadingDigit
+        );
+        let too_long = "a".repeat(MAX_RESOURCE_NAME_LEN + 1);
+        assert!(matches!(
+            ResourceName::new(too_long.as_str()).unwrap_err().original,
+            ResourceNameError::TooLong { .. }
+        ));
+    }
+
+    #[test]
+    fn test_resource_name_dialect_length() {
+        let long_name = name(&"a".repeat(64));
+        assert_eq!(
+            long_name.validate_for(&Dialect::PostgreSql),
+            Err(ResourceNameError::TooLong { len: 64, max: 63 })
+        );
+        assert!(long_name.validate_for(&Dialect::Snowflake).is_ok());
+        // 200 characters but 400 bytes: the character is what's wrong, not the length.
+        let accented = "é".repeat(200);
+        assert_eq!(
+            ResourceName::new(accented.as_str()).unwrap_err().original,
+            ResourceNameError::IllegalCharacter('é')
+        );
+    }
+
+    #[test]
+    fn test_resource_name_validated_on_deserialize() {
+        let parse = |yaml: &str| {
+            figment::Figment::from(Yaml::string(yaml))
+                .extract::<SourceConfig>()
+                .map_err(|e| e.to_string())
+        };
+        assert!(parse("name: orders\nenabled: true").is_ok());
+        let err = parse("name: raw orders\nenabled: true").unwrap_err();
+        assert!(err.contains("only ASCII letters"));
+    }
+
+    #[test]
+    fn test_alias_overrides_relation_name() {
+        let parse = |yaml: &str| {
+            figment::Figment::from(Yaml::string(yaml))
+                .extract::<ResourceConfig>()
+                .unwrap()
+        };
+        let base = "name: stg_orders\nenabled: true\ndatabase: analytics\nschema: staging\nexclude_full_refresh: false\n";
+        let plain = parse(base);
+        assert_eq!(plain.relation_name().get(), "stg_orders");
+        let aliased = parse(&format!("{base}alias: staging_orders\n"));
+        assert_eq!(aliased.name.get(), "stg_orders");
+        assert_eq!(aliased.relation_name().get(), "staging_orders");
+    }
+
+    #[test]
+    fn test_schema_naming_strategies() {
+        let analytics = name("analytics");
+        let templated = figment::Figment::from(Yaml::string("template: \"{target}_{schema}\""))
+            .extract::<SchemaNaming>()
+            .unwrap();
+        assert_eq!(templated.schema_for("dev", &analytics), "dev_analytics");
+        assert_eq!(SchemaNaming::Raw.schema_for("dev", &analytics), "analytics");
+        assert_eq!(SchemaNaming::default(), SchemaNaming::Raw);
+
+        let project = |settings: &str| {
+            let yaml = format!(
+                "name: shop\nversion: \"0.1.0\"\nmodel_path: .\nseed_path: .\n\
+                 clean_targets: target\nlog_path: logs\nseeds: []\nsources: []\n\
+                 models: [{{ name: Orders, database: warehouse, schema: analytics }}]\n{settings}"
+            );
+            figment::Figment::from(Yaml::string(&yaml))
+                .extract::<Project>()
+                .unwrap()
+        };
+        let relation = |project: Project| project.relation_sql(&project.models[0]);
+        assert_eq!(relation(project("")), r#""warehouse"."analytics"."Orders""#);
+        assert_eq!(
+            relation(project(
+                "schema_naming: { template: \"{target}_{schema}\" }"
+            )),
+            r#""warehouse"."dev_analytics"."Orders""#
+        );
+        assert_eq!(
+            relation(project(
+                "schema_naming: { template: \"{target}_{schema}\" }\ntarget_name: prod\n\
+                 identifier_case: lower"
+            )),
+            r#""warehouse"."prod_analytics"."orders""#
+        );
+    }
+
+    #[test]
+    fn test_default_dialect_applies_unless_overridden() {
+        use crate::query_graph::query::QueryCollection;
+        let yaml = r#"
+name: shop
+version: "0.1.0"
+model_path: models
+seed_path: seeds
+clean_targets: target
+log_path: logs
+default_dialect: mssql
+models: []
+seeds: []
+sources: []
+"#;
+        let parse = |yaml: &str| {
+            figment::Figment::from(Yaml::string(yaml))
+                .extract::<Project>()
+                .unwrap()
+        };
+        let project = parse(yaml);
+        assert_eq!(project.dialect(), Dialect::MsSql);
+        let collection = QueryCollection::new();
+        let compile = |prql: &str| {
+            collection
+                .prepare_query(prql, "recent")
+                .unwrap()
+                .compile(&project.dialect())
+                .unwrap()
+        };
+        assert!(compile("from orders | take 3").contains("TOP (3)"));
+        let headered = compile("prql dialect:postgres\nfrom orders | take 3");
+        assert!(
+            headered.contains("LIMIT") && !headered.contains("TOP"),
+            "{headered}"
+        );
+
+        let with_adapter = parse(&format!("{yaml}connection: {{ adapter: postgres }}\n"));
+        assert_eq!(with_adapter.dialect(), Dialect::PostgreSql);
+        let custom_adapter = parse(&format!("{yaml}connection: {{ adapter: in_house }}\n"));
+        assert_eq!(custom_adapter.dialect(), Dialect::MsSql);
+        assert_eq!(
+            parse(&yaml.replace("default_dialect: mssql\n", "")).dialect(),
+            Dialect::Generic
+        );
+    }
+
+    #[test]
+    fn test_resources_inherit_project_defaults() {
+        let yaml = r#"
+name: shop
+version: "0.1.0"
+model_path: models
+seed_path: seeds
+clean_targets: target
+log_path: logs
+defaults:
+  database: analytics
+  schema: marts
+models:
+  - name: orders
+  - { name: stg_orders, schema: staging, enabled: false }
+seeds: []
+sources: []
+"#;
+        let project = Project::from_figment(figment::Figment::from(Yaml::string(yaml))).unwrap();
+        let orders = &project.models[0];
+        assert_eq!(orders.database.as_ref(), "analytics");
+        assert_eq!(orders.schema.as_ref(), "marts");
+        assert!(orders.enabled);
+        assert!(!orders.exclude_full_refresh);
+        let stg_orders = &project.models[1];
+        assert_eq!(stg_orders.database.as_ref(), "analytics");
+        assert_eq!(stg_orders.schema.as_ref(), "staging");
+        assert!(!stg_orders.enabled);
+
+        let without_defaults = yaml.replace("defaults:", "unused:");
+        assert!(
+            Project::from_figment(figment::Figment::from(Yaml::string(&without_defaults))).is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_reports_every_config_problem() {
+        let yaml = r#"
+name: shop
+version: "0.1.0"
+model_path: does/not/exist
+seed_path: .
+clean_targets: target
+log_path: logs
+max_models: 500
+models: []
+seeds: []
+sources:
+  - name: orders
+    enabled: true
+    freshness:
+      loaded_at_field:
+        table: { database: raw, schema: public, table: orders }
+        column: loaded_at
+      warn_after: { count: 2, period: Day }
+      error_after: { count: 12, period: Hour }
+"#;
+        let project = figment::Figment::from(Yaml::string(yaml))
+            .extract::<Project>()
+            .unwrap();
+        assert_eq!(project.limits.max_models, Some(500));
+        assert_eq!(project.limits.max_edges, None);
+        assert_eq!(
+            project.validate(),
+            vec![
+                ConfigError::MissingPath {
+                    field: "model_path",
+                    path: PathBuf::from("does/not/exist"),
+                },
+                ConfigError::WarnAfterExceedsErrorAfter(String::from("orders")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_every_missing_loaded_at_column_is_reported() {
+        let yaml = r#"
+name: shop
+version: "0.1.0"
+model_path: .
+seed_path: .
+clean_targets: target
+log_path: logs
+models: []
+seeds: []
+sources:
+  - name: orders
+    enabled: true
+    freshness:
+      loaded_at_field:
+        table: { database: raw, schema: public, table: orders }
+        column: loaded_at
+      warn_after: { count: 12, period: Hour }
+      error_after: { count: 1, period: Day }
+  - name: events
+    enabled: true
+    freshness:
+      loaded_at_field:
+        table: { database: raw, schema: public, table: events }
+        columns: [eu_loaded_at, us_loaded_at, apac_loaded_at]
+      warn_after: { count: 12, period: Hour }
+      error_after: { count: 1, period: Day }
+  - name: refunds
+    enabled: false
+    freshness:
+      loaded_at_field:
+        table: { database: raw, schema: public, table: refunds }
+        column: loaded_at
+      warn_after: { count: 12, period: Hour }
+      error_after: { count: 1, period: Day }
+"#;
+        let project = figment::Figment::from(Yaml::string(yaml))
+            .extract::<Project>()
+            .unwrap();
+        let mut backend = crate::testing::StubBackend::new("postgres")
+            .with_columns("raw", "public", "orders", &["id", "_loaded_at"])
+            .with_columns("raw", "public", "events", &["eu_loaded_at"]);
+        assert_eq!(
+            project
+                .validate_loaded_at_fields(&mut backend)
+                .unwrap()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "source orders is loaded at raw.public.orders.loaded_at, which does not exist",
+                "source events is loaded at raw.public.events.us_loaded_at, which does not exist",
+                "source events is loaded at raw.public.events.apac_loaded_at, which does not exist",
+            ]
+        );
+
+        let mut backend = backend.with_columns("raw", "public", "orders", &["loaded_at"]);
+        assert_eq!(
+            project
+                .validate_loaded_at_fields(&mut backend)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_models_find_the_connection_for_their_database() {
+        let yaml = r#"
+name: shop
+version: "0.1.0"
+model_path: .
+seed_path: .
+clean_targets: target
+log_path: logs
+connections:
+  analytics: { adapter: snowflake }
+  finance: { adapter: postgres }
+models:
+  - { name: orders, database: analytics, schema: marts }
+  - { name: ledger, database: accounting, schema: marts }
+seeds: []
+sources: []
+"#;
+        let project = figment::Figment::from(Yaml::string(yaml))
+            .extract::<Project>()
+            .unwrap();
+        let adapter = |db: &str| project.connection_for(db).map(|c| c.adapter.as_str());
+        assert_eq!(adapter("analytics"), Some("snowflake"));
+        assert_eq!(adapter("finance"), Some("postgres"));
+        assert_eq!(adapter("accounting"), None);
+        assert_eq!(
+            project.validate(),
+            vec![ConfigError::NoConnection {
+                resource: String::from("ledger"),
+                database: String::from("accounting"),
+            }]
+        );
+
+        let mut factory = BackendFactory::new();
+        factory.register_backend("snowflake", |_| {
+            Ok(Box::new(crate::testing::InMemoryBackend::new()))
+        });
+        let unsupported = project.validate_adapters(&factory);
+        assert_eq!(
+            unsupported
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "connections.finance uses unsupported adapter \"postgres\"; \
+                 supported adapters are snowflake"
+            ]
+        );
+        assert_eq!(project.validate_adapters(&BackendFactory::new()).len(), 2);
+    }
+
+    #[test]
+    fn test_models_aliased_to_the_same_relation_conflict() {
+        let yaml = r#"
+name: shop
+version: "0.1.0"
+model_path: .
+seed_path: .
+clean_targets: target
+log_path: logs
+models:
+  - { name: orders, database: analytics, schema: marts }
+  - { name: orders_v2, database: analytics, schema: marts, alias: orders }
+  - { name: orders_old, database: analytics, schema: marts, alias: orders, enabled: false }
+  - { name: stg_orders, database: analytics, schema: staging, alias: orders }
+seeds: []
+sources: []
+"#;
+        let project = figment::Figment::from(Yaml::string(yaml))
+            .extract::<Project>()
+            .unwrap();
+        assert_eq!(
+            project.validate(),
+            vec![ConfigError::DuplicateTarget {
+                target: String::from("analytics.marts.orders"),
+                first: String::from("orders"),
+                second: String::from("orders_v2"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_incremental_materialization_config() {
+        let materialization = figment::Figment::from(Yaml::string(
+            "incremental:\n  unique_key: [id]\n  filter: updated_at > now() - interval '1 day'",
+        ))
+        .extract::<Materialization>()
+        .unwrap();
+        assert_eq!(
+            materialization,
+            Materialization::Incremental {
+                unique_key: vec![String::from("id")],
+                filter: Some(String::from("updated_at > now() - interval '1 day'")),
+                watermark: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_materialization_must_fit_the_model() {
+        let parse = |materialized: &str| {
+            let yaml = format!(
+                "name: orders\nenabled: true\ndatabase: analytics\nschema: marts\nexclude_full_refresh: false\nmaterialized: {materialized}"
+            );
+            figment::Figment::from(Yaml::string(&yaml))
+                .extract::<ResourceConfig>()
+                .map_err(|e| e.to_string())
+        };
+        let keyless = parse("{ incremental: { unique_key: [] } }").unwrap();
+        assert_eq!(
+            keyless.materialization_problem(),
+            Some("is incremental but has no unique_key to merge on")
+        );
+        let keyed = parse("{ incremental: { unique_key: [id] } }").unwrap();
+        assert_eq!(keyed.materialization_problem(), None);
+        assert!(parse("{ view: { unique_key: [id] } }").is_err());
+        assert_eq!(parse("view").unwrap().materialization_problem(), None);
+    }
+
+    #[test]
+    fn test_snapshot_materialization_config() {
+        let materialization = figment::Figment::from(Yaml::string(
+            "snapshot:\n  strategy: check\n  unique_key: [customer_id]\n  updated_at: updated_at",
+        ))
+        .extract::<Materialization>()
+        .unwrap();
+        assert_eq!(
+            materialization,
+            Materialization::Snapshot {
+                strategy: SnapshotStrategy::Check,
+                unique_key: vec![String::from("customer_id")],
+                updated_at: String::from("updated_at"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_typed_vars_substitute_with_correct_quoting() {
+        let vars =
+            figment::Figment::from(Yaml::string("min_age: 35\ncountry: \"35\"\nactive: true"))
+                .extract::<HashMap<String, VarValue>>()
+                .unwrap();
+        assert_eq!(vars["min_age"], VarValue::Int(35));
+        assert_eq!(vars["country"], VarValue::String(String::from("35")));
+        assert_eq!(vars["active"], VarValue::Bool(true));
+
+        let query = substitute_vars(
+            "from people | filter age > {{ min_age }} | filter country_code == {{country}}",
+            &vars,
+        )
+        .unwrap();
+        let sql = prql_compiler::compile(&query).unwrap();
+        assert!(sql.contains("age > 35"));
+        assert!(sql.contains("country_code = '35'"));
+        assert_eq!(
+            substitute_vars("from people | filter age > {{ max_age }}", &vars),
+            Err(VarError::Undefined("max_age".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_properties_metadata_overrides_project_metadata() {
+        let config = |metadata: &str| {
+            let yaml = format!(
+                "name: orders\nenabled: true\ndatabase: analytics\nschema: marts\nexclude_full_refresh: false\nmetadata: {metadata}"
+            );
+            figment::Figment::from(Yaml::string(&yaml))
+                .extract::<ResourceConfig>()
+                .unwrap()
+        };
+        let project = config("{ owner: data-eng, tier: gold }");
+        let properties = ResourceProperties {
+            name: name("orders"),
+            description: None,
+            config: config("{ owner: finance, pii: \"false\" }"),
+            columns: Vec::new(),
+        };
+        let metadata = properties.effective_metadata(&project);
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata["owner"], "finance");
+        assert_eq!(metadata["tier"], "gold");
+        assert_eq!(metadata["pii"], "false");
+    }
+
+    #[test]
+    fn test_freshness_check_sql_for_postgres() {
+        assert_eq!(
+            orders_freshness(None).build_check_sql(&Dialect::PostgreSql),
+            r#"SELECT max("loaded_at") FROM "raw"."public"."orders""#
+        );
+        assert_eq!(
+            orders_freshness(Some("status != 'test'")).build_check_sql(&Dialect::PostgreSql),
+            r#"SELECT max("loaded_at") FROM "raw"."public"."orders" WHERE status != 'test'"#
+        );
+    }
+
+    #[test]
+    fn test_composite_freshness_takes_latest_column() {
+        let yaml = r#"
+loaded_at_field:
+  table: { database: raw, schema: public, table: events }
+  columns: [eu_loaded_at, us_loaded_at]
+warn_after: { count: 12, period: Hour }
+error_after: { count: 1, period: Day }
+"#;
+        let freshness = figment::Figment::from(Yaml::string(yaml))
+            .extract::<Freshness>()
+            .unwrap();
+        assert_eq!(
+            freshness.build_check_sql(&Dialect::PostgreSql),
+            r#"SELECT greatest(max("eu_loaded_at"), max("us_loaded_at")) FROM "raw"."public"."events""#
+        );
+        let hours = |h: u64| Some(Duration::from_secs(h * 60 * 60));
+        assert_eq!(freshness.classify(hours(2)), FreshnessStatus::Fresh);
+        assert_eq!(freshness.classify(hours(13)), FreshnessStatus::Warn);
+    }
+
+    #[test]
+    fn test_excluded_sources_are_not_probed() {
+        let yaml = r#"
+name: shop
+version: "0.1.0"
+model_path: .
+seed_path: .
+clean_targets: target
+log_path: logs
+freshness_exclude: [events]
+models: []
+seeds: []
+sources:
+  - name: orders
+    enabled: true
+    freshness:
+      loaded_at_field:
+        table: { database: raw, schema: public, table: orders }
+        column: loaded_at
+      warn_after: { count: 12, period: Hour }
+      error_after: { count: 1, period: Day }
+  - name: customers
+    enabled: true
+    freshness:
+      loaded_at_field:
+        table: { database: raw, schema: public, table: customers }
+        column: loaded_at
+      warn_after: { count: 12, period: Hour }
+      error_after: { count: 1, period: Day }
+  - { name: events, enabled: true }
+"#;
+        let project = figment::Figment::from(Yaml::string(yaml))
+            .extract::<Project>()
+            .unwrap();
+        let checks = project
+            .freshness_checks(&Dialect::PostgreSql, &["customers"])
+            .unwrap();
+        let planned: Vec<_> = checks
+            .iter()
+            .map(|c| (c.source.get().as_str(), c.sql.as_deref()))
+            .collect();
+        assert_eq!(
+            planned,
+            vec![
+                (
+                    "orders",
+                    Some(r#"SELECT max("loaded_at") FROM "raw"."public"."orders""#)
+                ),
+                ("customers", None),
+            ]
+        );
+        assert_eq!(checks[1].assumed_status(), Some(FreshnessStatus::Fresh));
+        assert_eq!(
+            project
+                .freshness_checks(&Dialect::PostgreSql, &["custmers"])
+                .unwrap_err(),
+            vec![ConfigError::UnknownSource(String::from("custmers"))]
+        );
+    }
+
+    #[test]
+    fn test_freshness_classification_boundaries() {
+        let freshness = orders_freshness(None);
+        let hours = |h: u64| Some(Duration::from_secs(h * 60 * 60));
+        assert_eq!(freshness.classify(hours(0)), FreshnessStatus::Fresh);
+        assert_eq!(freshness.classify(hours(11)), FreshnessStatus::Fresh);
+        assert_eq!(freshness.classify(hours(12)), FreshnessStatus::Warn);
+        assert_eq!(freshness.classify(hours(23)), FreshnessStatus::Warn);
+        assert_eq!(freshness.classify(hours(24)), FreshnessStatus::Error);
+        assert_eq!(freshness.classify(None), FreshnessStatus::Error);
+    }
+
+    #[test]
+    fn test_worst_freshness_status_sets_exit_code() {
+        use FreshnessStatus::*;
+        assert_eq!(FreshnessStatus::worst([]).exit_code(), 0);
+        assert_eq!(FreshnessStatus::worst([Fresh, Fresh]).exit_code(), 0);
+        assert_eq!(FreshnessStatus::worst([Fresh, Warn, Fresh]).exit_code(), 1);
+        assert_eq!(FreshnessStatus::worst([Warn, Error, Fresh]).exit_code(), 2);
+    }
+}
@@ -0,0 +1,399 @@
+use crate::settings::{Materialization, SnapshotStrategy};
+use prql_compiler::ast::pl::Dialect;
+use sqlformat::{FormatOptions, Indent, QueryParams};
+
+/// Quotes an identifier using the dialect's quote character, doubling any embedded quotes.
+pub fn quote_ident(ident: &str, dialect: &Dialect) -> String {
+    let quote = dialect.handler().ident_quote();
+    let escaped = ident.replace(quote, &format!("{quote}{quote}"));
+    format!("{quote}{escaped}{quote}")
+}
+
+/// Canonical layout for generated SQL, so that compiled artifacts diff cleanly regardless of
+/// how the compiler happened to lay out its output. Keywords are uppercased, except for
+/// ClickHouse, where unquoted identifiers are case-sensitive and a column sharing a keyword's
+/// name would be renamed by it.
+pub fn format_sql(sql: &str, dialect: Dialect) -> String {
+    let options = FormatOptions {
+        indent: Indent::Spaces(2),
+        uppercase: !matches!(dialect, Dialect::ClickHouse),
+        lines_between_queries: 1,
+    };
+    sqlformat::format(sql, &QueryParams::None, options)
+}
+
+/// The statement removing a model's relation, if it has one. Ephemeral models are never
+/// built, so there's nothing to drop.
+pub fn drop_statement(target: &str, materialized: &Materialization) -> Option<String> {
+    match materialized {
+        Materialization::Ephemeral => None,
+        Materialization::View => Some(format!("DROP VIEW IF EXISTS {target}")),
+        _ => Some(format!("DROP TABLE IF EXISTS {target}")),
+    }
+}
+
+/// The statements building a model's relation from `select` from scratch, replacing
+/// whatever was there. Ephemeral models are never built, so there are none.
+pub fn create_statements(
+    target: &str,
+    select: &str,
+    materialized: &Materialization,
+) -> Vec<String> {
+    match materialized {
+        Materialization::Ephemeral => Vec::new(),
+        Materialization::View => vec![format!("CREATE OR REPLACE VIEW {target} AS {select}")],
+        _ => vec![
+            format!("DROP TABLE IF EXISTS {target}"),
+            format!("CREATE TABLE {target} AS {select}"),
+        ],
+    }
+}
+
+/// An incremental model's compiled query and what to merge it into.
+pub struct IncrementalSql<'a> {
+    pub target: &'a str, // Already-rendered relation, e.g. from `FullyQualifiedTable::to_sql`
+    pub select: &'a str,
+    pub columns: &'a [&'a str],
+    pub unique_key: &'a [&'a str],
+    pub filter: Option<&'a str>,
+    pub watermark: Option<(&'a str, &'a str)>, // (column, last merged value) from a `WatermarkStore`
+}
+
+impl IncrementalSql<'_> {
+    /// Statements to run, in order. While the target doesn't exist yet it's built in full,
+    /// ignoring the filter and watermark. Otherwise only rows matching the filter and past
+    /// the watermark are merged.
+    pub fn statements(&self, dialect: &Dialect, target_exists: bool) -> Vec<String> {
+        let target = self.target;
+        if !target_exists {
+            return vec![format!("CREATE TABLE {target} AS {}", self.select)];
+        }
+        let quote_all = |names: &[&str]| {
+            names
+                .iter()
+                .map(|n| quote_ident(n, dialect))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let columns = quote_all(self.columns);
+        let keys = quote_all(self.unique_key);
+        let watermark = self.watermark.map(|(column, value)| {
+            format!(
+                "{} > '{}'",
+                quote_ident(column, dialect),
+                value.replace('\'', "''")
+            )
+        });
+        // Each in parentheses, so a filter with an `OR` doesn't swallow the watermark.
+        let conditions: Vec<_> = self
+            .filter
+            .into_iter()
+            .chain(watermark.as_deref())
+            .map(|condition| format!("({condition})"))
+            .collect();
+        let new_rows = if conditions.is_empty() {
+            format!("SELECT {columns} FROM ({}) AS src", self.select)
+        } else {
+            format!(
+                "SELECT {columns} FROM ({}) AS src WHERE {}",
+                self.select,
+                conditions.join(" AND ")
+            )
+        };
+        let insert = format!("INSERT INTO {target} ({columns}) {new_rows}");
+        match dialect {
+            // Needs a unique index on the key, which the initial create doesn't add.
+            Dialect::PostgreSql => {
+                let updates: Vec<_> = self
+                    .columns
+                    .iter()
+                    .filter(|c| !self.unique_key.contains(c))
+                    .map(|c| {
+                        let c = quote_ident(c, dialect);
+                        format!("{c} = EXCLUDED.{c}")
+                    })
+                    .collect();
+                let action = if updates.is_empty() {
+                    "DO NOTHING".to_string()
+                } else {
+                    format!("DO UPDATE SET {}", updates.join(", "))
+                };
+                vec![format!("{insert} ON CONFLICT ({keys}) {action}")]
+            }
+            // A ReplacingMergeTree keyed on the unique key collapses duplicates itself.
+            Dialect::ClickHouse => vec![insert],
+            _ => vec![
+                format!(
+                    "DELETE FROM {target} WHERE ({keys}) IN (SELECT {keys} FROM ({new_rows}) AS new_rows)"
+                ),
+                insert,
+            ],
+        }
+    }
+}
+
+pub const VALID_FROM_COLUMN: &str = "valid_from";
+pub const VALID_TO_COLUMN: &str = "valid_to";
+
+/// A snapshot model's compiled query and the history table it's tracked in. Current versions
+/// are the rows whose `valid_to` is null.
+pub struct SnapshotSql<'a> {
+    pub target: &'a str, // Already-rendered relation, e.g. from `FullyQualifiedTable::to_sql`
+    pub select: &'a str,
+    pub columns: &'a [&'a str],
+    pub unique_key: &'a [&'a str],
+    pub updated_at: &'a str,
+    pub strategy: SnapshotStrategy,
+}
+
+impl SnapshotSql<'_> {
+    /// Statements to run, in order: changed rows have their current version closed out at the
+    /// new `updated_at`, then every key without a current version gets one.
+    pub fn statements(&self, dialect: &Dialect, target_exists: bool) -> Vec<String> {
+        let (target, select) = (self.target, self.select);
+        let q = |name: &str| quote_ident(name, dialect);
+        let (valid_from, valid_to, updated_at) =
+            (q(VALID_FROM_COLUMN), q(VALID_TO_COLUMN), q(self.updated_at));
+        if !target_exists {
+            return vec![format!(
+                "CREATE TABLE {target} AS SELECT src.*, src.{updated_at} AS {valid_from}, \
+                 CAST(NULL AS TIMESTAMP) AS {valid_to} FROM ({select}) AS src"
+            )];
+        }
+        let keys_match = self
+            .unique_key
+            .iter()
+            .map(|k| format!("src.{k} = {target}.{k}", k = q(k)))
+            .collect::<Vec<_>>()
+            .join(" AND ");
+        let changed = match self.strategy {
+            SnapshotStrategy::Timestamp => format!("src.{updated_at} > {target}.{updated_at}"),
+            // Null-safe, so a column going to or from null counts as a change.
+            SnapshotStrategy::Check => {
+                let checks: Vec<_> = self
+                    .columns
+                    .iter()
+                    .filter(|c| !self.unique_key.contains(c))
+                    .map(|c| format!("src.{c} IS DISTINCT FROM {target}.{c}", c = q(c)))
+                    .collect();
+                format!("({})", checks.join(" OR "))
+            }
+        };
+        let columns = self
+            .columns
+            .iter()
+            .map(|c| q(c))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let src_columns = self
+            .columns
+            .iter()
+            .map(|c| format!("src.{}", q(c)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        vec![
+            format!(
+                "UPDATE {target} SET {valid_to} = (SELECT src.{updated_at} FROM ({select}) AS src \
+                 WHERE {keys_match}) WHERE {target}.{valid_to} IS NULL AND EXISTS (SELECT 1 FROM \
+                 ({select}) AS src WHERE {keys_match} AND {changed})"
+            ),
+            format!(
+                "INSERT INTO {target} ({columns}, {valid_from}, {valid_to}) SELECT {src_columns}, \
+                 src.{updated_at}, NULL FROM ({select}) AS src WHERE NOT EXISTS (SELECT 1 FROM \
+                 {target} WHERE {keys_match} AND {target}.{valid_to} IS NULL)"
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test_sql {
+    use super::*;
+
+    #[test]
+    fn test_quote_ident_per_dialect() {
+        assert_eq!(quote_ident("orders", &Dialect::PostgreSql), "\"orders\"");
+        assert_eq!(quote_ident("orders", &Dialect::ClickHouse), "`orders`");
+        assert_eq!(
+            quote_ident("we\"ird", &Dialect::PostgreSql),
+            "\"we\"\"ird\""
+        );
+    }
+
+    #[test]
+    fn test_equivalent_prql_formats_identically() {
+        let terse = "from employees | filter age > 35 | select [name, age]";
+        let spread = r#"from employees
+        filter   age > 35
+
+        select [
+            name,
+            age,
+        ]"#;
+        let terse_sql = format_sql(&prql_compiler::compile(terse).unwrap(), Dialect::Generic);
+        let spread_sql = format_sql(&prql_compiler::compile(spread).unwrap(), Dialect::Generic);
+        assert_eq!(terse_sql, spread_sql);
+    }
+
+    #[test]
+    fn test_whitespace_is_canonicalised() {
+        let a = "select name,age from employees where age > 35";
+        let b = "SELECT\n  name,\n     age\nFROM employees\n\nWHERE age > 35";
+        assert_eq!(
+            format_sql(a, Dialect::PostgreSql),
+            format_sql(b, Dialect::PostgreSql)
+        );
+        assert_eq!(
+            format_sql(a, Dialect::PostgreSql),
+            "SELECT\n  name,\n  age\nFROM\n  employees\nWHERE\n  age > 35"
+        );
+    }
+
+    #[test]
+    fn test_clickhouse_keeps_the_case_it_was_given() {
+        assert_eq!(
+            format_sql("select date, count() from events", Dialect::ClickHouse),
+            "select\n  date,\n  count()\nfrom\n  events"
+        );
+    }
+
+    #[test]
+    fn test_tables_are_rebuilt_and_views_replaced() {
+        let select = "SELECT id FROM raw_orders";
+        assert_eq!(
+            create_statements("orders", select, &Materialization::Table),
+            vec![
+                "DROP TABLE IF EXISTS orders",
+                "CREATE TABLE orders AS SELECT id FROM raw_orders"
+            ]
+        );
+        assert_eq!(
+            create_statements("orders", select, &Materialization::View),
+            vec!["CREATE OR REPLACE VIEW orders AS SELECT id FROM raw_orders"]
+        );
+        assert!(create_statements("orders", select, &Materialization::Ephemeral).is_empty());
+    }
+
+    fn orders_incremental<'a>() -> IncrementalSql<'a> {
+        IncrementalSql {
+            target: "\"analytics\".\"orders\"",
+            select: "SELECT id, amount, updated_at FROM raw_orders",
+            columns: &["id", "amount", "updated_at"],
+            unique_key: &["id"],
+            filter: Some("updated_at > now() - interval '1 day'"),
+            watermark: None,
+        }
+    }
+
+    #[test]
+    fn test_incremental_postgres_upserts_on_unique_key() {
+        assert_eq!(
+            orders_incremental().statements(&Dialect::PostgreSql, true),
+            vec![concat!(
+                r#"INSERT INTO "analytics"."orders" ("id", "amount", "updated_at") "#,
+                r#"SELECT "id", "amount", "updated_at" FROM (SELECT id, amount, updated_at FROM raw_orders) AS src "#,
+                r#"WHERE (updated_at > now() - interval '1 day') "#,
+                r#"ON CONFLICT ("id") DO UPDATE SET "amount" = EXCLUDED."amount", "updated_at" = EXCLUDED."updated_at""#,
+            )]
+        );
+    }
+
+    #[test]
+    fn test_incremental_watermark_bounds_new_rows() {
+        let sql = IncrementalSql {
+            filter: None,
+            watermark: Some(("updated_at", "2024-03-01 00:00:00")),
+            ..orders_incremental()
+        };
+        let statements = sql.statements(&Dialect::PostgreSql, true);
+        assert!(
+            statements[0].contains(r#"AS src WHERE ("updated_at" > '2024-03-01 00:00:00') ON"#),
+            "{statements:?}"
+        );
+        let both = IncrementalSql {
+            watermark: Some(("updated_at", "it's")),
+            ..orders_incremental()
+        };
+        assert!(both.statements(&Dialect::PostgreSql, true)[0].contains(
+            r#"WHERE (updated_at > now() - interval '1 day') AND ("updated_at" > 'it''s')"#
+        ));
+        let either = IncrementalSql {
+            filter: Some("status = 'new' OR status = 'late'"),
+            watermark: Some(("updated_at", "2024-03-01")),
+            ..orders_incremental()
+        };
+        assert!(either.statements(&Dialect::PostgreSql, true)[0].contains(
+            r#"WHERE (status = 'new' OR status = 'late') AND ("updated_at" > '2024-03-01')"#
+        ));
+    }
+
+    #[test]
+    fn test_incremental_first_run_creates_table() {
+        assert_eq!(
+            orders_incremental().statements(&Dialect::PostgreSql, false),
+            vec![
+                r#"CREATE TABLE "analytics"."orders" AS SELECT id, amount, updated_at FROM raw_orders"#
+            ]
+        );
+        let generic = orders_incremental().statements(&Dialect::Generic, true);
+        assert_eq!(generic.len(), 2);
+        assert!(generic[0].starts_with(r#"DELETE FROM "analytics"."orders" WHERE ("id") IN"#));
+    }
+
+    fn customers_snapshot(strategy: SnapshotStrategy) -> SnapshotSql<'static> {
+        SnapshotSql {
+            target: "\"snapshots\".\"customers\"",
+            select: "SELECT id, tier, updated_at FROM raw_customers",
+            columns: &["id", "tier", "updated_at"],
+            unique_key: &["id"],
+            updated_at: "updated_at",
+            strategy,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_closes_out_changed_rows_and_inserts_new_versions() {
+        let statements =
+            customers_snapshot(SnapshotStrategy::Timestamp).statements(&Dialect::PostgreSql, true);
+        assert_eq!(
+            statements,
+            vec![
+                concat!(
+                    r#"UPDATE "snapshots"."customers" SET "valid_to" = (SELECT src."updated_at" "#,
+                    r#"FROM (SELECT id, tier, updated_at FROM raw_customers) AS src "#,
+                    r#"WHERE src."id" = "snapshots"."customers"."id") "#,
+                    r#"WHERE "snapshots"."customers"."valid_to" IS NULL AND EXISTS (SELECT 1 "#,
+                    r#"FROM (SELECT id, tier, updated_at FROM raw_customers) AS src "#,
+                    r#"WHERE src."id" = "snapshots"."customers"."id" "#,
+                    r#"AND src."updated_at" > "snapshots"."customers"."updated_at")"#,
+                ),
+                concat!(
+                    r#"INSERT INTO "snapshots"."customers" ("id", "tier", "updated_at", "valid_from", "valid_to") "#,
+                    r#"SELECT src."id", src."tier", src."updated_at", src."updated_at", NULL "#,
+                    r#"FROM (SELECT id, tier, updated_at FROM raw_customers) AS src "#,
+                    r#"WHERE NOT EXISTS (SELECT 1 FROM "snapshots"."customers" "#,
+                    r#"WHERE src."id" = "snapshots"."customers"."id" AND "snapshots"."customers"."valid_to" IS NULL)"#,
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_check_strategy_compares_non_key_columns() {
+        let statements =
+            customers_snapshot(SnapshotStrategy::Check).statements(&Dialect::PostgreSql, true);
+        assert!(statements[0].ends_with(concat!(
+            r#"AND (src."tier" IS DISTINCT FROM "snapshots"."customers"."tier" "#,
+            r#"OR src."updated_at" IS DISTINCT FROM "snapshots"."customers"."updated_at"))"#
+        )));
+        let initial =
+            customers_snapshot(SnapshotStrategy::Check).statements(&Dialect::PostgreSql, false);
+        assert_eq!(
+            initial,
+            vec![concat!(
+                r#"CREATE TABLE "snapshots"."customers" AS SELECT src.*, src."updated_at" AS "valid_from", "#,
+                r#"CAST(NULL AS TIMESTAMP) AS "valid_to" FROM (SELECT id, tier, updated_at FROM raw_customers) AS src"#
+            )]
+        );
+    }
+}
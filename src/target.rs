@@ -0,0 +1,173 @@
+use crate::{
+    manifest::{Manifest, MANIFEST_FILE_NAME},
+    query_graph::{query::QueryName, GraphMeta},
+};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub const COMPILED_DIR_NAME: &str = "compiled";
+
+/// The one directory every generated file lives under, so CI can cache or clean it whole:
+/// compiled SQL in `compiled/<model>.sql`, and `manifest.json` at the top.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetDir {
+    root: PathBuf,
+}
+
+impl TargetDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.root.join(MANIFEST_FILE_NAME)
+    }
+
+    /// Writes `model`'s compiled SQL, creating directories as needed, and returns its path.
+    pub fn write_compiled(&self, model: &str, sql: &str) -> io::Result<PathBuf> {
+        let dir = self.root.join(COMPILED_DIR_NAME);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{model}.sql"));
+        fs::write(&path, sql)?;
+        Ok(path)
+    }
+
+    pub fn write_manifest(&self, manifest: &Manifest) -> io::Result<PathBuf> {
+        manifest.write_to(&self.root)
+    }
+}
+
+/// The names `compile --layered` writes each node's SQL under: its name prefixed with its
+/// execution layer, zero-padded, e.g. `002_orders`. Running the files in lexical order then
+/// builds every model after everything it reads from, without orbital present.
+pub fn layered_names(meta: &GraphMeta) -> HashMap<QueryName, std::string::String> {
+    meta.named_execution_layers()
+        .into_iter()
+        .enumerate()
+        .flat_map(|(layer, names)| {
+            names.into_iter().map(move |name| {
+                let file = format!("{layer:03}_{name}");
+                (QueryName::from(name.as_str()), file)
+            })
+        })
+        .collect()
+}
+
+/// Removes each of `paths` that exists, with everything under it, returning those removed.
+/// Nothing else is touched.
+pub fn clean<P: AsRef<Path>>(paths: &[P]) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for path in paths.iter().map(AsRef::as_ref) {
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else if path.exists() {
+            fs::remove_file(path)?;
+        } else {
+            continue;
+        }
+        removed.push(path.to_path_buf());
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod test_target {
+    use super::*;
+    use crate::{
+        query_graph::query::{QueryCollection, QueryMap, QueryName, RawQuery},
+        settings::Project,
+    };
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_artifacts_land_under_configured_target_path() {
+        let dir = std::env::temp_dir().join(format!("orbital-target-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("orbital.yml");
+        fs::write(
+            &config,
+            r#"
+name: shop
+version: "0.1.0"
+model_path: models
+seed_path: seeds
+clean_targets: target
+log_path: logs
+target_path: build
+models: []
+seeds: []
+sources: []
+"#,
+        )
+        .unwrap();
+        let project = Project::from_file(&config).unwrap();
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![RawQuery::new("orders", "from raw_orders | take 3")]);
+        let sql = collection
+            .show(
+                &QueryName::from("orders"),
+                &HashSet::new(),
+                &QueryMap::default(),
+                &project.dialect(),
+            )
+            .unwrap();
+
+        let target = TargetDir::new(project.target_dir());
+        let compiled = target.write_compiled("orders", &sql).unwrap();
+        let manifest = target
+            .write_manifest(&Manifest::from_collection(&collection))
+            .unwrap();
+        let written = fs::read_to_string(&compiled).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(project.target_dir(), dir.join("build"));
+        assert_eq!(compiled, dir.join("build/compiled/orders.sql"));
+        assert_eq!(manifest, dir.join("build/manifest.json"));
+        assert_eq!(manifest, target.manifest_path());
+        assert_eq!(written, sql);
+    }
+
+    #[test]
+    fn test_layered_names_sort_dependencies_first() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("q1", "from arcana | filter source != 'necronomicron'"),
+            RawQuery::new("q2", "from rituals | join side:inner q1 [==source]"),
+            RawQuery::new("q3", "from q2 | filter something == 'blah'"),
+        ]);
+        let names = layered_names(&GraphMeta::new(collection).unwrap());
+        let name = |node: &str| names[&QueryName::from(node)].as_str();
+
+        assert_eq!(name("arcana"), "000_arcana");
+        assert_eq!(name("q1"), "001_q1");
+        assert_eq!(name("q3"), "003_q3");
+        assert!(name("q1") > name("arcana"));
+        assert!(name("q2") > name("rituals") && name("q2") > name("q1"));
+        assert!(name("q3") > name("q2"));
+    }
+
+    #[test]
+    fn test_clean_removes_only_the_given_targets() {
+        let dir = std::env::temp_dir().join(format!("orbital-clean-{}", std::process::id()));
+        let (build, logs, models) = (dir.join("build"), dir.join("logs"), dir.join("models"));
+        for sub in [&build, &logs, &models] {
+            fs::create_dir_all(sub.join("nested")).unwrap();
+            fs::write(sub.join("nested/file"), "x").unwrap();
+        }
+        let removed = clean(&[&build, &logs, &dir.join("missing")]).unwrap();
+        let models_kept = models.join("nested/file").exists();
+        let gone = !build.exists() && !logs.exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(removed, vec![build, logs]);
+        assert!(gone);
+        assert!(models_kept);
+    }
+}
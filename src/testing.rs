@@ -0,0 +1,369 @@
+use crate::backend::{Backend, BackendError};
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+/// A [`Backend`] that runs nothing. It records every statement in the order it arrived and
+/// remembers which relations were created or dropped, so `probe_table` answers as a real
+/// warehouse would. Useful for exercising the executor, and for trying a project without one.
+#[derive(Debug, Default)]
+pub struct InMemoryBackend {
+    executed: Vec<String>,
+    relations: BTreeSet<String>, // `database.schema.table`, unquoted
+}
+
+impl InMemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts out with `database.schema.table` already present, e.g. a source.
+    pub fn with_relation(mut self, database: &str, schema: &str, table: &str) -> Self {
+        self.relations
+            .insert(format!("{database}.{schema}.{table}"));
+        self
+    }
+
+    /// Every statement run so far, oldest first.
+    pub fn executed(&self) -> &[String] {
+        &self.executed
+    }
+
+    /// Relations that currently exist, sorted.
+    pub fn relations(&self) -> impl Iterator<Item = &str> {
+        self.relations.iter().map(String::as_str)
+    }
+}
+
+// The relation a `CREATE [OR REPLACE] TABLE|VIEW [IF NOT EXISTS] <name>` or
+// `DROP TABLE|VIEW [IF EXISTS] <name>` statement acts on, with quotes stripped.
+fn ddl_target(sql: &str) -> Option<(bool, String)> {
+    let words: Vec<_> = sql.split_whitespace().collect();
+    let upper: Vec<_> = words.iter().map(|w| w.to_ascii_uppercase()).collect();
+    let created = match upper.first()?.as_str() {
+        "CREATE" => true,
+        "DROP" => false,
+        _ => return None,
+    };
+    let kind = upper.iter().position(|w| w == "TABLE" || w == "VIEW")?;
+    let mut name = kind + 1;
+    while upper
+        .get(name)
+        .is_some_and(|w| matches!(w.as_str(), "IF" | "NOT" | "EXISTS"))
+    {
+        name += 1;
+    }
+    let target = words.get(name)?.replace(['"', '`', '[', ']'], "");
+    Some((created, target))
+}
+
+impl Backend for InMemoryBackend {
+    fn adapter(&self) -> &str {
+        "memory"
+    }
+
+    fn execute(&mut self, sql: &str) -> Result<u64, BackendError> {
+        self.executed.push(sql.to_string());
+        match ddl_target(sql) {
+            Some((true, relation)) => {
+                self.relations.insert(relation);
+            }
+            Some((false, relation)) => {
+                self.relations.remove(&relation);
+            }
+            None => {}
+        }
+        Ok(0)
+    }
+
+    /// Every relation lives in the same map, whatever its database.
+    fn supports_cross_database(&self) -> bool {
+        true
+    }
+
+    /// There are no rows to count, so every count is zero.
+    fn fetch_count(&mut self, sql: &str) -> Result<u64, BackendError> {
+        self.executed.push(sql.to_string());
+        Ok(0)
+    }
+
+    /// There are no rows, so every value is null.
+    fn fetch_value(&mut self, sql: &str) -> Result<Option<String>, BackendError> {
+        self.executed.push(sql.to_string());
+        Ok(None)
+    }
+
+    fn probe_table(
+        &mut self,
+        database: &str,
+        schema: &str,
+        table: &str,
+    ) -> Result<bool, BackendError> {
+        Ok(self
+            .relations
+            .contains(&format!("{database}.{schema}.{table}")))
+    }
+
+    /// No columns are kept, so every column of an existing relation counts as present.
+    fn probe_column(
+        &mut self,
+        database: &str,
+        schema: &str,
+        table: &str,
+        _column: &str,
+    ) -> Result<bool, BackendError> {
+        self.probe_table(database, schema, table)
+    }
+}
+
+/// A [`Backend`] that answers however a test sets it up to. Clones share what they record,
+/// so backends handed out by a factory or pool can still be checked once they're gone.
+#[derive(Debug, Clone)]
+pub struct StubBackend {
+    adapter: String,
+    log: Arc<Mutex<Vec<String>>>, // Every statement and query, oldest first
+    running: Arc<AtomicUsize>,
+    peak: Arc<AtomicUsize>,        // Most statements running at once
+    failing: Vec<(String, bool)>,  // Statements containing the pattern fail; once, if set
+    slow: Vec<(String, Duration)>, // Statements containing the pattern take this long
+    rows: u64,
+    count: u64,
+    values: Vec<(String, Result<Option<String>, BackendError>)>,
+    relations: BTreeSet<String>, // `database.schema.table`
+    columns: BTreeSet<String>,   // `database.schema.table.column`
+    health_checked: bool,
+}
+
+impl StubBackend {
+    /// Runs everything, affecting no rows, and counts every table as empty.
+    pub fn new(adapter: &str) -> Self {
+        Self {
+            adapter: adapter.to_string(),
+            log: Arc::default(),
+            running: Arc::default(),
+            peak: Arc::default(),
+            failing: Vec::new(),
+            slow: Vec::new(),
+            rows: 0,
+            count: 0,
+            values: Vec::new(),
+            relations: BTreeSet::new(),
+            columns: BTreeSet::new(),
+            health_checked: false,
+        }
+    }
+
+    /// Reports itself as `adapter`, still sharing its log with the backend it came from.
+    pub fn with_adapter(mut self, adapter: &str) -> Self {
+        self.adapter = adapter.to_string();
+        self
+    }
+
+    /// Statements containing `pattern` fail.
+    pub fn failing_on(mut self, pattern: &str) -> Self {
+        self.failing.push((pattern.to_string(), false));
+        self
+    }
+
+    /// The next statement containing `pattern` fails; later ones run.
+    pub fn failing_once_on(mut self, pattern: &str) -> Self {
+        self.failing.push((pattern.to_string(), true));
+        self
+    }
+
+    /// Statements containing `pattern` take `duration`. An empty pattern slows every one.
+    pub fn slow_on(mut self, pattern: &str, duration: Duration) -> Self {
+        self.slow.push((pattern.to_string(), duration));
+        self
+    }
+
+    /// Each statement reports affecting `rows` rows.
+    pub fn with_rows(mut self, rows: u64) -> Self {
+        self.rows = rows;
+        self
+    }
+
+    /// Every count comes back as `count`.
+    pub fn with_count(mut self, count: u64) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Queries containing `pattern` return `value`. Other queries return no value.
+    pub fn with_value(mut self, pattern: &str, value: Result<Option<&str>, BackendError>) -> Self {
+        self.values
+            .push((pattern.to_string(), value.map(|v| v.map(String::from))));
+        self
+    }
+
+    /// `database.schema.table` exists.
+    pub fn with_relation(mut self, database: &str, schema: &str, table: &str) -> Self {
+        self.relations
+            .insert(format!("{database}.{schema}.{table}"));
+        self
+    }
+
+    /// `database.schema.table` exists and has each of `columns`.
+    pub fn with_columns(
+        mut self,
+        database: &str,
+        schema: &str,
+        table: &str,
+        columns: &[&str],
+    ) -> Self {
+        let relation = format!("{database}.{schema}.{table}");
+        self.columns
+            .extend(columns.iter().map(|column| format!("{relation}.{column}")));
+        self.relations.insert(relation);
+        self
+    }
+
+    /// Health checks run `SELECT 1` like a real backend's, rather than always passing
+    /// without a trace in the log.
+    pub fn health_checked(mut self) -> Self {
+        self.health_checked = true;
+        self
+    }
+
+    /// Every statement and query any clone has run, oldest first.
+    pub fn executed(&self) -> Vec<String> {
+        self.log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The most statements any clones had running at once.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+
+    fn record(&self, sql: &str) {
+        self.log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sql.to_string());
+    }
+}
+
+impl Backend for StubBackend {
+    fn adapter(&self) -> &str {
+        &self.adapter
+    }
+
+    fn execute(&mut self, sql: &str) -> Result<u64, BackendError> {
+        self.record(sql);
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(running, Ordering::SeqCst);
+        for (_, duration) in self.slow.iter().filter(|(p, _)| sql.contains(p.as_str())) {
+            std::thread::sleep(*duration);
+        }
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        match self
+            .failing
+            .iter()
+            .position(|(p, _)| sql.contains(p.as_str()))
+        {
+            Some(rule) => {
+                if self.failing[rule].1 {
+                    self.failing.remove(rule);
+                }
+                Err(BackendError::Execution(sql.to_string()))
+            }
+            None => Ok(self.rows),
+        }
+    }
+
+    fn fetch_count(&mut self, sql: &str) -> Result<u64, BackendError> {
+        self.record(sql);
+        Ok(self.count)
+    }
+
+    fn fetch_value(&mut self, sql: &str) -> Result<Option<String>, BackendError> {
+        self.record(sql);
+        self.values
+            .iter()
+            .find(|(pattern, _)| sql.contains(pattern.as_str()))
+            .map_or(Ok(None), |(_, value)| value.clone())
+    }
+
+    fn is_healthy(&mut self) -> bool {
+        !self.health_checked || self.execute("SELECT 1").is_ok()
+    }
+
+    fn probe_table(
+        &mut self,
+        database: &str,
+        schema: &str,
+        table: &str,
+    ) -> Result<bool, BackendError> {
+        Ok(self
+            .relations
+            .contains(&format!("{database}.{schema}.{table}")))
+    }
+
+    fn probe_column(
+        &mut self,
+        database: &str,
+        schema: &str,
+        table: &str,
+        column: &str,
+    ) -> Result<bool, BackendError> {
+        Ok(self
+            .columns
+            .contains(&format!("{database}.{schema}.{table}.{column}")))
+    }
+}
+
+#[cfg(test)]
+mod test_testing {
+    use super::*;
+    use crate::{
+        executor::{Executor, FailurePolicy},
+        query_graph::graph::QueryGraph,
+    };
+    use fnv::FnvHashMap;
+
+    #[test]
+    fn test_chain_is_created_in_dependency_order() {
+        // Source 0 feeds model 1, which feeds model 2.
+        let graph = QueryGraph::new_from_edges(vec![(1, 2), (0, 1)]).unwrap();
+        let statements: FnvHashMap<_, _> = [
+            (
+                2,
+                r#"CREATE TABLE "dev"."marts"."orders" AS SELECT * FROM dev.staging.stg_orders"#,
+            ),
+            (
+                1,
+                r#"CREATE TABLE "dev"."staging"."stg_orders" AS SELECT * FROM raw.shop.orders"#,
+            ),
+        ]
+        .into_iter()
+        .map(|(id, sql)| (id, vec![sql.to_string()]))
+        .collect();
+        let mut backend = InMemoryBackend::new().with_relation("raw", "shop", "orders");
+        assert!(!backend.probe_table("dev", "marts", "orders").unwrap());
+
+        let report = Executor::new(&mut backend, FailurePolicy::FailFast).run(&graph, &statements);
+
+        assert!(!report.has_failures());
+        assert_eq!(
+            backend.executed(),
+            [statements[&1][0].clone(), statements[&2][0].clone()]
+        );
+        assert!(backend.probe_table("dev", "staging", "stg_orders").unwrap());
+        assert!(backend.probe_table("dev", "marts", "orders").unwrap());
+        backend
+            .execute("DROP TABLE IF EXISTS dev.marts.orders")
+            .unwrap();
+        assert_eq!(
+            backend.relations().collect::<Vec<_>>(),
+            vec!["dev.staging.stg_orders", "raw.shop.orders"]
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{
+    query_graph::{
+        query::{QueryKind, QueryName},
+        GraphMeta,
+    },
+    settings::Project,
+};
+use smartstring::alias::String;
+use std::{collections::HashSet, fmt};
+
+/// Something worth telling the user about that doesn't stop a run. Variants are declared in
+/// display order, so the derived `Ord` sorts by category and then by name.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Warning {
+    /// A source listed more than once in the project file.
+    DuplicateSource(String),
+    /// A query that neither reads from nor feeds any other node.
+    OrphanQuery(String),
+    /// A model with the same name as a declared source. Every `from <name>` reads the model,
+    /// so the source can't be reached.
+    ShadowedSource(String),
+    /// An enabled source that no query reads from.
+    UnusedSource(String),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::DuplicateSource(name) => write!(f, "source {name} is listed more than once"),
+            Warning::OrphanQuery(name) => write!(f, "query {name} is connected to nothing"),
+            Warning::ShadowedSource(name) => {
+                write!(f, "model {name} shadows the source of the same name")
+            }
+            Warning::UnusedSource(name) => write!(f, "source {name} is never read from"),
+        }
+    }
+}
+
+/// Gathers warnings from every check so they can be reported together, in a stable order
+/// regardless of the hash-map iteration order they were found in.
+#[derive(Debug, Default)]
+pub struct WarningCollector {
+    warnings: Vec<Warning>,
+}
+
+impl WarningCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, warning: Warning) -> &mut Self {
+        self.warnings.push(warning);
+        self
+    }
+
+    /// Every warning pushed so far, sorted and with repeats removed.
+    pub fn sorted(&self) -> Vec<Warning> {
+        let mut warnings = self.warnings.clone();
+        warnings.sort();
+        warnings.dedup();
+        warnings
+    }
+
+    /// One `warning: ...` line per warning, in [`WarningCollector::sorted`] order.
+    pub fn render(&self) -> std::string::String {
+        self.sorted()
+            .iter()
+            .map(|warning| format!("warning: {warning}\n"))
+            .collect()
+    }
+
+    /// Runs the project-wide checks that need both the config and the parsed queries.
+    pub fn check_project(&mut self, project: &Project, meta: &GraphMeta) -> &mut Self {
+        let mut seen = HashSet::new();
+        for source in &project.sources {
+            let name = source.name.as_ref();
+            if !seen.insert(name) {
+                self.push(Warning::DuplicateSource(name.clone()));
+            }
+            match meta.collection().get(&QueryName::from(name.as_str())) {
+                Some(QueryKind::Query(_)) => {
+                    self.push(Warning::ShadowedSource(name.clone()));
+                }
+                None if source.enabled => {
+                    self.push(Warning::UnusedSource(name.clone()));
+                }
+                _ => {}
+            }
+        }
+        for name in meta.orphans_named() {
+            self.push(Warning::OrphanQuery(name.to_string().into()));
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod test_warning {
+    use super::*;
+    use crate::query_graph::query::{QueryCollection, RawQuery};
+    use figment::providers::{Format, Yaml};
+
+    fn project() -> Project {
+        let yaml = r#"
+name: shop
+version: "0.1.0"
+model_path: models
+seed_path: seeds
+clean_targets: target
+log_path: logs
+models: []
+seeds: []
+sources:
+  - { name: refunds, enabled: true }
+  - { name: orders, enabled: true }
+  - { name: archive, enabled: false }
+  - { name: customers, enabled: true }
+  - { name: refunds, enabled: true }
+"#;
+        figment::Figment::from(Yaml::string(yaml))
+            .extract::<Project>()
+            .unwrap()
+    }
+
+    fn meta() -> GraphMeta {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("stg_orders", "from orders"),
+            RawQuery::new("zz_stray", "from s\"SELECT 1 AS a\""),
+            RawQuery::new("aa_stray", "from s\"SELECT 2 AS b\""),
+        ]);
+        GraphMeta::new(collection).unwrap()
+    }
+
+    #[test]
+    fn test_model_named_after_source_is_reported() {
+        let mut collection = QueryCollection::new();
+        collection.add_queries(vec![
+            RawQuery::new("orders", "from raw_orders"),
+            RawQuery::new("order_totals", "from orders"),
+        ]);
+        let warnings = WarningCollector::new()
+            .check_project(&project(), &GraphMeta::new(collection).unwrap())
+            .sorted();
+        assert!(warnings.contains(&Warning::ShadowedSource(String::from("orders"))));
+        assert!(!warnings.contains(&Warning::UnusedSource(String::from("orders"))));
+        assert!(warnings.contains(&Warning::UnusedSource(String::from("refunds"))));
+    }
+
+    #[test]
+    fn test_warnings_are_sorted_and_stable() {
+        let render = || {
+            WarningCollector::new()
+                .check_project(&project(), &meta())
+                .render()
+        };
+        let first = render();
+        assert_eq!(first, render());
+        assert_eq!(
+            first,
+            "warning: source refunds is listed more than once\n\
+             warning: query aa_stray is connected to nothing\n\
+             warning: query zz_stray is connected to nothing\n\
+             warning: source customers is never read from\n\
+             warning: source refunds is never read from\n"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use crate::executor::{NodeStatus, RunReport};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub const WATERMARK_FILE_NAME: &str = "watermarks.json";
+
+/// The last watermark each incremental model merged up to, kept in a file under `log_path` so
+/// a retried run carries on from the last successful one instead of reprocessing or skipping
+/// rows.
+#[derive(Debug)]
+pub struct WatermarkStore {
+    path: PathBuf,
+    watermarks: BTreeMap<String, String>,
+}
+
+impl WatermarkStore {
+    /// Reads the store in `dir`, starting empty if nothing has been recorded yet.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let path = dir.as_ref().join(WATERMARK_FILE_NAME);
+        let watermarks = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, watermarks })
+    }
+
+    pub fn get(&self, model: &str) -> Option<&str> {
+        self.watermarks.get(model).map(String::as_str)
+    }
+
+    /// Records `watermark` for `model` and writes the store out. The file is replaced whole,
+    /// so a run killed mid-write leaves the previous watermarks intact.
+    pub fn set(&mut self, model: &str, watermark: &str) -> io::Result<()> {
+        self.watermarks
+            .insert(model.to_string(), watermark.to_string());
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let staged = self.path.with_extension("json.tmp");
+        fs::write(&staged, serde_json::to_string_pretty(&self.watermarks)?)?;
+        fs::rename(&staged, &self.path)
+    }
+
+    /// [`WatermarkStore::set`], but only if `node_id` succeeded in `report`. Returns whether
+    /// the watermark moved.
+    pub fn advance(
+        &mut self,
+        report: &RunReport,
+        node_id: u64,
+        model: &str,
+        watermark: &str,
+    ) -> io::Result<bool> {
+        if report.status(node_id) != Some(&NodeStatus::Succeeded) {
+            return Ok(false);
+        }
+        self.set(model, watermark)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test_watermark {
+    use super::*;
+
+    fn report(status: NodeStatus) -> RunReport {
+        let mut report = RunReport::default();
+        report.statuses.insert(1, status);
+        report
+    }
+
+    #[test]
+    fn test_watermark_only_advances_on_success() {
+        let dir = std::env::temp_dir().join(format!("orbital-watermark-{}", std::process::id()));
+        let mut store = WatermarkStore::open(&dir).unwrap();
+        assert_eq!(store.get("orders"), None);
+        store.set("orders", "2024-03-01").unwrap();
+
+        let failed = report(NodeStatus::Failed("statement failed".to_string()));
+        assert!(!store.advance(&failed, 1, "orders", "2024-03-02").unwrap());
+        assert!(!store
+            .advance(&report(NodeStatus::Skipped), 1, "orders", "2024-03-02")
+            .unwrap());
+        assert_eq!(
+            WatermarkStore::open(&dir).unwrap().get("orders"),
+            Some("2024-03-01")
+        );
+
+        assert!(store
+            .advance(&report(NodeStatus::Succeeded), 1, "orders", "2024-03-02")
+            .unwrap());
+        let reopened = WatermarkStore::open(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(reopened.get("orders"), Some("2024-03-02"));
+    }
+}